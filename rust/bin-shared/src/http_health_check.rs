@@ -1,33 +1,121 @@
-use axum::http::StatusCode;
+use axum::http::{header, StatusCode};
 use axum::routing::get;
 use axum::Router;
+use std::fmt::{Display, Write as _};
 use std::net::SocketAddr;
 
+/// The content type of the Prometheus text exposition format.
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
 /// Runs an HTTP server that responds to `GET /healthz` with 200 OK or 400 BAD REQUEST, depending on the return value of `is_healthy`.
 pub async fn serve(
     addr: impl Into<SocketAddr>,
     is_healthy: impl Fn() -> bool + Clone + Send + Sync + 'static,
 ) -> std::io::Result<()> {
-    let addr = addr.into();
-
-    let service = Router::new()
-        .route(
-            "/healthz",
-            get(move || async move {
-                if is_healthy() {
-                    StatusCode::OK
-                } else {
-                    StatusCode::BAD_REQUEST
-                }
-            }),
-        )
-        .into_make_service();
+    serve_router(addr.into(), health_router(is_healthy)).await
+}
+
+/// Like [`serve`] but additionally responds to `GET /metrics` with the output of `render_metrics`.
+///
+/// `render_metrics` is expected to return metrics in the Prometheus text format, see [`PrometheusText`].
+pub async fn serve_with_metrics(
+    addr: impl Into<SocketAddr>,
+    is_healthy: impl Fn() -> bool + Clone + Send + Sync + 'static,
+    render_metrics: impl Fn() -> String + Clone + Send + Sync + 'static,
+) -> std::io::Result<()> {
+    let router = health_router(is_healthy).route(
+        "/metrics",
+        get(move || async move {
+            (
+                [(header::CONTENT_TYPE, PROMETHEUS_CONTENT_TYPE)],
+                render_metrics(),
+            )
+        }),
+    );
 
-    axum::serve(tokio::net::TcpListener::bind(addr).await?, service).await?;
+    serve_router(addr.into(), router).await
+}
+
+fn health_router(is_healthy: impl Fn() -> bool + Clone + Send + Sync + 'static) -> Router {
+    Router::new().route(
+        "/healthz",
+        get(move || async move {
+            if is_healthy() {
+                StatusCode::OK
+            } else {
+                StatusCode::BAD_REQUEST
+            }
+        }),
+    )
+}
+
+async fn serve_router(addr: SocketAddr, router: Router) -> std::io::Result<()> {
+    axum::serve(
+        tokio::net::TcpListener::bind(addr).await?,
+        router.into_make_service(),
+    )
+    .await?;
 
     Ok(())
 }
 
+/// Renders metrics in the [Prometheus text format](https://prometheus.io/docs/instrumenting/exposition_formats/#text-based-format).
+#[derive(Debug, Default)]
+pub struct PrometheusText {
+    out: String,
+}
+
+impl PrometheusText {
+    /// Starts a new metric family of the given type, e.g. `gauge` or `counter`.
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) -> &mut Self {
+        let _ = writeln!(self.out, "# HELP {name} {help}");
+        let _ = writeln!(self.out, "# TYPE {name} {kind}");
+
+        self
+    }
+
+    /// Adds a sample to the current metric family.
+    pub fn sample(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        value: impl Display,
+    ) -> &mut Self {
+        self.out.push_str(name);
+
+        if !labels.is_empty() {
+            self.out.push('{');
+            for (i, (key, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.out.push(',');
+                }
+                let _ = write!(self.out, "{key}=\"{}\"", escape_label_value(value));
+            }
+            self.out.push('}');
+        }
+
+        let _ = writeln!(self.out, " {value}");
+
+        self
+    }
+
+    /// Adds a metric family with a single, unlabelled sample.
+    pub fn single(&mut self, name: &str, kind: &str, help: &str, value: impl Display) -> &mut Self {
+        self.family(name, kind, help).sample(name, &[], value)
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
 #[derive(clap::Args, Debug, Clone)]
 pub struct HealthCheckArgs {
     /// The address of the local interface where we should serve our health-check endpoint.
@@ -35,4 +123,29 @@ pub struct HealthCheckArgs {
     /// The actual health-check endpoint will be at `http://<health_check_addr>/healthz`.
     #[arg(long, env, hide = true, default_value = "0.0.0.0:8080")]
     pub health_check_addr: SocketAddr,
+
+    /// Expose metrics in the Prometheus text format at `http://<health_check_addr>/metrics`.
+    #[arg(long, env = "FIREZONE_METRICS", default_value_t = false)]
+    pub metrics: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let mut text = PrometheusText::default();
+        text.single("up", "gauge", "Whether we are up.", 1);
+        text.family("bytes_total", "counter", "Bytes sent.").sample(
+            "bytes_total",
+            &[("peer", "a\"b")],
+            42,
+        );
+
+        assert_eq!(
+            text.finish(),
+            "# HELP up Whether we are up.\n# TYPE up gauge\nup 1\n# HELP bytes_total Bytes sent.\n# TYPE bytes_total counter\nbytes_total{peer=\"a\\\"b\"} 42\n"
+        );
+    }
 }
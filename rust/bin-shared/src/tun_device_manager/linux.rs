@@ -77,6 +77,19 @@ impl TunDeviceManager {
         Ok(Tun::new()?)
     }
 
+    /// Creates the tunnel device with `num_queues` queues.
+    ///
+    /// Each queue has its own file descriptor and can be read from and written to independently.
+    /// The kernel steers the packets of a flow to the queue that we last wrote a packet of this flow to.
+    pub fn make_tun_queues(&mut self, num_queues: usize) -> Result<Vec<Tun>> {
+        let queues = (0..num_queues)
+            .map(|_| Tun::new_queue())
+            .collect::<io::Result<Vec<_>>>()
+            .context("Failed to open TUN queue")?;
+
+        Ok(queues)
+    }
+
     #[tracing::instrument(level = "trace", skip(self))]
     pub async fn set_ips(&mut self, ipv4: Ipv4Addr, ipv6: Ipv6Addr) -> Result<()> {
         let name = Self::IFACE_NAME;
@@ -289,6 +302,20 @@ pub struct Tun {
 
 impl Tun {
     pub fn new() -> io::Result<Self> {
+        Self::open(ioctl::Request::<ioctl::SetTunFlagsPayload>::new(
+            TunDeviceManager::IFACE_NAME,
+        ))
+    }
+
+    /// Opens a new queue of the multi-queue TUN device.
+    pub fn new_queue() -> io::Result<Self> {
+        Self::open(
+            ioctl::Request::<ioctl::SetTunFlagsPayload>::new(TunDeviceManager::IFACE_NAME)
+                .with_multi_queue(),
+        )
+    }
+
+    fn open(mut request: ioctl::Request<ioctl::SetTunFlagsPayload>) -> io::Result<Self> {
         create_tun_device()?;
 
         let fd = match unsafe { open(TUN_FILE.as_ptr() as _, O_RDWR) } {
@@ -297,12 +324,11 @@ impl Tun {
         };
 
         // Safety: We just opened the file descriptor.
-        unsafe {
-            ioctl::exec(
-                fd,
-                TUNSETIFF,
-                &mut ioctl::Request::<ioctl::SetTunFlagsPayload>::new(TunDeviceManager::IFACE_NAME),
-            )?;
+        if let Err(e) = unsafe { ioctl::exec(fd, TUNSETIFF, &mut request) } {
+            // Safety: We just opened the file descriptor and nobody else owns it.
+            unsafe { close(fd) };
+
+            return Err(e);
         }
 
         set_non_blocking(fd)?;
@@ -9,7 +9,9 @@ use connlib_client_shared::{
     keypair, Callbacks, ConnectArgs, DisconnectError, LoginUrl, LoginUrlError, Session,
     V4RouteList, V6RouteList,
 };
-use connlib_shared::{callbacks::ResourceDescription, get_user_agent, messages::ResourceId};
+use connlib_shared::{
+    callbacks::ResourceDescription, get_user_agent, messages::ResourceId, DomainName,
+};
 use ip_network::{Ipv4Network, Ipv6Network};
 use jni::{
     objects::{GlobalRef, JClass, JObject, JString, JValue},
@@ -158,6 +160,7 @@ impl Callbacks for CallbackHandler {
         tunnel_address_v4: Ipv4Addr,
         tunnel_address_v6: Ipv6Addr,
         dns_addresses: Vec<IpAddr>,
+        search_domains: Vec<DomainName>,
     ) {
         self.env(|mut env| {
             let tunnel_address_v4 =
@@ -178,15 +181,27 @@ impl Callbacks for CallbackHandler {
                     name: "dns_addresses",
                     source,
                 })?;
+            let search_domains = env
+                .new_string(serde_json::to_string(
+                    &search_domains
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>(),
+                )?)
+                .map_err(|source| CallbackError::NewStringFailed {
+                    name: "search_domains",
+                    source,
+                })?;
             let name = "onSetInterfaceConfig";
             env.call_method(
                 &self.callback_handler,
                 name,
-                "(Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;)V",
+                "(Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;)V",
                 &[
                     JValue::from(&tunnel_address_v4),
                     JValue::from(&tunnel_address_v6),
                     JValue::from(&dns_addresses),
+                    JValue::from(&search_domains),
                 ],
             )
             .map_err(|source| CallbackError::CallMethodFailed { name, source })?;
@@ -383,6 +398,7 @@ fn connect(
             .with_max_elapsed_time(Some(MAX_PARTITION_TIME))
             .build(),
         tcp_socket_factory,
+        None,
     )?;
     let session = Session::connect(args, portal, runtime.handle().clone());
 
@@ -9,7 +9,7 @@ use backoff::ExponentialBackoffBuilder;
 use connlib_client_shared::{
     keypair, Callbacks, ConnectArgs, DisconnectError, LoginUrl, Session, V4RouteList, V6RouteList,
 };
-use connlib_shared::{callbacks::ResourceDescription, get_user_agent};
+use connlib_shared::{callbacks::ResourceDescription, get_user_agent, DomainName};
 use ip_network::{Ipv4Network, Ipv6Network};
 use phoenix_channel::PhoenixChannel;
 use secrecy::{Secret, SecretString};
@@ -73,6 +73,7 @@ mod ffi {
             tunnelAddressIPv4: String,
             tunnelAddressIPv6: String,
             dnsAddresses: String,
+            searchDomains: String,
         );
 
         #[swift_bridge(swift_name = "onUpdateRoutes")]
@@ -116,12 +117,20 @@ impl Callbacks for CallbackHandler {
         tunnel_address_v4: Ipv4Addr,
         tunnel_address_v6: Ipv6Addr,
         dns_addresses: Vec<IpAddr>,
+        search_domains: Vec<DomainName>,
     ) {
+        let search_domains = search_domains
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+
         self.inner.on_set_interface_config(
             tunnel_address_v4.to_string(),
             tunnel_address_v6.to_string(),
             serde_json::to_string(&dns_addresses)
                 .expect("developer error: a list of ips should always be serializable"),
+            serde_json::to_string(&search_domains)
+                .expect("developer error: a list of strings should always be serializable"),
         );
     }
 
@@ -220,6 +229,7 @@ impl WrappedSession {
                 .with_max_elapsed_time(Some(MAX_PARTITION_TIME))
                 .build(),
             Arc::new(socket_factory::tcp),
+            None,
         )?;
         let session = Session::connect(args, portal, runtime.handle().clone());
         session.set_tun(Box::new(Tun::new()?));
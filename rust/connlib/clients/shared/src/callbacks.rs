@@ -1,5 +1,9 @@
-use connlib_shared::callbacks::ResourceDescription;
+use connlib_shared::callbacks::{DnsResolution, ResourceDescription};
+use connlib_shared::messages::{GatewayId, ResourceId};
+use connlib_shared::DomainName;
+use firezone_tunnel::{CacheStats, ConnectionPath, ConnectionStats};
 use ip_network::{Ipv4Network, Ipv6Network};
+use std::collections::{BTreeMap, BTreeSet};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 /// Traits that will be used by connlib to callback the client upper layers.
@@ -9,7 +13,16 @@ pub trait Callbacks: Clone + Send + Sync {
     /// The first time this is called, the Resources list is also ready,
     /// the routes are also ready, and the Client can consider the tunnel
     /// to be ready for incoming traffic.
-    fn on_set_interface_config(&self, _: Ipv4Addr, _: Ipv6Addr, _: Vec<IpAddr>) {}
+    ///
+    /// The search domains should be appended to single-label names by the system's resolver.
+    fn on_set_interface_config(
+        &self,
+        _: Ipv4Addr,
+        _: Ipv6Addr,
+        _: Vec<IpAddr>,
+        _: Vec<DomainName>,
+    ) {
+    }
 
     /// Called when the route list changes.
     fn on_update_routes(&self, _: Vec<Ipv4Network>, _: Vec<Ipv6Network>) {}
@@ -21,6 +34,25 @@ pub trait Callbacks: Clone + Send + Sync {
     /// or if all Resources for a user are disabled by policy.
     fn on_update_resources(&self, _: Vec<ResourceDescription>) {}
 
+    /// Called for every DNS query that connlib answers or forwards.
+    ///
+    /// Intended for debugging, e.g. to find out which proxy IPs an application got for a resource.
+    fn on_dns_resolution(&self, _: DnsResolution) {}
+
+    /// Called periodically with the statistics of our connections to gateways.
+    ///
+    /// Intended for debugging, e.g. to tell whether a connection is relayed.
+    fn on_connection_stats(&self, _: BTreeMap<GatewayId, ConnectionStats>) {}
+
+    /// Called periodically with how many forwarded DNS queries were answered from the cache.
+    fn on_dns_cache_stats(&self, _: CacheStats) {}
+
+    /// Called when the path to a gateway changes, e.g. from relayed to direct.
+    ///
+    /// The resources are the ones we access through this gateway.
+    fn on_connection_path_changed(&self, _: GatewayId, _: BTreeSet<ResourceId>, _: ConnectionPath) {
+    }
+
     /// Called when the tunnel is disconnected.
     ///
     /// If the tunnel disconnected due to a fatal error, `error` is the error
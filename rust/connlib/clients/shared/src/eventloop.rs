@@ -8,27 +8,39 @@ use crate::{
 };
 use anyhow::Result;
 use connlib_shared::messages::{
-    ClientPayload, ConnectionAccepted, GatewayResponse, RelaysPresence, RequestConnection,
-    ResourceAccepted, ResourceId, ReuseConnection,
+    ClientPayload, ConnectionAccepted, GatewayResponse, Relay, RelayId, RelaysPresence,
+    RequestConnection, ResourceAccepted, ResourceId, ReuseConnection,
 };
 use firezone_tunnel::ClientTunnel;
-use phoenix_channel::{ErrorReply, OutboundRequestId, PhoenixChannel};
+use phoenix_channel::{ControlPlane, ErrorReply, OutboundRequestId};
 use std::{
     collections::{BTreeMap, BTreeSet},
     io,
     net::IpAddr,
     task::{Context, Poll},
+    time::Duration,
 };
 use tun::Tun;
 
-pub struct Eventloop<C: Callbacks> {
+/// How often we report the statistics of our connections to gateways.
+const CONNECTION_STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct Eventloop<C: Callbacks, P> {
     tunnel: ClientTunnel,
     callbacks: C,
 
-    portal: PhoenixChannel<(), IngressMessages, ReplyMessages>,
+    portal: P,
     rx: tokio::sync::mpsc::UnboundedReceiver<Command>,
 
     connection_intents: SentConnectionIntents,
+
+    /// The relays the portal told us to use.
+    ///
+    /// The portal sends a new [`InitClient`] every time we re-join, e.g. after a flaky link.
+    /// Tracking the relays allows us to diff it against our current state instead of starting over.
+    relays: BTreeSet<RelayId>,
+
+    connection_stats_interval: tokio::time::Interval,
 }
 
 /// Commands that can be sent to the [`Eventloop`].
@@ -36,30 +48,34 @@ pub enum Command {
     Stop,
     Reset,
     SetDns(Vec<IpAddr>),
+    SetDnsResourceTtl(Duration),
     SetTun(Box<dyn Tun>),
     SetDisabledResources(BTreeSet<ResourceId>),
 }
 
-impl<C: Callbacks> Eventloop<C> {
+impl<C: Callbacks, P> Eventloop<C, P> {
     pub(crate) fn new(
         tunnel: ClientTunnel,
         callbacks: C,
-        portal: PhoenixChannel<(), IngressMessages, ReplyMessages>,
+        portal: P,
         rx: tokio::sync::mpsc::UnboundedReceiver<Command>,
     ) -> Self {
         Self {
             tunnel,
             portal,
             connection_intents: SentConnectionIntents::default(),
+            relays: BTreeSet::default(),
             rx,
             callbacks,
+            connection_stats_interval: tokio::time::interval(CONNECTION_STATS_INTERVAL),
         }
     }
 }
 
-impl<C> Eventloop<C>
+impl<C, P> Eventloop<C, P>
 where
     C: Callbacks + 'static,
+    P: ControlPlane<IngressMessages, ReplyMessages>,
 {
     pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), phoenix_channel::Error>> {
         loop {
@@ -70,6 +86,10 @@ where
 
                     continue;
                 }
+                Poll::Ready(Some(Command::SetDnsResourceTtl(ttl))) => {
+                    self.tunnel.set_dns_resource_ttl(ttl);
+                    continue;
+                }
                 Poll::Ready(Some(Command::SetDisabledResources(resources))) => {
                     self.tunnel.set_disabled_resources(resources);
                     continue;
@@ -110,6 +130,19 @@ where
                 Poll::Pending => {}
             }
 
+            if self.connection_stats_interval.poll_tick(cx).is_ready() {
+                let stats = self.tunnel.connection_stats();
+
+                if !stats.is_empty() {
+                    self.callbacks.on_connection_stats(stats);
+                }
+
+                self.callbacks
+                    .on_dns_cache_stats(self.tunnel.dns_cache_stats());
+
+                continue;
+            }
+
             return Poll::Pending;
         }
     }
@@ -149,7 +182,7 @@ where
                 resource,
                 ..
             } => {
-                let id = self.portal.send(
+                let id = self.portal.send_reliable(
                     PHOENIX_TOPIC,
                     EgressMessages::PrepareConnection {
                         resource_id: resource,
@@ -163,14 +196,20 @@ where
                 gateway_id,
                 maybe_domain,
             } => {
-                self.portal.send(
-                    PHOENIX_TOPIC,
-                    EgressMessages::ReuseConnection(ReuseConnection {
-                        resource_id,
-                        gateway_id,
-                        payload: maybe_domain,
-                    }),
-                );
+                // The portal only replies if it has to forward a domain to the gateway.
+                // Without a reply, a reliable message would be re-sent after every reconnect.
+                let expects_reply = maybe_domain.is_some();
+                let msg = EgressMessages::ReuseConnection(ReuseConnection {
+                    resource_id,
+                    gateway_id,
+                    payload: maybe_domain,
+                });
+
+                if expects_reply {
+                    self.portal.send_reliable(PHOENIX_TOPIC, msg);
+                } else {
+                    self.portal.send(PHOENIX_TOPIC, msg);
+                }
             }
             firezone_tunnel::ClientEvent::ResourcesChanged { resources } => {
                 self.callbacks.on_update_resources(resources)
@@ -178,13 +217,31 @@ where
             firezone_tunnel::ClientEvent::TunInterfaceUpdated(config) => {
                 let dns_servers = config.dns_by_sentinel.left_values().copied().collect();
 
-                self.callbacks
-                    .on_set_interface_config(config.ip4, config.ip6, dns_servers);
+                self.callbacks.on_set_interface_config(
+                    config.ip4,
+                    config.ip6,
+                    dns_servers,
+                    config.search_domains,
+                );
                 self.callbacks.on_update_routes(
                     Vec::from_iter(config.ipv4_routes),
                     Vec::from_iter(config.ipv6_routes),
                 );
             }
+            firezone_tunnel::ClientEvent::DnsResolved(resolution) => {
+                self.callbacks.on_dns_resolution(resolution);
+            }
+            firezone_tunnel::ClientEvent::ConnectionPathChanged {
+                gateway_id,
+                resources,
+                old,
+                new,
+            } => {
+                tracing::debug!(%gateway_id, ?old, ?new, "Connection path changed");
+
+                self.callbacks
+                    .on_connection_path_changed(gateway_id, resources, new);
+            }
             firezone_tunnel::ClientEvent::RequestConnection {
                 gateway_id,
                 offer,
@@ -192,7 +249,7 @@ where
                 resource_id,
                 maybe_domain,
             } => {
-                self.portal.send(
+                self.portal.send_reliable(
                     PHOENIX_TOPIC,
                     EgressMessages::RequestConnection(RequestConnection {
                         gateway_id,
@@ -248,9 +305,12 @@ where
                 resources,
                 relays,
             }) => {
+                let stale_relays = replace_relays(&mut self.relays, &relays);
+
+                // All of these are diffed against the current state, so a repeated `Init` doesn't disrupt existing connections.
                 self.tunnel.set_new_interface_config(interface);
                 self.tunnel.set_resources(resources);
-                self.tunnel.update_relays(BTreeSet::default(), relays);
+                self.tunnel.update_relays(stale_relays, relays);
 
                 tracing::info!("Firezone Started!");
             }
@@ -263,9 +323,15 @@ where
             IngressMessages::RelaysPresence(RelaysPresence {
                 disconnected_ids,
                 connected,
-            }) => self
-                .tunnel
-                .update_relays(BTreeSet::from_iter(disconnected_ids), connected),
+            }) => {
+                for id in &disconnected_ids {
+                    self.relays.remove(id);
+                }
+                self.relays.extend(connected.iter().map(|r| r.id()));
+
+                self.tunnel
+                    .update_relays(BTreeSet::from_iter(disconnected_ids), connected)
+            }
             IngressMessages::InvalidateIceCandidates(GatewayIceCandidates {
                 gateway_id,
                 candidates,
@@ -358,6 +424,15 @@ where
     }
 }
 
+/// Replaces the relays we know about with the given ones, returning the ones that are no longer present.
+fn replace_relays(current: &mut BTreeSet<RelayId>, relays: &[Relay]) -> BTreeSet<RelayId> {
+    let relay_ids = relays.iter().map(|r| r.id()).collect::<BTreeSet<_>>();
+    let stale_relays = current.difference(&relay_ids).copied().collect();
+    *current = relay_ids;
+
+    stale_relays
+}
+
 #[derive(Default)]
 struct SentConnectionIntents {
     inner: BTreeMap<OutboundRequestId, ResourceId>,
@@ -407,6 +482,57 @@ impl SentConnectionIntents {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use connlib_shared::{messages::Interface, DomainName};
+    use phoenix_channel::LoopbackRequest;
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr},
+        sync::Arc,
+    };
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn applies_interface_config_from_init() {
+        let (portal, mut loopback_portal) =
+            phoenix_channel::loopback::<IngressMessages, ReplyMessages>(PHOENIX_TOPIC, ());
+        let (interface_tx, mut interface_rx) = mpsc::unbounded_channel();
+        let (_cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let tunnel = ClientTunnel::new(
+            firezone_tunnel::keypair().0,
+            Arc::new(socket_factory::tcp),
+            Arc::new(socket_factory::udp),
+            BTreeMap::new(),
+        );
+        let mut eventloop =
+            Eventloop::new(tunnel, InterfaceCallbacks(interface_tx), portal, cmd_rx);
+
+        assert!(matches!(
+            loopback_portal.next_request().await,
+            Some(LoopbackRequest::Join { topic, .. }) if topic == PHOENIX_TOPIC
+        ));
+        loopback_portal.joined(PHOENIX_TOPIC);
+        loopback_portal.push(
+            PHOENIX_TOPIC,
+            IngressMessages::Init(InitClient {
+                interface: Interface {
+                    ipv4: Ipv4Addr::new(100, 64, 0, 1),
+                    ipv6: "fd00:2021:1111::1".parse().unwrap(),
+                    upstream_dns: Vec::new(),
+                    search_domains: Vec::new(),
+                },
+                resources: Vec::new(),
+                relays: Vec::new(),
+            }),
+        );
+
+        let ipv4 = tokio::select! {
+            ipv4 = interface_rx.recv() => ipv4.unwrap(),
+            result = std::future::poll_fn(|cx| eventloop.poll(cx)) => {
+                panic!("Eventloop exited: {result:?}")
+            }
+        };
+
+        assert_eq!(ipv4, Ipv4Addr::new(100, 64, 0, 1));
+    }
 
     #[test]
     fn discards_old_connection_intent() {
@@ -459,4 +585,41 @@ mod tests {
         assert!(should_accept_2);
         assert!(!should_accept_1);
     }
+
+    #[test]
+    fn repeated_init_only_removes_stale_relays() {
+        let mut relays = BTreeSet::default();
+
+        let stale = replace_relays(&mut relays, &[stun(1), stun(2)]);
+        assert!(stale.is_empty());
+
+        let stale = replace_relays(&mut relays, &[stun(2), stun(3)]);
+        assert_eq!(stale, BTreeSet::from([RelayId::from_u128(1)]));
+        assert_eq!(
+            relays,
+            BTreeSet::from([RelayId::from_u128(2), RelayId::from_u128(3)])
+        );
+    }
+
+    #[derive(Clone)]
+    struct InterfaceCallbacks(mpsc::UnboundedSender<Ipv4Addr>);
+
+    impl Callbacks for InterfaceCallbacks {
+        fn on_set_interface_config(
+            &self,
+            ipv4: Ipv4Addr,
+            _: Ipv6Addr,
+            _: Vec<IpAddr>,
+            _: Vec<DomainName>,
+        ) {
+            let _ = self.0.send(ipv4);
+        }
+    }
+
+    fn stun(id: u128) -> Relay {
+        Relay::Stun(connlib_shared::messages::Stun {
+            id: RelayId::from_u128(id),
+            addr: "203.0.113.1:3478".parse().unwrap(),
+        })
+    }
 }
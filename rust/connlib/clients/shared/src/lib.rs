@@ -4,24 +4,25 @@ pub use callbacks::{Callbacks, DisconnectError};
 pub use connlib_shared::messages::client::ResourceDescription;
 pub use connlib_shared::{LoginUrl, LoginUrlError, StaticSecret};
 pub use eventloop::Eventloop;
-pub use firezone_tunnel::keypair;
+pub use firezone_tunnel::{keypair, CacheStats, ConnectionPath, ConnectionStats};
 
 use connlib_shared::messages::ResourceId;
 use eventloop::Command;
 use firezone_tunnel::ClientTunnel;
 use messages::{IngressMessages, ReplyMessages};
-use phoenix_channel::PhoenixChannel;
+use phoenix_channel::ControlPlane;
 use socket_factory::{SocketFactory, TcpSocket, UdpSocket};
 use std::collections::{BTreeMap, BTreeSet};
 use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::task::JoinHandle;
 use tun::Tun;
 
 mod callbacks;
 mod eventloop;
-mod messages;
+pub mod messages;
 mod serde_routelist;
 
 const PHOENIX_TOPIC: &str = "client";
@@ -46,11 +47,13 @@ impl Session {
     /// Creates a new [`Session`].
     ///
     /// This connects to the portal a specified using [`LoginUrl`] and creates a wireguard tunnel using the provided private key.
-    pub fn connect<CB: Callbacks + 'static>(
-        args: ConnectArgs<CB>,
-        portal: PhoenixChannel<(), IngressMessages, ReplyMessages>,
-        handle: tokio::runtime::Handle,
-    ) -> Self {
+    ///
+    /// Usually, the `portal` is a [`PhoenixChannel`](phoenix_channel::PhoenixChannel) but any [`ControlPlane`] works, e.g. a [`Loopback`](phoenix_channel::Loopback) in tests.
+    pub fn connect<CB, P>(args: ConnectArgs<CB>, portal: P, handle: tokio::runtime::Handle) -> Self
+    where
+        CB: Callbacks + 'static,
+        P: ControlPlane<IngressMessages, ReplyMessages> + Send + 'static,
+    {
         let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
 
         let callbacks = args.callbacks.clone();
@@ -94,6 +97,11 @@ impl Session {
         let _ = self.channel.send(Command::SetDns(new_dns));
     }
 
+    /// Sets the TTL of the DNS records connlib synthesises for DNS resources.
+    pub fn set_dns_resource_ttl(&self, ttl: Duration) {
+        let _ = self.channel.send(Command::SetDnsResourceTtl(ttl));
+    }
+
     pub fn set_disabled_resources(&self, disabled_resources: BTreeSet<ResourceId>) {
         let _ = self
             .channel
@@ -116,13 +124,14 @@ impl Session {
 /// Connects to the portal and starts a tunnel.
 ///
 /// When this function exits, the tunnel failed unrecoverably and you need to call it again.
-async fn connect<CB>(
+async fn connect<CB, P>(
     args: ConnectArgs<CB>,
-    portal: PhoenixChannel<(), IngressMessages, ReplyMessages>,
+    portal: P,
     rx: UnboundedReceiver<Command>,
 ) -> Result<(), DisconnectError>
 where
     CB: Callbacks + 'static,
+    P: ControlPlane<IngressMessages, ReplyMessages>,
 {
     let ConnectArgs {
         private_key,
@@ -135,7 +144,7 @@ where
         private_key,
         tcp_socket_factory,
         udp_socket_factory,
-        BTreeMap::from([(portal.server_host().to_owned(), portal.resolved_addresses())]),
+        BTreeMap::from_iter(portal.resolved_host()),
     );
 
     let mut eventloop = Eventloop::new(tunnel, callbacks, portal, rx);
@@ -6,7 +6,7 @@ use connlib_shared::messages::{
 use serde::{Deserialize, Serialize};
 use std::{collections::BTreeSet, net::IpAddr};
 
-#[derive(Debug, PartialEq, Eq, Deserialize, Clone)]
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone)]
 pub struct InitClient {
     pub interface: Interface,
     #[serde(default)]
@@ -15,12 +15,12 @@ pub struct InitClient {
     pub relays: Vec<Relay>,
 }
 
-#[derive(Debug, PartialEq, Eq, Deserialize, Clone)]
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone)]
 pub struct ConfigUpdate {
     pub interface: Interface,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct ConnectionDetails {
     pub resource_id: ResourceId,
     pub gateway_id: GatewayId,
@@ -29,7 +29,7 @@ pub struct ConnectionDetails {
     pub site_id: SiteId,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Connect {
     pub gateway_payload: GatewayResponse,
     pub resource_id: ResourceId,
@@ -39,7 +39,7 @@ pub struct Connect {
 
 // These messages are the messages that can be received
 // by a client.
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(rename_all = "snake_case", tag = "event", content = "payload")]
 pub enum IngressMessages {
     Init(InitClient),
@@ -73,7 +73,7 @@ pub struct GatewayIceCandidates {
 }
 
 /// The replies that can arrive from the channel by a client
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(untagged)]
 #[allow(clippy::large_enum_variant)]
 pub enum ReplyMessages {
@@ -104,7 +104,7 @@ mod test {
     use chrono::DateTime;
     use connlib_shared::messages::{
         client::{ResourceDescriptionCidr, ResourceDescriptionDns, Site},
-        DnsServer, IpDnsServer, Turn,
+        DnsServer, HttpsDnsServer, IpDnsServer, TlsDnsServer, Turn,
     };
     use phoenix_channel::{OutboundRequestId, PhoenixMessage};
 
@@ -191,6 +191,7 @@ mod test {
                     upstream_dns: vec![DnsServer::IpPort(IpDnsServer {
                         address: "1.1.1.1:53".parse().unwrap(),
                     })],
+                    search_domains: vec![],
                 },
             }),
             None,
@@ -219,6 +220,96 @@ mod test {
         assert_eq!(m, ingress_message);
     }
 
+    #[test]
+    fn config_updated_with_encrypted_upstream_dns() {
+        let m = PhoenixMessage::new_message(
+            "client",
+            IngressMessages::ConfigChanged(ConfigUpdate {
+                interface: Interface {
+                    ipv4: "100.67.138.25".parse().unwrap(),
+                    ipv6: "fd00:2021:1111::e:65ea".parse().unwrap(),
+                    upstream_dns: vec![
+                        DnsServer::Tls(TlsDnsServer {
+                            address: "1.1.1.1:853".parse().unwrap(),
+                            server_name: "one.one.one.one".to_owned(),
+                        }),
+                        DnsServer::Https(HttpsDnsServer {
+                            address: "8.8.8.8:443".parse().unwrap(),
+                            server_name: "dns.google".to_owned(),
+                            path: "/dns-query".to_owned(),
+                        }),
+                    ],
+                    search_domains: vec![],
+                },
+            }),
+            None,
+        );
+        let message = r#"
+        {
+            "event": "config_changed",
+            "ref": null,
+            "topic": "client",
+            "payload": {
+              "interface": {
+                "ipv6": "fd00:2021:1111::e:65ea",
+                "upstream_dns": [
+                  {
+                    "protocol": "tls",
+                    "address": "1.1.1.1:853",
+                    "server_name": "one.one.one.one"
+                  },
+                  {
+                    "protocol": "https",
+                    "address": "8.8.8.8:443",
+                    "server_name": "dns.google"
+                  }
+                ],
+                "ipv4": "100.67.138.25"
+              }
+            }
+          }
+        "#;
+        let ingress_message: PhoenixMessage<IngressMessages, ReplyMessages> =
+            serde_json::from_str(message).unwrap();
+        assert_eq!(m, ingress_message);
+    }
+
+    #[test]
+    fn config_updated_with_search_domains() {
+        let m = PhoenixMessage::new_message(
+            "client",
+            IngressMessages::ConfigChanged(ConfigUpdate {
+                interface: Interface {
+                    ipv4: "100.67.138.25".parse().unwrap(),
+                    ipv6: "fd00:2021:1111::e:65ea".parse().unwrap(),
+                    upstream_dns: vec![],
+                    search_domains: vec![
+                        "corp.example.com".parse().unwrap(),
+                        "example.com".parse().unwrap(),
+                    ],
+                },
+            }),
+            None,
+        );
+        let message = r#"
+        {
+            "event": "config_changed",
+            "ref": null,
+            "topic": "client",
+            "payload": {
+              "interface": {
+                "ipv6": "fd00:2021:1111::e:65ea",
+                "search_domains": ["corp.example.com", "example.com"],
+                "ipv4": "100.67.138.25"
+              }
+            }
+          }
+        "#;
+        let ingress_message: PhoenixMessage<IngressMessages, ReplyMessages> =
+            serde_json::from_str(message).unwrap();
+        assert_eq!(m, ingress_message);
+    }
+
     #[test]
     fn init_phoenix_message() {
         let m = PhoenixMessage::new_message(
@@ -228,6 +319,7 @@ mod test {
                     ipv4: "100.72.112.111".parse().unwrap(),
                     ipv6: "fd00:2021:1111::13:efb9".parse().unwrap(),
                     upstream_dns: vec![],
+                    search_domains: vec![],
                 },
                 resources: vec![
                     ResourceDescription::Cidr(ResourceDescriptionCidr {
@@ -336,6 +428,7 @@ mod test {
                     ipv4: "100.72.112.111".parse().unwrap(),
                     ipv6: "fd00:2021:1111::13:efb9".parse().unwrap(),
                     upstream_dns: vec![],
+                    search_domains: vec![],
                 },
                 resources: vec![
                     ResourceDescription::Cidr(ResourceDescriptionCidr {
@@ -412,6 +505,7 @@ mod test {
                     ipv4: "100.72.112.111".parse().unwrap(),
                     ipv6: "fd00:2021:1111::13:efb9".parse().unwrap(),
                     upstream_dns: vec![],
+                    search_domains: vec![],
                 },
                 resources: vec![],
                 relays: vec![],
@@ -446,6 +540,7 @@ mod test {
                     ipv4: "100.72.112.111".parse().unwrap(),
                     ipv6: "fd00:2021:1111::13:efb9".parse().unwrap(),
                     upstream_dns: vec![],
+                    search_domains: vec![],
                 },
                 resources: vec![],
                 relays: vec![],
@@ -480,6 +575,7 @@ mod test {
                     ipv4: "100.72.112.111".parse().unwrap(),
                     ipv6: "fd00:2021:1111::13:efb9".parse().unwrap(),
                     upstream_dns: vec![],
+                    search_domains: vec![],
                 },
                 resources: vec![],
                 relays: vec![],
@@ -514,6 +610,7 @@ mod test {
                     ipv4: "100.72.112.111".parse().unwrap(),
                     ipv6: "fd00:2021:1111::13:efb9".parse().unwrap(),
                     upstream_dns: vec![],
+                    search_domains: vec![],
                 },
                 resources: vec![],
                 relays: vec![],
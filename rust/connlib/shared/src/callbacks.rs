@@ -2,9 +2,11 @@ use ip_network::IpNetwork;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::fmt::Debug;
+use std::net::IpAddr;
 
 use crate::messages::client::Site;
 use crate::messages::ResourceId;
+use crate::DomainName;
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum Status {
@@ -143,6 +145,37 @@ impl Ord for ResourceDescription {
     }
 }
 
+/// A DNS query that connlib answered or forwarded on behalf of an application.
+///
+/// Meant for debugging, e.g. to find out which proxy IPs an application got for a resource.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct DnsResolution {
+    /// The queried domain.
+    pub name: DomainName,
+    /// The queried record type, e.g. `A` or `SRV`.
+    pub qtype: String,
+    /// The resource the domain belongs to, if any.
+    pub resource: Option<ResourceId>,
+    /// The IPs we answered with.
+    ///
+    /// For resources, these are the proxy IPs we assigned to the domain.
+    /// Empty if the query was forwarded.
+    pub ips: Vec<IpAddr>,
+    pub answered_by: DnsAnswerSource,
+}
+
+/// Who answers a DNS query.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsAnswerSource {
+    /// connlib answered the query itself.
+    Local,
+    /// The query was forwarded to an upstream resolver.
+    Upstream,
+    /// The query was forwarded to the gateway of the resource.
+    Gateway,
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
@@ -250,30 +250,51 @@ pub enum GatewayResponse {
     ResourceAccepted(ResourceAccepted),
 }
 
-#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
 #[serde(tag = "protocol", rename_all = "snake_case")]
 pub enum DnsServer {
     IpPort(IpDnsServer),
+    /// DNS-over-TLS, see RFC 7858.
+    Tls(TlsDnsServer),
+    /// DNS-over-HTTPS, see RFC 8484.
+    Https(HttpsDnsServer),
 }
 
 impl fmt::Debug for DnsServer {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::IpPort(IpDnsServer { address }) => address.fmt(f),
+            Self::Tls(TlsDnsServer {
+                address,
+                server_name,
+            }) => write!(f, "tls://{server_name} ({address})"),
+            Self::Https(HttpsDnsServer {
+                address,
+                server_name,
+                path,
+            }) => write!(f, "https://{server_name}{path} ({address})"),
         }
     }
 }
 
 impl DnsServer {
     pub fn ip(&self) -> IpAddr {
-        match self {
-            DnsServer::IpPort(s) => s.address.ip(),
-        }
+        self.address().ip()
     }
 
     pub fn address(&self) -> SocketAddr {
         match self {
             DnsServer::IpPort(s) => s.address,
+            DnsServer::Tls(s) => s.address,
+            DnsServer::Https(s) => s.address,
+        }
+    }
+
+    /// Whether queries to this server are encrypted in transit.
+    pub fn is_encrypted(&self) -> bool {
+        match self {
+            DnsServer::IpPort(_) => false,
+            DnsServer::Tls(_) | DnsServer::Https(_) => true,
         }
     }
 }
@@ -294,6 +315,29 @@ pub struct IpDnsServer {
     pub address: SocketAddr,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct TlsDnsServer {
+    /// The socket to connect to, usually on port 853.
+    pub address: SocketAddr,
+    /// The name to verify the server's certificate against.
+    pub server_name: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct HttpsDnsServer {
+    /// The socket to connect to, usually on port 443.
+    pub address: SocketAddr,
+    /// The name to verify the server's certificate against, also sent as the `Host` header.
+    pub server_name: String,
+    /// The path of the DNS query endpoint.
+    #[serde(default = "default_doh_path")]
+    pub path: String,
+}
+
+fn default_doh_path() -> String {
+    "/dns-query".to_owned()
+}
+
 /// Represents a wireguard interface configuration.
 ///
 /// Note that the ips are /32 for ipv4 and /128 for ipv6.
@@ -308,10 +352,14 @@ pub struct Interface {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
     pub upstream_dns: Vec<DnsServer>,
+    /// Domains to append to single-label names, e.g. to resolve `build01` as `build01.corp.example.com`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub search_domains: Vec<DomainName>,
 }
 
 /// A single relay
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Relay {
     /// STUN type of relay
@@ -320,8 +368,17 @@ pub enum Relay {
     Turn(Turn),
 }
 
+impl Relay {
+    pub fn id(&self) -> RelayId {
+        match self {
+            Relay::Stun(stun) => stun.id,
+            Relay::Turn(turn) => turn.id,
+        }
+    }
+}
+
 /// Represent a TURN relay
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct Turn {
     pub id: RelayId,
     //// Expire time of the username/password in unix millisecond timestamp UTC
@@ -337,7 +394,7 @@ pub struct Turn {
 }
 
 /// Stun kind of relay
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct Stun {
     pub id: RelayId,
 
@@ -346,7 +403,7 @@ pub struct Stun {
 }
 
 /// A update to the presence of several relays.
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct RelaysPresence {
     /// These relays have disconnected from the portal. We need to stop using them.
     pub disconnected_ids: Vec<RelayId>,
@@ -1,17 +1,19 @@
 //! Gateway related messages that are needed within connlib
 
+use std::collections::BTreeSet;
 use std::net::IpAddr;
+use std::ops::RangeInclusive;
 
 use ip_network::{IpNetwork, Ipv4Network, Ipv6Network};
 use itertools::Itertools;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use super::ResourceId;
 
 pub type Filters = Vec<Filter>;
 
 /// Description of a resource that maps to a DNS record.
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct ResourceDescriptionDns {
     /// Resource's id.
     pub id: ResourceId,
@@ -26,7 +28,7 @@ pub struct ResourceDescriptionDns {
 }
 
 /// Description of a resource that maps to a CIDR.
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct ResourceDescriptionCidr {
     /// Resource's id.
     pub id: ResourceId,
@@ -57,12 +59,12 @@ pub struct ResolvedResourceDescriptionDns {
 }
 
 /// Description of an Internet resource.
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct ResourceDescriptionInternet {
     pub id: ResourceId,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ResourceDescription<TDNS = ResourceDescriptionDns> {
     Dns(TDNS),
@@ -70,15 +72,83 @@ pub enum ResourceDescription<TDNS = ResourceDescriptionDns> {
     Internet(ResourceDescriptionInternet),
 }
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
 #[serde(tag = "protocol", rename_all = "snake_case")]
 pub enum Filter {
     Udp(PortRange),
     Tcp(PortRange),
-    Icmp,
+    Icmp(IcmpFilter),
+    Sctp(PortRange),
+    /// GRE doesn't have ports, thus this allows all GRE traffic.
+    Gre,
 }
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash, Default)]
+pub struct IcmpFilter {
+    /// The ICMP message types allowed by this filter.
+    ///
+    /// If absent, all ICMP and ICMPv6 messages are allowed.
+    #[serde(default)]
+    pub icmp_types: Option<BTreeSet<IcmpType>>,
+}
+
+impl IcmpFilter {
+    pub fn all() -> Self {
+        Self { icmp_types: None }
+    }
+
+    pub fn only(types: impl IntoIterator<Item = IcmpType>) -> Self {
+        Self {
+            icmp_types: Some(BTreeSet::from_iter(types)),
+        }
+    }
+}
+
+/// An ICMP message type that exists in both ICMPv4 and ICMPv6.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum IcmpType {
+    /// Echo request and reply, i.e. `ping`.
+    Echo,
+    DestinationUnreachable,
+    TimeExceeded,
+    ParameterProblem,
+    Redirect,
+}
+
+impl IcmpType {
+    pub const ALL: [IcmpType; 5] = [
+        IcmpType::Echo,
+        IcmpType::DestinationUnreachable,
+        IcmpType::TimeExceeded,
+        IcmpType::ParameterProblem,
+        IcmpType::Redirect,
+    ];
+
+    /// The raw ICMPv4 types (RFC 792) of this message type.
+    pub fn icmpv4_types(&self) -> &'static [u8] {
+        match self {
+            IcmpType::Echo => &[0, 8],
+            IcmpType::DestinationUnreachable => &[3],
+            IcmpType::TimeExceeded => &[11],
+            IcmpType::ParameterProblem => &[12],
+            IcmpType::Redirect => &[5],
+        }
+    }
+
+    /// The raw ICMPv6 types (RFC 4443 & RFC 4861) of this message type.
+    pub fn icmpv6_types(&self) -> &'static [u8] {
+        match self {
+            IcmpType::Echo => &[128, 129],
+            IcmpType::DestinationUnreachable => &[1],
+            IcmpType::TimeExceeded => &[3],
+            IcmpType::ParameterProblem => &[4],
+            IcmpType::Redirect => &[137],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct PortRange {
     // TODO: we can use a custom deserializer
     // or maybe change the control plane to use start and end would suffice
@@ -86,6 +156,32 @@ pub struct PortRange {
     pub port_range_end: u16,
     #[serde(default = "min_port")]
     pub port_range_start: u16,
+    /// Restricts the filter to packets from these source ports.
+    ///
+    /// If neither bound is present, packets from any source port are allowed.
+    #[serde(default)]
+    pub source_port_range_start: Option<u16>,
+    #[serde(default)]
+    pub source_port_range_end: Option<u16>,
+}
+
+impl PortRange {
+    /// The destination ports allowed by this range.
+    pub fn ports(&self) -> RangeInclusive<u16> {
+        self.port_range_start..=self.port_range_end
+    }
+
+    /// The source ports allowed by this range, `None` if any source port is allowed.
+    pub fn source_ports(&self) -> Option<RangeInclusive<u16>> {
+        if self.source_port_range_start.is_none() && self.source_port_range_end.is_none() {
+            return None;
+        }
+
+        Some(
+            self.source_port_range_start.unwrap_or_else(min_port)
+                ..=self.source_port_range_end.unwrap_or_else(max_port),
+        )
+    }
 }
 
 // Note: these 2 functions are needed since serde doesn't yet support default_value
@@ -180,6 +276,8 @@ mod tests {
         let expected_filter = Filter::Udp(PortRange {
             port_range_start: 10,
             port_range_end: 20,
+            source_port_range_start: None,
+            source_port_range_end: None,
         });
 
         let actual_filter = serde_json::from_str(msg).unwrap();
@@ -193,6 +291,8 @@ mod tests {
         let expected_filter = Filter::Udp(PortRange {
             port_range_start: 0,
             port_range_end: u16::MAX,
+            source_port_range_start: None,
+            source_port_range_end: None,
         });
 
         let actual_filter = serde_json::from_str(msg).unwrap();
@@ -206,6 +306,8 @@ mod tests {
         let expected_filter = Filter::Tcp(PortRange {
             port_range_start: 10,
             port_range_end: 20,
+            source_port_range_start: None,
+            source_port_range_end: None,
         });
 
         let actual_filter = serde_json::from_str(msg).unwrap();
@@ -219,6 +321,8 @@ mod tests {
         let expected_filter = Filter::Tcp(PortRange {
             port_range_start: 0,
             port_range_end: u16::MAX,
+            source_port_range_start: None,
+            source_port_range_end: None,
         });
 
         let actual_filter = serde_json::from_str(msg).unwrap();
@@ -226,10 +330,77 @@ mod tests {
         assert_eq!(expected_filter, actual_filter);
     }
 
+    #[test]
+    fn can_deserialize_udp_filter_with_source_ports() {
+        let msg = r#"{ "protocol": "udp", "port_range_start": 53, "port_range_end": 53, "source_port_range_start": 1024 }"#;
+        let expected_filter = Filter::Udp(PortRange {
+            port_range_start: 53,
+            port_range_end: 53,
+            source_port_range_start: Some(1024),
+            source_port_range_end: None,
+        });
+
+        let actual_filter: Filter = serde_json::from_str(msg).unwrap();
+
+        assert_eq!(expected_filter, actual_filter);
+        let Filter::Udp(range) = actual_filter else {
+            unreachable!()
+        };
+        assert_eq!(range.source_ports(), Some(1024..=u16::MAX));
+    }
+
+    #[test]
+    fn can_deserialize_sctp_filter() {
+        let msg = r#"{ "protocol": "sctp", "port_range_start": 3868, "port_range_end": 3868 }"#;
+        let expected_filter = Filter::Sctp(PortRange {
+            port_range_start: 3868,
+            port_range_end: 3868,
+            source_port_range_start: None,
+            source_port_range_end: None,
+        });
+
+        let actual_filter = serde_json::from_str(msg).unwrap();
+
+        assert_eq!(expected_filter, actual_filter);
+    }
+
+    #[test]
+    fn can_deserialize_gre_filter() {
+        let msg = r#"{ "protocol": "gre" }"#;
+        let expected_filter = Filter::Gre;
+
+        let actual_filter = serde_json::from_str(msg).unwrap();
+
+        assert_eq!(expected_filter, actual_filter);
+    }
+
     #[test]
     fn can_deserialize_icmp_filter() {
         let msg = r#"{ "protocol": "icmp" }"#;
-        let expected_filter = Filter::Icmp;
+        let expected_filter = Filter::Icmp(IcmpFilter::all());
+
+        let actual_filter = serde_json::from_str(msg).unwrap();
+
+        assert_eq!(expected_filter, actual_filter);
+    }
+
+    #[test]
+    fn can_deserialize_icmp_filter_with_types() {
+        let msg = r#"{ "protocol": "icmp", "icmp_types": ["echo", "destination_unreachable"] }"#;
+        let expected_filter = Filter::Icmp(IcmpFilter::only([
+            IcmpType::Echo,
+            IcmpType::DestinationUnreachable,
+        ]));
+
+        let actual_filter = serde_json::from_str(msg).unwrap();
+
+        assert_eq!(expected_filter, actual_filter);
+    }
+
+    #[test]
+    fn can_deserialize_icmp_filter_without_types() {
+        let msg = r#"{ "protocol": "icmp", "icmp_types": [] }"#;
+        let expected_filter = Filter::Icmp(IcmpFilter::only([]));
 
         let actual_filter = serde_json::from_str(msg).unwrap();
 
@@ -1,6 +1,6 @@
 use crate::{
     backoff::{self, ExponentialBackoff},
-    node::{CandidateEvent, SessionId, Transmit},
+    node::{CandidateEvent, SessionId, Transmit, Transport},
     ringbuffer::RingBuffer,
     utils::earliest,
     EncryptedPacket,
@@ -38,6 +38,9 @@ use tracing::{field, Span};
 
 const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
 
+/// How long we wait for the relay to answer our BINDING requests before we fall back to the next [`Transport`].
+const FALLBACK_TIMEOUT: Duration = Duration::from_secs(4);
+
 /// Represents a TURN allocation that refreshes itself.
 ///
 /// Allocations have a lifetime and need to be continuously refreshed to stay active.
@@ -53,6 +56,11 @@ pub struct Allocation {
     /// To figure out, how to communicate with the relay, we start by sending a BINDING request on all known sockets.
     /// Whatever comes back first, wins.
     active_socket: Option<SocketAddr>,
+    /// How we talk to the relay.
+    ///
+    /// We start with UDP and fall back to TCP if the relay doesn't respond within [`FALLBACK_TIMEOUT`], i.e. because UDP is blocked by a firewall.
+    /// If TCP doesn't work either, we try TLS on port 443, which firewalls that only allow HTTPS let through.
+    transport: Transport,
 
     software: Software,
 
@@ -201,6 +209,7 @@ impl Allocation {
         let mut allocation = Self {
             server,
             active_socket: None,
+            transport: Transport::Udp,
             ip4_srflx_candidate: Default::default(),
             ip6_srflx_candidate: Default::default(),
             ip4_allocation: Default::default(),
@@ -253,6 +262,7 @@ impl Allocation {
             tracing::debug!("Attempting to make a new allocation");
 
             self.active_socket = None;
+            self.transport = Transport::Udp; // The network may have changed, try UDP again.
             self.send_binding_requests();
             return;
         }
@@ -449,8 +459,12 @@ impl Allocation {
                     SocketAddr::V6(_) => &mut self.ip6_srflx_candidate,
                 };
 
-                let maybe_candidate = message.attributes().find_map(|a| srflx_candidate(local, a));
-                update_candidate(maybe_candidate, current_srflx_candidate, &mut self.events);
+                // The address observed on a TCP connection is of no use for hole-punching UDP.
+                if self.transport == Transport::Udp {
+                    let maybe_candidate =
+                        message.attributes().find_map(|a| srflx_candidate(local, a));
+                    update_candidate(maybe_candidate, current_srflx_candidate, &mut self.events);
+                }
 
                 self.log_update(now);
 
@@ -626,6 +640,24 @@ impl Allocation {
                 continue;
             }
 
+            let fallback = match self.transport {
+                Transport::Udp => Some(Transport::Tcp),
+                Transport::Tcp => Some(Transport::Tls),
+                Transport::Tls => None,
+            };
+
+            if let Some(fallback) = fallback.filter(|_| {
+                !self.received_any_response()
+                    && now.duration_since(backoff.start_time) >= FALLBACK_TIMEOUT
+            }) {
+                tracing::info!(from = ?self.transport, to = ?fallback, "Relay did not respond, falling back");
+
+                self.transport = fallback;
+                self.sent_requests.clear();
+                self.send_binding_requests();
+                break;
+            }
+
             self.queue(dst, request, Some(backoff));
         }
 
@@ -737,6 +769,7 @@ impl Allocation {
         Some(EncryptedPacket {
             src: None,
             dst: self.active_socket?,
+            transport: self.transport,
             packet_start: 0,
             packet_len: buffer_len,
         })
@@ -754,6 +787,7 @@ impl Allocation {
         Some(Transmit {
             src: None,
             dst: self.active_socket?,
+            transport: self.transport,
             payload: Cow::Owned(channel_data),
         })
     }
@@ -839,6 +873,10 @@ impl Allocation {
         self.server
     }
 
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
     pub fn ip4_socket(&self) -> Option<Socket> {
         let address = self.ip4_allocation.as_ref().map(|c| c.addr())?;
 
@@ -979,6 +1017,7 @@ impl Allocation {
         self.buffered_transmits.push_back(Transmit {
             src: None,
             dst,
+            transport: self.transport,
             payload: encode(message).into(),
         });
 
@@ -1756,7 +1795,15 @@ mod tests {
         let start = Instant::now();
         let mut allocation = Allocation::for_test_ip4(start);
 
-        let mut expected_backoffs = VecDeque::from(backoff::steps(start));
+        // After 3 unanswered requests via UDP, we start over via TCP and after another 3 via TLS.
+        let tcp_fallback = backoff::steps(start)[2];
+        let tls_fallback = backoff::steps(tcp_fallback)[2];
+        let mut expected_backoffs = backoff::steps(start)
+            .into_iter()
+            .take(3)
+            .chain(backoff::steps(tcp_fallback).into_iter().take(3))
+            .chain(backoff::steps(tls_fallback))
+            .collect::<VecDeque<_>>();
 
         loop {
             let Some(timeout) = allocation.poll_timeout() else {
@@ -1774,6 +1821,61 @@ mod tests {
         assert!(expected_backoffs.is_empty())
     }
 
+    #[test]
+    fn falls_back_to_tcp_if_relay_does_not_respond_via_udp() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test_ip4(start);
+
+        assert_eq!(
+            allocation.poll_transmit().unwrap().transport,
+            Transport::Udp
+        );
+
+        for _ in 0..3 {
+            allocation.advance_to_next_timeout();
+        }
+
+        let transmit = iter::from_fn(|| allocation.poll_transmit()).last().unwrap();
+        assert_eq!(transmit.transport, Transport::Tcp);
+        assert_eq!(allocation.transport(), Transport::Tcp);
+
+        let binding = decode(&transmit.payload).unwrap().unwrap();
+        allocation.handle_test_input_ip4(&binding_response(&binding, PEER1), start);
+
+        let allocate = allocation.poll_transmit().unwrap();
+        assert_eq!(allocate.transport, Transport::Tcp);
+        assert_eq!(
+            decode(&allocate.payload).unwrap().unwrap().method(),
+            ALLOCATE
+        );
+        assert!(
+            allocation.poll_event().is_none(),
+            "should not emit server-reflexive candidate observed via TCP"
+        );
+    }
+
+    #[test]
+    fn falls_back_to_tls_if_relay_does_not_respond_via_tcp() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test_ip4(start);
+
+        for _ in 0..6 {
+            allocation.advance_to_next_timeout();
+        }
+
+        let transmit = iter::from_fn(|| allocation.poll_transmit()).last().unwrap();
+        assert_eq!(transmit.transport, Transport::Tls);
+        assert_eq!(transmit.dst, SocketAddr::V4(RELAY_V4));
+
+        let binding = decode(&transmit.payload).unwrap().unwrap();
+        allocation.handle_test_input_ip4(&binding_response(&binding, PEER1), start);
+
+        assert_eq!(
+            allocation.poll_transmit().unwrap().transport,
+            Transport::Tls
+        );
+    }
+
     #[test]
     fn given_no_ip6_allocation_does_not_attempt_to_bind_channel_to_ip6_address() {
         let mut allocation = Allocation::for_test_ip4(Instant::now())
@@ -12,6 +12,6 @@ mod utils;
 pub use allocation::RelaySocket;
 pub use node::{
     Answer, Client, ClientNode, Credentials, EncryptBuffer, EncryptedPacket, Error, Event, Node,
-    Offer, Server, ServerNode, Transmit, HANDSHAKE_TIMEOUT,
+    Offer, Server, ServerNode, Transmit, Transport, HANDSHAKE_TIMEOUT, RELAY_TLS_PORT,
 };
-pub use stats::{ConnectionStats, NodeStats};
+pub use stats::{ConnectionPath, ConnectionStats, NodeStats};
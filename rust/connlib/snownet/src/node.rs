@@ -1,7 +1,7 @@
 use crate::allocation::{Allocation, RelaySocket, Socket};
 use crate::index::IndexLfsr;
 use crate::ringbuffer::RingBuffer;
-use crate::stats::{ConnectionStats, NodeStats};
+use crate::stats::{ConnectionPath, ConnectionStats, NodeStats};
 use crate::utils::earliest;
 use boringtun::noise::errors::WireGuardError;
 use boringtun::noise::{Tunn, TunnResult};
@@ -295,7 +295,10 @@ where
         now: Instant,
         buffer: &'b mut [u8],
     ) -> Result<Option<(TId, MutableIpPacket<'b>)>, Error> {
-        self.add_local_as_host_candidate(local)?;
+        // Packets from relays that we talk to via TCP or TLS arrive on a stream socket, which is of no use as a host candidate.
+        if !self.is_tcp_relay(from) {
+            self.add_local_as_host_candidate(local)?;
+        }
 
         let (from, packet, relayed) = match self.allocations_try_handle(from, local, packet, now) {
             ControlFlow::Continue(c) => c,
@@ -359,6 +362,7 @@ where
             } => Ok(Some(EncryptedPacket {
                 src: Some(source),
                 dst: remote,
+                transport: Transport::Udp,
                 packet_start,
                 packet_len,
             })),
@@ -427,7 +431,13 @@ where
         self.bindings_and_allocations_drain_events();
 
         for (id, connection) in self.connections.iter_established_mut() {
-            connection.handle_timeout(id, now, &mut self.allocations, &mut self.buffered_transmits);
+            connection.handle_timeout(
+                id,
+                now,
+                &mut self.allocations,
+                &mut self.buffered_transmits,
+                &mut self.pending_events,
+            );
         }
 
         for (id, connection) in self.connections.initial.iter_mut() {
@@ -589,6 +599,12 @@ where
         }
     }
 
+    fn is_tcp_relay(&self, from: SocketAddr) -> bool {
+        self.allocations
+            .values()
+            .any(|a| a.transport() != Transport::Udp && a.server().matches(from))
+    }
+
     /// Attempt to add the `local` address as a host candidate.
     ///
     /// Receiving traffic on a certain interface means we at least have a connection to a relay via this interface.
@@ -1023,7 +1039,7 @@ where
     }
 
     fn stats(&self) -> impl Iterator<Item = (TId, ConnectionStats)> + '_ {
-        self.established.iter().map(move |(id, c)| (*id, c.stats))
+        self.established.iter().map(move |(id, c)| (*id, c.stats()))
     }
 
     fn agent_mut(&mut self, id: TId) -> Option<&mut IceAgent> {
@@ -1241,6 +1257,15 @@ pub enum Event<TId> {
 
     /// We closed a connection (e.g. due to inactivity, roaming, etc).
     ConnectionClosed(TId),
+
+    /// ICE nominated a new path for sending data on this connection.
+    ///
+    /// `old` is `None` if this is the first path we nominated for the connection.
+    ConnectionPathChanged {
+        connection: TId,
+        old: Option<ConnectionPath>,
+        new: ConnectionPath,
+    },
 }
 
 pub struct EncryptBuffer {
@@ -1259,6 +1284,7 @@ impl EncryptBuffer {
 pub struct EncryptedPacket {
     pub(crate) src: Option<SocketAddr>,
     pub(crate) dst: SocketAddr,
+    pub(crate) transport: Transport,
     pub(crate) packet_start: usize,
     pub(crate) packet_len: usize,
 }
@@ -1268,6 +1294,7 @@ impl EncryptedPacket {
         Transmit {
             src: self.src,
             dst: self.dst,
+            transport: self.transport,
             payload: Cow::Borrowed(
                 &buf.inner[self.packet_start..(self.packet_start + self.packet_len)],
             ),
@@ -1277,6 +1304,28 @@ impl EncryptedPacket {
     pub fn dst(&self) -> SocketAddr {
         self.dst
     }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+}
+
+/// The port relays accept TURN over TLS on.
+pub const RELAY_TLS_PORT: u16 = 443;
+
+/// The transport protocol over which a [`Transmit`] needs to be sent.
+///
+/// Everything is sent over UDP, except for traffic to relays that we can only reach via TCP or TLS.
+/// Messages to those relays must be framed as per <https://www.rfc-editor.org/rfc/rfc8656#section-12.5>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Transport {
+    #[default]
+    Udp,
+    Tcp,
+    /// Via TLS to [`RELAY_TLS_PORT`] on the IP of the [`Transmit`]'s `dst`.
+    ///
+    /// Messages from the relay are still reported as coming from `dst`.
+    Tls,
 }
 
 #[derive(Clone, PartialEq, PartialOrd, Eq, Ord)]
@@ -1290,6 +1339,8 @@ pub struct Transmit<'a> {
     pub src: Option<SocketAddr>,
     /// The remote the packet should be sent to.
     pub dst: SocketAddr,
+    /// Whether the packet should be sent over UDP or via a TCP stream to `dst`.
+    pub transport: Transport,
     /// The data that should be sent.
     pub payload: Cow<'a, [u8]>,
 }
@@ -1299,6 +1350,7 @@ impl<'a> fmt::Debug for Transmit<'a> {
         f.debug_struct("Transmit")
             .field("src", &self.src)
             .field("dst", &self.dst)
+            .field("transport", &self.transport)
             .field("len", &self.payload.len())
             .finish()
     }
@@ -1309,6 +1361,7 @@ impl<'a> Transmit<'a> {
         Transmit {
             src: self.src,
             dst: self.dst,
+            transport: self.transport,
             payload: Cow::Owned(self.payload.into_owned()),
         }
     }
@@ -1518,6 +1571,7 @@ where
         now: Instant,
         allocations: &mut BTreeMap<RId, Allocation>,
         transmits: &mut VecDeque<Transmit<'static>>,
+        events: &mut VecDeque<Event<TId>>,
     ) where
         TId: Copy + Ord + fmt::Display,
         RId: Copy + Ord + fmt::Display,
@@ -1565,6 +1619,10 @@ where
                     tracing::warn!(?e);
                 }
                 TunnResult::WriteToNetwork(b) => {
+                    if is_handshake_initiation(b) {
+                        self.stats.handshakes_initiated += 1;
+                    }
+
                     transmits.extend(make_owned_transmit(peer_socket, b, allocations, now));
                 }
                 TunnResult::WriteToTunnelV4(..) | TunnResult::WriteToTunnelV6(..) => {
@@ -1587,19 +1645,32 @@ where
                     source,
                     ..
                 } => {
-                    let remote_socket = allocations
+                    let (remote_socket, path) = match allocations
                         .iter()
-                        .find_map(|(relay, allocation)| {
-                            allocation.has_socket(source).then_some(*relay)
-                        })
-                        .map(|relay| PeerSocket::Relay {
-                            relay,
-                            dest: destination,
-                        })
-                        .unwrap_or(PeerSocket::Direct {
-                            source,
-                            dest: destination,
-                        });
+                        .find(|(_, allocation)| allocation.has_socket(source))
+                    {
+                        Some((relay, allocation)) => (
+                            PeerSocket::Relay {
+                                relay: *relay,
+                                dest: destination,
+                            },
+                            ConnectionPath::Relayed {
+                                relay: allocation.server(),
+                                local: source,
+                                remote: destination,
+                            },
+                        ),
+                        None => (
+                            PeerSocket::Direct {
+                                source,
+                                dest: destination,
+                            },
+                            ConnectionPath::Direct {
+                                local: source,
+                                remote: destination,
+                            },
+                        ),
+                    };
 
                     let old = match mem::replace(&mut self.state, ConnectionState::Failed) {
                         ConnectionState::Connecting {
@@ -1642,6 +1713,15 @@ where
                         ConnectionState::Idle | ConnectionState::Failed => continue, // Failed and idle connections are cleaned up, don't bother handling events.
                     };
 
+                    let old_path = self.stats.path.replace(path);
+                    if old_path != Some(path) {
+                        events.push_back(Event::ConnectionPathChanged {
+                            connection: cid,
+                            old: old_path,
+                            new: path,
+                        });
+                    }
+
                     tracing::info!(?old, new = ?remote_socket, duration_since_intent = ?self.duration_since_intent(now), "Updating remote socket");
 
                     self.force_handshake(allocations, transmits, now);
@@ -1667,6 +1747,7 @@ where
                 transmits.push_back(Transmit {
                     src: Some(source),
                     dst,
+                    transport: Transport::Udp,
                     payload: Cow::Owned(packet.into()),
                 });
                 continue;
@@ -1693,8 +1774,23 @@ where
     ) -> Result<Option<&'b [u8]>, Error> {
         let len = match self.tunnel.encapsulate(packet, buffer) {
             TunnResult::Done => return Ok(None),
-            TunnResult::Err(e) => return Err(Error::Encapsulate(e)),
-            TunnResult::WriteToNetwork(packet) => packet.len(),
+            TunnResult::Err(e) => {
+                self.stats.packets_dropped += 1;
+
+                return Err(Error::Encapsulate(e));
+            }
+            // Without a session, `boringtun` queues the packet and hands us a handshake initiation instead.
+            TunnResult::WriteToNetwork(packet) if is_handshake_initiation(packet) => {
+                self.stats.handshakes_initiated += 1;
+
+                packet.len()
+            }
+            TunnResult::WriteToNetwork(packet) => {
+                self.stats.packets_sent += 1;
+                self.stats.data_bytes_sent += packet.len();
+
+                packet.len()
+            }
             TunnResult::WriteToTunnelV4(_, _) | TunnResult::WriteToTunnelV6(_, _) => {
                 unreachable!("never returned from encapsulate")
             }
@@ -1716,9 +1812,15 @@ where
     ) -> ControlFlow<Result<(), Error>, MutableIpPacket<'b>> {
         let _guard = self.span.enter();
 
+        let ciphertext_len = packet.len();
+
         let control_flow = match self.tunnel.decapsulate(None, packet, &mut buffer[20..]) {
             TunnResult::Done => ControlFlow::Break(Ok(())),
-            TunnResult::Err(e) => ControlFlow::Break(Err(Error::Decapsulate(e))),
+            TunnResult::Err(e) => {
+                self.stats.packets_dropped += 1;
+
+                ControlFlow::Break(Err(Error::Decapsulate(e)))
+            }
 
             // For WriteToTunnel{V4,V6}, boringtun returns the source IP of the packet that was tunneled to us.
             // I am guessing this was done for convenience reasons.
@@ -1789,6 +1891,8 @@ where
 
         if control_flow.is_continue() {
             self.last_incoming = now;
+            self.stats.packets_received += 1;
+            self.stats.data_bytes_received += ciphertext_len;
         }
 
         control_flow
@@ -1819,9 +1923,23 @@ where
             .socket()
             .expect("cannot force handshake while not connected");
 
+        self.stats.handshakes_initiated += 1;
+
         transmits.extend(make_owned_transmit(socket, bytes, allocations, now));
     }
 
+    /// Our own counters, completed with the ones [`Tunn`] tracks internally.
+    fn stats(&self) -> ConnectionStats {
+        let (time_since_last_handshake, _, _, estimated_loss, rtt) = self.tunnel.stats();
+
+        ConnectionStats {
+            time_since_last_handshake,
+            rtt: rtt.map(|millis| Duration::from_millis(u64::from(millis))),
+            estimated_loss,
+            ..self.stats
+        }
+    }
+
     fn socket(&self) -> Option<PeerSocket<RId>> {
         match self.state {
             ConnectionState::Connected { peer_socket, .. } => Some(peer_socket),
@@ -1840,6 +1958,13 @@ where
     }
 }
 
+/// Whether the given WireGuard message is a handshake initiation.
+///
+/// Handshake initiations are always `148` bytes (`HANDSHAKE_INIT_SZ` in [`boringtun`]) and have message type `1`.
+fn is_handshake_initiation(message: &[u8]) -> bool {
+    message.len() == 148 && message.first() == Some(&1)
+}
+
 #[must_use]
 fn make_owned_transmit<RId>(
     socket: PeerSocket<RId>,
@@ -1857,6 +1982,7 @@ where
         } => Transmit {
             src: Some(source),
             dst: remote,
+            transport: Transport::Udp,
             payload: Cow::Owned(message.into()),
         },
         PeerSocket::Relay { relay, dest: peer } => {
@@ -1,4 +1,6 @@
-use std::ops::AddAssign;
+use std::{net::SocketAddr, ops::AddAssign, time::Duration};
+
+use crate::RelaySocket;
 
 #[derive(Default, Debug, Clone, Copy)]
 pub struct NodeStats {
@@ -12,6 +14,54 @@ pub struct ConnectionStats {
     pub stun_bytes_to_peer_direct: HumanBytes,
     /// How many bytes we sent as part of exchanging STUN messages to other peers via relays.
     pub stun_bytes_to_peer_relayed: HumanBytes,
+
+    /// How many bytes of encrypted WireGuard packets we sent to the peer.
+    pub data_bytes_sent: HumanBytes,
+    /// How many bytes of encrypted WireGuard packets we received from the peer.
+    pub data_bytes_received: HumanBytes,
+    /// How many IP packets we sent through the tunnel.
+    pub packets_sent: u64,
+    /// How many IP packets we received through the tunnel.
+    pub packets_received: u64,
+    /// How many packets we dropped because we failed to encrypt or decrypt them.
+    pub packets_dropped: u64,
+
+    /// How many WireGuard handshakes we initiated.
+    pub handshakes_initiated: u64,
+    /// `None` if we never completed a WireGuard handshake with the peer.
+    pub time_since_last_handshake: Option<Duration>,
+    /// The round-trip time to the peer, as measured by WireGuard's handshakes.
+    pub rtt: Option<Duration>,
+    /// The packet loss estimated by WireGuard, between `0.0` and `1.0`.
+    pub estimated_loss: f32,
+
+    /// The candidate pair ICE nominated for sending data.
+    ///
+    /// `None` until ICE nominated a pair.
+    pub path: Option<ConnectionPath>,
+}
+
+/// The path data to a peer takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionPath {
+    /// We send directly from one of our sockets to the peer.
+    Direct {
+        local: SocketAddr,
+        remote: SocketAddr,
+    },
+    /// We send via an allocation on a relay.
+    Relayed {
+        relay: RelaySocket,
+        /// The address of our allocation on the relay.
+        local: SocketAddr,
+        remote: SocketAddr,
+    },
+}
+
+impl ConnectionPath {
+    pub fn is_relayed(&self) -> bool {
+        matches!(self, ConnectionPath::Relayed { .. })
+    }
 }
 
 #[derive(Default, Clone, Copy)]
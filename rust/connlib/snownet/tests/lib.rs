@@ -1,5 +1,7 @@
 use boringtun::x25519::StaticSecret;
-use snownet::{Answer, ClientNode, Event, ServerNode};
+use snownet::{
+    Answer, ClientNode, ConnectionPath, ConnectionStats, EncryptBuffer, Event, Node, ServerNode,
+};
 use std::{
     iter,
     net::{IpAddr, Ipv4Addr, SocketAddr},
@@ -107,6 +109,101 @@ fn only_generate_candidate_event_after_answer() {
         }));
 }
 
+#[test]
+fn connection_stats_count_handshakes_rtt_and_path() {
+    let _guard = firezone_logging::test("trace");
+    let mut now = Instant::now();
+
+    let (mut alice, mut bob) = alice_and_bob();
+    connect(&mut alice, &mut bob, &mut now);
+
+    let alice_stats = connection_stats(&alice);
+    assert!(alice_stats.handshakes_initiated >= 1);
+    assert!(alice_stats.rtt.is_some());
+    assert!(matches!(
+        alice_stats.path,
+        Some(ConnectionPath::Direct { .. })
+    ));
+    assert!(matches!(
+        connection_stats(&bob).path,
+        Some(ConnectionPath::Direct { .. })
+    ));
+}
+
+#[test]
+fn connection_stats_count_data_packets() {
+    let _guard = firezone_logging::test("trace");
+    let mut now = Instant::now();
+
+    let (mut alice, mut bob) = alice_and_bob();
+    connect(&mut alice, &mut bob, &mut now);
+    let alice_before = connection_stats(&alice);
+    let bob_before = connection_stats(&bob);
+
+    let packet = ip_packet::make::udp_packet(
+        Ipv4Addr::new(100, 64, 0, 1),
+        Ipv4Addr::new(100, 64, 0, 2),
+        1234,
+        53,
+        b"foobar".to_vec(),
+    )
+    .unwrap();
+    let mut buffer = EncryptBuffer::new(65_535);
+    let transmit = alice
+        .encapsulate(1, packet.as_immutable(), now, &mut buffer)
+        .unwrap()
+        .unwrap()
+        .to_transmit(&buffer);
+    let (src, dst, ciphertext) = (
+        transmit.src.unwrap(),
+        transmit.dst,
+        transmit.payload.to_vec(),
+    );
+
+    let received = bob
+        .decapsulate(dst, src, &ciphertext, now, &mut [0u8; 65_535])
+        .unwrap();
+    assert!(received.is_some());
+
+    let mut tampered = ciphertext.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert!(bob
+        .decapsulate(dst, src, &tampered, now, &mut [0u8; 65_535])
+        .is_err());
+
+    let alice_after = connection_stats(&alice);
+    let bob_after = connection_stats(&bob);
+    assert_eq!(alice_after.packets_sent - alice_before.packets_sent, 1);
+    assert_eq!(
+        alice_after.data_bytes_sent.0 - alice_before.data_bytes_sent.0,
+        ciphertext.len()
+    );
+    assert_eq!(bob_after.packets_received - bob_before.packets_received, 1);
+    assert_eq!(
+        bob_after.data_bytes_received.0 - bob_before.data_bytes_received.0,
+        ciphertext.len()
+    );
+    assert_eq!(bob_after.packets_dropped - bob_before.packets_dropped, 1);
+}
+
+#[test]
+fn connection_path_changed_is_emitted_once_ice_nominated_a_path() {
+    let _guard = firezone_logging::test("trace");
+    let mut now = Instant::now();
+
+    let (mut alice, mut bob) = alice_and_bob();
+    let events = connect(&mut alice, &mut bob, &mut now);
+
+    assert!(events.contains(&Event::ConnectionPathChanged {
+        connection: 1,
+        old: None,
+        new: ConnectionPath::Direct {
+            local: s("10.0.0.1:1000"),
+            remote: s("10.0.0.2:2000"),
+        },
+    }));
+}
+
 fn alice_and_bob() -> (ClientNode<u64, u64>, ServerNode<u64, u64>) {
     let alice = ClientNode::new(
         StaticSecret::random_from_rng(rand::thread_rng()),
@@ -132,6 +229,80 @@ fn send_offer(
     bob.accept_connection(1, offer, alice.public_key(), now)
 }
 
+/// Connects alice and bob directly via host candidates and waits until alice completed a WireGuard handshake.
+///
+/// Returns the events alice emitted, except for her candidates.
+fn connect(
+    alice: &mut ClientNode<u64, u64>,
+    bob: &mut ServerNode<u64, u64>,
+    now: &mut Instant,
+) -> Vec<Event<u64>> {
+    let mut alice_events = Vec::new();
+
+    let answer = send_offer(alice, bob, *now);
+    alice.accept_answer(1, bob.public_key(), answer, *now);
+    alice.add_local_host_candidate(s("10.0.0.1:1000")).unwrap();
+    bob.add_local_host_candidate(s("10.0.0.2:2000")).unwrap();
+
+    for _ in 0..1000 {
+        if alice
+            .stats()
+            .1
+            .any(|(id, stats)| id == 1 && stats.time_since_last_handshake.is_some())
+        {
+            return alice_events;
+        }
+
+        progress(alice, bob, &mut alice_events, *now);
+        progress(bob, alice, &mut Vec::new(), *now);
+
+        *now += Duration::from_millis(10);
+    }
+
+    panic!("alice and bob failed to connect");
+}
+
+/// Signals `from`'s candidates to `to` and delivers all packets `from` wants to send.
+///
+/// All other events of `from` are appended to `events`.
+fn progress<A, B>(
+    from: &mut Node<A, u64, u64>,
+    to: &mut Node<B, u64, u64>,
+    events: &mut Vec<Event<u64>>,
+    now: Instant,
+) {
+    while let Some(event) = from.poll_event() {
+        if let Event::NewIceCandidate {
+            connection,
+            candidate,
+        } = event
+        {
+            to.add_remote_candidate(connection, candidate, now);
+        } else {
+            events.push(event);
+        }
+    }
+
+    while let Some(transmit) = from.poll_transmit() {
+        let _ = to.decapsulate(
+            transmit.dst,
+            transmit.src.unwrap(),
+            &transmit.payload,
+            now,
+            &mut [0u8; 65_535],
+        );
+    }
+
+    from.handle_timeout(now);
+}
+
+fn connection_stats<T>(node: &Node<T, u64, u64>) -> ConnectionStats {
+    node.stats()
+        .1
+        .find_map(|(id, stats)| (id == 1).then_some(stats))
+        .unwrap()
+}
+
 fn host(socket: &str) -> String {
     Candidate::host(s(socket), Protocol::Udp)
         .unwrap()
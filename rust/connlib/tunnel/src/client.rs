@@ -1,4 +1,4 @@
-use crate::dns::StubResolver;
+use crate::dns::{CacheStats, StubResolver};
 use crate::peer_store::PeerStore;
 use crate::{dns, TunConfig, BUF_SIZE};
 use anyhow::Context;
@@ -22,10 +22,11 @@ use crate::{ClientEvent, ClientTunnel, Tun};
 use domain::base::Message;
 use lru::LruCache;
 use secrecy::{ExposeSecret as _, Secret};
-use snownet::{ClientNode, EncryptBuffer, RelaySocket, Transmit};
+use snownet::{ClientNode, ConnectionStats, EncryptBuffer, RelaySocket, Transmit, Transport};
 use std::borrow::Cow;
 use std::collections::hash_map::Entry;
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::io;
 use std::iter;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::num::NonZeroUsize;
@@ -122,6 +123,21 @@ impl ClientTunnel {
         self.role_state.update_system_resolvers(new_dns);
     }
 
+    /// Sets the TTL of the DNS records we synthesise for DNS resources.
+    pub fn set_dns_resource_ttl(&mut self, ttl: Duration) {
+        self.role_state.set_dns_resource_ttl(ttl);
+    }
+
+    /// How many forwarded DNS queries we answered from the cache.
+    pub fn dns_cache_stats(&self) -> CacheStats {
+        self.role_state.dns_cache_stats()
+    }
+
+    /// Statistics of our established connections to gateways.
+    pub fn connection_stats(&self) -> BTreeMap<GatewayId, ConnectionStats> {
+        self.role_state.connection_stats()
+    }
+
     #[tracing::instrument(level = "trace", skip(self))]
     pub fn set_new_interface_config(&mut self, config: InterfaceConfig) {
         self.role_state.update_interface_config(config);
@@ -236,8 +252,32 @@ pub struct ClientState {
     /// DNS query IDs don't appear to be unique across servers they are being sent to on some operating systems (looking at you, Windows).
     /// Hence, we need to index by ID + socket of the DNS server.
     forwarded_dns_queries: HashMap<(u16, SocketAddr), (SocketAddr, Instant)>,
+    /// DNS queries for resources that we sent to a gateway for resolution, indexed by the DNS query ID + the sentinel it was sent to.
+    ///
+    /// The value is a tuple of:
+    ///
+    /// - The [`GatewayId`] we sent the query to.
+    /// - The [`dns::QueryOrigin`] to send the response to.
+    /// - The [`Instant`] tracks when the DNS query expires.
+    gateway_dns_queries: HashMap<(u16, SocketAddr), (GatewayId, dns::QueryOrigin, Instant)>,
+    /// DNS queries that arrived over TCP and that we sent through the tunnel to an upstream resolver covered by a resource, indexed by the DNS query ID + the socket of the upstream resolver.
+    ///
+    /// The value is a tuple of:
+    ///
+    /// - The [`GatewayId`] we sent the query to.
+    /// - The [`dns::QueryOrigin`] to send the response to.
+    /// - The [`Instant`] tracks when the DNS query expires.
+    tunneled_dns_queries: HashMap<(u16, SocketAddr), (GatewayId, dns::QueryOrigin, Instant)>,
+    /// Buffer for encrypting the DNS queries we send to gateways.
+    dns_encrypt_buffer: EncryptBuffer,
     /// Manages internal dns records and emits forwarding event when not internally handled
     stub_resolver: StubResolver,
+    /// Responses of upstream resolvers to the queries we forwarded.
+    dns_cache: dns::Cache,
+    /// Terminates TCP connections to our sentinel DNS servers.
+    tcp_dns_server: dns::tcp::Server,
+    /// DNS queries that need to be forwarded to an upstream server over TCP, TLS or HTTPS.
+    buffered_dns_queries: VecDeque<dns::ForwardedQuery>,
 
     /// Configuration of the TUN device, when it is up.
     tun_config: Option<TunConfig>,
@@ -283,7 +323,13 @@ impl ClientState {
             gateways_site: Default::default(),
             mangled_dns_queries: Default::default(),
             forwarded_dns_queries: Default::default(),
+            gateway_dns_queries: Default::default(),
+            tunneled_dns_queries: Default::default(),
+            dns_encrypt_buffer: EncryptBuffer::new(BUF_SIZE),
             stub_resolver: StubResolver::new(known_hosts),
+            dns_cache: Default::default(),
+            tcp_dns_server: Default::default(),
+            buffered_dns_queries: Default::default(),
             disabled_resources: Default::default(),
             buffered_transmits: Default::default(),
             internet_resource: None,
@@ -394,6 +440,10 @@ impl ClientState {
         now: Instant,
         buffer: &mut EncryptBuffer,
     ) -> Option<snownet::EncryptedPacket> {
+        if self.try_handle_tcp_dns_segment(packet.as_immutable(), now) {
+            return None;
+        }
+
         let (packet, dst) = match self.try_handle_dns_query(packet, now) {
             Ok(response) => {
                 self.buffered_packets.push_back(response?);
@@ -454,7 +504,7 @@ impl ClientState {
         now: Instant,
         buffer: &'b mut [u8],
     ) -> Option<IpPacket<'b>> {
-        if let Some(response) = self.try_handle_forwarded_dns_response(from, packet) {
+        if let Some(response) = self.try_handle_forwarded_dns_response(from, packet, now) {
             return Some(response);
         };
 
@@ -468,6 +518,14 @@ impl ClientState {
         .inspect_err(|e| tracing::debug!(%local, num_bytes = %packet.len(), "Failed to decapsulate incoming packet: {e}"))
         .ok()??;
 
+        if self.try_handle_gateway_dns_response(gid, packet.as_immutable(), now) {
+            return None;
+        }
+
+        if self.try_handle_tunneled_dns_response(gid, packet.as_immutable(), now) {
+            return None;
+        }
+
         let Some(peer) = self.peers.get_mut(&gid) else {
             tracing::error!(%gid, "Couldn't find connection by ID");
 
@@ -627,17 +685,51 @@ impl ClientState {
         {
             Some(dns::ResolveStrategy::LocalResponse(query)) => Ok(Some(query)),
             Some(dns::ResolveStrategy::ForwardQuery {
-                upstream: server,
+                upstream,
                 query_id,
                 payload,
                 original_src,
             }) => {
+                let server = upstream.address();
                 let ip = server.ip();
 
-                if self.should_forward_dns_query_to_gateway(ip) {
+                // Queries to unencrypted upstreams covered by a resource are sent through the tunnel and answered by the gateway.
+                if !upstream.is_encrypted() && self.should_forward_dns_query_to_gateway(ip) {
                     return Err((packet, ip));
                 }
 
+                if let Some(response) = self.dns_cache.get(&upstream, &payload, now) {
+                    tracing::trace!(server = ?upstream, %query_id, "Answering DNS query from cache");
+
+                    let packet = ip_packet::make::udp_packet(
+                        packet.destination(),
+                        original_src.ip(),
+                        DNS_PORT,
+                        original_src.port(),
+                        response,
+                    )
+                    .expect("src and dst come from the same packet")
+                    .into_immutable();
+
+                    return Ok(Some(packet));
+                }
+
+                // Encrypted upstreams are always contacted directly: The query is protected in transit anyway.
+                if upstream.is_encrypted() {
+                    tracing::trace!(server = ?upstream, %query_id, "Forwarding DNS query");
+
+                    self.buffered_dns_queries.push_back(dns::ForwardedQuery {
+                        server: upstream,
+                        message: payload,
+                        origin: dns::QueryOrigin::Udp {
+                            sentinel: SocketAddr::new(packet.destination(), DNS_PORT),
+                            source: original_src,
+                        },
+                    });
+
+                    return Ok(None);
+                }
+
                 tracing::trace!(%server, %query_id, "Forwarding DNS query");
 
                 self.forwarded_dns_queries
@@ -645,11 +737,26 @@ impl ClientState {
                 self.buffered_transmits.push_back(Transmit {
                     src: None,
                     dst: server,
+                    transport: Transport::Udp,
                     payload: Cow::Owned(payload),
                 });
 
                 Ok(None)
             }
+            Some(dns::ResolveStrategy::ForwardToGateway {
+                resource,
+                proxy_ip,
+                original_src,
+                payload,
+            }) => {
+                let origin = dns::QueryOrigin::Udp {
+                    sentinel: SocketAddr::new(packet.destination(), DNS_PORT),
+                    source: original_src,
+                };
+                self.send_dns_query_via_gateway(resource, proxy_ip, payload, origin, now);
+
+                Ok(None)
+            }
             None => {
                 let dest = packet.destination();
                 Err((packet, dest))
@@ -657,10 +764,349 @@ impl ClientState {
         }
     }
 
+    /// Attempt to handle the given packet as part of a DNS-over-TCP connection to one of our sentinel DNS servers.
+    ///
+    /// Returns `true` if the packet was consumed.
+    fn try_handle_tcp_dns_segment(&mut self, packet: IpPacket<'_>, now: Instant) -> bool {
+        if !self.dns_mapping.contains_left(&packet.destination()) {
+            return false;
+        }
+
+        if !self.tcp_dns_server.handle_inbound(&packet, now) {
+            return false;
+        }
+
+        while let Some(query) = self.tcp_dns_server.poll_query() {
+            self.handle_tcp_dns_query(query, now);
+        }
+
+        true
+    }
+
+    fn handle_tcp_dns_query(&mut self, query: dns::tcp::Query, now: Instant) {
+        let Some(server) = self
+            .dns_mapping
+            .get_by_left(&query.connection.local.ip())
+            .cloned()
+        else {
+            return;
+        };
+
+        match self.stub_resolver.resolve(&query.message) {
+            Some(dns::Resolution::Local(response)) => {
+                self.tcp_dns_server
+                    .send_response(query.connection, &response, now);
+            }
+            Some(dns::Resolution::Forward) => {
+                // Our TCP connection to the upstream server would bypass the tunnel.
+                if !server.is_encrypted() && self.should_forward_dns_query_to_gateway(server.ip()) {
+                    self.send_dns_query_through_tunnel(
+                        server.address(),
+                        query.message,
+                        dns::QueryOrigin::Tcp(query.connection),
+                        now,
+                    );
+
+                    return;
+                }
+
+                if let Some(response) = self.dns_cache.get(&server, &query.message, now) {
+                    tracing::trace!(?server, "Answering DNS query over TCP from cache");
+
+                    self.tcp_dns_server
+                        .send_response(query.connection, &response, now);
+
+                    return;
+                }
+
+                tracing::trace!(?server, "Forwarding DNS query over TCP");
+
+                self.buffered_dns_queries.push_back(dns::ForwardedQuery {
+                    server,
+                    message: query.message,
+                    origin: dns::QueryOrigin::Tcp(query.connection),
+                });
+            }
+            Some(dns::Resolution::ViaGateway { resource, proxy_ip }) => {
+                self.send_dns_query_via_gateway(
+                    resource,
+                    proxy_ip,
+                    query.message,
+                    dns::QueryOrigin::Tcp(query.connection),
+                    now,
+                );
+            }
+            None => {
+                tracing::debug!(remote = %query.connection.remote, "Received invalid DNS query over TCP");
+            }
+        }
+    }
+
+    pub(crate) fn handle_dns_response(
+        &mut self,
+        query: dns::ForwardedQuery,
+        response: io::Result<Vec<u8>>,
+        now: Instant,
+    ) {
+        let response = match response {
+            Ok(response) => {
+                self.dns_cache.insert(&query.server, &response, now);
+
+                response
+            }
+            Err(e) => {
+                tracing::debug!(server = ?query.server, "Failed to forward DNS query: {e}");
+
+                let Some(servfail) = dns::servfail(&query.message) else {
+                    return;
+                };
+
+                servfail
+            }
+        };
+
+        tracing::trace!(server = ?query.server, "Received forwarded DNS response");
+
+        self.send_dns_response(query.origin, response, now);
+    }
+
+    /// Sends a DNS query for a domain of a DNS resource to the resource's gateway, which resolves it for us.
+    ///
+    /// We route the query via one of the domain's proxy IPs, which ensures that we are connected to the gateway and allowed to access the domain.
+    /// Until then, the query is dropped and we rely on the application to retry it.
+    fn send_dns_query_via_gateway(
+        &mut self,
+        resource: ResourceId,
+        proxy_ip: IpAddr,
+        message: Vec<u8>,
+        origin: dns::QueryOrigin,
+        now: Instant,
+    ) {
+        let Ok(query_id) = Message::from_slice(&message).map(|m| m.header().id()) else {
+            return;
+        };
+
+        let Some(peer) = peer_by_resource_mut(&self.resources_gateways, &mut self.peers, resource)
+        else {
+            self.on_not_connected_resource(resource, &proxy_ip, now);
+            return;
+        };
+        let gid = peer.id();
+
+        if peer.allowed_ips.exact_match(proxy_ip).is_none() {
+            self.request_access(&proxy_ip, resource, gid);
+            return;
+        }
+
+        let (sentinel, source) = (origin.sentinel(), origin.source());
+        let Ok(packet) = ip_packet::make::udp_packet(
+            source.ip(),
+            sentinel.ip(),
+            source.port(),
+            sentinel.port(),
+            message,
+        ) else {
+            return;
+        };
+
+        let Some(encrypted) = self
+            .node
+            .encapsulate(
+                gid,
+                packet.as_immutable(),
+                now,
+                &mut self.dns_encrypt_buffer,
+            )
+            .inspect_err(|e| tracing::debug!(%gid, "Failed to encapsulate: {e}"))
+            .ok()
+            .flatten()
+        else {
+            return;
+        };
+
+        tracing::trace!(%gid, %query_id, "Forwarding DNS query to gateway");
+
+        self.gateway_dns_queries
+            .insert((query_id, sentinel), (gid, origin, now + IDS_EXPIRE));
+        self.buffered_transmits
+            .push_back(encrypted.to_transmit(&self.dns_encrypt_buffer).into_owned());
+    }
+
+    /// Sends a DNS query that arrived over TCP as a UDP datagram through the tunnel to an upstream resolver that is covered by a resource.
+    ///
+    /// Queries over UDP are routed through the tunnel like any other packet, see [`ClientState::try_handle_dns_query`].
+    /// The response is passed back as is, even if it is truncated.
+    /// Like in [`ClientState::send_dns_query_via_gateway`], the query is dropped until we are connected to the gateway.
+    fn send_dns_query_through_tunnel(
+        &mut self,
+        server: SocketAddr,
+        message: Vec<u8>,
+        origin: dns::QueryOrigin,
+        now: Instant,
+    ) {
+        let Ok(query_id) = Message::from_slice(&message).map(|m| m.header().id()) else {
+            return;
+        };
+
+        let Some(resource) = self.get_resource_by_destination(server.ip()) else {
+            return;
+        };
+
+        let Some(peer) = peer_by_resource_mut(&self.resources_gateways, &mut self.peers, resource)
+        else {
+            self.on_not_connected_resource(resource, &server.ip(), now);
+            return;
+        };
+        let gid = peer.id();
+
+        let source = origin.source();
+        let Ok(packet) = ip_packet::make::udp_packet(
+            source.ip(),
+            server.ip(),
+            source.port(),
+            server.port(),
+            message,
+        ) else {
+            return;
+        };
+
+        let Some(encrypted) = self
+            .node
+            .encapsulate(
+                gid,
+                packet.as_immutable(),
+                now,
+                &mut self.dns_encrypt_buffer,
+            )
+            .inspect_err(|e| tracing::debug!(%gid, "Failed to encapsulate: {e}"))
+            .ok()
+            .flatten()
+        else {
+            return;
+        };
+
+        tracing::trace!(%gid, %server, %query_id, "Forwarding DNS query over TCP through the tunnel");
+
+        self.tunneled_dns_queries
+            .insert((query_id, server), (gid, origin, now + IDS_EXPIRE));
+        self.buffered_transmits
+            .push_back(encrypted.to_transmit(&self.dns_encrypt_buffer).into_owned());
+    }
+
+    /// Attempt to handle the given packet as the response to a DNS query that we sent through the tunnel on behalf of a TCP connection.
+    ///
+    /// Returns `true` if the packet was consumed.
+    fn try_handle_tunneled_dns_response(
+        &mut self,
+        gid: GatewayId,
+        packet: IpPacket<'_>,
+        now: Instant,
+    ) -> bool {
+        let Some(datagram) = packet.as_udp() else {
+            return false;
+        };
+        let server = SocketAddr::new(packet.source(), datagram.get_source());
+        let destination = SocketAddr::new(packet.destination(), datagram.get_destination());
+
+        let Ok(message) = Message::from_slice(datagram.payload()) else {
+            return false;
+        };
+        let query_id = message.header().id();
+
+        let Entry::Occupied(entry) = self.tunneled_dns_queries.entry((query_id, server)) else {
+            return false;
+        };
+        let (expected_gid, origin, _) = entry.get();
+        if *expected_gid != gid || origin.source() != destination {
+            return false;
+        }
+        let (_, origin, _) = entry.remove();
+
+        tracing::trace!(%gid, %server, %query_id, "Received DNS response through the tunnel");
+
+        self.send_dns_response(origin, datagram.payload().to_vec(), now);
+
+        true
+    }
+
+    /// Attempt to handle the given packet as the response to a DNS query that we sent to a gateway.
+    ///
+    /// Returns `true` if the packet was consumed.
+    fn try_handle_gateway_dns_response(
+        &mut self,
+        gid: GatewayId,
+        packet: IpPacket<'_>,
+        now: Instant,
+    ) -> bool {
+        let Some(datagram) = packet.as_udp() else {
+            return false;
+        };
+        let sentinel = SocketAddr::new(packet.source(), datagram.get_source());
+
+        if !self.dns_mapping.contains_left(&sentinel.ip()) {
+            return false;
+        }
+
+        let Ok(message) = Message::from_slice(datagram.payload()) else {
+            return false;
+        };
+        let query_id = message.header().id();
+
+        let Entry::Occupied(entry) = self.gateway_dns_queries.entry((query_id, sentinel)) else {
+            return false;
+        };
+        if entry.get().0 != gid {
+            return false;
+        }
+        let (_, origin, _) = entry.remove();
+
+        tracing::trace!(%gid, %query_id, "Received DNS response from gateway");
+
+        let Some(response) = self
+            .stub_resolver
+            .rewrite_gateway_response(datagram.payload())
+        else {
+            tracing::debug!(%gid, %query_id, "Failed to rewrite DNS response from gateway");
+            return true;
+        };
+
+        self.send_dns_response(origin, response, now);
+
+        true
+    }
+
+    /// Sends a DNS response back to the application that sent the query.
+    fn send_dns_response(&mut self, origin: dns::QueryOrigin, response: Vec<u8>, now: Instant) {
+        match origin {
+            dns::QueryOrigin::Udp { sentinel, source } => {
+                let Ok(packet) = ip_packet::make::udp_packet(
+                    sentinel.ip(),
+                    source.ip(),
+                    sentinel.port(),
+                    source.port(),
+                    response,
+                ) else {
+                    return;
+                };
+
+                self.buffered_packets.push_back(packet.into_immutable());
+            }
+            dns::QueryOrigin::Tcp(connection) => {
+                self.tcp_dns_server
+                    .send_response(connection, &response, now);
+            }
+        }
+    }
+
+    pub(crate) fn poll_dns_query(&mut self) -> Option<dns::ForwardedQuery> {
+        self.buffered_dns_queries.pop_front()
+    }
+
     fn try_handle_forwarded_dns_response<'a>(
         &mut self,
         from: SocketAddr,
         packet: &[u8],
+        now: Instant,
     ) -> Option<IpPacket<'a>> {
         // The sentinel DNS server shall be the source. If we don't have a sentinel DNS for this socket, it cannot be a DNS response.
         let saddr = *self.dns_mapping.get_by_right(&DnsServer::from(from))?;
@@ -673,6 +1119,8 @@ impl ClientState {
 
         tracing::trace!(server = %from, %query_id, "Received forwarded DNS response");
 
+        self.dns_cache.insert(&DnsServer::from(from), packet, now);
+
         let daddr = destination.ip();
         let dport = destination.port();
 
@@ -833,6 +1281,20 @@ impl ClientState {
             .or(self.internet_resource)
     }
 
+    pub(crate) fn set_dns_resource_ttl(&mut self, ttl: Duration) {
+        self.stub_resolver.set_resource_ttl(ttl);
+    }
+
+    pub(crate) fn dns_cache_stats(&self) -> CacheStats {
+        self.dns_cache.stats()
+    }
+
+    pub(crate) fn connection_stats(&self) -> BTreeMap<GatewayId, ConnectionStats> {
+        let (_, connections) = self.node.stats();
+
+        connections.collect()
+    }
+
     pub(crate) fn update_system_resolvers(&mut self, new_dns: Vec<IpAddr>) {
         tracing::debug!(servers = ?new_dns, "Received system-defined DNS servers");
 
@@ -842,13 +1304,21 @@ impl ClientState {
     }
 
     pub(crate) fn update_interface_config(&mut self, config: InterfaceConfig) {
-        tracing::trace!(upstream_dns = ?config.upstream_dns, ipv4 = %config.ipv4, ipv6 = %config.ipv6, "Received interface configuration from portal");
+        tracing::trace!(upstream_dns = ?config.upstream_dns, search_domains = ?config.search_domains, ipv4 = %config.ipv4, ipv6 = %config.ipv6, "Received interface configuration from portal");
 
         match self.tun_config.as_mut() {
             Some(existing) => {
                 // We don't really expect these to change but let's update them anyway.
                 existing.ip4 = config.ipv4;
                 existing.ip6 = config.ipv6;
+
+                // Search domains are applied by the OS, so changes to them need to be propagated.
+                let new_tun_config = TunConfig {
+                    search_domains: config.search_domains,
+                    ..existing.clone()
+                };
+
+                self.maybe_update_tun_config(new_tun_config);
             }
             None => {
                 let (ipv4_routes, ipv6_routes) = self.routes().partition_map(|route| match route {
@@ -859,6 +1329,7 @@ impl ClientState {
                     ip4: config.ipv4,
                     ip6: config.ipv6,
                     dns_by_sentinel: Default::default(),
+                    search_domains: config.search_domains,
                     ipv4_routes,
                     ipv6_routes,
                 };
@@ -873,7 +1344,9 @@ impl ClientState {
     }
 
     pub fn poll_packets(&mut self) -> Option<IpPacket<'static>> {
-        self.buffered_packets.pop_front()
+        self.buffered_packets
+            .pop_front()
+            .or_else(|| self.tcp_dns_server.poll_packet())
     }
 
     pub fn poll_timeout(&mut self) -> Option<Instant> {
@@ -881,14 +1354,22 @@ impl ClientState {
         // Thus, sorting these values on-demand even within `poll_timeout` is expected to be performant enough.
         let next_dns_query_expiry = self.mangled_dns_queries.values().min().copied();
         let next_node_timeout = self.node.poll_timeout();
+        let next_tcp_dns_timeout = self.tcp_dns_server.poll_timeout();
 
-        earliest(next_dns_query_expiry, next_node_timeout)
+        earliest(
+            earliest(next_dns_query_expiry, next_node_timeout),
+            next_tcp_dns_timeout,
+        )
     }
 
     pub fn handle_timeout(&mut self, now: Instant) {
         self.node.handle_timeout(now);
         self.mangled_dns_queries.retain(|_, exp| now < *exp);
         self.forwarded_dns_queries.retain(|_, (_, exp)| now < *exp);
+        self.gateway_dns_queries.retain(|_, (_, _, exp)| now < *exp);
+        self.tunneled_dns_queries
+            .retain(|_, (_, _, exp)| now < *exp);
+        self.tcp_dns_server.handle_timeout(now);
 
         self.drain_node_events();
     }
@@ -963,6 +1444,26 @@ impl ClientState {
                     self.update_site_status_by_gateway(&id, Status::Online);
                     resources_changed = true;
                 }
+                snownet::Event::ConnectionPathChanged {
+                    connection,
+                    old,
+                    new,
+                } => {
+                    let resources = self
+                        .resources_gateways
+                        .iter()
+                        .filter(|(_, gateway)| **gateway == connection)
+                        .map(|(resource, _)| *resource)
+                        .collect();
+
+                    self.buffered_events
+                        .push_back(ClientEvent::ConnectionPathChanged {
+                            gateway_id: connection,
+                            resources,
+                            old,
+                            new,
+                        });
+                }
             }
         }
 
@@ -1002,7 +1503,11 @@ impl ClientState {
     }
 
     pub(crate) fn poll_event(&mut self) -> Option<ClientEvent> {
-        self.buffered_events.pop_front()
+        self.buffered_events.pop_front().or_else(|| {
+            self.stub_resolver
+                .poll_resolution()
+                .map(ClientEvent::DnsResolved)
+        })
     }
 
     pub(crate) fn reset(&mut self) {
@@ -1159,6 +1664,10 @@ impl ClientState {
     }
 
     fn update_dns_mapping(&mut self) {
+        // Our DNS servers may have changed, as may have the network we are on: Don't serve any stale answers.
+        tracing::debug!(stats = ?self.dns_cache.stats(), "Flushing DNS cache");
+        self.dns_cache.clear();
+
         let Some(config) = self.tun_config.clone() else {
             // For the Tauri clients this can happen because it's called immediately after phoenix_channel's connect, before on_set_interface_config
             tracing::debug!("Unable to update DNS servers without interface configuration");
@@ -1198,6 +1707,7 @@ impl ClientState {
                 .iter()
                 .map(|(sentinel_dns, effective_dns)| (*sentinel_dns, effective_dns.address()))
                 .collect::<BiMap<_, _>>(),
+            search_domains: config.search_domains,
             ipv4_routes,
             ipv6_routes,
         };
@@ -1505,6 +2015,25 @@ mod tests {
         )
     }
 
+    #[test]
+    fn repeated_interface_config_does_not_update_tun() {
+        let mut client_state = ClientState::for_test();
+        let config = InterfaceConfig {
+            ipv4: "100.64.0.1".parse().unwrap(),
+            ipv6: "fd00:2021:1111::1".parse().unwrap(),
+            upstream_dns: dns_list(),
+            search_domains: Vec::new(),
+        };
+
+        client_state.update_interface_config(config.clone());
+        assert!(std::iter::from_fn(|| client_state.poll_event())
+            .any(|e| matches!(e, ClientEvent::TunInterfaceUpdated(_))));
+
+        client_state.update_interface_config(config);
+        assert!(!std::iter::from_fn(|| client_state.poll_event())
+            .any(|e| matches!(e, ClientEvent::TunInterfaceUpdated(_))));
+    }
+
     impl ClientState {
         pub fn for_test() -> ClientState {
             ClientState::new(
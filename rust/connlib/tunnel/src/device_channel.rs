@@ -1,3 +1,6 @@
+use crate::gateway::ShardRouter;
+use futures::channel::mpsc;
+use futures_util::StreamExt as _;
 use ip_packet::{IpPacket, MutableIpPacket, Packet as _};
 use std::io;
 use std::task::{Context, Poll, Waker};
@@ -6,6 +9,11 @@ use tun::Tun;
 pub struct Device {
     tun: Option<Box<dyn Tun>>,
     waker: Option<Waker>,
+
+    /// Routes packets that belong to clients of other shards, if the TUN device has multiple queues.
+    router: Option<ShardRouter>,
+    /// Packets that other shards read from their TUN queue but which belong to our clients.
+    handoffs: Option<mpsc::Receiver<Vec<u8>>>,
 }
 
 impl Device {
@@ -13,9 +21,20 @@ impl Device {
         Self {
             tun: None,
             waker: None,
+            router: None,
+            handoffs: None,
         }
     }
 
+    pub(crate) fn set_shard_router(
+        &mut self,
+        router: ShardRouter,
+        handoffs: mpsc::Receiver<Vec<u8>>,
+    ) {
+        self.router = Some(router);
+        self.handoffs = Some(handoffs);
+    }
+
     pub(crate) fn set_tun(&mut self, tun: Box<dyn Tun>) {
         tracing::info!(name = %tun.name(), "Initializing TUN device");
 
@@ -33,19 +52,19 @@ impl Device {
     ) -> Poll<io::Result<MutableIpPacket<'b>>> {
         use ip_packet::Packet as _;
 
-        let Some(tun) = self.tun.as_mut() else {
-            self.waker = Some(cx.waker().clone());
-            return Poll::Pending;
-        };
+        let n = loop {
+            let n = std::task::ready!(self.poll_read_raw(&mut buf[20..], cx))?;
 
-        let n = std::task::ready!(tun.poll_read(&mut buf[20..], cx))?;
+            if self
+                .router
+                .as_mut()
+                .is_some_and(|router| router.try_hand_off(&buf[20..(n + 20)]))
+            {
+                continue;
+            }
 
-        if n == 0 {
-            return Poll::Ready(Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "device is closed",
-            )));
-        }
+            break n;
+        };
 
         let packet = MutableIpPacket::new(&mut buf[..(n + 20)]).ok_or_else(|| {
             io::Error::new(
@@ -59,6 +78,37 @@ impl Device {
         Poll::Ready(Ok(packet))
     }
 
+    fn poll_read_raw(&mut self, buf: &mut [u8], cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        while let Some(Poll::Ready(Some(packet))) =
+            self.handoffs.as_mut().map(|h| h.poll_next_unpin(cx))
+        {
+            // Never forward a truncated packet, the shard that read it from its TUN queue might use a larger buffer than us.
+            let Some(dst) = buf.get_mut(..packet.len()) else {
+                tracing::debug!(len = %packet.len(), buf_len = %buf.len(), "Dropping handed off packet that doesn't fit into our buffer");
+                continue;
+            };
+            dst.copy_from_slice(&packet);
+
+            return Poll::Ready(Ok(packet.len()));
+        }
+
+        let Some(tun) = self.tun.as_mut() else {
+            self.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        };
+
+        let n = std::task::ready!(tun.poll_read(buf, cx))?;
+
+        if n == 0 {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "device is closed",
+            )));
+        }
+
+        Poll::Ready(Ok(n))
+    }
+
     pub fn write(&self, packet: IpPacket<'_>) -> io::Result<usize> {
         tracing::trace!(target: "wire::dev::send", dst = %packet.destination(), src = %packet.source(), bytes = %packet.packet().len());
 
@@ -1,23 +1,44 @@
 use crate::client::IpProvider;
+use connlib_shared::callbacks::{DnsAnswerSource, DnsResolution};
 use connlib_shared::messages::{DnsServer, ResourceId};
 use connlib_shared::DomainName;
 use domain::base::{
     iana::{Class, Rcode, Rtype},
-    Message, MessageBuilder, ToName,
+    message::RecordSection,
+    rdata::{ComposeRecordData as _, UnknownRecordData},
+    Message, MessageBuilder, Record, ToName,
 };
 use domain::rdata::AllRecordData;
 use ip_packet::IpPacket;
 use ip_packet::Packet as _;
 use itertools::Itertools;
 use pattern::{Candidate, Pattern};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-
-const DNS_TTL: u32 = 1;
+use std::time::Duration;
+use trie::PatternTrie;
+
+mod cache;
+pub(crate) mod tcp;
+mod trie;
+
+pub(crate) use cache::Cache;
+pub use cache::CacheStats;
+
+/// The default TTL of the records we synthesise for DNS resources.
+///
+/// It is deliberately short such that applications re-query and pick up changes to resources quickly.
+const DEFAULT_RESOURCE_TTL: Duration = Duration::from_secs(1);
+/// How many resolutions we buffer at most if the embedding application doesn't consume them quickly enough.
+const MAX_BUFFERED_RESOLUTIONS: usize = 1000;
 const REVERSE_DNS_ADDRESS_END: &str = "arpa";
 const REVERSE_DNS_ADDRESS_V4: &str = "in-addr";
 const REVERSE_DNS_ADDRESS_V6: &str = "ip6";
 const DNS_PORT: u16 = 53;
+/// The `ipv4hint` key of SVCB and HTTPS records, see RFC 9460.
+const SVCB_IPV4_HINT: u16 = 4;
+/// The `ipv6hint` key of SVCB and HTTPS records, see RFC 9460.
+const SVCB_IPV6_HINT: u16 = 6;
 
 pub struct StubResolver {
     fqdn_to_ips: HashMap<DomainName, Vec<IpAddr>>,
@@ -25,8 +46,14 @@ pub struct StubResolver {
     ip_provider: IpProvider,
     /// All DNS resources we know about, indexed by the glob pattern they match against.
     dns_resources: HashMap<Pattern, ResourceId>,
+    /// The same patterns as in `dns_resources`, indexed for matching domains against them.
+    dns_resource_trie: PatternTrie,
     /// Fixed dns name that will be resolved to fixed ip addrs, similar to /etc/hosts
     known_hosts: KnownHosts,
+    /// The TTL of the records we synthesise.
+    resource_ttl: Duration,
+    /// The DNS queries we resolved, for consumption by embedding applications.
+    resolutions: VecDeque<DnsResolution>,
 }
 
 /// Tells the Client how to reply to a single DNS query
@@ -36,11 +63,75 @@ pub(crate) enum ResolveStrategy {
     LocalResponse(IpPacket<'static>),
     /// The query is for a non-Resource, forward it to an upstream or system resolver.
     ForwardQuery {
-        upstream: SocketAddr,
+        upstream: DnsServer,
         original_src: SocketAddr,
         query_id: u16,
         payload: Vec<u8>,
     },
+    /// The query is for a Resource but we cannot synthesise the record type, forward it to the Resource's gateway.
+    ForwardToGateway {
+        resource: ResourceId,
+        /// One of the proxy IPs we assigned to the queried domain.
+        proxy_ip: IpAddr,
+        original_src: SocketAddr,
+        payload: Vec<u8>,
+    },
+}
+
+/// How to answer a single DNS query message, independent of the transport it arrived on.
+#[derive(Debug)]
+pub(crate) enum Resolution {
+    /// We can answer the query ourselves with the contained response message.
+    Local(Vec<u8>),
+    /// The query is for a non-Resource and needs to be answered by the upstream resolver.
+    Forward,
+    /// The query is for a Resource but needs to be answered by the gateway of the Resource, e.g. SRV or TXT records.
+    ///
+    /// `proxy_ip` is one of the proxy IPs we assigned to the queried domain, routing to it establishes access to the domain on the gateway.
+    ViaGateway {
+        resource: ResourceId,
+        proxy_ip: IpAddr,
+    },
+}
+
+/// A DNS query that needs to be forwarded to an upstream resolver over a stream-based transport.
+///
+/// Depending on the [`DnsServer`], that is plain TCP, TLS or HTTPS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ForwardedQuery {
+    pub(crate) server: DnsServer,
+    pub(crate) message: Vec<u8>,
+    /// Where to send the response to.
+    pub(crate) origin: QueryOrigin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum QueryOrigin {
+    /// The query was sent as a UDP datagram from `source` to our `sentinel`.
+    Udp {
+        sentinel: SocketAddr,
+        source: SocketAddr,
+    },
+    /// The query was sent over a TCP connection to one of our sentinels.
+    Tcp(tcp::ConnectionId),
+}
+
+impl QueryOrigin {
+    /// The sentinel DNS server that the query was sent to.
+    pub(crate) fn sentinel(&self) -> SocketAddr {
+        match self {
+            QueryOrigin::Udp { sentinel, .. } => *sentinel,
+            QueryOrigin::Tcp(connection) => connection.local,
+        }
+    }
+
+    /// The socket of the application that sent the query.
+    pub(crate) fn source(&self) -> SocketAddr {
+        match self {
+            QueryOrigin::Udp { source, .. } => *source,
+            QueryOrigin::Tcp(connection) => connection.remote,
+        }
+    }
 }
 
 struct KnownHosts {
@@ -93,10 +184,17 @@ impl StubResolver {
             ips_to_fqdn: Default::default(),
             ip_provider: IpProvider::for_resources(),
             dns_resources: Default::default(),
+            dns_resource_trie: Default::default(),
             known_hosts: KnownHosts::new(known_hosts),
+            resource_ttl: DEFAULT_RESOURCE_TTL,
+            resolutions: Default::default(),
         }
     }
 
+    pub(crate) fn set_resource_ttl(&mut self, ttl: Duration) {
+        self.resource_ttl = ttl;
+    }
+
     /// Attempts to resolve an IP to a given resource.
     ///
     /// Semantically, this is like a PTR query, i.e. we check whether we handed out this IP as part of answering a DNS query for one of our resources.
@@ -121,13 +219,26 @@ impl StubResolver {
             }
         };
 
+        if let Err(e) = self.dns_resource_trie.insert(&parsed_pattern, id) {
+            tracing::warn!(%pattern, "Domain pattern is not supported: {e}");
+            return false;
+        }
         let existing = self.dns_resources.insert(parsed_pattern, id);
 
         existing.is_none()
     }
 
     pub(crate) fn remove_resource(&mut self, id: ResourceId) {
-        self.dns_resources.retain(|_, r| *r != id);
+        let dns_resource_trie = &mut self.dns_resource_trie;
+
+        self.dns_resources.retain(|pattern, r| {
+            if *r != id {
+                return true;
+            }
+
+            dns_resource_trie.remove(pattern);
+            false
+        });
     }
 
     fn get_or_assign_a_records(
@@ -167,24 +278,18 @@ impl StubResolver {
 
     /// Attempts to match the given domain against our list of possible patterns.
     ///
-    /// This performs a linear search and is thus O(N) and **must not** be called in the hot-path of packet routing.
+    /// If several patterns match, the most specific one wins, see [`PatternTrie`].
     #[tracing::instrument(level = "trace", skip_all, fields(%domain))]
-    fn match_resource_linear(&self, domain: &DomainName) -> Option<ResourceId> {
-        let name = Candidate::from_domain(domain);
-
-        for (pattern, id) in &self.dns_resources {
-            if pattern.matches(&name) {
-                tracing::trace!(%id, %pattern, "Matched domain");
-
-                return Some(*id);
-            }
+    fn match_resource(&self, domain: &DomainName) -> Option<ResourceId> {
+        let Some(id) = self.dns_resource_trie.find(domain) else {
+            tracing::trace!("No resources matched");
 
-            tracing::trace!(%pattern, %id, "No match");
-        }
+            return None;
+        };
 
-        tracing::trace!("No resources matched");
+        tracing::trace!(%id, "Matched domain");
 
-        None
+        Some(id)
     }
 
     fn resource_address_name_by_reservse_dns(
@@ -201,6 +306,12 @@ impl StubResolver {
         self.dns_resources.values().contains(resource)
     }
 
+    /// Returns the resource for the given domain, if it is one of our DNS resources.
+    fn resource_by_domain(&self, domain: &DomainName) -> Option<ResourceId> {
+        self.match_resource(domain)
+            .filter(|resource| self.knows_resource(resource))
+    }
+
     /// Parses an incoming packet as a DNS query and decides how to respond to it
     ///
     /// Returns:
@@ -211,7 +322,7 @@ impl StubResolver {
         dns_mapping: &bimap::BiMap<IpAddr, DnsServer>,
         packet: IpPacket,
     ) -> Option<ResolveStrategy> {
-        let upstream = dns_mapping.get_by_left(&packet.destination())?.address();
+        let upstream = dns_mapping.get_by_left(&packet.destination())?.clone();
         let datagram = packet.as_udp()?;
 
         // We only support DNS on port 53.
@@ -219,7 +330,44 @@ impl StubResolver {
             return None;
         }
 
-        let message = Message::from_octets(datagram.payload()).ok()?;
+        let query = datagram.payload();
+
+        match self.resolve(query)? {
+            Resolution::Local(response) => {
+                let packet = ip_packet::make::udp_packet(
+                    packet.destination(),
+                    packet.source(),
+                    datagram.get_destination(),
+                    datagram.get_source(),
+                    response,
+                )
+                .expect("src and dst come from the same packet")
+                .into_immutable();
+
+                Some(ResolveStrategy::LocalResponse(packet))
+            }
+            Resolution::Forward => Some(ResolveStrategy::ForwardQuery {
+                upstream,
+                query_id: Message::from_octets(query).ok()?.header().id(),
+                payload: query.to_vec(),
+                original_src: SocketAddr::new(packet.source(), datagram.get_source()),
+            }),
+            Resolution::ViaGateway { resource, proxy_ip } => {
+                Some(ResolveStrategy::ForwardToGateway {
+                    resource,
+                    proxy_ip,
+                    original_src: SocketAddr::new(packet.source(), datagram.get_source()),
+                    payload: query.to_vec(),
+                })
+            }
+        }
+    }
+
+    /// Decides how to respond to a single DNS query message, regardless of the transport it arrived on.
+    ///
+    /// Returns `None` if the message is not a valid DNS query.
+    pub(crate) fn resolve(&mut self, query: &[u8]) -> Option<Resolution> {
+        let message = Message::from_octets(query).ok()?;
 
         if message.header().qr() {
             return None;
@@ -234,67 +382,253 @@ impl StubResolver {
         let domain = question.qname().to_vec();
         let qtype = question.qtype();
 
-        tracing::trace!("Parsed packet as DNS query: '{qtype} {domain}'");
+        tracing::trace!("Parsed DNS query: '{qtype} {domain}'");
 
         if let Some(records) = self.known_hosts.get_records(qtype, &domain) {
-            let response = build_dns_with_answer(message, domain, records)?;
-            let packet = ip_packet::make::udp_packet(
-                packet.destination(),
-                packet.source(),
-                datagram.get_destination(),
-                datagram.get_source(),
-                response,
-            )
-            .expect("src and dst come from the same packet")
-            .into_immutable();
-
-            return Some(ResolveStrategy::LocalResponse(packet));
+            self.push_resolution(
+                domain.clone(),
+                qtype,
+                None,
+                record_ips(&records),
+                DnsAnswerSource::Local,
+            );
+            let response = build_dns_with_answer(message, domain, records, self.resource_ttl)?;
+
+            return Some(Resolution::Local(response));
         }
 
-        // `match_resource` is `O(N)` which we deem fine for DNS queries.
-        let maybe_resource = self.match_resource_linear(&domain);
+        let maybe_resource = self.match_resource(&domain);
 
         let resource_records = match (qtype, maybe_resource) {
             (_, Some(resource)) if !self.knows_resource(&resource) => {
-                return Some(ResolveStrategy::ForwardQuery {
-                    upstream,
-                    query_id: message.header().id(),
-                    payload: message.into_octets().to_vec(),
-                    original_src: SocketAddr::new(packet.source(), datagram.get_source()),
-                })
+                self.push_resolution(domain, qtype, None, vec![], DnsAnswerSource::Upstream);
+
+                return Some(Resolution::Forward);
             }
             (Rtype::A, Some(resource)) => self.get_or_assign_a_records(domain.clone(), resource),
             (Rtype::AAAA, Some(resource)) => {
                 self.get_or_assign_aaaa_records(domain.clone(), resource)
             }
+            (
+                Rtype::CNAME | Rtype::MX | Rtype::TXT | Rtype::SRV | Rtype::SVCB | Rtype::HTTPS,
+                Some(resource),
+            ) => {
+                let ips = self.get_or_assign_ips(domain.clone(), resource);
+                let proxy_ip = *ips.first()?;
+
+                self.push_resolution(domain, qtype, Some(resource), ips, DnsAnswerSource::Gateway);
+
+                return Some(Resolution::ViaGateway { resource, proxy_ip });
+            }
             (Rtype::PTR, _) => {
                 let fqdn = self.resource_address_name_by_reservse_dns(&domain)?;
 
                 vec![AllRecordData::Ptr(domain::rdata::Ptr::new(fqdn))]
             }
             _ => {
-                return Some(ResolveStrategy::ForwardQuery {
-                    upstream,
-                    query_id: message.header().id(),
-                    payload: message.into_octets().to_vec(),
-                    original_src: SocketAddr::new(packet.source(), datagram.get_source()),
-                })
+                self.push_resolution(
+                    domain,
+                    qtype,
+                    maybe_resource,
+                    vec![],
+                    DnsAnswerSource::Upstream,
+                );
+
+                return Some(Resolution::Forward);
             }
         };
 
-        let response = build_dns_with_answer(message, domain, resource_records)?;
-        let packet = ip_packet::make::udp_packet(
-            packet.destination(),
-            packet.source(),
-            datagram.get_destination(),
-            datagram.get_source(),
-            response,
-        )
-        .expect("src and dst come from the same packet")
-        .into_immutable();
+        self.push_resolution(
+            domain.clone(),
+            qtype,
+            maybe_resource,
+            record_ips(&resource_records),
+            DnsAnswerSource::Local,
+        );
+        let response = build_dns_with_answer(message, domain, resource_records, self.resource_ttl)?;
+
+        Some(Resolution::Local(response))
+    }
+
+    /// Returns the next DNS query we resolved, see [`DnsResolution`].
+    pub(crate) fn poll_resolution(&mut self) -> Option<DnsResolution> {
+        self.resolutions.pop_front()
+    }
+
+    fn push_resolution(
+        &mut self,
+        name: DomainName,
+        qtype: Rtype,
+        resource: Option<ResourceId>,
+        ips: Vec<IpAddr>,
+        answered_by: DnsAnswerSource,
+    ) {
+        if self.resolutions.len() >= MAX_BUFFERED_RESOLUTIONS {
+            tracing::trace!("Too many buffered DNS resolutions, dropping the oldest");
+            self.resolutions.pop_front();
+        }
+
+        self.resolutions.push_back(DnsResolution {
+            name,
+            qtype: qtype.to_string(),
+            resource,
+            ips,
+            answered_by,
+        });
+    }
+
+    /// Rewrites a DNS response that a gateway resolved for us such that it doesn't contain the real IPs of our DNS resources.
+    ///
+    /// - A and AAAA records of resources are removed, applications need to query for those separately and get our proxy IPs.
+    /// - IP hints within SVCB and HTTPS records of resources are replaced with our proxy IPs.
+    pub(crate) fn rewrite_gateway_response(&mut self, response: &[u8]) -> Option<Vec<u8>> {
+        let message = Message::from_octets(response).ok()?;
+
+        let answers = self.rewrite_records(message.answer().ok()?)?;
+        let authorities = self.rewrite_records(message.authority().ok()?)?;
+        let additionals = self.rewrite_records(message.additional().ok()?)?;
+
+        let mut builder = MessageBuilder::new_vec();
+        *builder.header_mut() = message.header();
+
+        let mut question_builder = builder.question();
+        for question in message.question() {
+            question_builder.push(question.ok()?).ok()?;
+        }
+
+        let mut answer_builder = question_builder.answer();
+        for record in answers {
+            answer_builder.push(record).ok()?;
+        }
+
+        let mut authority_builder = answer_builder.authority();
+        for record in authorities {
+            authority_builder.push(record).ok()?;
+        }
 
-        Some(ResolveStrategy::LocalResponse(packet))
+        let mut additional_builder = authority_builder.additional();
+        for record in additionals {
+            additional_builder.push(record).ok()?;
+        }
+
+        Some(additional_builder.finish())
     }
+
+    /// Rewrites all records of a section, see [`StubResolver::rewrite_gateway_response`].
+    ///
+    /// All record data is re-encoded without name compression because we change the layout of the message.
+    fn rewrite_records(
+        &mut self,
+        section: RecordSection<'_, &[u8]>,
+    ) -> Option<Vec<Record<DomainName, UnknownRecordData<Vec<u8>>>>> {
+        let mut records = Vec::new();
+
+        for record in section {
+            let record = record.ok()?.into_any_record::<AllRecordData<_, _>>().ok()?;
+            let owner = record.owner().to_name::<Vec<u8>>();
+            let rtype = record.rtype();
+
+            let mut rdata = Vec::new();
+            record.data().compose_rdata(&mut rdata).ok()?;
+
+            match rtype {
+                Rtype::A | Rtype::AAAA if self.resource_by_domain(&owner).is_some() => {
+                    tracing::trace!(%owner, "Removing record of DNS resource from response");
+
+                    continue;
+                }
+                Rtype::SVCB | Rtype::HTTPS => {
+                    let target = svcb_target(&rdata)?;
+                    // A target of `.` means the record applies to the owner itself.
+                    let domain = if target.is_root() {
+                        owner.clone()
+                    } else {
+                        target
+                    };
+
+                    if let Some(resource) = self.resource_by_domain(&domain) {
+                        let proxy_ips = self.get_or_assign_ips(domain, resource);
+
+                        rdata = rewrite_svcb_ip_hints(&rdata, &proxy_ips)?;
+                    }
+                }
+                _ => {}
+            }
+
+            records.push(Record::new(
+                owner,
+                record.class(),
+                record.ttl(),
+                UnknownRecordData::from_octets(rtype, rdata).ok()?,
+            ));
+        }
+
+        Some(records)
+    }
+}
+
+/// Parses the target name of SVCB or HTTPS record data.
+///
+/// RFC 9460 forbids name compression for the target, so we can parse it from the record data alone.
+fn svcb_target(rdata: &[u8]) -> Option<DomainName> {
+    let end = svcb_target_end(rdata)?;
+
+    DomainName::from_octets(rdata.get(2..end)?.to_vec()).ok()
+}
+
+/// Returns the offset at which the target name of SVCB or HTTPS record data ends, i.e. where the parameters start.
+fn svcb_target_end(rdata: &[u8]) -> Option<usize> {
+    let mut pos = 2; // Skip the priority.
+
+    loop {
+        let len = usize::from(*rdata.get(pos)?);
+
+        // Labels are at most 63 bytes, anything longer would be a compression pointer.
+        if len > 63 {
+            return None;
+        }
+
+        pos += 1 + len;
+
+        if len == 0 {
+            return Some(pos);
+        }
+    }
+}
+
+/// Replaces the values of the `ipv4hint` and `ipv6hint` parameters of SVCB or HTTPS record data with the given IPs.
+fn rewrite_svcb_ip_hints(rdata: &[u8], ips: &[IpAddr]) -> Option<Vec<u8>> {
+    let params_start = svcb_target_end(rdata)?;
+    let mut params = rdata.get(params_start..)?;
+    let mut rewritten = rdata.get(..params_start)?.to_vec();
+
+    while !params.is_empty() {
+        let key = u16::from_be_bytes([*params.first()?, *params.get(1)?]);
+        let len = usize::from(u16::from_be_bytes([*params.get(2)?, *params.get(3)?]));
+        let value = params.get(4..4 + len)?;
+
+        let value = match key {
+            SVCB_IPV4_HINT => ips
+                .iter()
+                .filter_map(|ip| get_v4(*ip))
+                .flat_map(|ip| ip.octets())
+                .collect_vec(),
+            SVCB_IPV6_HINT => ips
+                .iter()
+                .filter_map(|ip| get_v6(*ip))
+                .flat_map(|ip| ip.octets())
+                .collect_vec(),
+            _ => value.to_vec(),
+        };
+
+        rewritten.extend_from_slice(&key.to_be_bytes());
+        rewritten.extend_from_slice(&u16::try_from(value.len()).ok()?.to_be_bytes());
+        rewritten.extend_from_slice(&value);
+
+        params = &params[4 + len..];
+    }
+
+    Some(rewritten)
 }
 
 fn to_a_records(ips: impl Iterator<Item = IpAddr>) -> Vec<AllRecordData<Vec<u8>, DomainName>> {
@@ -311,25 +645,54 @@ fn to_aaaa_records(ips: impl Iterator<Item = IpAddr>) -> Vec<AllRecordData<Vec<u
         .collect_vec()
 }
 
+/// The IPs contained in the given A and AAAA records.
+fn record_ips(records: &[AllRecordData<Vec<u8>, DomainName>]) -> Vec<IpAddr> {
+    records
+        .iter()
+        .filter_map(|record| {
+            if let AllRecordData::A(a) = record {
+                return Some(IpAddr::from(a.addr()));
+            }
+            if let AllRecordData::Aaaa(aaaa) = record {
+                return Some(IpAddr::from(aaaa.addr()));
+            }
+
+            None
+        })
+        .collect()
+}
+
 fn build_dns_with_answer(
     message: Message<&[u8]>,
     qname: DomainName,
     records: Vec<AllRecordData<Vec<u8>, DomainName>>,
+    ttl: Duration,
 ) -> Option<Vec<u8>> {
+    let ttl = u32::try_from(ttl.as_secs()).unwrap_or(u32::MAX);
+
     let mut answer_builder = MessageBuilder::new_vec()
         .start_answer(&message, Rcode::NOERROR)
         .ok()?;
     answer_builder.header_mut().set_ra(true);
 
     for record in records {
-        answer_builder
-            .push((&qname, Class::IN, DNS_TTL, record))
-            .ok()?;
+        answer_builder.push((&qname, Class::IN, ttl, record)).ok()?;
     }
 
     Some(answer_builder.finish())
 }
 
+/// Builds a `SERVFAIL` response for the given query.
+pub(crate) fn servfail(query: &[u8]) -> Option<Vec<u8>> {
+    let message = Message::from_octets(query).ok()?;
+
+    let response = MessageBuilder::new_vec()
+        .start_answer(&message, Rcode::SERVFAIL)
+        .ok()?;
+
+    Some(response.finish())
+}
+
 pub fn is_subdomain(name: &DomainName, resource: &str) -> bool {
     let pattern = match Pattern::new(resource) {
         Ok(p) => p,
@@ -438,6 +801,11 @@ mod pattern {
             })
         }
 
+        /// The labels of this pattern, from left to right.
+        pub fn labels(&self) -> impl DoubleEndedIterator<Item = &str> {
+            self.original.split('.')
+        }
+
         /// Matches a [`Candidate`] against this [`Pattern`].
         ///
         /// Matching only requires a reference, thus allowing users to test a [`Candidate`] against multiple [`Pattern`]s.
@@ -567,6 +935,7 @@ mod tests {
         let matches = pattern.matches(&candidate);
 
         assert!(matches);
+        assert!(trie_matches(&pattern, domain));
     }
 
     #[test_case("app.*.example.com", "app.foo.bar.example.com"; "single star does not match two level")]
@@ -579,6 +948,150 @@ mod tests {
         let matches = pattern.matches(&candidate);
 
         assert!(!matches);
+        assert!(!trie_matches(&pattern, domain));
+    }
+
+    #[test]
+    fn gateway_responses_dont_leak_ips_of_resources() {
+        let mut resolver = StubResolver::new(BTreeMap::default());
+        resolver.add_resource(ResourceId::from_u128(1), "*.example.com".to_owned());
+
+        let domain = DomainName::vec_from_str("foo.example.com").unwrap();
+        let real_ip = Ipv4Addr::new(10, 0, 0, 1);
+
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((&domain, Rtype::HTTPS)).unwrap();
+        let query = query.into_message();
+
+        let mut response = MessageBuilder::new_vec()
+            .start_answer(&query, Rcode::NOERROR)
+            .unwrap();
+        response
+            .push((
+                &domain,
+                Class::IN,
+                300,
+                UnknownRecordData::from_octets(Rtype::HTTPS, https_rdata(&[real_ip])).unwrap(),
+            ))
+            .unwrap();
+        let mut response = response.additional();
+        response
+            .push((&domain, Class::IN, 300, domain::rdata::A::new(real_ip)))
+            .unwrap();
+        response
+            .push((
+                DomainName::vec_from_str("example.org").unwrap(),
+                Class::IN,
+                300,
+                domain::rdata::A::new(Ipv4Addr::new(1, 1, 1, 1)),
+            ))
+            .unwrap();
+        let response = response.finish();
+
+        let rewritten = resolver.rewrite_gateway_response(&response).unwrap();
+
+        let proxy_ips = resolver
+            .fqdn_to_ips
+            .get(&domain)
+            .unwrap()
+            .iter()
+            .copied()
+            .filter_map(get_v4)
+            .collect_vec();
+        let expected_rdata = https_rdata(&proxy_ips);
+        let counts = Message::from_octets(rewritten.as_slice())
+            .unwrap()
+            .header_counts();
+
+        assert_eq!(counts.ancount(), 1);
+        assert_eq!(counts.arcount(), 1);
+        assert!(rewritten
+            .windows(expected_rdata.len())
+            .any(|w| w == expected_rdata));
+        assert!(!rewritten.windows(4).any(|w| w == real_ip.octets()));
+    }
+
+    #[test]
+    fn resource_srv_queries_are_resolved_by_gateway() {
+        let mut resolver = StubResolver::new(BTreeMap::default());
+        resolver.add_resource(ResourceId::from_u128(1), "**.corp.example.com".to_owned());
+
+        let domain = DomainName::vec_from_str("_ldap._tcp.corp.example.com").unwrap();
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((&domain, Rtype::SRV)).unwrap();
+
+        let resolution = resolver.resolve(&query.finish()).unwrap();
+
+        assert!(matches!(
+            resolution,
+            Resolution::ViaGateway { resource, .. } if resource == ResourceId::from_u128(1)
+        ));
+    }
+
+    #[test]
+    fn resolutions_record_assigned_proxy_ips() {
+        let mut resolver = StubResolver::new(BTreeMap::default());
+        resolver.add_resource(ResourceId::from_u128(1), "app.example.com".to_owned());
+
+        let domain = DomainName::vec_from_str("app.example.com").unwrap();
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((&domain, Rtype::A)).unwrap();
+
+        resolver.resolve(&query.finish()).unwrap();
+        let resolution = resolver.poll_resolution().unwrap();
+
+        assert_eq!(resolution.name, domain);
+        assert_eq!(resolution.qtype, "A");
+        assert_eq!(resolution.resource, Some(ResourceId::from_u128(1)));
+        assert_eq!(resolution.ips.len(), 4);
+        assert!(resolution.ips.iter().all(IpAddr::is_ipv4));
+        assert_eq!(resolution.answered_by, DnsAnswerSource::Local);
+        assert!(resolver.poll_resolution().is_none());
+    }
+
+    #[test]
+    fn buffered_resolutions_are_bounded() {
+        let mut resolver = StubResolver::new(BTreeMap::default());
+        resolver.add_resource(ResourceId::from_u128(1), "app.example.com".to_owned());
+
+        let domain = DomainName::vec_from_str("app.example.com").unwrap();
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((&domain, Rtype::A)).unwrap();
+        let query = query.finish();
+
+        for _ in 0..MAX_BUFFERED_RESOLUTIONS + 10 {
+            resolver.resolve(&query).unwrap();
+        }
+
+        assert_eq!(
+            std::iter::from_fn(|| resolver.poll_resolution()).count(),
+            MAX_BUFFERED_RESOLUTIONS
+        );
+    }
+
+    /// Makes the record data of an HTTPS record in service mode with an `alpn` and `ipv4hint` parameter.
+    fn https_rdata(ips: &[Ipv4Addr]) -> Vec<u8> {
+        let mut rdata = vec![0, 1, 0]; // Priority 1, target `.`
+
+        rdata.extend_from_slice(&1u16.to_be_bytes()); // alpn
+        rdata.extend_from_slice(&3u16.to_be_bytes());
+        rdata.extend_from_slice(b"\x02h2");
+
+        rdata.extend_from_slice(&SVCB_IPV4_HINT.to_be_bytes());
+        rdata.extend_from_slice(&(ips.len() as u16 * 4).to_be_bytes());
+        for ip in ips {
+            rdata.extend_from_slice(&ip.octets());
+        }
+
+        rdata
+    }
+
+    fn trie_matches(pattern: &Pattern, domain: &str) -> bool {
+        let mut trie = PatternTrie::default();
+        trie.insert(pattern, ResourceId::from_u128(1)).unwrap();
+
+        trie.find(&DomainName::vec_from_str(domain).unwrap())
+            .is_some()
     }
 }
 
@@ -590,7 +1103,7 @@ mod benches {
     #[divan::bench(
         consts = [10, 100, 1_000, 10_000, 100_000]
     )]
-    fn match_domain_linear<const NUM_RES: u128>(bencher: divan::Bencher) {
+    fn match_domain<const NUM_RES: u128>(bencher: divan::Bencher) {
         bencher
             .with_inputs(|| {
                 let mut resolver = StubResolver::new(BTreeMap::default());
@@ -611,7 +1124,7 @@ mod benches {
 
                 (resolver, needle)
             })
-            .bench_refs(|(resolver, needle)| resolver.match_resource_linear(needle).unwrap());
+            .bench_refs(|(resolver, needle)| resolver.match_resource(needle).unwrap());
     }
 
     fn make_domain(rng: &mut impl Rng) -> String {
@@ -0,0 +1,344 @@
+//! A cache for DNS responses of upstream resolvers.
+//!
+//! Responses are stored in their wire format and served until the smallest TTL of their records expires.
+//! When serving a response from the cache, we patch the query ID, the question and the remaining TTLs of all records in place.
+use connlib_shared::messages::DnsServer;
+use connlib_shared::DomainName;
+use domain::base::{
+    iana::{Rcode, Rtype},
+    Message,
+};
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+
+/// How many responses we cache at most.
+const MAX_ENTRIES: NonZeroUsize = unsafe { NonZeroUsize::new_unchecked(1000) };
+/// We never cache a response for longer than this, regardless of the TTLs of its records.
+const MAX_TTL: Duration = Duration::from_secs(60 * 60);
+
+const HEADER_LEN: usize = 12;
+/// The record type of the EDNS pseudo-record, whose TTL field carries flags instead of a TTL.
+const OPT_RTYPE: u16 = 41;
+
+/// A bounded, TTL-aware cache of DNS responses.
+///
+/// Responses are keyed by the upstream server that answered them, the queried domain and the record type.
+pub(crate) struct Cache {
+    entries: LruCache<(DnsServer, DomainName, Rtype), Entry>,
+    hits: u64,
+    misses: u64,
+}
+
+/// The number of queries we answered from the cache and those we had to forward.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+struct Entry {
+    response: Vec<u8>,
+    /// Byte offsets of the TTL fields of all records in `response`.
+    ttl_offsets: Vec<usize>,
+    inserted_at: Instant,
+    expires_at: Instant,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self {
+            entries: LruCache::new(MAX_ENTRIES),
+            hits: 0,
+            misses: 0,
+        }
+    }
+}
+
+impl Cache {
+    /// Looks up a response to the given query that `server` sent us earlier.
+    ///
+    /// Returns `None` if the query is invalid or we don't have an unexpired response.
+    pub(crate) fn get(
+        &mut self,
+        server: &DnsServer,
+        query: &[u8],
+        now: Instant,
+    ) -> Option<Vec<u8>> {
+        let key = cache_key(server, query)?;
+
+        let Some(entry) = self.entries.get(&key) else {
+            self.misses += 1;
+            return None;
+        };
+
+        if now >= entry.expires_at {
+            self.entries.pop(&key);
+            self.misses += 1;
+            return None;
+        }
+
+        let elapsed = now.duration_since(entry.inserted_at).as_secs();
+        let elapsed = u32::try_from(elapsed).unwrap_or(u32::MAX);
+
+        let mut response = entry.response.clone();
+
+        for offset in &entry.ttl_offsets {
+            let ttl_bytes = &mut response[*offset..*offset + 4];
+            let ttl = u32::from_be_bytes(ttl_bytes.try_into().expect("slice has length 4"));
+
+            ttl_bytes.copy_from_slice(&ttl.saturating_sub(elapsed).to_be_bytes());
+        }
+
+        // Applications expect the response to carry the ID of their query and the question exactly as they sent it, i.e. including the capitalisation of the domain.
+        response[..2].copy_from_slice(&query[..2]);
+        let query_question_end = skip_questions(query)?;
+        let response_question_end = skip_questions(&response)?;
+        if query_question_end == response_question_end {
+            response[HEADER_LEN..response_question_end]
+                .copy_from_slice(&query[HEADER_LEN..query_question_end]);
+        }
+
+        self.hits += 1;
+
+        Some(response)
+    }
+
+    /// Caches the response that `server` sent us, if it is cacheable.
+    pub(crate) fn insert(&mut self, server: &DnsServer, response: &[u8], now: Instant) {
+        let Ok(message) = Message::from_octets(response) else {
+            return;
+        };
+        let header = message.header();
+
+        if !header.qr() || header.tc() || message.header_counts().qdcount() != 1 {
+            return;
+        }
+
+        if !matches!(header.rcode(), Rcode::NOERROR | Rcode::NXDOMAIN) {
+            return;
+        }
+
+        let Some(key) = cache_key(server, response) else {
+            return;
+        };
+        let Some(ttl_offsets) = ttl_offsets(response) else {
+            return;
+        };
+
+        let Some(ttl) = ttl_offsets
+            .iter()
+            .map(|offset| read_u32(response, *offset))
+            .min()
+        else {
+            // A response without any records doesn't tell us how long it is valid for.
+            return;
+        };
+
+        let ttl = Duration::from_secs(ttl.into()).min(MAX_TTL);
+        if ttl.is_zero() {
+            return;
+        }
+
+        self.entries.put(
+            key,
+            Entry {
+                response: response.to_vec(),
+                ttl_offsets,
+                inserted_at: now,
+                expires_at: now + ttl,
+            },
+        );
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+        }
+    }
+}
+
+fn cache_key(server: &DnsServer, message: &[u8]) -> Option<(DnsServer, DomainName, Rtype)> {
+    let message = Message::from_octets(message).ok()?;
+    let question = message.sole_question().ok()?;
+
+    Some((server.clone(), question.qname().to_vec(), question.qtype()))
+}
+
+/// Computes the byte offsets of the TTL fields of all records in the given message, excluding the EDNS pseudo-record.
+fn ttl_offsets(message: &[u8]) -> Option<Vec<usize>> {
+    let num_records = [6, 8, 10]
+        .into_iter()
+        .map(|offset| usize::from(read_u16(message, offset)))
+        .sum::<usize>();
+
+    let mut pos = skip_questions(message)?;
+    let mut offsets = Vec::with_capacity(num_records);
+
+    for _ in 0..num_records {
+        pos = skip_name(message, pos)?;
+
+        let rtype = read_u16(message, pos);
+        let rdlen = usize::from(read_u16(message, pos + 8));
+
+        if rtype != OPT_RTYPE {
+            offsets.push(pos + 4);
+        }
+
+        pos += 10 + rdlen;
+    }
+
+    (pos <= message.len()).then_some(offsets)
+}
+
+/// Returns the position right after the question section of the given message.
+fn skip_questions(message: &[u8]) -> Option<usize> {
+    let num_questions = read_u16(message, 4);
+
+    let mut pos = HEADER_LEN;
+    for _ in 0..num_questions {
+        pos = skip_name(message, pos)? + 4;
+    }
+
+    (pos <= message.len()).then_some(pos)
+}
+
+/// Returns the position right after the (possibly compressed) domain name starting at `pos`.
+fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)?;
+
+        match len {
+            0 => return Some(pos + 1),
+            len if len & 0xC0 == 0xC0 => return Some(pos + 2),
+            len => pos += 1 + usize::from(len),
+        }
+    }
+}
+
+/// Reads a big-endian `u16` at `offset`, treating out-of-bounds bytes as zero.
+fn read_u16(message: &[u8], offset: usize) -> u16 {
+    let byte = |i: usize| message.get(offset + i).copied().unwrap_or_default();
+
+    u16::from_be_bytes([byte(0), byte(1)])
+}
+
+/// Reads a big-endian `u32` at `offset`, treating out-of-bounds bytes as zero.
+fn read_u32(message: &[u8], offset: usize) -> u32 {
+    (u32::from(read_u16(message, offset)) << 16) | u32::from(read_u16(message, offset + 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::base::{iana::Class, MessageBuilder, Name};
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::str::FromStr as _;
+
+    #[test]
+    fn serves_cached_response_with_decremented_ttl() {
+        let mut cache = Cache::default();
+        let now = Instant::now();
+
+        cache.insert(&server(), &response("example.com", 1, 300), now);
+        let cached = cache
+            .get(
+                &server(),
+                &query("EXAMPLE.com", 2),
+                now + Duration::from_secs(100),
+            )
+            .unwrap();
+
+        let message = Message::from_octets(cached.as_slice()).unwrap();
+        let question = message.sole_question().unwrap();
+        let record = message.answer().unwrap().next().unwrap().unwrap();
+
+        assert_eq!(message.header().id(), 2);
+        assert_eq!(question.qname().to_string(), "EXAMPLE.com");
+        assert_eq!(record.ttl().as_secs(), 200);
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 0 });
+    }
+
+    #[test]
+    fn expired_responses_are_not_served() {
+        let mut cache = Cache::default();
+        let now = Instant::now();
+
+        cache.insert(&server(), &response("example.com", 1, 30), now);
+
+        assert!(cache
+            .get(
+                &server(),
+                &query("example.com", 2),
+                now + Duration::from_secs(30)
+            )
+            .is_none());
+        assert_eq!(cache.stats(), CacheStats { hits: 0, misses: 1 });
+    }
+
+    #[test]
+    fn responses_are_cached_per_server() {
+        let mut cache = Cache::default();
+        let now = Instant::now();
+        let other_server = DnsServer::from(SocketAddr::from((Ipv4Addr::new(1, 0, 0, 1), 53)));
+
+        cache.insert(&server(), &response("example.com", 1, 300), now);
+
+        assert!(cache
+            .get(&other_server, &query("example.com", 2), now)
+            .is_none());
+    }
+
+    #[test]
+    fn responses_with_zero_ttl_are_not_cached() {
+        let mut cache = Cache::default();
+        let now = Instant::now();
+
+        cache.insert(&server(), &response("example.com", 1, 0), now);
+
+        assert!(cache
+            .get(&server(), &query("example.com", 2), now)
+            .is_none());
+    }
+
+    fn server() -> DnsServer {
+        DnsServer::from(SocketAddr::from((Ipv4Addr::new(1, 1, 1, 1), 53)))
+    }
+
+    fn query(domain: &str, id: u16) -> Vec<u8> {
+        let mut builder = MessageBuilder::new_vec();
+        builder.header_mut().set_id(id);
+        builder.header_mut().set_rd(true);
+
+        let mut question = builder.question();
+        question
+            .push((Name::<Vec<u8>>::from_str(domain).unwrap(), Rtype::A))
+            .unwrap();
+
+        question.finish()
+    }
+
+    fn response(domain: &str, id: u16, ttl: u32) -> Vec<u8> {
+        let query = query(domain, id);
+        let query = Message::from_octets(query.as_slice()).unwrap();
+
+        let mut answer = MessageBuilder::new_vec()
+            .start_answer(&query, Rcode::NOERROR)
+            .unwrap();
+        answer
+            .push((
+                Name::<Vec<u8>>::from_str(domain).unwrap(),
+                Class::IN,
+                ttl,
+                domain::rdata::A::new(Ipv4Addr::new(93, 184, 216, 34)),
+            ))
+            .unwrap();
+
+        answer.finish()
+    }
+}
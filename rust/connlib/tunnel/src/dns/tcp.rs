@@ -0,0 +1,748 @@
+//! A minimal userspace TCP server for DNS queries sent to our sentinel IPs.
+//!
+//! Applications retry a DNS query over TCP if the UDP response was truncated.
+//! To resolve these queries in the same way as UDP ones, we need to terminate the TCP connection ourselves.
+//!
+//! The TCP connection only ever spans the TUN device, i.e. the application and connlib live on the same host.
+//! Hence, we keep things simple: Out-of-order segments are dropped and the application's TCP stack will retransmit any segment that we didn't acknowledge.
+//! Our own segments are sent within the application's receive window and are retransmitted (go-back-N) if they are not acknowledged in time.
+
+use super::DNS_PORT;
+use ip_packet::{
+    make::TcpControl,
+    tcp::{TcpFlags, TcpPacket},
+    IpPacket, Packet as _,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    iter,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// How long we keep a connection without any activity around.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long we wait for the application to acknowledge our segments before we send them again.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+/// The maximum number of concurrent TCP connections we are willing to handle.
+const MAX_CONNECTIONS: usize = 100;
+/// The maximum payload size of a segment we send, chosen to fit into the minimum IPv6 MTU.
+const MAX_SEGMENT_SIZE: usize = 1200;
+/// The maximum number of bytes we buffer per connection: a single DNS message and its length prefix.
+///
+/// We advertise a receive window that never exceeds this, so only a misbehaving application can send us more.
+const MAX_RECV_BUF_LEN: usize = 2 + u16::MAX as usize;
+
+#[derive(Default)]
+pub(crate) struct Server {
+    connections: HashMap<ConnectionId, Connection>,
+
+    /// DNS queries that were fully received and are waiting to be answered.
+    queries: VecDeque<Query>,
+    /// Segments to be sent back to the application.
+    packets: VecDeque<IpPacket<'static>>,
+}
+
+/// Identifies a TCP connection to one of our sentinel DNS servers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct ConnectionId {
+    /// The sentinel DNS server, always on port 53.
+    pub(crate) local: SocketAddr,
+    /// The application's socket.
+    pub(crate) remote: SocketAddr,
+}
+
+/// A single DNS query message received over a TCP connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Query {
+    pub(crate) connection: ConnectionId,
+    pub(crate) message: Vec<u8>,
+}
+
+struct Connection {
+    /// The sequence number of our SYN-ACK.
+    initial_seq: u32,
+    /// The oldest sequence number the application hasn't acknowledged yet.
+    snd_una: u32,
+    /// The next sequence number we are going to send.
+    snd_nxt: u32,
+    /// How many bytes the application is willing to receive, counted from `snd_una`.
+    snd_wnd: u16,
+    /// The next sequence number we expect from the application.
+    rcv_nxt: u32,
+
+    /// Response bytes starting at `snd_una`: first those in flight, then those we haven't sent yet.
+    send_buf: VecDeque<u8>,
+    /// When we send all unacknowledged segments again.
+    retransmit_at: Option<Instant>,
+
+    /// Received bytes that don't yet form a complete, length-prefixed DNS message.
+    recv_buf: Vec<u8>,
+    /// How many queries we still need to answer before we can close the connection.
+    unanswered_queries: usize,
+
+    remote_closed: bool,
+    fin_sent: bool,
+    last_activity: Instant,
+}
+
+impl Server {
+    /// Handles a TCP segment sent by an application to port 53 of a sentinel DNS server.
+    ///
+    /// Returns `false` if the packet is not such a segment.
+    pub(crate) fn handle_inbound(&mut self, packet: &IpPacket<'_>, now: Instant) -> bool {
+        let Some(segment) = packet.as_tcp() else {
+            return false;
+        };
+
+        if segment.get_destination() != DNS_PORT {
+            return false;
+        }
+
+        let id = ConnectionId {
+            local: SocketAddr::new(packet.destination(), segment.get_destination()),
+            remote: SocketAddr::new(packet.source(), segment.get_source()),
+        };
+
+        self.handle_segment(id, &segment, now);
+
+        true
+    }
+
+    /// Sends the response to a [`Query`] back to the application.
+    ///
+    /// Responses for connections that have since been closed are discarded.
+    pub(crate) fn send_response(&mut self, id: ConnectionId, message: &[u8], now: Instant) {
+        let Some(connection) = self.connections.get_mut(&id) else {
+            tracing::debug!(remote = %id.remote, "Dropping DNS response for closed TCP connection");
+            return;
+        };
+
+        let Ok(len) = u16::try_from(message.len()) else {
+            tracing::debug!(len = %message.len(), "DNS response is too large for TCP");
+            return;
+        };
+
+        connection.send_buf.extend(len.to_be_bytes());
+        connection.send_buf.extend(message);
+        connection.unanswered_queries = connection.unanswered_queries.saturating_sub(1);
+
+        connection.transmit(id, now, &mut self.packets);
+    }
+
+    pub(crate) fn poll_query(&mut self) -> Option<Query> {
+        self.queries.pop_front()
+    }
+
+    pub(crate) fn poll_packet(&mut self) -> Option<IpPacket<'static>> {
+        self.packets.pop_front()
+    }
+
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        self.connections
+            .values()
+            .flat_map(|c| iter::once(c.last_activity + IDLE_TIMEOUT).chain(c.retransmit_at))
+            .min()
+    }
+
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        let expired = self
+            .connections
+            .iter()
+            .filter(|(_, c)| now >= c.last_activity + IDLE_TIMEOUT)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for id in expired {
+            let Some(connection) = self.connections.remove(&id) else {
+                continue;
+            };
+
+            tracing::debug!(remote = %id.remote, "Closing idle DNS TCP connection");
+
+            self.packets.push_back(connection.make_rst(id));
+        }
+
+        for (id, connection) in self.connections.iter_mut() {
+            if connection.retransmit_at.is_some_and(|at| now >= at) {
+                tracing::trace!(remote = %id.remote, seq = %connection.snd_una, "Retransmitting unacknowledged segments");
+
+                connection.retransmit(*id, now, &mut self.packets);
+            }
+        }
+    }
+
+    fn handle_segment(&mut self, id: ConnectionId, segment: &TcpPacket<'_>, now: Instant) {
+        let flags = segment.get_flags();
+        let seq = segment.get_sequence();
+
+        if flags & TcpFlags::RST != 0 {
+            self.connections.remove(&id);
+            return;
+        }
+
+        if flags & TcpFlags::SYN != 0 {
+            self.handle_syn(id, seq, segment.get_window(), now);
+            return;
+        }
+
+        let Some(connection) = self.connections.get_mut(&id) else {
+            // We don't know this connection, tell the application to give up on it.
+            self.packets.push_back(make_rst(id, segment));
+            return;
+        };
+
+        connection.last_activity = now;
+
+        if flags & TcpFlags::ACK != 0 {
+            connection.handle_ack(segment.get_acknowledgement(), segment.get_window(), now);
+
+            if connection.fin_sent && connection.snd_una == connection.snd_nxt {
+                self.connections.remove(&id);
+                return;
+            }
+
+            // The application may have opened its window or acknowledged data, allowing us to send more.
+            connection.transmit(id, now, &mut self.packets);
+        }
+
+        let payload = segment.payload();
+        let is_fin = flags & TcpFlags::FIN != 0;
+
+        if payload.is_empty() && !is_fin {
+            return; // A pure ACK, nothing to do.
+        }
+
+        if seq != connection.rcv_nxt {
+            tracing::trace!(remote = %id.remote, %seq, expected = %connection.rcv_nxt, "Dropping out-of-order segment");
+
+            // Re-acknowledge what we have so the application retransmits the missing data.
+            self.packets
+                .push_back(connection.make_segment(id, TcpControl::default(), Vec::new()));
+            return;
+        }
+
+        if connection.recv_buf.len() + payload.len() > MAX_RECV_BUF_LEN {
+            tracing::debug!(remote = %id.remote, "Application exceeded our receive window");
+
+            self.packets.push_back(connection.make_rst(id));
+            self.connections.remove(&id);
+            return;
+        }
+
+        connection.rcv_nxt = connection.rcv_nxt.wrapping_add(payload.len() as u32);
+        connection.recv_buf.extend_from_slice(payload);
+
+        if is_fin {
+            connection.rcv_nxt = connection.rcv_nxt.wrapping_add(1);
+            connection.remote_closed = true;
+        }
+
+        while let Some(message) = connection.take_message() {
+            connection.unanswered_queries += 1;
+            self.queries.push_back(Query {
+                connection: id,
+                message,
+            });
+        }
+
+        // Acknowledge only once we consumed all complete messages so the advertised window is up to date.
+        self.packets
+            .push_back(connection.make_segment(id, TcpControl::default(), Vec::new()));
+
+        connection.transmit(id, now, &mut self.packets);
+    }
+
+    fn handle_syn(&mut self, id: ConnectionId, seq: u32, window: u16, now: Instant) {
+        if !self.connections.contains_key(&id) && self.connections.len() >= MAX_CONNECTIONS {
+            tracing::debug!(remote = %id.remote, "Too many DNS TCP connections");
+
+            let rst = ip_packet::make::tcp_segment(
+                id.local.ip(),
+                id.remote.ip(),
+                id.local.port(),
+                id.remote.port(),
+                TcpControl {
+                    ack: Some(seq.wrapping_add(1)),
+                    rst: true,
+                    ..Default::default()
+                },
+                Vec::new(),
+            )
+            .expect("src and dst come from the same packet")
+            .into_immutable();
+            self.packets.push_back(rst);
+
+            return;
+        }
+
+        let connection = self.connections.entry(id).or_insert_with(|| {
+            let initial_seq = rand::random::<u32>();
+
+            Connection {
+                initial_seq,
+                snd_una: initial_seq.wrapping_add(1),
+                snd_nxt: initial_seq.wrapping_add(1),
+                snd_wnd: window,
+                rcv_nxt: seq.wrapping_add(1),
+                send_buf: VecDeque::new(),
+                retransmit_at: None,
+                recv_buf: Vec::new(),
+                unanswered_queries: 0,
+                remote_closed: false,
+                fin_sent: false,
+                last_activity: now,
+            }
+        });
+
+        // Also handles a retransmitted SYN in case our SYN-ACK got lost.
+        let syn_ack = ip_packet::make::tcp_segment(
+            id.local.ip(),
+            id.remote.ip(),
+            id.local.port(),
+            id.remote.port(),
+            TcpControl {
+                seq: connection.initial_seq,
+                ack: Some(connection.rcv_nxt),
+                syn: true,
+                ..Default::default()
+            },
+            Vec::new(),
+        )
+        .expect("src and dst come from the same packet")
+        .into_immutable();
+
+        self.packets.push_back(syn_ack);
+    }
+}
+
+impl Connection {
+    /// Takes the next complete DNS message out of the receive buffer.
+    ///
+    /// Over TCP, each DNS message is prefixed with its length as a 2-byte, big-endian integer.
+    fn take_message(&mut self) -> Option<Vec<u8>> {
+        let len = u16::from_be_bytes([*self.recv_buf.first()?, *self.recv_buf.get(1)?]) as usize;
+
+        if self.recv_buf.len() < len + 2 {
+            return None;
+        }
+
+        let message = self.recv_buf[2..len + 2].to_vec();
+        self.recv_buf.drain(..len + 2);
+
+        Some(message)
+    }
+
+    /// Discards all bytes the application acknowledged and remembers its current receive window.
+    fn handle_ack(&mut self, ack: u32, window: u16, now: Instant) {
+        let acked = ack.wrapping_sub(self.snd_una);
+        let in_flight = self.snd_nxt.wrapping_sub(self.snd_una);
+
+        if acked > in_flight {
+            return; // Acknowledges something we never sent, i.e. an old duplicate.
+        }
+
+        self.snd_wnd = window;
+
+        if acked == 0 {
+            return;
+        }
+
+        // Our FIN occupies a sequence number but is not part of `send_buf`.
+        let acked_bytes = (acked as usize).min(self.send_buf.len());
+        self.send_buf.drain(..acked_bytes);
+        self.snd_una = ack;
+        self.retransmit_at = (self.snd_una != self.snd_nxt).then_some(now + RETRANSMIT_TIMEOUT);
+    }
+
+    /// Sends as much of `send_buf` as the application's window allows, followed by our FIN once we are done.
+    fn transmit(
+        &mut self,
+        id: ConnectionId,
+        now: Instant,
+        packets: &mut VecDeque<IpPacket<'static>>,
+    ) {
+        self.transmit_within(id, self.snd_wnd as usize, now, packets);
+    }
+
+    /// Sends all unacknowledged segments again, starting at `snd_una`.
+    ///
+    /// If the application's window is closed, we send a single byte to probe whether it opened again.
+    fn retransmit(
+        &mut self,
+        id: ConnectionId,
+        now: Instant,
+        packets: &mut VecDeque<IpPacket<'static>>,
+    ) {
+        self.snd_nxt = self.snd_una;
+        self.fin_sent = false;
+        self.retransmit_at = None;
+
+        self.transmit_within(id, (self.snd_wnd as usize).max(1), now, packets);
+    }
+
+    fn transmit_within(
+        &mut self,
+        id: ConnectionId,
+        window: usize,
+        now: Instant,
+        packets: &mut VecDeque<IpPacket<'static>>,
+    ) {
+        loop {
+            let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            let unsent = self.send_buf.len().saturating_sub(in_flight);
+            let len = unsent
+                .min(window.saturating_sub(in_flight))
+                .min(MAX_SEGMENT_SIZE);
+
+            if len == 0 {
+                break;
+            }
+
+            let payload = self
+                .send_buf
+                .range(in_flight..in_flight + len)
+                .copied()
+                .collect();
+            packets.push_back(self.make_segment(id, TcpControl::default(), payload));
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+        }
+
+        if let Some(fin) = self.maybe_close(id) {
+            packets.push_back(fin);
+        }
+
+        // Also covers unsent data if the application's window is closed, so we eventually probe it.
+        if self.snd_nxt != self.snd_una || !self.send_buf.is_empty() {
+            self.retransmit_at.get_or_insert(now + RETRANSMIT_TIMEOUT);
+        }
+    }
+
+    /// Closes our side of the connection once the application is done sending and we sent the answers to all its queries.
+    fn maybe_close(&mut self, id: ConnectionId) -> Option<IpPacket<'static>> {
+        let all_sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize == self.send_buf.len();
+
+        if !self.remote_closed || self.unanswered_queries > 0 || !all_sent || self.fin_sent {
+            return None;
+        }
+
+        let fin = self.make_segment(
+            id,
+            TcpControl {
+                fin: true,
+                ..Default::default()
+            },
+            Vec::new(),
+        );
+        self.snd_nxt = self.snd_nxt.wrapping_add(1);
+        self.fin_sent = true;
+
+        Some(fin)
+    }
+
+    fn make_rst(&self, id: ConnectionId) -> IpPacket<'static> {
+        self.make_segment(
+            id,
+            TcpControl {
+                rst: true,
+                ..Default::default()
+            },
+            Vec::new(),
+        )
+    }
+
+    fn make_segment(
+        &self,
+        id: ConnectionId,
+        control: TcpControl,
+        payload: Vec<u8>,
+    ) -> IpPacket<'static> {
+        let window = MAX_RECV_BUF_LEN.saturating_sub(self.recv_buf.len());
+
+        ip_packet::make::tcp_segment(
+            id.local.ip(),
+            id.remote.ip(),
+            id.local.port(),
+            id.remote.port(),
+            TcpControl {
+                seq: self.snd_nxt,
+                ack: Some(self.rcv_nxt),
+                window: Some(u16::try_from(window).unwrap_or(u16::MAX)),
+                ..control
+            },
+            payload,
+        )
+        .expect("src and dst come from the same packet")
+        .into_immutable()
+    }
+}
+
+fn make_rst(id: ConnectionId, segment: &TcpPacket<'_>) -> IpPacket<'static> {
+    ip_packet::make::tcp_segment(
+        id.local.ip(),
+        id.remote.ip(),
+        id.local.port(),
+        id.remote.port(),
+        TcpControl {
+            seq: segment.get_acknowledgement(),
+            rst: true,
+            ..Default::default()
+        },
+        Vec::new(),
+    )
+    .expect("src and dst come from the same packet")
+    .into_immutable()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const SENTINEL: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(100, 100, 111, 1)), 53);
+    const APP: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(100, 64, 0, 1)), 40000);
+
+    #[test]
+    fn receives_query_and_sends_response() {
+        let mut server = Server::default();
+        let now = Instant::now();
+
+        server.handle_inbound(&segment(1000, None, TcpFlags::SYN, &[]), now);
+        let syn_ack = server.poll_packet().unwrap();
+        let syn_ack = syn_ack.as_tcp().unwrap();
+        assert_eq!(syn_ack.get_flags(), TcpFlags::SYN | TcpFlags::ACK);
+        assert_eq!(syn_ack.get_acknowledgement(), 1001);
+
+        let server_seq = syn_ack.get_sequence().wrapping_add(1);
+
+        server.handle_inbound(
+            &segment(
+                1001,
+                Some(server_seq),
+                TcpFlags::ACK | TcpFlags::FIN,
+                &[0, 3, 1, 2, 3],
+            ),
+            now,
+        );
+
+        let query = server.poll_query().unwrap();
+        assert_eq!(query.message, vec![1, 2, 3]);
+
+        let ack = server.poll_packet().unwrap();
+        assert_eq!(ack.as_tcp().unwrap().get_acknowledgement(), 1007);
+        assert!(
+            server.poll_packet().is_none(),
+            "must not close before answering"
+        );
+
+        server.send_response(query.connection, &[4, 5], now);
+
+        let response = server.poll_packet().unwrap();
+        assert_eq!(response.as_tcp().unwrap().payload(), &[0, 2, 4, 5]);
+
+        let fin = server.poll_packet().unwrap();
+        let fin = fin.as_tcp().unwrap();
+        assert_ne!(fin.get_flags() & TcpFlags::FIN, 0);
+
+        server.handle_inbound(
+            &segment(
+                1007,
+                Some(fin.get_sequence().wrapping_add(1)),
+                TcpFlags::ACK,
+                &[],
+            ),
+            now,
+        );
+        assert!(server.connections.is_empty());
+    }
+
+    #[test]
+    fn drops_out_of_order_segments() {
+        let mut server = Server::default();
+        let now = Instant::now();
+
+        server.handle_inbound(&segment(1000, None, TcpFlags::SYN, &[]), now);
+        let _syn_ack = server.poll_packet().unwrap();
+
+        server.handle_inbound(&segment(1005, None, TcpFlags::ACK, &[0, 1, 1]), now);
+
+        let dup_ack = server.poll_packet().unwrap();
+        assert_eq!(dup_ack.as_tcp().unwrap().get_acknowledgement(), 1001);
+        assert!(server.poll_query().is_none());
+    }
+
+    #[test]
+    fn resets_unknown_connections() {
+        let mut server = Server::default();
+
+        server.handle_inbound(
+            &segment(1000, Some(5000), TcpFlags::ACK, &[0, 1, 1]),
+            Instant::now(),
+        );
+
+        let rst = server.poll_packet().unwrap();
+        let rst = rst.as_tcp().unwrap();
+        assert_ne!(rst.get_flags() & TcpFlags::RST, 0);
+        assert_eq!(rst.get_sequence(), 5000);
+    }
+
+    #[test]
+    fn resets_idle_connections() {
+        let mut server = Server::default();
+        let now = Instant::now();
+
+        server.handle_inbound(&segment(1000, None, TcpFlags::SYN, &[]), now);
+        let _syn_ack = server.poll_packet().unwrap();
+
+        let timeout = server.poll_timeout().unwrap();
+        server.handle_timeout(timeout);
+
+        let rst = server.poll_packet().unwrap();
+        assert_ne!(rst.as_tcp().unwrap().get_flags() & TcpFlags::RST, 0);
+        assert!(server.poll_timeout().is_none());
+    }
+
+    #[test]
+    fn sends_response_within_window_of_application() {
+        let mut server = Server::default();
+        let now = Instant::now();
+        let server_seq = handshake(&mut server, 1000, now);
+
+        server.handle_inbound(
+            &segment_with_window(
+                1001,
+                Some(server_seq),
+                TcpFlags::ACK | TcpFlags::FIN,
+                1000,
+                &[0, 1, 1],
+            ),
+            now,
+        );
+        let query = server.poll_query().unwrap();
+        let _ack = server.poll_packet().unwrap();
+
+        server.send_response(query.connection, &[0; 1500], now);
+
+        let first = server.poll_packet().unwrap();
+        assert_eq!(first.as_tcp().unwrap().payload().len(), 1000);
+        assert!(server.poll_packet().is_none(), "window is exhausted");
+
+        server.handle_inbound(
+            &segment_with_window(
+                1005,
+                Some(server_seq.wrapping_add(1000)),
+                TcpFlags::ACK,
+                1000,
+                &[],
+            ),
+            now,
+        );
+
+        let second = server.poll_packet().unwrap();
+        let second = second.as_tcp().unwrap();
+        assert_eq!(second.get_sequence(), server_seq.wrapping_add(1000));
+        assert_eq!(second.payload().len(), 502);
+
+        let fin = server.poll_packet().unwrap();
+        assert_ne!(fin.as_tcp().unwrap().get_flags() & TcpFlags::FIN, 0);
+    }
+
+    #[test]
+    fn retransmits_unacknowledged_segments() {
+        let mut server = Server::default();
+        let now = Instant::now();
+        let server_seq = handshake(&mut server, 1000, now);
+
+        server.handle_inbound(
+            &segment(1001, Some(server_seq), TcpFlags::ACK, &[0, 1, 1]),
+            now,
+        );
+        let query = server.poll_query().unwrap();
+        let _ack = server.poll_packet().unwrap();
+
+        server.send_response(query.connection, &[4, 5], now);
+        let response = server.poll_packet().unwrap();
+
+        let timeout = server.poll_timeout().unwrap();
+        assert_eq!(timeout, now + RETRANSMIT_TIMEOUT);
+        server.handle_timeout(timeout);
+
+        assert_eq!(server.poll_packet().unwrap(), response);
+
+        server.handle_inbound(
+            &segment(1004, Some(server_seq.wrapping_add(4)), TcpFlags::ACK, &[]),
+            timeout,
+        );
+        assert_eq!(server.poll_timeout().unwrap(), timeout + IDLE_TIMEOUT);
+    }
+
+    #[test]
+    fn resets_connections_that_exceed_receive_buffer() {
+        let mut server = Server::default();
+        let now = Instant::now();
+        let server_seq = handshake(&mut server, 1000, now);
+
+        let mut first = vec![0xFF, 0xFF];
+        first.resize(60_000, 0);
+        server.handle_inbound(&segment(1001, Some(server_seq), TcpFlags::ACK, &first), now);
+
+        let ack = server.poll_packet().unwrap();
+        assert_eq!(
+            ack.as_tcp().unwrap().get_window() as usize,
+            MAX_RECV_BUF_LEN - first.len()
+        );
+
+        server.handle_inbound(
+            &segment(
+                1001 + first.len() as u32,
+                Some(server_seq),
+                TcpFlags::ACK,
+                &[0; 6_000],
+            ),
+            now,
+        );
+
+        let rst = server.poll_packet().unwrap();
+        assert_ne!(rst.as_tcp().unwrap().get_flags() & TcpFlags::RST, 0);
+        assert!(server.connections.is_empty());
+        assert!(server.poll_query().is_none());
+    }
+
+    /// Opens a connection from the application and returns the next sequence number of the server.
+    fn handshake(server: &mut Server, seq: u32, now: Instant) -> u32 {
+        server.handle_inbound(&segment(seq, None, TcpFlags::SYN, &[]), now);
+        let syn_ack = server.poll_packet().unwrap();
+
+        syn_ack.as_tcp().unwrap().get_sequence().wrapping_add(1)
+    }
+
+    fn segment(seq: u32, ack: Option<u32>, flags: u8, payload: &[u8]) -> IpPacket<'static> {
+        segment_with_window(seq, ack, flags, u16::MAX, payload)
+    }
+
+    fn segment_with_window(
+        seq: u32,
+        ack: Option<u32>,
+        flags: u8,
+        window: u16,
+        payload: &[u8],
+    ) -> IpPacket<'static> {
+        ip_packet::make::tcp_segment(
+            APP.ip(),
+            SENTINEL.ip(),
+            APP.port(),
+            SENTINEL.port(),
+            TcpControl {
+                seq,
+                ack,
+                syn: flags & TcpFlags::SYN != 0,
+                fin: flags & TcpFlags::FIN != 0,
+                rst: flags & TcpFlags::RST != 0,
+                window: Some(window),
+            },
+            payload.to_vec(),
+        )
+        .unwrap()
+        .into_immutable()
+    }
+}
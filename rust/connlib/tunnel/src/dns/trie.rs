@@ -0,0 +1,300 @@
+//! An index over DNS resource [`Pattern`]s, allowing us to match a domain without testing every pattern.
+use super::pattern::Pattern;
+use connlib_shared::messages::ResourceId;
+use connlib_shared::DomainName;
+use std::collections::HashMap;
+
+/// A trie of domain patterns, keyed by their labels in reverse order (i.e. starting at the TLD).
+///
+/// Matching a domain only visits the branches whose labels can match, which keeps lookups fast even with thousands of resources.
+///
+/// If multiple patterns match a domain, the most specific one wins.
+/// Labels are compared from right to left: A literal label beats a wildcard label (`*`, `?`) which beats `**`.
+#[derive(Debug, Default)]
+pub(crate) struct PatternTrie {
+    root: Node,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    /// The resource whose pattern ends at this node.
+    resource: Option<ResourceId>,
+    /// Children for labels without any wildcards, keyed by their lowercase form.
+    literal: HashMap<String, Node>,
+    /// Children for labels containing `*`, `?` or `[...]`, tested in insertion order.
+    wildcard: Vec<(WildcardLabel, Node)>,
+    /// Child for a `**` label which matches zero or more labels.
+    recursive: Option<Box<Node>>,
+}
+
+#[derive(Debug)]
+struct WildcardLabel {
+    raw: String,
+    glob: glob::Pattern,
+}
+
+enum Label<'a> {
+    Literal(String),
+    Wildcard(&'a str),
+    Recursive,
+}
+
+impl PatternTrie {
+    /// Inserts a pattern, returning the resource that was previously stored for it.
+    ///
+    /// Fails without changing the trie if a label of the pattern is not a valid glob on its own,
+    /// e.g. in `[a.b].example.com` where a character class spans two labels.
+    pub(crate) fn insert(
+        &mut self,
+        pattern: &Pattern,
+        id: ResourceId,
+    ) -> Result<Option<ResourceId>, glob::PatternError> {
+        for label in labels(pattern) {
+            if let Label::Wildcard(raw) = label {
+                glob::Pattern::new(raw)?;
+            }
+        }
+
+        let mut node = &mut self.root;
+
+        for label in labels(pattern) {
+            node = match label {
+                Label::Literal(label) => node.literal.entry(label).or_default(),
+                Label::Wildcard(raw) => {
+                    let index = match node.wildcard.iter().position(|(l, _)| l.raw == raw) {
+                        Some(index) => index,
+                        None => {
+                            let glob = glob::Pattern::new(raw)
+                                .expect("all wildcard labels were checked above");
+
+                            node.wildcard.push((
+                                WildcardLabel {
+                                    raw: raw.to_owned(),
+                                    glob,
+                                },
+                                Node::default(),
+                            ));
+                            node.wildcard.len() - 1
+                        }
+                    };
+
+                    &mut node.wildcard[index].1
+                }
+                Label::Recursive => node.recursive.get_or_insert_with(Default::default),
+            };
+        }
+
+        Ok(node.resource.replace(id))
+    }
+
+    /// Removes a pattern, returning the resource that was stored for it.
+    pub(crate) fn remove(&mut self, pattern: &Pattern) -> Option<ResourceId> {
+        self.root.remove(&labels(pattern).collect::<Vec<_>>())
+    }
+
+    /// Finds the resource with the most specific pattern matching the given domain.
+    pub(crate) fn find(&self, domain: &DomainName) -> Option<ResourceId> {
+        let domain = domain.to_string().to_ascii_lowercase();
+        let labels = domain
+            .split('.')
+            .rev()
+            .filter(|l| !l.is_empty())
+            .collect::<Vec<_>>();
+
+        self.root.find(&labels, true)
+    }
+}
+
+impl Node {
+    fn find(&self, labels: &[&str], only_literals_so_far: bool) -> Option<ResourceId> {
+        let Some((label, rest)) = labels.split_first() else {
+            return self
+                .resource
+                .or_else(|| self.recursive.as_ref()?.find(&[], false))
+                .or_else(|| {
+                    // `*.example.com` also matches `example.com` as long as the rest of the pattern is literal.
+                    if !only_literals_so_far {
+                        return None;
+                    }
+
+                    self.wildcard
+                        .iter()
+                        .find(|(l, _)| l.raw == "*")
+                        .and_then(|(_, node)| node.resource)
+                });
+        };
+
+        if let Some(id) = self
+            .literal
+            .get(*label)
+            .and_then(|node| node.find(rest, only_literals_so_far))
+        {
+            return Some(id);
+        }
+
+        for (wildcard, node) in &self.wildcard {
+            if !wildcard.matches(label) {
+                continue;
+            }
+
+            if let Some(id) = node.find(rest, false) {
+                return Some(id);
+            }
+        }
+
+        let recursive = self.recursive.as_ref()?;
+
+        // `**` may consume any number of labels, including none.
+        (0..=labels.len()).find_map(|consumed| recursive.find(&labels[consumed..], false))
+    }
+
+    fn remove(&mut self, labels: &[Label<'_>]) -> Option<ResourceId> {
+        let Some((label, rest)) = labels.split_first() else {
+            return self.resource.take();
+        };
+
+        match label {
+            Label::Literal(label) => {
+                let child = self.literal.get_mut(label)?;
+                let removed = child.remove(rest);
+
+                if child.is_empty() {
+                    self.literal.remove(label);
+                }
+
+                removed
+            }
+            Label::Wildcard(raw) => {
+                let index = self.wildcard.iter().position(|(l, _)| l.raw == *raw)?;
+                let removed = self.wildcard[index].1.remove(rest);
+
+                if self.wildcard[index].1.is_empty() {
+                    self.wildcard.remove(index);
+                }
+
+                removed
+            }
+            Label::Recursive => {
+                let child = self.recursive.as_mut()?;
+                let removed = child.remove(rest);
+
+                if child.is_empty() {
+                    self.recursive = None;
+                }
+
+                removed
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.resource.is_none()
+            && self.literal.is_empty()
+            && self.wildcard.is_empty()
+            && self.recursive.is_none()
+    }
+}
+
+impl WildcardLabel {
+    fn matches(&self, label: &str) -> bool {
+        self.glob.matches_with(
+            label,
+            glob::MatchOptions {
+                case_sensitive: false,
+                require_literal_separator: true,
+                require_literal_leading_dot: false,
+            },
+        )
+    }
+}
+
+fn labels(pattern: &Pattern) -> impl Iterator<Item = Label<'_>> {
+    pattern
+        .labels()
+        .rev()
+        .filter(|l| !l.is_empty())
+        .map(|label| match label {
+            "**" => Label::Recursive,
+            l if l.contains(['*', '?', '[']) => Label::Wildcard(l),
+            l => Label::Literal(l.to_ascii_lowercase()),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn character_class_across_labels_is_rejected() {
+        let mut trie = PatternTrie::default();
+        let pattern = Pattern::new("[a.b].example.com").unwrap();
+
+        assert!(trie.insert(&pattern, resource(1)).is_err());
+        assert!(trie.root.is_empty());
+    }
+
+    #[test]
+    fn literal_beats_wildcard() {
+        let mut trie = PatternTrie::default();
+        trie.insert(&Pattern::new("**.example.com").unwrap(), resource(1))
+            .unwrap();
+        trie.insert(&Pattern::new("*.example.com").unwrap(), resource(2))
+            .unwrap();
+        trie.insert(&Pattern::new("app.example.com").unwrap(), resource(3))
+            .unwrap();
+
+        assert_eq!(trie.find(&domain("app.example.com")), Some(resource(3)));
+        assert_eq!(trie.find(&domain("web.example.com")), Some(resource(2)));
+        assert_eq!(trie.find(&domain("foo.web.example.com")), Some(resource(1)));
+        assert_eq!(trie.find(&domain("example.org")), None);
+    }
+
+    #[test]
+    fn matches_case_insensitive() {
+        let mut trie = PatternTrie::default();
+        trie.insert(&Pattern::new("App.Example.com").unwrap(), resource(1))
+            .unwrap();
+
+        assert_eq!(trie.find(&domain("app.EXAMPLE.com")), Some(resource(1)));
+    }
+
+    #[test]
+    fn single_star_matches_root_only_with_literal_rest() {
+        let mut trie = PatternTrie::default();
+        trie.insert(&Pattern::new("*.*.example.com").unwrap(), resource(1))
+            .unwrap();
+
+        assert_eq!(trie.find(&domain("foo.example.com")), None);
+        assert_eq!(trie.find(&domain("foo.bar.example.com")), Some(resource(1)));
+    }
+
+    #[test]
+    fn removed_pattern_no_longer_matches() {
+        let mut trie = PatternTrie::default();
+        trie.insert(&Pattern::new("**.example.com").unwrap(), resource(1))
+            .unwrap();
+        trie.insert(&Pattern::new("app.example.com").unwrap(), resource(2))
+            .unwrap();
+
+        assert_eq!(
+            trie.remove(&Pattern::new("app.example.com").unwrap()),
+            Some(resource(2))
+        );
+        assert_eq!(trie.find(&domain("app.example.com")), Some(resource(1)));
+
+        assert_eq!(
+            trie.remove(&Pattern::new("**.example.com").unwrap()),
+            Some(resource(1))
+        );
+        assert_eq!(trie.find(&domain("app.example.com")), None);
+        assert!(trie.root.is_empty());
+    }
+
+    fn resource(n: u128) -> ResourceId {
+        ResourceId::from_u128(n)
+    }
+
+    fn domain(name: &str) -> DomainName {
+        DomainName::vec_from_str(name).unwrap()
+    }
+}
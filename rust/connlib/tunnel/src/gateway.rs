@@ -1,4 +1,6 @@
-use crate::peer::ClientOnGateway;
+use crate::client::{DNS_SENTINELS_V4, DNS_SENTINELS_V6};
+use crate::dns::{self, ForwardedQuery, QueryOrigin};
+use crate::peer::{ClientOnGateway, FilterMode};
 use crate::peer_store::PeerStore;
 use crate::utils::earliest;
 use crate::{GatewayEvent, GatewayTunnel, BUF_SIZE};
@@ -6,19 +8,26 @@ use anyhow::bail;
 use boringtun::x25519::PublicKey;
 use chrono::{DateTime, Utc};
 use connlib_shared::messages::{
-    gateway::ResolvedResourceDescriptionDns, gateway::ResourceDescription, Answer, ClientId, Key,
-    Offer, RelayId, ResourceId,
+    gateway::ResolvedResourceDescriptionDns, gateway::ResourceDescription, Answer, ClientId,
+    DnsServer, Key, Offer, RelayId, ResourceId,
 };
 use connlib_shared::{DomainName, StaticSecret};
+use domain::base::Message;
 use ip_network::{Ipv4Network, Ipv6Network};
-use ip_packet::{IpPacket, MutableIpPacket};
+use ip_packet::{IpPacket, MutableIpPacket, Packet as _};
 use secrecy::{ExposeSecret as _, Secret};
 use snownet::{EncryptBuffer, RelaySocket, ServerNode};
 use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::time::{Duration, Instant};
 use tun::Tun;
 
+mod shards;
+
+pub(crate) use shards::ShardRouter;
+pub use shards::{GatewayStatsHandle, ShardedGatewayTunnel};
+
 pub const IPV4_PEERS: Ipv4Network = match Ipv4Network::new(Ipv4Addr::new(100, 64, 0, 0), 11) {
     Ok(n) => n,
     Err(_) => unreachable!(),
@@ -31,11 +40,22 @@ pub const IPV6_PEERS: Ipv6Network =
 
 const EXPIRE_RESOURCES_INTERVAL: Duration = Duration::from_secs(1);
 
+const DNS_PORT: u16 = 53;
+
 impl GatewayTunnel {
     pub fn set_tun(&mut self, tun: Box<dyn Tun>) {
         self.io.device_mut().set_tun(tun);
     }
 
+    pub fn set_filter_mode(&mut self, mode: FilterMode) {
+        self.role_state.set_filter_mode(mode);
+    }
+
+    /// Sets the DNS servers we use to resolve the DNS queries that clients send us for their DNS resources.
+    pub fn set_dns_resolvers(&mut self, resolvers: Vec<IpAddr>) {
+        self.role_state.set_dns_resolvers(resolvers);
+    }
+
     /// Accept a connection request from a client.
     pub fn accept(
         &mut self,
@@ -121,6 +141,10 @@ impl GatewayTunnel {
     pub fn remove_ice_candidate(&mut self, conn_id: ClientId, ice_candidate: String) {
         self.role_state.remove_ice_candidate(conn_id, ice_candidate);
     }
+
+    pub fn stats(&self) -> GatewayStats {
+        self.role_state.stats()
+    }
 }
 
 /// A SANS-IO implementation of a gateway's functionality.
@@ -137,6 +161,14 @@ pub struct GatewayState {
     /// When to next check whether a resource-access policy has expired.
     next_expiry_resources_check: Option<Instant>,
 
+    /// How we enforce the filters of the resources on our peers.
+    filter_mode: FilterMode,
+
+    /// The DNS servers we resolve the DNS queries of clients with.
+    dns_resolvers: Vec<SocketAddr>,
+    /// DNS queries of clients that need to be forwarded to one of our DNS servers.
+    buffered_dns_queries: VecDeque<ForwardedQuery>,
+
     buffered_events: VecDeque<GatewayEvent>,
 }
 
@@ -146,10 +178,30 @@ impl GatewayState {
             peers: Default::default(),
             node: ServerNode::new(private_key.into(), BUF_SIZE, seed),
             next_expiry_resources_check: Default::default(),
+            filter_mode: FilterMode::default(),
+            dns_resolvers: Vec::default(),
+            buffered_dns_queries: VecDeque::default(),
             buffered_events: VecDeque::default(),
         }
     }
 
+    pub(crate) fn set_dns_resolvers(&mut self, resolvers: Vec<IpAddr>) {
+        tracing::debug!(?resolvers, "Setting DNS resolvers");
+
+        self.dns_resolvers = resolvers
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, DNS_PORT))
+            .collect();
+    }
+
+    pub(crate) fn set_filter_mode(&mut self, mode: FilterMode) {
+        self.filter_mode = mode;
+
+        for peer in self.peers.iter_mut() {
+            peer.set_filter_mode(mode);
+        }
+    }
+
     #[cfg(all(feature = "proptest", test))]
     pub(crate) fn public_key(&self) -> PublicKey {
         self.node.public_key()
@@ -206,6 +258,11 @@ impl GatewayState {
         .inspect_err(|e| tracing::debug!(%from, num_bytes = %packet.len(), "Failed to decapsulate incoming packet: {e}"))
         .ok()??;
 
+        if is_dns_query_to_sentinel(&packet.as_immutable()) {
+            self.handle_dns_query(cid, packet.as_immutable());
+            return None;
+        }
+
         let Some(peer) = self.peers.get_mut(&cid) else {
             tracing::warn!(%cid, "Couldn't find connection by ID");
 
@@ -220,6 +277,112 @@ impl GatewayState {
         Some(packet.into_immutable())
     }
 
+    /// Handles a DNS query that a client sent us because it cannot answer it by itself, e.g. an SRV query for a DNS resource.
+    #[tracing::instrument(level = "debug", skip_all, fields(%cid))]
+    fn handle_dns_query(&mut self, cid: ClientId, packet: IpPacket<'_>) {
+        let Some(peer) = self.peers.get(&cid) else {
+            tracing::warn!("Couldn't find connection by ID");
+
+            return;
+        };
+        let Some(datagram) = packet.as_udp() else {
+            return;
+        };
+        let Ok(message) = Message::from_slice(datagram.payload()) else {
+            tracing::debug!("Received invalid DNS query");
+
+            return;
+        };
+        let Some(domain) = message.first_question().map(|q| q.qname().to_vec()) else {
+            return;
+        };
+
+        if !peer.may_resolve(&packet, &domain) {
+            tracing::debug!(%domain, "Client is not allowed to resolve domain");
+
+            return;
+        }
+
+        let Some(server) = self.dns_resolvers.first().copied() else {
+            tracing::warn!(%domain, "Unable to resolve DNS query without DNS resolvers");
+
+            return;
+        };
+
+        tracing::trace!(%domain, %server, "Forwarding DNS query");
+
+        self.buffered_dns_queries.push_back(ForwardedQuery {
+            server: DnsServer::from(server),
+            message: datagram.payload().to_vec(),
+            origin: QueryOrigin::Udp {
+                sentinel: SocketAddr::new(packet.destination(), datagram.get_destination()),
+                source: SocketAddr::new(packet.source(), datagram.get_source()),
+            },
+        });
+    }
+
+    pub(crate) fn poll_dns_query(&mut self) -> Option<ForwardedQuery> {
+        self.buffered_dns_queries.pop_front()
+    }
+
+    /// Sends the response to a DNS query back to the client, see [`GatewayState::handle_dns_query`].
+    pub(crate) fn handle_dns_response(
+        &mut self,
+        query: ForwardedQuery,
+        response: io::Result<Vec<u8>>,
+        now: Instant,
+        buffer: &mut EncryptBuffer,
+    ) -> Option<snownet::EncryptedPacket> {
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                if let Some(next) = self.next_dns_resolver(query.server.address()) {
+                    tracing::debug!(server = ?query.server, %next, "Failed to resolve DNS query, trying next DNS resolver: {e}");
+
+                    self.buffered_dns_queries.push_back(ForwardedQuery {
+                        server: DnsServer::from(next),
+                        ..query
+                    });
+
+                    return None;
+                }
+
+                tracing::debug!(server = ?query.server, "Failed to resolve DNS query: {e}");
+
+                dns::servfail(&query.message)?
+            }
+        };
+
+        let (sentinel, source) = (query.origin.sentinel(), query.origin.source());
+        let packet = ip_packet::make::udp_packet(
+            sentinel.ip(),
+            source.ip(),
+            sentinel.port(),
+            source.port(),
+            response,
+        )
+        .ok()?;
+
+        let Some(peer) = self.peers.peer_by_ip(source.ip()) else {
+            tracing::debug!(client = %source.ip(), "Couldn't find connection by IP");
+
+            return None;
+        };
+        let cid = peer.id();
+
+        self.node
+            .encapsulate(cid, packet.as_immutable(), now, buffer)
+            .inspect_err(|e| tracing::debug!(%cid, "Failed to encapsulate: {e}"))
+            .ok()?
+    }
+
+    /// The DNS resolver to try after `server` failed to answer a query, in the order they were configured.
+    fn next_dns_resolver(&self, server: SocketAddr) -> Option<SocketAddr> {
+        let index = self.dns_resolvers.iter().position(|s| *s == server)?;
+
+        self.dns_resolvers.get(index + 1).copied()
+    }
+
     pub fn add_ice_candidate(&mut self, conn_id: ClientId, ice_candidate: String, now: Instant) {
         self.node.add_remote_candidate(conn_id, ice_candidate, now);
     }
@@ -285,10 +448,12 @@ impl GatewayState {
             _ => {}
         }
 
-        let peer = self
-            .peers
-            .entry(client)
-            .or_insert_with(|| ClientOnGateway::new(client, ipv4, ipv6));
+        let peer = self.peers.entry(client).or_insert_with(|| {
+            let mut peer = ClientOnGateway::new(client, ipv4, ipv6);
+            peer.set_filter_mode(self.filter_mode);
+
+            peer
+        });
 
         peer.assign_proxies(&resource, domain.clone(), now)?;
         peer.add_resource(
@@ -297,6 +462,10 @@ impl GatewayState {
             resource.filters(),
             expires_at,
             domain.map(|(n, _)| n),
+            match &resource {
+                ResourceDescription::Dns(r) => Some(r.domain.clone()),
+                ResourceDescription::Cidr(_) | ResourceDescription::Internet(_) => None,
+            },
         );
         self.peers.add_ip(&client, &ipv4.into());
         self.peers.add_ip(&client, &ipv6.into());
@@ -354,6 +523,18 @@ impl GatewayState {
                         .insert(candidate);
                 }
                 snownet::Event::ConnectionEstablished(_) => {}
+                snownet::Event::ConnectionPathChanged {
+                    connection,
+                    old,
+                    new,
+                } => {
+                    self.buffered_events
+                        .push_back(GatewayEvent::ConnectionPathChanged {
+                            conn_id: connection,
+                            old,
+                            new,
+                        });
+                }
             }
         }
 
@@ -400,6 +581,62 @@ impl GatewayState {
     ) {
         self.node.update_relays(to_remove, &to_add, now);
     }
+
+    pub(crate) fn stats(&self) -> GatewayStats {
+        let (node, connections) = self.node.stats();
+        let mut connections = connections.collect::<BTreeMap<_, _>>();
+
+        let clients = self
+            .peers
+            .iter()
+            .map(|peer| {
+                let stats = ClientStats {
+                    connection: connections.remove(&peer.id()),
+                    nat_table_size: peer.nat_table_size(),
+                    conntrack_size: peer.conntrack_size(),
+                };
+
+                (peer.id(), stats)
+            })
+            .collect();
+
+        GatewayStats { node, clients }
+    }
+}
+
+/// A snapshot of the gateway's state, e.g. for exposing it as metrics.
+#[derive(Debug, Default, Clone)]
+pub struct GatewayStats {
+    pub node: snownet::NodeStats,
+    pub clients: BTreeMap<ClientId, ClientStats>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ClientStats {
+    /// `None` if the connection to the client is not (yet) established.
+    pub connection: Option<snownet::ConnectionStats>,
+    pub nat_table_size: usize,
+    pub conntrack_size: usize,
+}
+
+impl GatewayStats {
+    pub(crate) fn merge(&mut self, other: GatewayStats) {
+        self.node.stun_bytes_to_relays += other.node.stun_bytes_to_relays.0;
+        self.clients.extend(other.clients);
+    }
+}
+
+/// Clients send DNS queries to their sentinel DNS servers through the tunnel if they need us to resolve them.
+fn is_dns_query_to_sentinel(packet: &IpPacket<'_>) -> bool {
+    let is_sentinel = match packet.destination() {
+        IpAddr::V4(v4) => DNS_SENTINELS_V4.contains(v4),
+        IpAddr::V6(v6) => DNS_SENTINELS_V6.contains(v6),
+    };
+
+    is_sentinel
+        && packet
+            .as_udp()
+            .is_some_and(|d| d.get_destination() == DNS_PORT)
 }
 
 fn is_client(dst: IpAddr) -> bool {
@@ -412,9 +649,72 @@ fn is_client(dst: IpAddr) -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use domain::base::Rtype;
 
     #[test]
     fn mldv2_routers_are_not_clients() {
         assert!(!is_client("ff02::16".parse().unwrap()))
     }
+
+    #[test]
+    fn dns_queries_to_sentinels_are_intercepted() {
+        let domain = DomainName::vec_from_str("_ldap._tcp.example.com").unwrap();
+        let client = "100.64.0.1:40000".parse().unwrap();
+
+        let to_sentinel = ip_packet::make::dns_query(
+            domain.clone(),
+            Rtype::SRV,
+            client,
+            "100.100.111.1:53".parse().unwrap(),
+            0,
+        )
+        .unwrap();
+        let to_resource = ip_packet::make::dns_query(
+            domain,
+            Rtype::SRV,
+            client,
+            "10.0.0.1:53".parse().unwrap(),
+            0,
+        )
+        .unwrap();
+
+        assert!(is_dns_query_to_sentinel(&to_sentinel.as_immutable()));
+        assert!(!is_dns_query_to_sentinel(&to_resource.as_immutable()));
+    }
+
+    #[test]
+    fn failed_dns_queries_are_retried_with_next_resolver() {
+        let mut state = GatewayState::new(StaticSecret::from([1; 32]), [0; 32]);
+        state.set_dns_resolvers(vec!["1.1.1.1".parse().unwrap(), "8.8.8.8".parse().unwrap()]);
+
+        let query = ForwardedQuery {
+            server: DnsServer::from("1.1.1.1:53".parse::<SocketAddr>().unwrap()),
+            message: vec![0; 12],
+            origin: QueryOrigin::Udp {
+                sentinel: "100.100.111.1:53".parse().unwrap(),
+                source: "100.64.0.1:40000".parse().unwrap(),
+            },
+        };
+        let timeout = || Err(io::Error::from(io::ErrorKind::TimedOut));
+
+        let response = state.handle_dns_response(
+            query,
+            timeout(),
+            Instant::now(),
+            &mut EncryptBuffer::new(BUF_SIZE),
+        );
+        assert!(response.is_none());
+
+        let retried = state.poll_dns_query().unwrap();
+        assert_eq!(retried.server.address(), "8.8.8.8:53".parse().unwrap());
+
+        // There is no resolver after the last one, so the client gets a SERVFAIL instead.
+        state.handle_dns_response(
+            retried,
+            timeout(),
+            Instant::now(),
+            &mut EncryptBuffer::new(BUF_SIZE),
+        );
+        assert!(state.poll_dns_query().is_none());
+    }
 }
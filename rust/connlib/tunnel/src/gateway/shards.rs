@@ -0,0 +1,607 @@
+use crate::peer::FilterMode;
+use crate::{GatewayEvent, GatewayStats, GatewayTunnel};
+use anyhow::Context as _;
+use boringtun::x25519::PublicKey;
+use chrono::{DateTime, Utc};
+use connlib_shared::messages::{
+    gateway::ResolvedResourceDescriptionDns, gateway::ResourceDescription, Answer, ClientId, Key,
+    Offer, Relay, RelayId, ResourceId,
+};
+use connlib_shared::{DomainName, StaticSecret};
+use futures::channel::{mpsc, oneshot};
+use futures_util::StreamExt as _;
+use ip_packet::IpPacket;
+use secrecy::Secret;
+use socket_factory::{SocketFactory, TcpSocket, UdpSocket};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::future::Future;
+use std::hash::{Hash as _, Hasher as _};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::watch;
+use tun::Tun;
+
+/// How many packets we buffer at most for another shard before we start dropping them.
+const HANDOFF_QUEUE_SIZE: usize = 1024;
+
+/// How often each shard publishes its [`GatewayStats`].
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A change to the state of a shard, run by the task that owns the shard's [`GatewayTunnel`].
+type Command = Box<dyn FnOnce(&mut GatewayTunnel) + Send>;
+
+/// Runs several [`GatewayTunnel`]s in parallel, one per queue of a multi-queue TUN device.
+///
+/// Each client is assigned to exactly one shard, based on its ID.
+/// All shards use the same private key but bind their own sockets, so a client only ever talks to the sockets of its shard.
+///
+/// The kernel steers the packets of a flow to the TUN queue that we last wrote a packet of this flow to.
+/// Thus, most packets for a client arrive at the shard that owns the client.
+/// Those that don't (e.g. after the kernel's flow entry expired) are handed off to the owning shard.
+///
+/// Each shard's [`GatewayTunnel`] is owned by the task driving it, we only ever talk to it by sending [`Command`]s.
+pub struct ShardedGatewayTunnel {
+    shards: Vec<Shard>,
+    /// Which shard owns which client IP.
+    owners: Arc<RwLock<HashMap<IpAddr, usize>>>,
+    client_ips: HashMap<ClientId, (Ipv4Addr, Ipv6Addr)>,
+
+    events: mpsc::UnboundedReceiver<GatewayEvent>,
+}
+
+struct Shard {
+    commands: mpsc::UnboundedSender<Command>,
+    stats: watch::Receiver<GatewayStats>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+/// Allows reading the [`GatewayStats`] of all shards from another task or thread.
+///
+/// The stats lag behind by at most [`STATS_INTERVAL`].
+#[derive(Clone)]
+pub struct GatewayStatsHandle {
+    shards: Vec<watch::Receiver<GatewayStats>>,
+}
+
+/// Hands packets read from one shard's TUN queue off to the shard that owns the packet's destination.
+pub(crate) struct ShardRouter {
+    index: usize,
+    owners: Arc<RwLock<HashMap<IpAddr, usize>>>,
+    shards: Vec<mpsc::Sender<Vec<u8>>>,
+}
+
+impl ShardedGatewayTunnel {
+    /// Creates a new [`ShardedGatewayTunnel`] and spawns one task per shard.
+    ///
+    /// Must be called within a Tokio runtime context.
+    pub fn new(
+        private_key: StaticSecret,
+        tcp_socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
+        udp_socket_factory: Arc<dyn SocketFactory<UdpSocket>>,
+        num_shards: NonZeroUsize,
+    ) -> Self {
+        let owners = Arc::new(RwLock::new(HashMap::new()));
+        let (event_tx, event_rx) = mpsc::unbounded();
+        let (handoff_txs, handoff_rxs) = (0..num_shards.get())
+            .map(|_| mpsc::channel(HANDOFF_QUEUE_SIZE))
+            .unzip::<_, _, Vec<_>, Vec<_>>();
+
+        let shards = handoff_rxs
+            .into_iter()
+            .enumerate()
+            .map(|(index, handoffs)| {
+                let mut tunnel = GatewayTunnel::new(
+                    private_key.clone(),
+                    tcp_socket_factory.clone(),
+                    udp_socket_factory.clone(),
+                );
+
+                if num_shards.get() > 1 {
+                    tunnel.io.device_mut().set_shard_router(
+                        ShardRouter {
+                            index,
+                            owners: owners.clone(),
+                            shards: handoff_txs.clone(),
+                        },
+                        handoffs,
+                    );
+                }
+
+                let (commands, command_rx) = mpsc::unbounded();
+                let (stats_tx, stats) = watch::channel(GatewayStats::default());
+                let task = tokio::spawn(drive_shard(
+                    index,
+                    tunnel,
+                    command_rx,
+                    stats_tx,
+                    event_tx.clone(),
+                ));
+
+                Shard {
+                    commands,
+                    stats,
+                    task,
+                }
+            })
+            .collect();
+
+        Self {
+            shards,
+            owners,
+            client_ips: HashMap::new(),
+            events: event_rx,
+        }
+    }
+
+    /// Sets the queues of the TUN device, one per shard.
+    ///
+    /// Fails if there isn't exactly one queue per shard, a shard without a queue could never send packets to its clients' resources.
+    pub fn set_tun_queues(&mut self, queues: Vec<impl Tun>) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            queues.len() == self.shards.len(),
+            "Got {} TUN queues for {} shards",
+            queues.len(),
+            self.shards.len()
+        );
+
+        for (index, queue) in queues.into_iter().enumerate() {
+            self.send_to_shard(index, |tunnel| tunnel.set_tun(Box::new(queue)));
+        }
+
+        Ok(())
+    }
+
+    pub fn set_filter_mode(&mut self, mode: FilterMode) {
+        self.send_to_all_shards(move |tunnel| tunnel.set_filter_mode(mode));
+    }
+
+    pub fn set_dns_resolvers(&mut self, resolvers: Vec<IpAddr>) {
+        self.send_to_all_shards(move |tunnel| tunnel.set_dns_resolvers(resolvers.clone()));
+    }
+
+    pub fn update_relays(&mut self, to_remove: BTreeSet<RelayId>, to_add: Vec<Relay>) {
+        self.send_to_all_shards(move |tunnel| {
+            tunnel.update_relays(to_remove.clone(), to_add.clone())
+        });
+    }
+
+    pub fn update_resource(&mut self, resource: ResourceDescription) {
+        self.send_to_all_shards(move |tunnel| tunnel.update_resource(resource.clone()));
+    }
+
+    /// Accepts the connection on the client's shard.
+    ///
+    /// The returned future resolves once the shard processed the offer.
+    pub fn accept(
+        &mut self,
+        client_id: ClientId,
+        key: Secret<Key>,
+        offer: Offer,
+        client: PublicKey,
+    ) -> impl Future<Output = anyhow::Result<Answer>> + Send + 'static {
+        self.call_shard(self.shard_of(&client_id), move |tunnel| {
+            tunnel.accept(client_id, key, offer, client)
+        })
+    }
+
+    pub fn cleanup_connection(&mut self, id: &ClientId) {
+        let id = *id;
+        self.send_to_shard(self.shard_of(&id), move |tunnel| {
+            tunnel.cleanup_connection(&id)
+        });
+
+        if let Some((ipv4, ipv6)) = self.client_ips.remove(id) {
+            let mut owners = write(&self.owners);
+            owners.remove(&IpAddr::V4(ipv4));
+            owners.remove(&IpAddr::V6(ipv6));
+        }
+    }
+
+    /// Allows access to the resource on the client's shard.
+    ///
+    /// Packets for the client's IPs are handed off to its shard right away.
+    /// The returned future resolves once the shard processed the request.
+    pub fn allow_access(
+        &mut self,
+        client: ClientId,
+        ipv4: Ipv4Addr,
+        ipv6: Ipv6Addr,
+        domain: Option<(DomainName, Vec<IpAddr>)>,
+        expires_at: Option<DateTime<Utc>>,
+        resource: ResourceDescription<ResolvedResourceDescriptionDns>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send + 'static {
+        let index = self.shard_of(&client);
+
+        self.client_ips.insert(client, (ipv4, ipv6));
+        {
+            let mut owners = write(&self.owners);
+            owners.insert(IpAddr::V4(ipv4), index);
+            owners.insert(IpAddr::V6(ipv6), index);
+        }
+
+        let allowed = self.call_shard(index, move |tunnel| {
+            tunnel.allow_access(client, ipv4, ipv6, domain, expires_at, resource)
+        });
+
+        async move { allowed.await? }
+    }
+
+    pub fn refresh_translation(
+        &mut self,
+        client: ClientId,
+        resource_id: ResourceId,
+        name: DomainName,
+        resolved_ips: Vec<IpAddr>,
+    ) {
+        self.send_to_shard(self.shard_of(&client), move |tunnel| {
+            tunnel.refresh_translation(client, resource_id, name, resolved_ips)
+        });
+    }
+
+    pub fn remove_access(&mut self, client: &ClientId, resource: &ResourceId) {
+        let (client, resource) = (*client, *resource);
+        self.send_to_shard(self.shard_of(&client), move |tunnel| {
+            tunnel.remove_access(&client, &resource)
+        });
+    }
+
+    pub fn add_ice_candidate(&mut self, conn_id: ClientId, ice_candidate: String) {
+        self.send_to_shard(self.shard_of(&conn_id), move |tunnel| {
+            tunnel.add_ice_candidate(conn_id, ice_candidate)
+        });
+    }
+
+    pub fn remove_ice_candidate(&mut self, conn_id: ClientId, ice_candidate: String) {
+        self.send_to_shard(self.shard_of(&conn_id), move |tunnel| {
+            tunnel.remove_ice_candidate(conn_id, ice_candidate)
+        });
+    }
+
+    pub fn stats_handle(&self) -> GatewayStatsHandle {
+        GatewayStatsHandle {
+            shards: self.shards.iter().map(|s| s.stats.clone()).collect(),
+        }
+    }
+
+    /// Returns the next event emitted by any of the shards.
+    ///
+    /// Errors of individual shards are logged by the shards themselves.
+    /// Only fails once all shards have stopped, after which the tunnel can no longer route any packets.
+    pub fn poll_next_event(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<GatewayEvent>> {
+        match std::task::ready!(self.events.poll_next_unpin(cx)) {
+            Some(event) => Poll::Ready(Ok(event)),
+            None => Poll::Ready(Err(io::Error::other("All shards have stopped"))),
+        }
+    }
+
+    fn shard_of(&self, client: &ClientId) -> usize {
+        let mut hasher = DefaultHasher::new();
+        client.hash(&mut hasher);
+
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    fn send_to_all_shards(
+        &self,
+        command: impl FnOnce(&mut GatewayTunnel) + Clone + Send + 'static,
+    ) {
+        for index in 0..self.shards.len() {
+            self.send_to_shard(index, command.clone());
+        }
+    }
+
+    fn send_to_shard(
+        &self,
+        index: usize,
+        command: impl FnOnce(&mut GatewayTunnel) + Send + 'static,
+    ) {
+        if self.shards[index]
+            .commands
+            .unbounded_send(Box::new(command))
+            .is_err()
+        {
+            tracing::warn!(shard = %index, "Shard has stopped, dropping command");
+        }
+    }
+
+    /// Sends a command to the shard and returns a future that resolves to the command's result.
+    fn call_shard<R>(
+        &self,
+        index: usize,
+        f: impl FnOnce(&mut GatewayTunnel) -> R + Send + 'static,
+    ) -> impl Future<Output = anyhow::Result<R>> + Send + 'static
+    where
+        R: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.send_to_shard(index, move |tunnel| {
+            let _ = tx.send(f(tunnel)); // The caller may no longer be interested in the result.
+        });
+
+        async move { rx.await.context("Shard has stopped") }
+    }
+}
+
+impl Drop for ShardedGatewayTunnel {
+    fn drop(&mut self) {
+        for shard in &self.shards {
+            shard.task.abort();
+        }
+    }
+}
+
+impl GatewayStatsHandle {
+    pub fn get(&self) -> GatewayStats {
+        let mut stats = GatewayStats::default();
+
+        for shard in &self.shards {
+            stats.merge(shard.borrow().clone());
+        }
+
+        stats
+    }
+}
+
+impl ShardRouter {
+    /// Hands the given packet off to the shard that owns its destination.
+    ///
+    /// Returns `true` if the packet belongs to another shard.
+    pub(crate) fn try_hand_off(&mut self, packet: &[u8]) -> bool {
+        let Some(dst) = IpPacket::new(packet).map(|p| p.destination()) else {
+            return false;
+        };
+        let Some(owner) = read(&self.owners).get(&dst).copied() else {
+            return false;
+        };
+
+        if owner == self.index {
+            return false;
+        }
+
+        let Some(shard) = self.shards.get_mut(owner) else {
+            return false;
+        };
+
+        if let Err(e) = shard.try_send(packet.to_vec()) {
+            tracing::debug!(%dst, %owner, "Failed to hand off packet to shard: {e}");
+        }
+
+        true
+    }
+}
+
+async fn drive_shard(
+    index: usize,
+    mut tunnel: GatewayTunnel,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    stats: watch::Sender<GatewayStats>,
+    events: mpsc::UnboundedSender<GatewayEvent>,
+) {
+    let mut stats_interval = tokio::time::interval(STATS_INTERVAL);
+
+    loop {
+        let result = std::future::poll_fn(|cx| loop {
+            match commands.poll_next_unpin(cx) {
+                Poll::Ready(Some(command)) => {
+                    command(&mut tunnel);
+                    continue;
+                }
+                Poll::Ready(None) => return Poll::Ready(None), // The `ShardedGatewayTunnel` has been dropped.
+                Poll::Pending => {}
+            }
+
+            if stats_interval.poll_tick(cx).is_ready() {
+                stats.send_replace(tunnel.stats());
+                continue;
+            }
+
+            return tunnel.poll_next_event(cx).map(Some);
+        })
+        .await;
+
+        let Some(result) = result else {
+            return;
+        };
+
+        match result {
+            Ok(event) => {
+                if events.unbounded_send(event).is_err() {
+                    return; // The `ShardedGatewayTunnel` has been dropped.
+                }
+            }
+            Err(e) => tracing::warn!(shard = %index, "Tunnel error: {e}"),
+        }
+    }
+}
+
+fn read(owners: &RwLock<HashMap<IpAddr, usize>>) -> RwLockReadGuard<'_, HashMap<IpAddr, usize>> {
+    owners.read().unwrap_or_else(|e| e.into_inner())
+}
+
+fn write(owners: &RwLock<HashMap<IpAddr, usize>>) -> RwLockWriteGuard<'_, HashMap<IpAddr, usize>> {
+    owners.write().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use connlib_shared::messages::gateway::ResourceDescriptionCidr;
+    use ip_packet::Packet as _;
+
+    #[tokio::test]
+    async fn requires_one_tun_queue_per_shard() {
+        let mut tunnel = sharded_tunnel(2);
+
+        assert!(tunnel.set_tun_queues(vec![NoopTun]).is_err());
+        assert!(tunnel.set_tun_queues(vec![NoopTun, NoopTun]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn shards_reply_to_connection_requests() {
+        let mut tunnel = sharded_tunnel(2);
+        let client = ClientId::from_u128(1);
+        let (ipv4, ipv6) = (Ipv4Addr::new(100, 64, 0, 1), Ipv6Addr::LOCALHOST);
+
+        let answer = tunnel
+            .accept(
+                client,
+                Secret::new(Key([1; 32])),
+                Offer {
+                    username: "foo".to_owned(),
+                    password: "bar".to_owned(),
+                },
+                PublicKey::from([2; 32]),
+            )
+            .await
+            .unwrap();
+        assert!(!answer.username.is_empty());
+
+        tunnel
+            .allow_access(client, ipv4, ipv6, None, None, cidr_resource())
+            .await
+            .unwrap();
+        assert_eq!(
+            read(&tunnel.owners).get(&IpAddr::V4(ipv4)).copied(),
+            Some(tunnel.shard_of(&client))
+        );
+
+        tunnel.cleanup_connection(&client);
+        assert!(read(&tunnel.owners).is_empty());
+    }
+
+    #[tokio::test]
+    async fn errors_of_shards_are_returned_to_caller() {
+        let mut tunnel = sharded_tunnel(2);
+
+        let result = tunnel
+            .allow_access(
+                ClientId::from_u128(1),
+                Ipv4Addr::new(100, 64, 0, 1),
+                Ipv6Addr::LOCALHOST,
+                None,
+                None,
+                ResourceDescription::Dns(ResolvedResourceDescriptionDns {
+                    id: ResourceId::from_u128(1),
+                    domain: "example.com".to_owned(),
+                    name: "example.com".to_owned(),
+                    addresses: vec![],
+                    filters: vec![],
+                }),
+            )
+            .await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn fails_once_all_shards_have_stopped() {
+        let mut tunnel = sharded_tunnel(2);
+
+        tunnel.send_to_all_shards(|_| panic!("shard crashed"));
+        let result = std::future::poll_fn(|cx| tunnel.poll_next_event(cx)).await;
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn hands_off_packets_for_clients_of_other_shards() {
+        let client_a = IpAddr::from(Ipv4Addr::new(100, 64, 0, 1));
+        let client_b = IpAddr::from(Ipv4Addr::new(100, 64, 0, 2));
+        let owners = Arc::new(RwLock::new(HashMap::from([(client_a, 0), (client_b, 1)])));
+        let (tx0, mut rx0) = mpsc::channel(1);
+        let (tx1, mut rx1) = mpsc::channel(1);
+        let mut router = ShardRouter {
+            index: 0,
+            owners,
+            shards: vec![tx0, tx1],
+        };
+
+        assert!(!router.try_hand_off(&packet_to(client_a)));
+        assert!(!router.try_hand_off(&packet_to(Ipv4Addr::new(100, 64, 0, 3).into())));
+        assert!(router.try_hand_off(&packet_to(client_b)));
+
+        assert!(rx0.try_next().is_err());
+        assert_eq!(rx1.try_next().unwrap().unwrap(), packet_to(client_b));
+    }
+
+    #[test]
+    fn drops_handed_off_packets_that_dont_fit_into_buffer() {
+        let client = IpAddr::from(Ipv4Addr::new(100, 64, 0, 1));
+        let (mut handoff_tx, handoff_rx) = mpsc::channel(2);
+        let mut device = crate::device_channel::Device::new();
+        device.set_shard_router(
+            ShardRouter {
+                index: 0,
+                owners: Arc::new(RwLock::new(HashMap::from([(client, 0)]))),
+                shards: vec![handoff_tx.clone()],
+            },
+            handoff_rx,
+        );
+        let packet = packet_to(client);
+        let mut oversized = packet.clone();
+        oversized.resize(packet.len() + 1, 0);
+
+        handoff_tx.try_send(oversized).unwrap();
+        handoff_tx.try_send(packet.clone()).unwrap();
+
+        let mut buf = vec![0u8; 20 + packet.len()];
+        let Poll::Ready(Ok(received)) = device.poll_read(
+            &mut buf,
+            &mut Context::from_waker(futures::task::noop_waker_ref()),
+        ) else {
+            panic!("expected handed off packet");
+        };
+
+        assert_eq!(received.packet(), packet.as_slice());
+    }
+
+    fn sharded_tunnel(num_shards: usize) -> ShardedGatewayTunnel {
+        ShardedGatewayTunnel::new(
+            StaticSecret::from([1; 32]),
+            Arc::new(socket_factory::tcp),
+            Arc::new(socket_factory::udp),
+            NonZeroUsize::new(num_shards).unwrap(),
+        )
+    }
+
+    fn cidr_resource() -> ResourceDescription<ResolvedResourceDescriptionDns> {
+        ResourceDescription::Cidr(ResourceDescriptionCidr {
+            id: ResourceId::from_u128(2),
+            address: "10.0.0.0/24".parse().unwrap(),
+            name: "10.0.0.0/24".to_owned(),
+            filters: vec![],
+        })
+    }
+
+    struct NoopTun;
+
+    impl Tun for NoopTun {
+        fn write4(&self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn write6(&self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn poll_read(&mut self, _: &mut [u8], _: &mut Context<'_>) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+
+        fn name(&self) -> &str {
+            "noop"
+        }
+    }
+
+    fn packet_to(dst: IpAddr) -> Vec<u8> {
+        ip_packet::make::udp_packet(Ipv4Addr::new(10, 0, 0, 1).into(), dst, 1234, 53, vec![])
+            .unwrap()
+            .packet()
+            .to_vec()
+    }
+}
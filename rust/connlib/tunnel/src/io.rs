@@ -1,16 +1,36 @@
-use crate::{device_channel::Device, sockets::Sockets, BUF_SIZE};
-use futures_util::FutureExt as _;
+mod gso_queue;
+mod relay_streams;
+mod upstream_dns;
+
+use crate::{device_channel::Device, dns::ForwardedQuery, sockets::Sockets, BUF_SIZE};
+use connlib_shared::messages::DnsServer;
+use futures::{future::BoxFuture, stream::FuturesUnordered};
+use futures_util::{FutureExt as _, StreamExt as _};
+use gso_queue::GsoQueue;
 use ip_packet::{IpPacket, MutableIpPacket};
-use snownet::{EncryptBuffer, EncryptedPacket};
+use relay_streams::{Frame, RelayStreams};
+use snownet::{EncryptBuffer, EncryptedPacket, Transport};
 use socket_factory::{DatagramIn, DatagramOut, SocketFactory, TcpSocket, UdpSocket};
 use std::{
+    borrow::Cow,
+    collections::HashMap,
     io,
     pin::Pin,
     sync::Arc,
     task::{ready, Context, Poll},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
+/// How long we wait for an upstream DNS server to answer a forwarded query.
+const DNS_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A forwarded DNS query, its response and the connection to reuse for the next query to the same server.
+type DnsQueryResult = (
+    ForwardedQuery,
+    io::Result<Vec<u8>>,
+    Option<upstream_dns::Connection>,
+);
+
 /// Bundles together all side-effects that connlib needs to have access to.
 pub struct Io {
     /// The TUN device offered to the user.
@@ -19,11 +39,21 @@ pub struct Io {
     device: Device,
     /// The UDP sockets used to send & receive packets from the network.
     sockets: Sockets,
-    unwritten_packet: Option<EncryptedPacket>,
+    /// Encrypted packets waiting to be sent in batches.
+    gso_queue: GsoQueue,
+    /// TCP connections to relays that we cannot reach via UDP.
+    relay_streams: RelayStreams,
 
-    _tcp_socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
+    tcp_socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
     udp_socket_factory: Arc<dyn SocketFactory<UdpSocket>>,
 
+    /// DNS queries we are forwarding to an upstream server over TCP, TLS or HTTPS.
+    dns_queries: FuturesUnordered<BoxFuture<'static, DnsQueryResult>>,
+    /// Idle connections to upstream DNS servers, reused for the next query to the same server.
+    dns_connections: HashMap<DnsServer, upstream_dns::Connection>,
+    /// The TLS configuration for encrypted upstream DNS servers and relays, created on first use.
+    tls_config: Option<Arc<rustls::ClientConfig>>,
+
     timeout: Option<Pin<Box<tokio::time::Sleep>>>,
 }
 
@@ -31,6 +61,8 @@ pub enum Input<'a, I> {
     Timeout(Instant),
     Device(MutableIpPacket<'a>),
     Network(I),
+    RelayStream(Frame),
+    DnsResponse(ForwardedQuery, io::Result<Vec<u8>>),
 }
 
 impl Io {
@@ -48,9 +80,13 @@ impl Io {
             device: Device::new(),
             timeout: None,
             sockets,
-            _tcp_socket_factory: tcp_socket_factory,
+            tcp_socket_factory,
             udp_socket_factory,
-            unwritten_packet: None,
+            dns_queries: FuturesUnordered::new(),
+            dns_connections: HashMap::default(),
+            tls_config: None,
+            gso_queue: GsoQueue::default(),
+            relay_streams: RelayStreams::default(),
         }
     }
 
@@ -64,18 +100,36 @@ impl Io {
         ip4_buffer: &'b1 mut [u8],
         ip6_bffer: &'b1 mut [u8],
         device_buffer: &'b2 mut [u8],
-        encrypt_buffer: &EncryptBuffer,
     ) -> Poll<io::Result<Input<'b2, impl Iterator<Item = DatagramIn<'b1>>>>> {
-        ready!(self.poll_send_unwritten(cx, encrypt_buffer)?);
+        ready!(self.poll_flush(cx)?);
 
         if let Poll::Ready(network) = self.sockets.poll_recv_from(ip4_buffer, ip6_bffer, cx)? {
             return Poll::Ready(Ok(Input::Network(network.filter(is_max_wg_packet_size))));
         }
 
+        if let Poll::Ready(frame) = self.relay_streams.poll_recv(cx) {
+            return Poll::Ready(Ok(Input::RelayStream(frame)));
+        }
+
         if let Poll::Ready(packet) = self.device.poll_read(device_buffer, cx)? {
             return Poll::Ready(Ok(Input::Device(packet)));
         }
 
+        // The device has no more packets for us right now, send whatever we have batched up so far.
+        self.gso_queue.seal_all();
+        ready!(self.poll_flush(cx)?);
+
+        if let Poll::Ready(Some((query, response, connection))) =
+            self.dns_queries.poll_next_unpin(cx)
+        {
+            if let Some(connection) = connection {
+                self.dns_connections
+                    .insert(query.server.clone(), connection);
+            }
+
+            return Poll::Ready(Ok(Input::DnsResponse(query, response)));
+        }
+
         if let Some(timeout) = self.timeout.as_mut() {
             if timeout.poll_unpin(cx).is_ready() {
                 let deadline = timeout.deadline().into();
@@ -88,29 +142,47 @@ impl Io {
         Poll::Pending
     }
 
-    fn poll_send_unwritten(
-        &mut self,
-        cx: &mut Context<'_>,
-        buf: &EncryptBuffer,
-    ) -> Poll<io::Result<()>> {
-        ready!(self.sockets.poll_send_ready(cx))?;
-
-        // If the `unwritten_packet` is set, `EncryptBuffer` is still holding a packet that we need so send.
-        let Some(unwritten_packet) = self.unwritten_packet.take() else {
-            return Poll::Ready(Ok(()));
-        };
-
-        self.send_encrypted_packet(unwritten_packet, buf)?;
+    /// Sends all sealed batches of encrypted packets.
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.gso_queue.has_sealed() {
+            ready!(self.sockets.poll_send_ready(cx))?;
+
+            let Some(datagram) = self.gso_queue.pop() else {
+                break;
+            };
+
+            let res = self.sockets.send(DatagramOut {
+                src: datagram.src,
+                dst: datagram.dst,
+                packet: Cow::Borrowed(&datagram.packet[..]),
+                segment_size: datagram.segment_size,
+            });
+
+            match res {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    tracing::debug!(dst = %datagram.dst, "Socket busy");
+                    self.gso_queue.push_front(datagram);
+                }
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
 
         Poll::Ready(Ok(()))
     }
 
+    /// Seals all batches of encrypted packets, ensuring they will be sent on the next call to [`Io::poll`].
+    pub fn seal_batches(&mut self) {
+        self.gso_queue.seal_all();
+    }
+
     pub fn device_mut(&mut self) -> &mut Device {
         &mut self.device
     }
 
     pub fn rebind_sockets(&mut self) {
         self.sockets.rebind(self.udp_socket_factory.as_ref());
+        self.relay_streams.clear(); // The TCP connections may be bound to an interface that is gone.
     }
 
     pub fn reset_timeout(&mut self, timeout: Instant) {
@@ -126,35 +198,95 @@ impl Io {
     }
 
     pub fn send_network(&mut self, transmit: snownet::Transmit) -> io::Result<()> {
+        match transmit.transport {
+            Transport::Udp => {}
+            Transport::Tcp | Transport::Tls => {
+                self.send_via_relay_stream(&transmit);
+                return Ok(());
+            }
+        }
+
         self.sockets.send(DatagramOut {
             src: transmit.src,
             dst: transmit.dst,
             packet: transmit.payload,
+            segment_size: None,
         })?;
 
         Ok(())
     }
 
-    pub fn send_encrypted_packet(
-        &mut self,
-        packet: EncryptedPacket,
-        buf: &EncryptBuffer,
-    ) -> io::Result<()> {
-        let transmit = packet.to_transmit(buf);
-        let res = self.send_network(transmit);
-
-        if res
-            .as_ref()
-            .err()
-            .is_some_and(|e| e.kind() == io::ErrorKind::WouldBlock)
-        {
-            tracing::debug!(dst = %packet.dst(), "Socket busy");
-            self.unwritten_packet = Some(packet);
+    /// Queues an encrypted packet for sending.
+    ///
+    /// Packets to the same destination are coalesced and sent as a single GSO batch once the device has no more packets for us.
+    pub fn send_encrypted_packet(&mut self, packet: EncryptedPacket, buf: &EncryptBuffer) {
+        self.send_batched(packet.to_transmit(buf));
+    }
+
+    fn send_batched(&mut self, transmit: snownet::Transmit) {
+        // Packets to relays we talk to via TCP or TLS cannot be batched.
+        match transmit.transport {
+            Transport::Udp => {}
+            Transport::Tcp | Transport::Tls => {
+                self.send_via_relay_stream(&transmit);
+                return;
+            }
         }
 
-        res?;
+        let max_segments = self.sockets.max_gso_segments(transmit.dst);
 
-        Ok(())
+        self.gso_queue
+            .enqueue(transmit.src, transmit.dst, &transmit.payload, max_segments);
+    }
+
+    fn send_via_relay_stream(&mut self, transmit: &snownet::Transmit) {
+        let tls_config = self
+            .tls_config
+            .get_or_insert_with(upstream_dns::tls_config)
+            .clone();
+
+        self.relay_streams.send(
+            self.tcp_socket_factory.as_ref(),
+            &tls_config,
+            transmit.dst,
+            transmit.transport,
+            &transmit.payload,
+        );
+    }
+
+    /// Forwards a DNS query to an upstream server over TCP, optionally secured with TLS.
+    ///
+    /// Queries are sent over the idle connection to the server if we have one, otherwise over a new one.
+    pub fn send_dns_query(&mut self, query: ForwardedQuery) {
+        let tcp_socket_factory = self.tcp_socket_factory.clone();
+        let tls_config = self
+            .tls_config
+            .get_or_insert_with(upstream_dns::tls_config)
+            .clone();
+        let connection = self.dns_connections.remove(&query.server);
+
+        self.dns_queries.push(
+            async move {
+                let result = tokio::time::timeout(
+                    DNS_QUERY_TIMEOUT,
+                    upstream_dns::send(
+                        tcp_socket_factory,
+                        tls_config,
+                        connection,
+                        &query.server,
+                        &query.message,
+                    ),
+                )
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+
+                match result {
+                    Ok((response, connection)) => (query, Ok(response), connection),
+                    Err(e) => (query, Err(e), None),
+                }
+            }
+            .boxed(),
+        );
     }
 
     pub fn send_device(&self, packet: IpPacket<'_>) -> io::Result<()> {
@@ -174,3 +306,54 @@ fn is_max_wg_packet_size(d: &DatagramIn) -> bool {
 
     true
 }
+
+#[cfg(feature = "divan")]
+mod benches {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    const NUM_PACKETS: usize = 1_000;
+    const PACKET_LEN: usize = 1_200;
+
+    /// Sends packets to a socket on loopback, batched with GSO if the platform supports it.
+    #[divan::bench]
+    fn send_encrypted_packets(bencher: divan::Bencher) {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _guard = rt.enter();
+
+        // We only measure how fast we can send, hence nobody reads from this socket.
+        let receiver = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let dst = receiver.local_addr().unwrap();
+        let mut io = Io::new(Arc::new(socket_factory::tcp), Arc::new(socket_factory::udp));
+        let packet = vec![0u8; PACKET_LEN];
+
+        let mut ip4_buffer = vec![0u8; 65_535];
+        let mut ip6_buffer = vec![0u8; 65_535];
+        let mut device_buffer = vec![0u8; 65_535];
+
+        bencher
+            .counter(divan::counter::BytesCount::new(NUM_PACKETS * PACKET_LEN))
+            .bench_local(|| {
+                for _ in 0..NUM_PACKETS {
+                    io.send_batched(snownet::Transmit {
+                        src: None,
+                        dst,
+                        transport: Transport::Udp,
+                        payload: Cow::Borrowed(&packet),
+                    });
+                }
+
+                rt.block_on(std::future::poll_fn(|cx| {
+                    match io.poll(cx, &mut ip4_buffer, &mut ip6_buffer, &mut device_buffer) {
+                        Poll::Ready(Ok(_)) => unreachable!("nobody sends us anything"),
+                        Poll::Ready(Err(e)) => panic!("Failed to send: {e}"),
+                        Poll::Pending if io.gso_queue.has_sealed() => Poll::Pending,
+                        Poll::Pending => Poll::Ready(()),
+                    }
+                }));
+            });
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+};
+
+use socket_factory::{DatagramOut, MAX_UDP_PAYLOAD_LEN};
+
+/// Coalesces encrypted packets for the same destination into batches that can be sent with a single GSO syscall.
+///
+/// Each destination has at most one open batch.
+/// All segments within a batch have the same size, only the last one may be shorter.
+/// A batch is sealed once it is full or a segment doesn't fit anymore.
+/// All segments of a batch go out as a single UDP datagram, so a batch never exceeds [`MAX_UDP_PAYLOAD_LEN`].
+/// Sealed batches are sent in the order they were sealed, meaning packets to the same destination are never reordered.
+#[derive(Default)]
+pub(crate) struct GsoQueue {
+    open: HashMap<(Option<SocketAddr>, SocketAddr), Batch>,
+    sealed: VecDeque<DatagramOut<'static>>,
+}
+
+struct Batch {
+    buffer: Vec<u8>,
+    segment_size: usize,
+    num_segments: usize,
+}
+
+impl GsoQueue {
+    /// Adds a packet to the open batch for the given source and destination.
+    ///
+    /// `max_segments` is the number of segments the socket can send at once.
+    pub(crate) fn enqueue(
+        &mut self,
+        src: Option<SocketAddr>,
+        dst: SocketAddr,
+        payload: &[u8],
+        max_segments: usize,
+    ) {
+        let key = (src, dst);
+
+        let does_not_fit = self.open.get(&key).is_some_and(|batch| {
+            payload.len() > batch.segment_size
+                || batch.buffer.len() + payload.len() > MAX_UDP_PAYLOAD_LEN
+        });
+        if does_not_fit {
+            self.seal(key);
+        }
+
+        let batch = self.open.entry(key).or_insert_with(|| Batch {
+            buffer: Vec::with_capacity((payload.len() * max_segments).min(MAX_UDP_PAYLOAD_LEN)),
+            segment_size: payload.len(),
+            num_segments: 0,
+        });
+
+        batch.buffer.extend_from_slice(payload);
+        batch.num_segments += 1;
+
+        // A shorter segment can only be the last one of a batch.
+        let is_full = payload.len() < batch.segment_size || batch.num_segments >= max_segments;
+        if is_full {
+            self.seal(key);
+        }
+    }
+
+    /// Seals all open batches, making them available via [`GsoQueue::pop`].
+    pub(crate) fn seal_all(&mut self) {
+        let keys = self.open.keys().copied().collect::<Vec<_>>();
+
+        for key in keys {
+            self.seal(key);
+        }
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<DatagramOut<'static>> {
+        self.sealed.pop_front()
+    }
+
+    /// Puts a batch that we failed to send back at the front of the queue.
+    pub(crate) fn push_front(&mut self, datagram: DatagramOut<'static>) {
+        self.sealed.push_front(datagram);
+    }
+
+    pub(crate) fn has_sealed(&self) -> bool {
+        !self.sealed.is_empty()
+    }
+
+    fn seal(&mut self, key: (Option<SocketAddr>, SocketAddr)) {
+        let Some(batch) = self.open.remove(&key) else {
+            return;
+        };
+        let (src, dst) = key;
+
+        self.sealed.push_back(DatagramOut {
+            src,
+            dst,
+            packet: Cow::Owned(batch.buffer),
+            segment_size: (batch.num_segments > 1).then_some(batch.segment_size),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn coalesces_packets_for_same_destination() {
+        let mut queue = GsoQueue::default();
+
+        queue.enqueue(None, dst(1), &[1; 100], 64);
+        queue.enqueue(None, dst(1), &[2; 100], 64);
+        queue.enqueue(None, dst(2), &[3; 100], 64);
+        queue.seal_all();
+
+        let mut batches = std::iter::from_fn(|| queue.pop()).collect::<Vec<_>>();
+        batches.sort_by_key(|d| d.dst);
+
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].packet.len(), 200);
+        assert_eq!(batches[0].segment_size, Some(100));
+        assert_eq!(batches[1].packet.len(), 100);
+        assert_eq!(batches[1].segment_size, None);
+    }
+
+    #[test]
+    fn shorter_packet_seals_batch() {
+        let mut queue = GsoQueue::default();
+
+        queue.enqueue(None, dst(1), &[1; 100], 64);
+        queue.enqueue(None, dst(1), &[2; 50], 64);
+
+        let batch = queue.pop().unwrap();
+
+        assert_eq!(batch.packet.len(), 150);
+        assert_eq!(batch.segment_size, Some(100));
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn longer_packet_starts_new_batch() {
+        let mut queue = GsoQueue::default();
+
+        queue.enqueue(None, dst(1), &[1; 50], 64);
+        queue.enqueue(None, dst(1), &[2; 100], 64);
+        queue.seal_all();
+
+        assert_eq!(queue.pop().unwrap().packet.len(), 50);
+        assert_eq!(queue.pop().unwrap().packet.len(), 100);
+    }
+
+    #[test]
+    fn respects_max_segments() {
+        let mut queue = GsoQueue::default();
+
+        queue.enqueue(None, dst(1), &[1; 100], 2);
+        queue.enqueue(None, dst(1), &[2; 100], 2);
+        queue.enqueue(None, dst(1), &[3; 100], 2);
+
+        assert_eq!(queue.pop().unwrap().packet.len(), 200);
+        assert!(queue.pop().is_none());
+    }
+
+    fn dst(port: u16) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+    }
+}
@@ -0,0 +1,305 @@
+//! TCP and TLS connections to relays that we cannot reach via UDP.
+//!
+//! TURN messages sent over a stream need to be framed: STUN messages carry their own length and ChannelData messages are padded to a multiple of 4 bytes.
+//! See <https://www.rfc-editor.org/rfc/rfc8656#section-12.5>.
+
+use futures::future::BoxFuture;
+use futures_util::FutureExt as _;
+use rustls::pki_types::ServerName;
+use snownet::{Transport, RELAY_TLS_PORT};
+use socket_factory::{SocketFactory, TcpSocket};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::TlsConnector;
+
+/// How many bytes we buffer for a single relay before we start dropping packets.
+const MAX_PENDING_BYTES: usize = 256 * 1024;
+
+const STUN_HEADER_LEN: usize = 20;
+const CHANNEL_DATA_HEADER_LEN: usize = 4;
+
+#[derive(Default)]
+pub(crate) struct RelayStreams {
+    streams: HashMap<SocketAddr, RelayStream>,
+}
+
+/// A single TURN message received from a relay.
+pub(crate) struct Frame {
+    pub(crate) local: SocketAddr,
+    pub(crate) from: SocketAddr,
+    pub(crate) packet: Vec<u8>,
+}
+
+struct RelayStream {
+    transport: Transport,
+    state: State,
+    send_buf: Vec<u8>,
+    recv_buf: Vec<u8>,
+}
+
+/// A TCP stream, optionally wrapped in TLS.
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S> Stream for S where S: AsyncRead + AsyncWrite + Send + Unpin {}
+
+enum State {
+    Connecting(BoxFuture<'static, io::Result<(Box<dyn Stream>, SocketAddr)>>),
+    Connected {
+        stream: Box<dyn Stream>,
+        local: SocketAddr,
+    },
+}
+
+impl RelayStreams {
+    /// Queues a TURN message for the given relay, connecting to it first if necessary.
+    ///
+    /// If we are connected to the relay via a different transport, that connection is replaced.
+    pub(crate) fn send(
+        &mut self,
+        tcp_socket_factory: &dyn SocketFactory<TcpSocket>,
+        tls_config: &Arc<rustls::ClientConfig>,
+        relay: SocketAddr,
+        transport: Transport,
+        message: &[u8],
+    ) {
+        if self
+            .streams
+            .get(&relay)
+            .is_some_and(|s| s.transport != transport)
+        {
+            tracing::debug!(%relay, ?transport, "Switching transport to relay");
+            self.streams.remove(&relay);
+        }
+
+        let stream = match self.streams.entry(relay) {
+            Entry::Occupied(o) => o.into_mut(),
+            Entry::Vacant(v) => {
+                let socket = match tcp_socket_factory(&relay) {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        tracing::debug!(%relay, "Failed to create TCP socket: {e}");
+                        return;
+                    }
+                };
+
+                tracing::debug!(%relay, ?transport, "Connecting to relay");
+
+                v.insert(RelayStream {
+                    transport,
+                    state: State::Connecting(
+                        connect(socket, relay, transport, tls_config.clone()).boxed(),
+                    ),
+                    send_buf: Vec::new(),
+                    recv_buf: Vec::new(),
+                })
+            }
+        };
+
+        let padded_len = padded_len(message);
+
+        if stream.send_buf.len() + padded_len > MAX_PENDING_BYTES {
+            tracing::debug!(%relay, "Send buffer is full, dropping message");
+            return;
+        }
+
+        stream.send_buf.extend_from_slice(message);
+        stream
+            .send_buf
+            .resize(stream.send_buf.len() + padded_len - message.len(), 0);
+    }
+
+    pub(crate) fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Frame> {
+        let mut failed = Vec::new();
+        let mut frame = None;
+
+        for (relay, stream) in self.streams.iter_mut() {
+            match stream.poll(*relay, cx) {
+                Poll::Ready(Ok(f)) => {
+                    frame = Some(f);
+                    break;
+                }
+                Poll::Ready(Err(e)) => {
+                    tracing::debug!(%relay, "TCP connection to relay failed: {e}");
+                    failed.push(*relay);
+                }
+                Poll::Pending => {}
+            }
+        }
+
+        // Failed connections are re-established on the next message to the relay.
+        for relay in failed {
+            self.streams.remove(&relay);
+        }
+
+        match frame {
+            Some(frame) => Poll::Ready(frame),
+            None => Poll::Pending,
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.streams.clear();
+    }
+}
+
+impl RelayStream {
+    fn poll(&mut self, relay: SocketAddr, cx: &mut Context<'_>) -> Poll<io::Result<Frame>> {
+        loop {
+            let (stream, local) = match &mut self.state {
+                State::Connecting(connect) => {
+                    let (stream, local) = ready!(connect.poll_unpin(cx))?;
+
+                    tracing::debug!(%relay, %local, transport = ?self.transport, "Connected to relay");
+
+                    self.state = State::Connected { stream, local };
+                    continue;
+                }
+                State::Connected { stream, local } => (stream, *local),
+            };
+
+            if let Some((message_len, frame_len)) = frame_len(&self.recv_buf)? {
+                let packet = self.recv_buf[..message_len].to_vec();
+                self.recv_buf.drain(..frame_len);
+
+                return Poll::Ready(Ok(Frame {
+                    local,
+                    from: relay,
+                    packet,
+                }));
+            }
+
+            while !self.send_buf.is_empty() {
+                match Pin::new(&mut *stream).poll_write(cx, &self.send_buf) {
+                    Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                    Poll::Ready(Ok(n)) => {
+                        self.send_buf.drain(..n);
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => break,
+                }
+            }
+
+            let mut chunk = [0u8; 4096];
+            let mut read_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(stream).poll_read(cx, &mut read_buf))?;
+
+            if read_buf.filled().is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+
+            self.recv_buf.extend_from_slice(read_buf.filled());
+        }
+    }
+}
+
+/// Connects to the relay, returning the stream and its local address.
+///
+/// TLS connections go to [`RELAY_TLS_PORT`] instead of the relay's TURN port.
+/// We only know relays by their IP, so their certificate must be valid for it.
+async fn connect(
+    socket: TcpSocket,
+    relay: SocketAddr,
+    transport: Transport,
+    tls_config: Arc<rustls::ClientConfig>,
+) -> io::Result<(Box<dyn Stream>, SocketAddr)> {
+    match transport {
+        Transport::Tcp => {
+            let stream = socket.connect(relay).await?;
+            let local = stream.local_addr()?;
+
+            Ok((Box::new(stream), local))
+        }
+        Transport::Tls => {
+            let stream = socket
+                .connect(SocketAddr::new(relay.ip(), RELAY_TLS_PORT))
+                .await?;
+            let local = stream.local_addr()?;
+            let stream = TlsConnector::from(tls_config)
+                .connect(ServerName::IpAddress(relay.ip().into()), stream)
+                .await?;
+
+            Ok((Box::new(stream), local))
+        }
+        Transport::Udp => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "UDP is not a stream transport",
+        )),
+    }
+}
+
+/// Returns the length of the message at the start of `buf` and the length of its frame (i.e. incl. padding), if it has been fully received.
+fn frame_len(buf: &[u8]) -> io::Result<Option<(usize, usize)>> {
+    let Some(header) = buf.get(..4) else {
+        return Ok(None);
+    };
+    let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+
+    let (message_len, frame_len) = match header[0] {
+        0..=3 => (STUN_HEADER_LEN + len, STUN_HEADER_LEN + len),
+        64..=79 => (
+            CHANNEL_DATA_HEADER_LEN + len,
+            (CHANNEL_DATA_HEADER_LEN + len).next_multiple_of(4),
+        ),
+        other => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown message type on relay stream: {other}"),
+            ))
+        }
+    };
+
+    if buf.len() < frame_len {
+        return Ok(None);
+    }
+
+    Ok(Some((message_len, frame_len)))
+}
+
+/// ChannelData messages need to be padded to a multiple of 4 bytes when sent over a stream.
+fn padded_len(message: &[u8]) -> usize {
+    match message.first() {
+        Some(64..=79) => message.len().next_multiple_of(4),
+        Some(_) | None => message.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_for_full_stun_message() {
+        let mut buf = vec![0x00, 0x01, 0x00, 0x08];
+        buf.extend_from_slice(&[0; 24]);
+
+        assert_eq!(frame_len(&buf[..4]).unwrap(), None);
+        assert_eq!(frame_len(&buf[..27]).unwrap(), None);
+        assert_eq!(frame_len(&buf).unwrap(), Some((28, 28)));
+    }
+
+    #[test]
+    fn channel_data_frame_includes_padding() {
+        let buf = [0x40, 0x00, 0x00, 0x03, 1, 2, 3, 0];
+
+        assert_eq!(frame_len(&buf[..7]).unwrap(), None);
+        assert_eq!(frame_len(&buf).unwrap(), Some((7, 8)));
+    }
+
+    #[test]
+    fn pads_channel_data_but_not_stun() {
+        assert_eq!(padded_len(&[0x40, 0x00, 0x00, 0x01, 1]), 8);
+        assert_eq!(padded_len(&[0x00, 0x01, 0x00, 0x00]), 4);
+    }
+
+    #[test]
+    fn rejects_unknown_message_type() {
+        assert!(frame_len(&[0xff, 0x00, 0x00, 0x00]).is_err());
+    }
+}
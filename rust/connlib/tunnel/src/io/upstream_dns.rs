@@ -0,0 +1,504 @@
+//! Forwarding of DNS queries to upstream servers over TCP, DNS-over-TLS (RFC 7858) and DNS-over-HTTPS (RFC 8484).
+//!
+//! Connections are kept open after a query and reused for the next query to the same server.
+
+use super::relay_streams::Stream;
+use connlib_shared::messages::{DnsServer, HttpsDnsServer, TlsDnsServer};
+use rustls::pki_types::ServerName;
+use socket_factory::{SocketFactory, TcpSocket};
+use std::{io, sync::Arc};
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use tokio_rustls::TlsConnector;
+
+const DNS_MESSAGE_CONTENT_TYPE: &str = "application/dns-message";
+
+/// The maximum size of the HTTP response header, as well as of a single chunk-size line or trailer.
+const MAX_HEADER_LEN: usize = 64 * 1024;
+/// The maximum size of the HTTP response body.
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+/// An open connection to an upstream server, optionally secured with TLS.
+pub(crate) type Connection = Box<dyn Stream>;
+
+pub(crate) fn tls_config() -> Arc<rustls::ClientConfig> {
+    let roots = rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Arc::new(config)
+}
+
+/// Sends a DNS query to the upstream server and reads the response.
+///
+/// If given, the query is sent over an existing `connection` first.
+/// Should that fail, e.g. because the server closed the connection in the meantime, the query is retried over a new connection.
+/// Returns the connection alongside the response if it can be reused for further queries.
+pub(crate) async fn send(
+    tcp_socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
+    tls_config: Arc<rustls::ClientConfig>,
+    connection: Option<Connection>,
+    server: &DnsServer,
+    query: &[u8],
+) -> io::Result<(Vec<u8>, Option<Connection>)> {
+    if let Some(mut connection) = connection {
+        match round_trip(&mut connection, server, query).await {
+            Ok((response, keep_alive)) => return Ok((response, keep_alive.then_some(connection))),
+            Err(e) => {
+                tracing::debug!(
+                    ?server,
+                    "Failed to send DNS query over existing connection: {e}"
+                )
+            }
+        }
+    }
+
+    let mut connection = connect(tcp_socket_factory.as_ref(), tls_config, server).await?;
+    let (response, keep_alive) = round_trip(&mut connection, server, query).await?;
+
+    Ok((response, keep_alive.then_some(connection)))
+}
+
+async fn connect(
+    tcp_socket_factory: &dyn SocketFactory<TcpSocket>,
+    tls_config: Arc<rustls::ClientConfig>,
+    server: &DnsServer,
+) -> io::Result<Connection> {
+    let socket = tcp_socket_factory(&server.address())?;
+    let stream = socket.connect(server.address()).await?;
+
+    match server {
+        DnsServer::IpPort(_) => Ok(Box::new(stream)),
+        DnsServer::Tls(TlsDnsServer { server_name, .. })
+        | DnsServer::Https(HttpsDnsServer { server_name, .. }) => {
+            let stream = tls_handshake(tls_config, server_name, stream).await?;
+
+            Ok(Box::new(stream))
+        }
+    }
+}
+
+/// Returns the response and whether the connection can be reused.
+async fn round_trip(
+    connection: &mut Connection,
+    server: &DnsServer,
+    query: &[u8],
+) -> io::Result<(Vec<u8>, bool)> {
+    match server {
+        DnsServer::IpPort(_) | DnsServer::Tls(_) => {
+            let response = length_prefixed_round_trip(connection, query).await?;
+
+            Ok((response, true))
+        }
+        DnsServer::Https(HttpsDnsServer {
+            server_name, path, ..
+        }) => https_round_trip(connection, server_name, path, query).await,
+    }
+}
+
+async fn tls_handshake<S>(
+    config: Arc<rustls::ClientConfig>,
+    server_name: &str,
+    stream: S,
+) -> io::Result<tokio_rustls::client::TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let server_name = ServerName::try_from(server_name.to_owned())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    TlsConnector::from(config)
+        .connect(server_name, stream)
+        .await
+}
+
+/// Sends a single DNS query and reads the response, using the 2-byte length prefix from RFC 1035, Section 4.2.2.
+async fn length_prefixed_round_trip<S>(stream: &mut S, query: &[u8]) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let len = u16::try_from(query.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "DNS query too large"))?;
+
+    let mut request = Vec::with_capacity(query.len() + 2);
+    request.extend_from_slice(&len.to_be_bytes());
+    request.extend_from_slice(query);
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    let len = stream.read_u16().await?;
+    let mut response = vec![0u8; len as usize];
+    stream.read_exact(&mut response).await?;
+
+    Ok(response)
+}
+
+/// Sends a single DNS query as an HTTP/1.1 `POST` request and reads the response.
+///
+/// Returns the response and whether the server is willing to keep the connection open.
+async fn https_round_trip<S>(
+    stream: &mut S,
+    host: &str,
+    path: &str,
+    query: &[u8],
+) -> io::Result<(Vec<u8>, bool)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: {DNS_MESSAGE_CONTENT_TYPE}\r\nAccept: {DNS_MESSAGE_CONTENT_TYPE}\r\nContent-Length: {}\r\n\r\n",
+        query.len()
+    );
+    stream.write_all(request.as_bytes()).await?;
+    stream.write_all(query).await?;
+    stream.flush().await?;
+
+    let mut buf = Vec::new();
+
+    let (header_len, framing, keep_alive) = loop {
+        read_more(stream, &mut buf).await?;
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut response = httparse::Response::new(&mut headers);
+
+        match response.parse(&buf).map_err(invalid_data)? {
+            httparse::Status::Complete(header_len) => {
+                if response.code != Some(200) {
+                    return Err(invalid_data(format!(
+                        "Unexpected HTTP status: {:?}",
+                        response.code
+                    )));
+                }
+
+                let keep_alive = response.version == Some(1)
+                    && !response.headers.iter().any(|h| {
+                        h.name.eq_ignore_ascii_case("connection")
+                            && h.value.eq_ignore_ascii_case(b"close")
+                    });
+
+                break (
+                    header_len,
+                    Framing::from_headers(response.headers)?,
+                    keep_alive,
+                );
+            }
+            httparse::Status::Partial if buf.len() >= MAX_HEADER_LEN => {
+                return Err(invalid_data("HTTP response header too large"));
+            }
+            httparse::Status::Partial => continue,
+        }
+    };
+
+    buf.drain(..header_len);
+
+    let (body, rest) = match framing {
+        Framing::ContentLength(len) => {
+            while buf.len() < len {
+                read_more(stream, &mut buf).await?;
+            }
+            let rest = buf.split_off(len);
+
+            (buf, rest)
+        }
+        Framing::Chunked => read_chunked_body(stream, buf).await?,
+    };
+
+    // We only send one request at a time, anything beyond the response means we are out of sync with the server.
+    Ok((body, keep_alive && rest.is_empty()))
+}
+
+enum Framing {
+    ContentLength(usize),
+    Chunked,
+}
+
+impl Framing {
+    fn from_headers(headers: &[httparse::Header<'_>]) -> io::Result<Self> {
+        for header in headers {
+            if header.name.eq_ignore_ascii_case("content-length") {
+                let len = std::str::from_utf8(header.value)
+                    .ok()
+                    .and_then(|v| v.trim().parse::<usize>().ok())
+                    .ok_or_else(|| invalid_data("Invalid content-length"))?;
+
+                if len > MAX_BODY_LEN {
+                    return Err(invalid_data("HTTP response body too large"));
+                }
+
+                return Ok(Self::ContentLength(len));
+            }
+
+            if header.name.eq_ignore_ascii_case("transfer-encoding")
+                && header.value.eq_ignore_ascii_case(b"chunked")
+            {
+                return Ok(Self::Chunked);
+            }
+        }
+
+        Err(invalid_data(
+            "Response has neither content-length nor chunked encoding",
+        ))
+    }
+}
+
+/// Reads a body in chunked transfer encoding, see RFC 9112, Section 7.1.
+///
+/// Returns the body and any bytes read past its end.
+async fn read_chunked_body<S>(stream: &mut S, mut buf: Vec<u8>) -> io::Result<(Vec<u8>, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut body = Vec::new();
+
+    loop {
+        let (consumed, size) = match httparse::parse_chunk_size(&buf)
+            .map_err(|_| invalid_data("Invalid chunk size"))?
+        {
+            httparse::Status::Complete((consumed, size)) => (consumed, size),
+            httparse::Status::Partial if buf.len() >= MAX_HEADER_LEN => {
+                return Err(invalid_data("Chunk size line too large"));
+            }
+            httparse::Status::Partial => {
+                read_more(stream, &mut buf).await?;
+                continue;
+            }
+        };
+
+        if size == 0 {
+            buf.drain(..consumed);
+            break;
+        }
+
+        let size = usize::try_from(size)
+            .ok()
+            .filter(|size| body.len() + size <= MAX_BODY_LEN)
+            .ok_or_else(|| invalid_data("HTTP response body too large"))?;
+
+        let chunk_end = consumed + size + 2; // Each chunk is terminated by CRLF.
+
+        while buf.len() < chunk_end {
+            read_more(stream, &mut buf).await?;
+        }
+
+        if &buf[consumed + size..chunk_end] != b"\r\n" {
+            return Err(invalid_data("Chunk is not terminated by CRLF"));
+        }
+
+        body.extend_from_slice(&buf[consumed..consumed + size]);
+        buf.drain(..chunk_end);
+    }
+
+    // The last chunk is followed by optional trailer fields and an empty line.
+    loop {
+        match buf.windows(2).position(|w| w == b"\r\n") {
+            Some(0) => {
+                buf.drain(..2);
+                return Ok((body, buf));
+            }
+            Some(trailer_end) => {
+                buf.drain(..trailer_end + 2);
+            }
+            None if buf.len() >= MAX_HEADER_LEN => {
+                return Err(invalid_data("Trailer field too large"));
+            }
+            None => read_more(stream, &mut buf).await?,
+        }
+    }
+}
+
+async fn read_more<S>(stream: &mut S, buf: &mut Vec<u8>) -> io::Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut chunk = [0u8; 4096];
+    let n = stream.read(&mut chunk).await?;
+
+    if n == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    buf.extend_from_slice(&chunk[..n]);
+
+    Ok(())
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn parses_content_length_response() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        let server = tokio::spawn(async move {
+            let mut request = vec![0u8; 512];
+            let n = server.read(&mut request).await.unwrap();
+            let request = String::from_utf8_lossy(&request[..n]).into_owned();
+
+            server
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\nContent-Length: 3\r\n\r\nabc")
+                .await
+                .unwrap();
+
+            request
+        });
+
+        let (response, keep_alive) =
+            https_round_trip(&mut client, "dns.example.com", "/dns-query", b"query")
+                .await
+                .unwrap();
+        let request = server.await.unwrap();
+
+        assert_eq!(response, b"abc");
+        assert!(keep_alive);
+        assert!(request.starts_with("POST /dns-query HTTP/1.1\r\nHost: dns.example.com\r\n"));
+        assert!(request.ends_with("\r\n\r\nquery"));
+    }
+
+    #[tokio::test]
+    async fn parses_chunked_response() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        tokio::spawn(async move {
+            let mut request = vec![0u8; 512];
+            let _ = server.read(&mut request).await.unwrap();
+
+            server
+                .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nab\r\n1\r\nc\r\n0\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let (response, keep_alive) =
+            https_round_trip(&mut client, "dns.example.com", "/dns-query", b"query")
+                .await
+                .unwrap();
+
+        assert_eq!(response, b"abc");
+        assert!(keep_alive);
+    }
+
+    #[tokio::test]
+    async fn reuses_connection_after_chunked_response_with_trailer() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        tokio::spawn(async move {
+            for response in [&b"ab"[..], b"cd"] {
+                let mut request = vec![0u8; 512];
+                let _ = server.read(&mut request).await.unwrap();
+
+                server
+                    .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n")
+                    .await
+                    .unwrap();
+                server.write_all(response).await.unwrap();
+                server
+                    .write_all(b"\r\n0\r\nX-Trailer: 1\r\n\r\n")
+                    .await
+                    .unwrap();
+            }
+        });
+
+        for expected in [b"ab", b"cd"] {
+            let (response, keep_alive) =
+                https_round_trip(&mut client, "dns.example.com", "/dns-query", b"query")
+                    .await
+                    .unwrap();
+
+            assert_eq!(&response, expected);
+            assert!(keep_alive);
+        }
+    }
+
+    #[tokio::test]
+    async fn does_not_reuse_connection_closed_by_server() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        tokio::spawn(async move {
+            let mut request = vec![0u8; 512];
+            let _ = server.read(&mut request).await.unwrap();
+
+            server
+                .write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 3\r\n\r\nabc")
+                .await
+                .unwrap();
+        });
+
+        let (_, keep_alive) =
+            https_round_trip(&mut client, "dns.example.com", "/dns-query", b"query")
+                .await
+                .unwrap();
+
+        assert!(!keep_alive);
+    }
+
+    #[tokio::test]
+    async fn rejects_chunk_without_crlf() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        tokio::spawn(async move {
+            let mut request = vec![0u8; 512];
+            let _ = server.read(&mut request).await.unwrap();
+
+            server
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nabc\r\n0\r\n\r\n",
+                )
+                .await
+                .unwrap();
+        });
+
+        let error = https_round_trip(&mut client, "dns.example.com", "/dns-query", b"query")
+            .await
+            .unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejects_too_large_header() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        tokio::spawn(async move {
+            let mut request = vec![0u8; 512];
+            let _ = server.read(&mut request).await.unwrap();
+
+            server
+                .write_all(b"HTTP/1.1 200 OK\r\nX-Padding: ")
+                .await
+                .unwrap();
+            let _ = server.write_all(&vec![b'a'; MAX_HEADER_LEN]).await;
+        });
+
+        let error = https_round_trip(&mut client, "dns.example.com", "/dns-query", b"query")
+            .await
+            .unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn length_prefixed_round_trip_frames_messages() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        tokio::spawn(async move {
+            let len = server.read_u16().await.unwrap();
+            let mut query = vec![0u8; len as usize];
+            server.read_exact(&mut query).await.unwrap();
+
+            server.write_u16(2).await.unwrap();
+            server.write_all(b"ok").await.unwrap();
+        });
+
+        let response = length_prefixed_round_trip(&mut client, b"query")
+            .await
+            .unwrap();
+
+        assert_eq!(response, b"ok");
+    }
+}
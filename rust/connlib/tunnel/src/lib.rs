@@ -64,8 +64,14 @@ pub type GatewayTunnel = Tunnel<GatewayState>;
 pub type ClientTunnel = Tunnel<ClientState>;
 
 pub use client::ClientState;
-pub use gateway::{GatewayState, IPV4_PEERS, IPV6_PEERS};
+pub use dns::CacheStats;
+pub use gateway::{
+    ClientStats, GatewayState, GatewayStats, GatewayStatsHandle, ShardedGatewayTunnel, IPV4_PEERS,
+    IPV6_PEERS,
+};
+pub use peer::FilterMode;
 use snownet::EncryptBuffer;
+pub use snownet::{ConnectionPath, ConnectionStats};
 
 /// [`Tunnel`] glues together connlib's [`Io`] component and the respective (pure) state of a client or gateway.
 ///
@@ -132,6 +138,11 @@ impl ClientTunnel {
                 continue;
             }
 
+            if let Some(query) = self.role_state.poll_dns_query() {
+                self.io.send_dns_query(query);
+                continue;
+            }
+
             if let Some(timeout) = self.role_state.poll_timeout() {
                 self.io.reset_timeout(timeout);
             }
@@ -141,12 +152,16 @@ impl ClientTunnel {
                 self.ip4_read_buf.as_mut(),
                 self.ip6_read_buf.as_mut(),
                 self.device_read_buf.as_mut(),
-                &self.encrypt_buf,
             )? {
                 Poll::Ready(io::Input::Timeout(timeout)) => {
                     self.role_state.handle_timeout(timeout);
                     continue;
                 }
+                Poll::Ready(io::Input::DnsResponse(query, response)) => {
+                    self.role_state
+                        .handle_dns_response(query, response, Instant::now());
+                    continue;
+                }
                 Poll::Ready(io::Input::Device(packet)) => {
                     let now = Instant::now();
                     let Some(enc_packet) =
@@ -157,8 +172,7 @@ impl ClientTunnel {
                         continue;
                     };
 
-                    self.io
-                        .send_encrypted_packet(enc_packet, &self.encrypt_buf)?;
+                    self.io.send_encrypted_packet(enc_packet, &self.encrypt_buf);
 
                     continue;
                 }
@@ -179,6 +193,21 @@ impl ClientTunnel {
 
                     continue;
                 }
+                Poll::Ready(io::Input::RelayStream(frame)) => {
+                    let Some(packet) = self.role_state.decapsulate(
+                        frame.local,
+                        frame.from,
+                        &frame.packet,
+                        Instant::now(),
+                        self.decrypt_buf.as_mut(),
+                    ) else {
+                        continue;
+                    };
+
+                    self.io.device_mut().write(packet)?;
+
+                    continue;
+                }
                 Poll::Pending => {}
             }
 
@@ -186,6 +215,7 @@ impl ClientTunnel {
         }
 
         self.role_state.handle_timeout(Instant::now()); // Ensure time advances, even if we are busy handling packets.
+        self.io.seal_batches(); // Ensure batched packets get sent, even if the device keeps us busy.
         cx.waker().wake_by_ref(); // Schedule another wake-up with the runtime to avoid getting suspended forever.
         Poll::Pending
     }
@@ -226,6 +256,11 @@ impl GatewayTunnel {
                 continue;
             }
 
+            if let Some(query) = self.role_state.poll_dns_query() {
+                self.io.send_dns_query(query);
+                continue;
+            }
+
             if let Some(timeout) = self.role_state.poll_timeout() {
                 self.io.reset_timeout(timeout);
             }
@@ -235,12 +270,25 @@ impl GatewayTunnel {
                 self.ip4_read_buf.as_mut(),
                 self.ip6_read_buf.as_mut(),
                 self.device_read_buf.as_mut(),
-                &self.encrypt_buf,
             )? {
                 Poll::Ready(io::Input::Timeout(timeout)) => {
                     self.role_state.handle_timeout(timeout, Utc::now());
                     continue;
                 }
+                Poll::Ready(io::Input::DnsResponse(query, response)) => {
+                    let Some(enc_packet) = self.role_state.handle_dns_response(
+                        query,
+                        response,
+                        Instant::now(),
+                        &mut self.encrypt_buf,
+                    ) else {
+                        continue;
+                    };
+
+                    self.io.send_encrypted_packet(enc_packet, &self.encrypt_buf);
+
+                    continue;
+                }
                 Poll::Ready(io::Input::Device(packet)) => {
                     let now = Instant::now();
                     let Some(enc_packet) =
@@ -251,8 +299,7 @@ impl GatewayTunnel {
                         continue;
                     };
 
-                    self.io
-                        .send_encrypted_packet(enc_packet, &self.encrypt_buf)?;
+                    self.io.send_encrypted_packet(enc_packet, &self.encrypt_buf);
 
                     continue;
                 }
@@ -273,6 +320,21 @@ impl GatewayTunnel {
 
                     continue;
                 }
+                Poll::Ready(io::Input::RelayStream(frame)) => {
+                    let Some(packet) = self.role_state.decapsulate(
+                        frame.local,
+                        frame.from,
+                        &frame.packet,
+                        Instant::now(),
+                        self.device_read_buf.as_mut(),
+                    ) else {
+                        continue;
+                    };
+
+                    self.io.device_mut().write(packet)?;
+
+                    continue;
+                }
                 Poll::Pending => {}
             }
 
@@ -280,6 +342,7 @@ impl GatewayTunnel {
         }
 
         self.role_state.handle_timeout(Instant::now(), Utc::now()); // Ensure time advances, even if we are busy handling packets.
+        self.io.seal_batches(); // Ensure batched packets get sent, even if the device keeps us busy.
         cx.waker().wake_by_ref(); // Schedule another wake-up with the runtime to avoid getting suspended forever.
         Poll::Pending
     }
@@ -323,6 +386,16 @@ pub enum ClientEvent {
         resources: Vec<callbacks::ResourceDescription>,
     },
     TunInterfaceUpdated(TunConfig),
+    /// We answered or forwarded a DNS query of an application.
+    DnsResolved(callbacks::DnsResolution),
+    /// ICE nominated a new path to a gateway, e.g. we switched from a relayed to a direct connection.
+    ConnectionPathChanged {
+        gateway_id: GatewayId,
+        /// The resources we access through this gateway.
+        resources: BTreeSet<ResourceId>,
+        old: Option<ConnectionPath>,
+        new: ConnectionPath,
+    },
 }
 
 #[derive(Clone, derivative::Derivative, PartialEq, Eq)]
@@ -337,6 +410,8 @@ pub struct TunConfig {
     ///   If upstream DNS servers are configured (in the portal), we will use those.
     ///   Otherwise, we will use the DNS servers configured on the system.
     pub dns_by_sentinel: BiMap<IpAddr, SocketAddr>,
+    /// The domains that the OS should try to append to single-label names before resolving them.
+    pub search_domains: Vec<DomainName>,
 
     #[derivative(Debug(format_with = "fmt_routes"))]
     pub ipv4_routes: BTreeSet<Ipv4Network>,
@@ -359,6 +434,12 @@ pub enum GatewayEvent {
         conn_id: ClientId,
         resource_id: ResourceId,
     },
+    /// ICE nominated a new path to a client.
+    ConnectionPathChanged {
+        conn_id: ClientId,
+        old: Option<ConnectionPath>,
+        new: ConnectionPath,
+    },
 }
 
 pub fn keypair() -> (StaticSecret, PublicKey) {
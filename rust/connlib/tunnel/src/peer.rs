@@ -1,11 +1,13 @@
 use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::RangeInclusive;
 use std::time::{Duration, Instant};
 
 use chrono::{DateTime, Utc};
 use connlib_shared::messages::gateway::{ResolvedResourceDescriptionDns, ResourceDescription};
 use connlib_shared::messages::{
-    gateway::Filter, gateway::Filters, ClientId, GatewayId, ResourceId,
+    gateway::Filter, gateway::Filters, gateway::IcmpFilter, gateway::PortRange, ClientId,
+    GatewayId, ResourceId,
 };
 use connlib_shared::DomainName;
 use ip_network::IpNetwork;
@@ -19,10 +21,24 @@ use crate::utils::network_contains_network;
 use crate::GatewayEvent;
 
 use anyhow::{bail, Context};
+use conntrack::ConnTrack;
 use nat_table::NatTable;
 
+mod conntrack;
 mod nat_table;
 
+/// How a gateway enforces the filters of a resource.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FilterMode {
+    /// Every packet from a client is checked against the filters on its own.
+    #[default]
+    Stateless,
+    /// Clients can only open new flows to allowed ports and only packets belonging to those flows are forwarded in either direction.
+    ///
+    /// TCP connections are tracked through their handshake until FIN or RST, UDP flows and ICMP echos expire after being idle.
+    Stateful,
+}
+
 #[derive(Debug)]
 enum FilterEngine {
     PermitAll,
@@ -31,9 +47,42 @@ enum FilterEngine {
 
 #[derive(Debug)]
 struct AllowRules {
-    udp: RangeInclusiveSet<u16>,
-    tcp: RangeInclusiveSet<u16>,
-    icmp: bool,
+    udp: PortRules,
+    tcp: PortRules,
+    sctp: PortRules,
+    icmp: RangeInclusiveSet<u8>,
+    icmpv6: RangeInclusiveSet<u8>,
+    gre: bool,
+}
+
+/// The allowed ports of a protocol.
+#[derive(Debug, Default)]
+struct PortRules {
+    /// Destination ports that may be reached from any source port.
+    any_source: RangeInclusiveSet<u16>,
+    /// Destination ports that may only be reached from certain source ports.
+    ///
+    /// We expect very few of these, hence a linear scan is good enough.
+    restricted_source: Vec<(RangeInclusive<u16>, RangeInclusive<u16>)>,
+}
+
+impl PortRules {
+    fn insert(&mut self, range: &PortRange) {
+        match range.source_ports() {
+            None => self.any_source.insert(range.ports()),
+            Some(source_ports) => self.restricted_source.push((source_ports, range.ports())),
+        }
+    }
+
+    fn contains(&self, source: u16, destination: u16) -> bool {
+        self.any_source.contains(&destination)
+            || self
+                .restricted_source
+                .iter()
+                .any(|(sources, destinations)| {
+                    sources.contains(&source) && destinations.contains(&destination)
+                })
+    }
 }
 
 impl FilterEngine {
@@ -63,9 +112,12 @@ impl FilterEngine {
 impl AllowRules {
     fn new() -> AllowRules {
         AllowRules {
-            udp: RangeInclusiveSet::new(),
-            tcp: RangeInclusiveSet::new(),
-            icmp: false,
+            udp: PortRules::default(),
+            tcp: PortRules::default(),
+            sctp: PortRules::default(),
+            icmp: RangeInclusiveSet::new(),
+            icmpv6: RangeInclusiveSet::new(),
+            gre: false,
         }
     }
 
@@ -77,11 +129,20 @@ impl AllowRules {
             // but it might be a bit harder to read
             IpNextHeaderProtocols::Tcp => packet
                 .as_tcp()
-                .is_some_and(|p| self.tcp.contains(&p.get_destination())),
+                .is_some_and(|p| self.tcp.contains(p.get_source(), p.get_destination())),
             IpNextHeaderProtocols::Udp => packet
                 .as_udp()
-                .is_some_and(|p| self.udp.contains(&p.get_destination())),
-            IpNextHeaderProtocols::Icmp | IpNextHeaderProtocols::Icmpv6 => self.icmp,
+                .is_some_and(|p| self.udp.contains(p.get_source(), p.get_destination())),
+            IpNextHeaderProtocols::Sctp => packet
+                .as_sctp()
+                .is_some_and(|p| self.sctp.contains(p.get_source(), p.get_destination())),
+            IpNextHeaderProtocols::Gre => self.gre,
+            IpNextHeaderProtocols::Icmp | IpNextHeaderProtocols::Icmpv6 => {
+                packet.as_icmp().is_some_and(|p| match p.icmp_type() {
+                    ip_packet::IcmpType::V4(v4) => self.icmp.contains(&v4.0),
+                    ip_packet::IcmpType::V6(v6) => self.icmpv6.contains(&v6.0),
+                })
+            }
             _ => false,
         }
     }
@@ -90,15 +151,32 @@ impl AllowRules {
         for filter in filters {
             match filter {
                 Filter::Udp(range) => {
-                    self.udp
-                        .insert(range.port_range_start..=range.port_range_end);
+                    self.udp.insert(range);
                 }
                 Filter::Tcp(range) => {
-                    self.tcp
-                        .insert(range.port_range_start..=range.port_range_end);
+                    self.tcp.insert(range);
+                }
+                Filter::Sctp(range) => {
+                    self.sctp.insert(range);
+                }
+                Filter::Gre => {
+                    self.gre = true;
                 }
-                Filter::Icmp => {
-                    self.icmp = true;
+                Filter::Icmp(IcmpFilter { icmp_types: None }) => {
+                    self.icmp.insert(0..=u8::MAX);
+                    self.icmpv6.insert(0..=u8::MAX);
+                }
+                Filter::Icmp(IcmpFilter {
+                    icmp_types: Some(types),
+                }) => {
+                    for ty in types {
+                        for v4 in ty.icmpv4_types() {
+                            self.icmp.insert(*v4..=*v4);
+                        }
+                        for v6 in ty.icmpv6_types() {
+                            self.icmpv6.insert(*v6..=*v6);
+                        }
+                    }
                 }
             }
         }
@@ -146,10 +224,19 @@ impl ClientOnGateway {
             filters: IpNetworkTable::new(),
             permanent_translations: Default::default(),
             nat_table: Default::default(),
+            conntrack: None,
             buffered_events: Default::default(),
         }
     }
 
+    pub(crate) fn set_filter_mode(&mut self, mode: FilterMode) {
+        match (mode, self.conntrack.is_some()) {
+            (FilterMode::Stateless, _) => self.conntrack = None,
+            (FilterMode::Stateful, true) => {}
+            (FilterMode::Stateful, false) => self.conntrack = Some(ConnTrack::default()),
+        }
+    }
+
     /// A client is only allowed to send packets from their (portal-assigned) tunnel IPs.
     ///
     /// Failure to enforce this would allow one client to send traffic masquarading as a different client.
@@ -259,6 +346,19 @@ impl ClientOnGateway {
         self.resources.is_empty()
     }
 
+    /// Whether the client may have us resolve the given domain, as part of a DNS query sent in the given packet.
+    ///
+    /// Clients can only resolve domains covered by a DNS resource they have been granted access to.
+    pub(crate) fn may_resolve(&self, packet: &IpPacket<'_>, domain: &DomainName) -> bool {
+        self.allowed_ips().contains(&packet.source())
+            && self
+                .resources
+                .values()
+                .flatten()
+                .filter_map(|r| r.pattern.as_deref())
+                .any(|pattern| crate::dns::is_subdomain(domain, pattern))
+    }
+
     pub(crate) fn expire_resources(&mut self, now: DateTime<Utc>) {
         for resource in self.resources.values_mut() {
             resource.retain(|r| !r.expires_at.is_some_and(|e| e <= now));
@@ -307,6 +407,10 @@ impl ClientOnGateway {
         }
 
         self.nat_table.handle_timeout(now);
+
+        if let Some(conntrack) = self.conntrack.as_mut() {
+            conntrack.handle_timeout(now);
+        }
     }
 
     pub(crate) fn remove_resource(&mut self, resource: &ResourceId) {
@@ -321,12 +425,14 @@ impl ClientOnGateway {
         filters: Filters,
         expires_at: Option<DateTime<Utc>>,
         domain: Option<DomainName>,
+        pattern: Option<String>,
     ) {
         self.resources
             .entry(resource)
             .or_default()
             .push(ResourceOnGateway {
                 domain,
+                pattern,
                 ips,
                 filters,
                 // Each resource subdomain can expire individually so it's worth keeping a list
@@ -373,6 +479,10 @@ impl ClientOnGateway {
                 self.filters.insert(*ip, filter_engine);
             }
         }
+
+        if let Some(conntrack) = self.conntrack.as_mut() {
+            conntrack.retain_resources(|dst| self.filters.longest_match(dst).is_some());
+        }
     }
 
     fn transform_network_to_tun<'a>(
@@ -408,6 +518,7 @@ impl ClientOnGateway {
         let packet = self.transform_network_to_tun(packet, now)?;
 
         self.ensure_allowed_dst(&packet)?;
+        self.ensure_tracked_outbound(&packet, now)?;
 
         Ok(packet)
     }
@@ -417,6 +528,8 @@ impl ClientOnGateway {
         packet: MutableIpPacket<'a>,
         now: Instant,
     ) -> anyhow::Result<Option<MutableIpPacket<'a>>> {
+        self.ensure_tracked_inbound(&packet, now)?;
+
         let Some((proto, ip)) = self
             .nat_table
             .translate_incoming(packet.as_immutable(), now)?
@@ -462,9 +575,53 @@ impl ClientOnGateway {
         Ok(())
     }
 
+    /// In [`FilterMode::Stateful`], check that a packet arriving over the network either opens a new flow or belongs to an existing one.
+    fn ensure_tracked_outbound(
+        &mut self,
+        packet: &MutableIpPacket<'_>,
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        let Some(conntrack) = self.conntrack.as_mut() else {
+            return Ok(());
+        };
+
+        if !conntrack.handle_outbound(&packet.as_immutable(), now) {
+            return Err(anyhow::Error::new(NoFlow(packet.destination())));
+        }
+
+        Ok(())
+    }
+
+    /// In [`FilterMode::Stateful`], check that a packet read from the TUN device belongs to a flow opened by the client.
+    fn ensure_tracked_inbound(
+        &mut self,
+        packet: &MutableIpPacket<'_>,
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        let Some(conntrack) = self.conntrack.as_mut() else {
+            return Ok(());
+        };
+
+        if !conntrack.handle_inbound(&packet.as_immutable(), now) {
+            return Err(anyhow::Error::new(NoFlow(packet.source())));
+        }
+
+        Ok(())
+    }
+
     pub fn id(&self) -> ClientId {
         self.id
     }
+
+    /// The number of active NAT sessions for DNS resources.
+    pub(crate) fn nat_table_size(&self) -> usize {
+        self.nat_table.len()
+    }
+
+    /// The number of tracked flows, always 0 in [`FilterMode::Stateless`].
+    pub(crate) fn conntrack_size(&self) -> usize {
+        self.conntrack.as_ref().map_or(0, |c| c.len())
+    }
 }
 
 impl GatewayOnClient {
@@ -491,12 +648,18 @@ pub(crate) struct SrcNotAllowed(IpAddr);
 #[error("Destination not allowed: {0}")]
 pub(crate) struct DstNotAllowed(IpAddr);
 
+#[derive(Debug, thiserror::Error)]
+#[error("Packet doesn't belong to a tracked flow: {0}")]
+pub(crate) struct NoFlow(IpAddr);
+
 #[derive(Debug)]
 struct ResourceOnGateway {
     ips: Vec<IpNetwork>,
     filters: Filters,
     expires_at: Option<DateTime<Utc>>,
     domain: Option<DomainName>,
+    /// The address of a DNS resource, which may be a pattern like `*.example.com`.
+    pattern: Option<String>,
 }
 
 // Current state of a translation for a given proxy ip
@@ -598,6 +761,8 @@ pub struct ClientOnGateway {
     filters: IpNetworkTable<FilterEngine>,
     permanent_translations: BTreeMap<IpAddr, TranslationState>,
     nat_table: NatTable,
+    /// Only present in [`FilterMode::Stateful`].
+    conntrack: Option<ConnTrack>,
     buffered_events: VecDeque<GatewayEvent>,
 }
 
@@ -632,13 +797,17 @@ mod tests {
     };
 
     use chrono::Utc;
-    use connlib_shared::messages::{
-        gateway::{Filter, PortRange},
-        ClientId, ResourceId,
+    use connlib_shared::{
+        messages::{
+            gateway::{Filter, IcmpFilter, IcmpType, PortRange},
+            ClientId, ResourceId,
+        },
+        DomainName,
     };
     use ip_network::Ipv4Network;
+    use ip_packet::{tcp::TcpFlags, MutableIpPacket};
 
-    use super::{ClientOnGateway, TranslationState};
+    use super::{ClientOnGateway, FilterMode, TranslationState};
 
     #[test]
     fn gateway_filters_expire_individually() {
@@ -652,9 +821,12 @@ mod tests {
             vec![Filter::Tcp(PortRange {
                 port_range_start: 20,
                 port_range_end: 100,
+                source_port_range_start: None,
+                source_port_range_end: None,
             })],
             Some(then),
             None,
+            None,
         );
 
         peer.add_resource(
@@ -663,9 +835,12 @@ mod tests {
             vec![Filter::Udp(PortRange {
                 port_range_start: 20,
                 port_range_end: 100,
+                source_port_range_start: None,
+                source_port_range_end: None,
             })],
             Some(after_then),
             None,
+            None,
         );
 
         let tcp_packet = ip_packet::make::tcp_packet(
@@ -702,6 +877,182 @@ mod tests {
         assert!(peer.ensure_allowed_dst(&udp_packet).is_err());
     }
 
+    #[test]
+    fn gateway_filters_icmp_types() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        peer.add_resource(
+            vec![cidr_v4_resource().into()],
+            resource_id(),
+            vec![Filter::Icmp(IcmpFilter::only([IcmpType::Echo]))],
+            None,
+            None,
+            None,
+        );
+        let resource = cidr_v4_resource().hosts().next().unwrap();
+
+        let echo_request =
+            ip_packet::make::icmp_request_packet(source_v4_addr().into(), resource, 1, 0, &[])
+                .unwrap();
+        let redirect =
+            ip_packet::make::icmp_packet(source_v4_addr().into(), resource, 5, 0).unwrap();
+        let timestamp =
+            ip_packet::make::icmp_packet(source_v4_addr().into(), resource, 13, 0).unwrap();
+
+        assert!(peer.ensure_allowed_dst(&echo_request).is_ok());
+        assert!(peer.ensure_allowed_dst(&redirect).is_err());
+        assert!(peer.ensure_allowed_dst(&timestamp).is_err());
+    }
+
+    #[test]
+    fn may_resolve_names_covered_by_wildcard_resources() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        peer.add_resource(
+            vec![],
+            resource_id(),
+            vec![],
+            None,
+            Some(DomainName::vec_from_str("app.example.com").unwrap()),
+            Some("*.example.com".to_owned()),
+        );
+        let query =
+            ip_packet::make::udp_packet(source_v4_addr(), sentinel(), 40000, 53, vec![]).unwrap();
+        let other_client = ip_packet::make::udp_packet(
+            "100.64.0.2".parse::<Ipv4Addr>().unwrap(),
+            sentinel(),
+            40000,
+            53,
+            vec![],
+        )
+        .unwrap();
+
+        let name = |n: &str| DomainName::vec_from_str(n).unwrap();
+
+        assert!(peer.may_resolve(&query.as_immutable(), &name("app.example.com")));
+        assert!(peer.may_resolve(&query.as_immutable(), &name("other.example.com")));
+        assert!(!peer.may_resolve(&query.as_immutable(), &name("a.b.example.com")));
+        assert!(!peer.may_resolve(&query.as_immutable(), &name("example.org")));
+        assert!(!peer.may_resolve(&other_client.as_immutable(), &name("app.example.com")));
+    }
+
+    #[test]
+    fn gateway_filters_source_ports() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        peer.add_resource(
+            vec![cidr_v4_resource().into()],
+            resource_id(),
+            vec![Filter::Udp(PortRange {
+                port_range_start: 53,
+                port_range_end: 53,
+                source_port_range_start: Some(1024),
+                source_port_range_end: Some(2048),
+            })],
+            None,
+            None,
+            None,
+        );
+        let resource = cidr_v4_resource().hosts().next().unwrap();
+
+        let allowed_source =
+            ip_packet::make::udp_packet(source_v4_addr(), resource, 1500, 53, vec![]).unwrap();
+        let privileged_source =
+            ip_packet::make::udp_packet(source_v4_addr(), resource, 53, 53, vec![]).unwrap();
+        let other_destination =
+            ip_packet::make::udp_packet(source_v4_addr(), resource, 1500, 54, vec![]).unwrap();
+
+        assert!(peer.ensure_allowed_dst(&allowed_source).is_ok());
+        assert!(peer.ensure_allowed_dst(&privileged_source).is_err());
+        assert!(peer.ensure_allowed_dst(&other_destination).is_err());
+    }
+
+    #[test]
+    fn gateway_filters_sctp_and_gre() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        peer.add_resource(
+            vec![cidr_v4_resource().into()],
+            resource_id(),
+            vec![Filter::Sctp(PortRange {
+                port_range_start: 3868,
+                port_range_end: 3868,
+                source_port_range_start: None,
+                source_port_range_end: None,
+            })],
+            None,
+            None,
+            None,
+        );
+        let resource = cidr_v4_resource().hosts().next().unwrap();
+
+        let diameter =
+            ip_packet::make::sctp_packet(source_v4_addr(), resource, 5000, 3868, vec![]).unwrap();
+        let other_sctp =
+            ip_packet::make::sctp_packet(source_v4_addr(), resource, 5000, 3869, vec![]).unwrap();
+        let tcp =
+            ip_packet::make::tcp_packet(source_v4_addr(), resource, 5000, 3868, vec![]).unwrap();
+        let gre = ip_packet::make::gre_packet(source_v4_addr(), resource, vec![]).unwrap();
+
+        assert!(peer.ensure_allowed_dst(&diameter).is_ok());
+        assert!(peer.ensure_allowed_dst(&other_sctp).is_err());
+        assert!(peer.ensure_allowed_dst(&tcp).is_err());
+        assert!(peer.ensure_allowed_dst(&gre).is_err());
+
+        peer.add_resource(
+            vec![cidr_v4_resource().into()],
+            resource2_id(),
+            vec![Filter::Gre],
+            None,
+            None,
+            None,
+        );
+
+        assert!(peer.ensure_allowed_dst(&gre).is_ok());
+    }
+
+    #[test]
+    fn stateful_gateway_only_forwards_flows_opened_by_client() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        peer.set_filter_mode(FilterMode::Stateful);
+        peer.add_resource(
+            vec![cidr_v4_resource().into()],
+            resource_id(),
+            vec![Filter::Tcp(PortRange {
+                port_range_start: 20,
+                port_range_end: 100,
+                source_port_range_start: None,
+                source_port_range_end: None,
+            })],
+            None,
+            None,
+            None,
+        );
+        let now = Instant::now();
+        let resource = cidr_v4_resource().hosts().next().unwrap();
+
+        let unsolicited_syn_ack = tcp_packet_with_flags(
+            source_v4_addr(),
+            resource,
+            5401,
+            80,
+            TcpFlags::SYN | TcpFlags::ACK,
+        );
+        assert!(peer.decapsulate(unsolicited_syn_ack, now).is_err());
+
+        let syn = tcp_packet_with_flags(source_v4_addr(), resource, 5401, 80, TcpFlags::SYN);
+        assert!(peer.decapsulate(syn, now).is_ok());
+
+        let syn_ack = tcp_packet_with_flags(
+            resource,
+            source_v4_addr(),
+            80,
+            5401,
+            TcpFlags::SYN | TcpFlags::ACK,
+        );
+        assert!(peer.encapsulate(syn_ack, now).unwrap().is_some());
+
+        let unsolicited_syn =
+            tcp_packet_with_flags(resource, source_v4_addr(), 80, 5402, TcpFlags::SYN);
+        assert!(peer.encapsulate(unsolicited_syn, now).is_err());
+    }
+
     #[test]
     fn initial_translation_state_is_not_expired() {
         let now = Instant::now();
@@ -969,6 +1320,19 @@ mod tests {
         assert!(state.is_expired(now));
     }
 
+    fn tcp_packet_with_flags(
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        sport: u16,
+        dport: u16,
+        flags: u8,
+    ) -> MutableIpPacket<'static> {
+        let mut packet = ip_packet::make::tcp_packet(src, dst, sport, dport, vec![]).unwrap();
+        packet.as_tcp().unwrap().set_flags(flags);
+
+        packet
+    }
+
     fn source_v4_addr() -> Ipv4Addr {
         "100.64.0.1".parse().unwrap()
     }
@@ -977,6 +1341,10 @@ mod tests {
         "fd00:2021:1111::1".parse().unwrap()
     }
 
+    fn sentinel() -> Ipv4Addr {
+        "100.100.111.1".parse().unwrap()
+    }
+
     fn cidr_v4_resource() -> Ipv4Network {
         "10.0.0.0/24".parse().unwrap()
     }
@@ -998,8 +1366,8 @@ mod tests {
 mod proptests {
     use super::*;
     use crate::proptest::*;
-    use connlib_shared::messages::gateway::PortRange;
-    use ip_packet::make::{icmp_request_packet, tcp_packet, udp_packet};
+    use connlib_shared::messages::gateway::{IcmpType, PortRange};
+    use ip_packet::make::{gre_packet, sctp_packet, tcp_packet, udp_packet, IpVersionMismatch};
     use proptest::{
         arbitrary::any,
         collection, prop_oneof,
@@ -1047,6 +1415,7 @@ mod proptests {
                 filter.clone(),
                 None,
                 None,
+                None,
             );
             resources += 1;
             resource_addr = supernet(addr);
@@ -1056,7 +1425,9 @@ mod proptests {
             let packet = match protocol {
                 Protocol::Tcp { dport } => tcp_packet(src, dest, sport, *dport, payload.clone()),
                 Protocol::Udp { dport } => udp_packet(src, dest, sport, *dport, payload.clone()),
-                Protocol::Icmp => icmp_request_packet(src, dest, 1, 0, &[]),
+                Protocol::Sctp { dport } => sctp_packet(src, dest, sport, *dport, payload.clone()),
+                Protocol::Gre => gre_packet(src, dest, payload.clone()),
+                Protocol::Icmp(kind) => icmp_packet(src, dest, *kind),
             }
             .unwrap();
             assert!(peer.ensure_allowed_dst(&packet).is_ok());
@@ -1089,7 +1460,9 @@ mod proptests {
             let packet = match protocol {
                 Protocol::Tcp { dport } => tcp_packet(src, dest, sport, dport, payload.clone()),
                 Protocol::Udp { dport } => udp_packet(src, dest, sport, dport, payload.clone()),
-                Protocol::Icmp => icmp_request_packet(src, dest, 1, 0, &[]),
+                Protocol::Sctp { dport } => sctp_packet(src, dest, sport, dport, payload.clone()),
+                Protocol::Gre => gre_packet(src, dest, payload.clone()),
+                Protocol::Icmp(kind) => icmp_packet(src, dest, kind),
             }
             .unwrap();
             assert!(peer.ensure_allowed_dst(&packet).is_ok());
@@ -1131,7 +1504,9 @@ mod proptests {
             let packet = match protocol {
                 Protocol::Tcp { dport } => tcp_packet(src, dest, sport, dport, payload.clone()),
                 Protocol::Udp { dport } => udp_packet(src, dest, sport, dport, payload.clone()),
-                Protocol::Icmp => icmp_request_packet(src, dest, 1, 0, &[]),
+                Protocol::Sctp { dport } => sctp_packet(src, dest, sport, dport, payload.clone()),
+                Protocol::Gre => gre_packet(src, dest, payload.clone()),
+                Protocol::Icmp(kind) => icmp_packet(src, dest, kind),
             }
             .unwrap();
             assert!(peer.ensure_allowed_dst(&packet).is_ok());
@@ -1146,7 +1521,9 @@ mod proptests {
             let packet = match protocol {
                 Protocol::Tcp { dport } => tcp_packet(src, dest, sport, dport, payload.clone()),
                 Protocol::Udp { dport } => udp_packet(src, dest, sport, dport, payload.clone()),
-                Protocol::Icmp => icmp_request_packet(src, dest, 1, 0, &[]),
+                Protocol::Sctp { dport } => sctp_packet(src, dest, sport, dport, payload.clone()),
+                Protocol::Gre => gre_packet(src, dest, payload.clone()),
+                Protocol::Icmp(kind) => icmp_packet(src, dest, kind),
             }
             .unwrap();
             assert!(peer.ensure_allowed_dst(&packet).is_ok());
@@ -1182,6 +1559,7 @@ mod proptests {
                 filters.clone(),
                 None,
                 None,
+                None,
             );
         }
 
@@ -1189,7 +1567,9 @@ mod proptests {
             let packet = match protocol {
                 Protocol::Tcp { dport } => tcp_packet(src, dest, sport, dport, payload.clone()),
                 Protocol::Udp { dport } => udp_packet(src, dest, sport, dport, payload.clone()),
-                Protocol::Icmp => icmp_request_packet(src, dest, 1, 0, &[]),
+                Protocol::Sctp { dport } => sctp_packet(src, dest, sport, dport, payload.clone()),
+                Protocol::Gre => gre_packet(src, dest, payload.clone()),
+                Protocol::Icmp(kind) => icmp_packet(src, dest, kind),
             }
             .unwrap();
 
@@ -1220,7 +1600,9 @@ mod proptests {
         let packet = match protocol {
             Protocol::Tcp { dport } => tcp_packet(src, dest, sport, dport, payload),
             Protocol::Udp { dport } => udp_packet(src, dest, sport, dport, payload),
-            Protocol::Icmp => icmp_request_packet(src, dest, 1, 0, &[]),
+            Protocol::Sctp { dport } => sctp_packet(src, dest, sport, dport, payload),
+            Protocol::Gre => gre_packet(src, dest, payload),
+            Protocol::Icmp(kind) => icmp_packet(src, dest, kind),
         }
         .unwrap();
 
@@ -1258,14 +1640,18 @@ mod proptests {
         let packet_allowed = match protocol_allowed {
             Protocol::Tcp { dport } => tcp_packet(src, dest, sport, dport, payload.clone()),
             Protocol::Udp { dport } => udp_packet(src, dest, sport, dport, payload.clone()),
-            Protocol::Icmp => icmp_request_packet(src, dest, 1, 0, &[]),
+            Protocol::Sctp { dport } => sctp_packet(src, dest, sport, dport, payload.clone()),
+            Protocol::Gre => gre_packet(src, dest, payload.clone()),
+            Protocol::Icmp(kind) => icmp_packet(src, dest, kind),
         }
         .unwrap();
 
         let packet_rejected = match protocol_removed {
             Protocol::Tcp { dport } => tcp_packet(src, dest, sport, dport, payload),
             Protocol::Udp { dport } => udp_packet(src, dest, sport, dport, payload),
-            Protocol::Icmp => icmp_request_packet(src, dest, 1, 0, &[]),
+            Protocol::Sctp { dport } => sctp_packet(src, dest, sport, dport, payload),
+            Protocol::Gre => gre_packet(src, dest, payload),
+            Protocol::Icmp(kind) => icmp_packet(src, dest, kind),
         }
         .unwrap();
 
@@ -1275,6 +1661,7 @@ mod proptests {
             filters_allowed,
             None,
             None,
+            None,
         );
 
         peer.add_resource(
@@ -1283,6 +1670,7 @@ mod proptests {
             filters_removed,
             None,
             None,
+            None,
         );
         peer.remove_resource(&resource_id_removed);
 
@@ -1297,7 +1685,7 @@ mod proptests {
     fn filters_with_allowed_protocol() -> impl Strategy<Value = (Filters, Protocol)> {
         filters().prop_flat_map(|filters| {
             if filters.is_empty() {
-                any::<Protocol>().prop_map(|p| (vec![], p)).boxed()
+                protocol().prop_map(|p| (vec![], p)).boxed()
             } else {
                 select(filters.clone())
                     .prop_flat_map(move |filter| {
@@ -1338,15 +1726,21 @@ mod proptests {
                 let filters = f.clone();
                 any::<ProtocolKind>()
                     .prop_filter_map(
-                        "If ICMP is contained there is no way to generate gaps",
-                        move |p| {
-                            (p != ProtocolKind::Icmp || !filters.contains(&Filter::Icmp))
-                                .then_some(p)
+                        "If all ICMP types or GRE are allowed there is no way to generate gaps",
+                        move |p| match p {
+                            ProtocolKind::Icmp => (!icmp_gaps(&filters).is_empty()).then_some(p),
+                            ProtocolKind::Gre => (!filters.contains(&Filter::Gre)).then_some(p),
+                            ProtocolKind::Tcp | ProtocolKind::Udp | ProtocolKind::Sctp => Some(p),
                         },
                     )
                     .prop_flat_map(move |p| {
                         if p == ProtocolKind::Icmp {
-                            Just((f.clone(), Protocol::Icmp)).boxed()
+                            let f = f.clone();
+                            select(icmp_gaps(&f))
+                                .prop_map(move |kind| (f.clone(), Protocol::Icmp(kind)))
+                                .boxed()
+                        } else if p == ProtocolKind::Gre {
+                            Just((f.clone(), Protocol::Gre)).boxed()
                         } else {
                             let f = f.clone();
                             select(gaps(f.clone(), p))
@@ -1370,6 +1764,9 @@ mod proptests {
                 (Filter::Tcp(inner), ProtocolKind::Tcp) => {
                     Some(inner.port_range_start..=inner.port_range_end)
                 }
+                (Filter::Sctp(inner), ProtocolKind::Sctp) => {
+                    Some(inner.port_range_start..=inner.port_range_end)
+                }
                 (_, _) => None,
             })
             .collect::<RangeInclusiveSet<u16>>()
@@ -1377,41 +1774,102 @@ mod proptests {
             .collect_vec()
     }
 
+    fn icmp_gaps(filters: &Filters) -> Vec<IcmpType> {
+        let mut allowed = Vec::new();
+
+        for filter in filters {
+            match filter {
+                Filter::Icmp(IcmpFilter { icmp_types: None }) => return vec![],
+                Filter::Icmp(IcmpFilter {
+                    icmp_types: Some(types),
+                }) => allowed.extend(types.iter().copied()),
+                Filter::Udp(_) | Filter::Tcp(_) | Filter::Sctp(_) | Filter::Gre => {}
+            }
+        }
+
+        IcmpType::ALL
+            .into_iter()
+            .filter(|t| !allowed.contains(t))
+            .collect_vec()
+    }
+
     fn protocol_from_filter(f: Filter) -> impl Strategy<Value = Protocol> {
         match f {
             Filter::Udp(PortRange {
                 port_range_end,
                 port_range_start,
+                ..
             }) => (port_range_start..=port_range_end)
                 .prop_map(|dport| Protocol::Udp { dport })
                 .boxed(),
             Filter::Tcp(PortRange {
                 port_range_end,
                 port_range_start,
+                ..
             }) => (port_range_start..=port_range_end)
                 .prop_map(|dport| Protocol::Tcp { dport })
                 .boxed(),
-            Filter::Icmp => Just(Protocol::Icmp).boxed(),
+            Filter::Sctp(PortRange {
+                port_range_end,
+                port_range_start,
+                ..
+            }) => (port_range_start..=port_range_end)
+                .prop_map(|dport| Protocol::Sctp { dport })
+                .boxed(),
+            Filter::Gre => Just(Protocol::Gre).boxed(),
+            Filter::Icmp(IcmpFilter { icmp_types: None }) => {
+                icmp_type().prop_map(Protocol::Icmp).boxed()
+            }
+            Filter::Icmp(IcmpFilter {
+                icmp_types: Some(types),
+            }) => select(Vec::from_iter(types))
+                .prop_map(Protocol::Icmp)
+                .boxed(),
         }
     }
 
     fn filters_in_gaps(filters: Filters) -> impl Strategy<Value = Filters> {
-        let contains_icmp_filter = filters.contains(&Filter::Icmp);
+        let ranges_without_icmp_filter = icmp_gaps(&filters);
 
         let ranges_without_tcp_filter = gaps(filters.clone(), ProtocolKind::Tcp);
         let tcp_filters = filter_from_vec(ranges_without_tcp_filter, ProtocolKind::Tcp);
 
-        let ranges_without_udp_filter = gaps(filters, ProtocolKind::Udp);
+        let ranges_without_udp_filter = gaps(filters.clone(), ProtocolKind::Udp);
         let udp_filters = filter_from_vec(ranges_without_udp_filter, ProtocolKind::Udp);
 
-        let icmp_filter = if contains_icmp_filter {
+        let ranges_without_sctp_filter = gaps(filters.clone(), ProtocolKind::Sctp);
+        let sctp_filters = filter_from_vec(ranges_without_sctp_filter, ProtocolKind::Sctp);
+
+        let gre_filter = if filters.contains(&Filter::Gre) {
             Just(vec![])
         } else {
-            Just(vec![Filter::Icmp])
+            Just(vec![Filter::Gre])
         };
 
-        (tcp_filters, udp_filters, icmp_filter)
-            .prop_map(|(udp, tcp, icmp)| Vec::from_iter(tcp.into_iter().chain(udp).chain(icmp)))
+        let icmp_filter = if ranges_without_icmp_filter.is_empty() {
+            Just(vec![])
+        } else {
+            Just(vec![Filter::Icmp(IcmpFilter::only(
+                ranges_without_icmp_filter,
+            ))])
+        };
+
+        (
+            tcp_filters,
+            udp_filters,
+            sctp_filters,
+            icmp_filter,
+            gre_filter,
+        )
+            .prop_map(|(udp, tcp, sctp, icmp, gre)| {
+                Vec::from_iter(
+                    tcp.into_iter()
+                        .chain(udp)
+                        .chain(sctp)
+                        .chain(icmp)
+                        .chain(gre),
+                )
+            })
     }
 
     fn filter_from_vec(
@@ -1437,9 +1895,11 @@ mod proptests {
     fn filters() -> impl Strategy<Value = Filters> {
         collection::vec(
             prop_oneof![
-                Just(Filter::Icmp),
+                icmp_filter().prop_map(Filter::Icmp),
                 port_range().prop_map(Filter::Udp),
                 port_range().prop_map(Filter::Tcp),
+                port_range().prop_map(Filter::Sctp),
+                Just(Filter::Gre),
             ],
             0..=100,
         )
@@ -1450,10 +1910,46 @@ mod proptests {
             (s..=u16::MAX).prop_map(move |d| PortRange {
                 port_range_start: s,
                 port_range_end: d,
+                source_port_range_start: None,
+                source_port_range_end: None,
             })
         })
     }
 
+    fn icmp_filter() -> impl Strategy<Value = IcmpFilter> {
+        prop_oneof![
+            Just(IcmpFilter::all()),
+            collection::btree_set(icmp_type(), 1..=IcmpType::ALL.len()).prop_map(IcmpFilter::only),
+        ]
+    }
+
+    fn icmp_type() -> impl Strategy<Value = IcmpType> {
+        select(IcmpType::ALL.to_vec())
+    }
+
+    fn protocol() -> impl Strategy<Value = Protocol> {
+        prop_oneof![
+            any::<u16>().prop_map(|dport| Protocol::Tcp { dport }),
+            any::<u16>().prop_map(|dport| Protocol::Udp { dport }),
+            any::<u16>().prop_map(|dport| Protocol::Sctp { dport }),
+            icmp_type().prop_map(Protocol::Icmp),
+            Just(Protocol::Gre),
+        ]
+    }
+
+    fn icmp_packet(
+        src: IpAddr,
+        dst: IpAddr,
+        kind: IcmpType,
+    ) -> Result<MutableIpPacket<'static>, IpVersionMismatch> {
+        let icmp_type = match dst {
+            IpAddr::V4(_) => kind.icmpv4_types()[0],
+            IpAddr::V6(_) => kind.icmpv6_types()[0],
+        };
+
+        ip_packet::make::icmp_packet(src, dst, icmp_type, 0)
+    }
+
     fn supernet(ip: IpNetwork) -> Option<IpNetwork> {
         match ip {
             IpNetwork::V4(v4) => v4.supernet().map(Into::into),
@@ -1461,11 +1957,13 @@ mod proptests {
         }
     }
 
-    #[derive(Debug, Clone, Copy, Arbitrary)]
+    #[derive(Debug, Clone, Copy)]
     enum Protocol {
         Tcp { dport: u16 },
         Udp { dport: u16 },
-        Icmp,
+        Sctp { dport: u16 },
+        Icmp(IcmpType),
+        Gre,
     }
 
     impl From<&Filter> for ProtocolKind {
@@ -1473,7 +1971,9 @@ mod proptests {
             match value {
                 Filter::Udp(_) => ProtocolKind::Udp,
                 Filter::Tcp(_) => ProtocolKind::Tcp,
-                Filter::Icmp => ProtocolKind::Icmp,
+                Filter::Sctp(_) => ProtocolKind::Sctp,
+                Filter::Icmp(_) => ProtocolKind::Icmp,
+                Filter::Gre => ProtocolKind::Gre,
             }
         }
     }
@@ -1482,7 +1982,9 @@ mod proptests {
     enum ProtocolKind {
         Tcp,
         Udp,
+        Sctp,
         Icmp,
+        Gre,
     }
 
     impl ProtocolKind {
@@ -1490,7 +1992,9 @@ mod proptests {
             match self {
                 ProtocolKind::Tcp => Protocol::Tcp { dport },
                 ProtocolKind::Udp => Protocol::Udp { dport },
-                ProtocolKind::Icmp => Protocol::Icmp,
+                ProtocolKind::Sctp => Protocol::Sctp { dport },
+                ProtocolKind::Icmp => unreachable!("ICMP doesn't have ports"),
+                ProtocolKind::Gre => unreachable!("GRE doesn't have ports"),
             }
         }
 
@@ -1499,12 +2003,23 @@ mod proptests {
                 ProtocolKind::Tcp => Filter::Tcp(PortRange {
                     port_range_start: *range.start(),
                     port_range_end: *range.end(),
+                    source_port_range_start: None,
+                    source_port_range_end: None,
                 }),
                 ProtocolKind::Udp => Filter::Udp(PortRange {
                     port_range_start: *range.start(),
                     port_range_end: *range.end(),
+                    source_port_range_start: None,
+                    source_port_range_end: None,
+                }),
+                ProtocolKind::Sctp => Filter::Sctp(PortRange {
+                    port_range_start: *range.start(),
+                    port_range_end: *range.end(),
+                    source_port_range_start: None,
+                    source_port_range_end: None,
                 }),
-                ProtocolKind::Icmp => Filter::Icmp,
+                ProtocolKind::Icmp => Filter::Icmp(IcmpFilter::all()),
+                ProtocolKind::Gre => Filter::Gre,
             }
         }
     }
@@ -0,0 +1,574 @@
+//! Connection tracking for the gateway's stateful filtering mode.
+use ip_packet::tcp::TcpFlags;
+use ip_packet::{FailedPacket, IpPacket, Protocol};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How long we wait for a TCP handshake to complete.
+const TCP_SYN_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long an established TCP connection may be idle.
+///
+/// Matches the default of the Linux kernel's `nf_conntrack_tcp_timeout_established`.
+const TCP_ESTABLISHED_TIMEOUT: Duration = Duration::from_secs(5 * 24 * 60 * 60);
+/// How long we keep a TCP connection around after either side sent a FIN.
+const TCP_CLOSING_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long a UDP, SCTP or GRE flow may be idle before the resource has replied.
+const UNREPLIED_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a UDP, SCTP or GRE flow may be idle once the resource has replied.
+const STREAM_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long we wait for an ICMP echo reply.
+const ICMP_TIMEOUT: Duration = Duration::from_secs(30);
+/// How many flows we track per client.
+///
+/// Once reached, opening a new flow evicts the one that has been idle the longest.
+const MAX_FLOWS: usize = 16_384;
+
+/// Tracks the flows a client has opened to its resources.
+///
+/// A flow can only be started by the client (i.e. in the direction of the resource).
+/// Packets from the resource are only forwarded to the client if they belong to such a flow.
+///
+/// Flows are keyed by the addresses seen on the TUN device, i.e. after the client's packets have been NAT'ed.
+#[derive(Default, Debug)]
+pub(crate) struct ConnTrack {
+    flows: HashMap<FlowKey, Flow>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FlowKey {
+    client: (Protocol, IpAddr),
+    resource: (Protocol, IpAddr),
+}
+
+#[derive(Debug)]
+struct Flow {
+    state: FlowState,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FlowState {
+    /// The client has sent a SYN.
+    TcpSynSent,
+    /// The resource has answered with a SYN-ACK.
+    TcpSynReceived,
+    TcpEstablished,
+    /// At least one side has sent a FIN.
+    TcpClosing,
+    /// A flow of a protocol we don't track in detail (UDP, SCTP, GRE) that the resource hasn't replied to yet.
+    Unreplied,
+    Replied,
+    IcmpEcho,
+}
+
+impl FlowState {
+    fn timeout(&self) -> Duration {
+        match self {
+            FlowState::TcpSynSent | FlowState::TcpSynReceived => TCP_SYN_TIMEOUT,
+            FlowState::TcpEstablished => TCP_ESTABLISHED_TIMEOUT,
+            FlowState::TcpClosing => TCP_CLOSING_TIMEOUT,
+            FlowState::Unreplied => UNREPLIED_TIMEOUT,
+            FlowState::Replied => STREAM_TIMEOUT,
+            FlowState::IcmpEcho => ICMP_TIMEOUT,
+        }
+    }
+}
+
+impl ConnTrack {
+    pub(crate) fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    /// Handles a packet sent from the client to a resource.
+    ///
+    /// The packet must have already been allowed by the [`FilterEngine`](super::FilterEngine).
+    /// Returns whether the packet should be forwarded.
+    pub(crate) fn handle_outbound(&mut self, packet: &IpPacket<'_>, now: Instant) -> bool {
+        let Ok(client) = packet.source_protocol() else {
+            return false;
+        };
+        let Ok(resource) = packet.destination_protocol() else {
+            return false;
+        };
+        let key = FlowKey {
+            client: (client, packet.source()),
+            resource: (resource, packet.destination()),
+        };
+        let tcp_flags = packet.as_tcp().map(|tcp| tcp.get_flags());
+
+        if packet.as_icmp().is_some_and(|icmp| !icmp.is_echo_request()) {
+            return false;
+        }
+
+        if let Some(flow) = self.flows.get_mut(&key) {
+            if tcp_flags.is_some_and(is_rst) {
+                tracing::trace!(?key, "Flow reset by client");
+
+                self.flows.remove(&key);
+                return true;
+            }
+
+            let Some(next) = next_outbound_state(flow.state, tcp_flags) else {
+                tracing::trace!(?key, state = ?flow.state, "Dropping outbound packet for flow");
+                return false;
+            };
+
+            flow.state = next;
+            flow.last_seen = now;
+
+            return true;
+        }
+
+        let state = match (client, tcp_flags) {
+            (Protocol::Tcp(_), Some(flags)) if is_syn_only(flags) => FlowState::TcpSynSent,
+            (Protocol::Tcp(_), _) => {
+                tracing::trace!(?key, "Refusing to open TCP flow without SYN");
+                return false;
+            }
+            (Protocol::Udp(_) | Protocol::Sctp(_) | Protocol::Gre, _) => FlowState::Unreplied,
+            (Protocol::Icmp(_), _) => FlowState::IcmpEcho,
+        };
+
+        tracing::trace!(?key, ?state, "New flow");
+
+        if self.flows.len() >= MAX_FLOWS {
+            self.evict_oldest();
+        }
+
+        self.flows.insert(
+            key,
+            Flow {
+                state,
+                last_seen: now,
+            },
+        );
+
+        true
+    }
+
+    /// Handles a packet sent from a resource to the client.
+    ///
+    /// Returns whether the packet belongs to a flow opened by the client and should thus be forwarded.
+    pub(crate) fn handle_inbound(&mut self, packet: &IpPacket<'_>, now: Instant) -> bool {
+        if let Some(failed) = packet.as_icmp().and_then(|icmp| icmp.failed_packet()) {
+            return self.is_error_for_flow(packet.destination(), failed);
+        }
+
+        let Ok(resource) = packet.source_protocol() else {
+            return false;
+        };
+        let Ok(client) = packet.destination_protocol() else {
+            return false;
+        };
+        let key = FlowKey {
+            client: (client, packet.destination()),
+            resource: (resource, packet.source()),
+        };
+        let tcp_flags = packet.as_tcp().map(|tcp| tcp.get_flags());
+
+        if packet.as_icmp().is_some_and(|icmp| !icmp.is_echo_reply()) {
+            return false;
+        }
+
+        let Some(flow) = self.flows.get_mut(&key) else {
+            tracing::trace!(?key, "Dropping inbound packet without flow");
+            return false;
+        };
+
+        if tcp_flags.is_some_and(is_rst) {
+            tracing::trace!(?key, "Flow reset by resource");
+
+            self.flows.remove(&key);
+            return true;
+        }
+
+        let Some(next) = next_inbound_state(flow.state, tcp_flags) else {
+            tracing::trace!(?key, state = ?flow.state, "Dropping inbound packet for flow");
+            return false;
+        };
+
+        flow.state = next;
+        flow.last_seen = now;
+
+        true
+    }
+
+    /// ICMP errors like "Fragmentation Needed" or "Packet Too Big" may come from any router on the path to a resource.
+    ///
+    /// They are forwarded if the packet they quote belongs to one of the client's flows, otherwise path MTU discovery breaks.
+    /// They don't count as activity on the flow.
+    fn is_error_for_flow(&self, client: IpAddr, failed: FailedPacket) -> bool {
+        if failed.src != client {
+            return false;
+        }
+
+        let key = FlowKey {
+            client: (failed.src_proto, failed.src),
+            resource: (failed.dst_proto, failed.dst),
+        };
+
+        if !self.flows.contains_key(&key) {
+            tracing::trace!(?key, "Dropping ICMP error without flow");
+            return false;
+        }
+
+        true
+    }
+
+    fn evict_oldest(&mut self) {
+        let Some(oldest) = self
+            .flows
+            .iter()
+            .min_by_key(|(_, flow)| flow.last_seen)
+            .map(|(key, _)| *key)
+        else {
+            return;
+        };
+
+        tracing::debug!(key = ?oldest, "Too many flows, evicting the oldest one");
+
+        self.flows.remove(&oldest);
+    }
+
+    /// Removes all flows whose resource no longer passes `is_allowed`.
+    pub(crate) fn retain_resources(&mut self, mut is_allowed: impl FnMut(IpAddr) -> bool) {
+        self.flows.retain(|key, _| is_allowed(key.resource.1));
+    }
+
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        self.flows.retain(|key, flow| {
+            let expired = now.duration_since(flow.last_seen) >= flow.state.timeout();
+
+            if expired {
+                tracing::trace!(?key, state = ?flow.state, "Flow expired");
+            }
+
+            !expired
+        });
+    }
+}
+
+fn next_outbound_state(current: FlowState, tcp_flags: Option<u8>) -> Option<FlowState> {
+    let Some(flags) = tcp_flags else {
+        return Some(current);
+    };
+
+    match current {
+        FlowState::TcpSynSent if is_syn_only(flags) => Some(FlowState::TcpSynSent), // Retransmit.
+        FlowState::TcpSynSent => None,
+        FlowState::TcpSynReceived if is_ack(flags) => Some(FlowState::TcpEstablished),
+        FlowState::TcpSynReceived => None,
+        FlowState::TcpEstablished if is_fin(flags) => Some(FlowState::TcpClosing),
+        FlowState::TcpEstablished => Some(FlowState::TcpEstablished),
+        FlowState::TcpClosing if is_syn_only(flags) => Some(FlowState::TcpSynSent), // Port re-use after close.
+        FlowState::TcpClosing => Some(FlowState::TcpClosing),
+        FlowState::Unreplied | FlowState::Replied | FlowState::IcmpEcho => None,
+    }
+}
+
+fn next_inbound_state(current: FlowState, tcp_flags: Option<u8>) -> Option<FlowState> {
+    let Some(flags) = tcp_flags else {
+        return match current {
+            FlowState::Unreplied | FlowState::Replied => Some(FlowState::Replied),
+            FlowState::IcmpEcho => Some(FlowState::IcmpEcho),
+            FlowState::TcpSynSent
+            | FlowState::TcpSynReceived
+            | FlowState::TcpEstablished
+            | FlowState::TcpClosing => None,
+        };
+    };
+
+    match current {
+        FlowState::TcpSynSent if is_syn(flags) && is_ack(flags) => Some(FlowState::TcpSynReceived),
+        FlowState::TcpSynSent => None,
+        FlowState::TcpSynReceived if is_syn(flags) && is_ack(flags) => {
+            Some(FlowState::TcpSynReceived) // Retransmit.
+        }
+        FlowState::TcpSynReceived => None,
+        FlowState::TcpEstablished if is_fin(flags) => Some(FlowState::TcpClosing),
+        FlowState::TcpEstablished => Some(FlowState::TcpEstablished),
+        FlowState::TcpClosing => Some(FlowState::TcpClosing),
+        FlowState::Unreplied | FlowState::Replied | FlowState::IcmpEcho => None,
+    }
+}
+
+fn is_syn(flags: u8) -> bool {
+    flags & TcpFlags::SYN != 0
+}
+
+/// A SYN that opens a connection, i.e. without any of the flags that only appear later in a connection.
+fn is_syn_only(flags: u8) -> bool {
+    is_syn(flags) && flags & (TcpFlags::ACK | TcpFlags::RST | TcpFlags::FIN) == 0
+}
+
+fn is_ack(flags: u8) -> bool {
+    flags & TcpFlags::ACK != 0
+}
+
+fn is_fin(flags: u8) -> bool {
+    flags & TcpFlags::FIN != 0
+}
+
+fn is_rst(flags: u8) -> bool {
+    flags & TcpFlags::RST != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ip_packet::make::{icmp_error_packet, icmp_reply_packet, icmp_request_packet, udp_packet};
+    use ip_packet::MutableIpPacket;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn tcp_handshake_establishes_flow() {
+        let mut conntrack = ConnTrack::default();
+        let now = Instant::now();
+
+        assert!(conntrack.handle_outbound(&client_tcp(TcpFlags::SYN).to_immutable(), now));
+        assert!(conntrack.handle_inbound(
+            &resource_tcp(TcpFlags::SYN | TcpFlags::ACK).to_immutable(),
+            now
+        ));
+        assert!(conntrack.handle_outbound(&client_tcp(TcpFlags::ACK).to_immutable(), now));
+        assert!(conntrack.handle_inbound(&resource_tcp(TcpFlags::ACK).to_immutable(), now));
+    }
+
+    #[test]
+    fn tcp_flow_cannot_be_opened_without_syn() {
+        let mut conntrack = ConnTrack::default();
+        let now = Instant::now();
+
+        assert!(!conntrack.handle_outbound(
+            &client_tcp(TcpFlags::SYN | TcpFlags::ACK).to_immutable(),
+            now
+        ));
+        assert!(!conntrack.handle_outbound(&client_tcp(TcpFlags::ACK).to_immutable(), now));
+        assert_eq!(conntrack.len(), 0);
+    }
+
+    #[test]
+    fn unsolicited_inbound_tcp_is_dropped() {
+        let mut conntrack = ConnTrack::default();
+        let now = Instant::now();
+
+        assert!(!conntrack.handle_inbound(&resource_tcp(TcpFlags::SYN).to_immutable(), now));
+        assert!(!conntrack.handle_inbound(&resource_tcp(TcpFlags::ACK).to_immutable(), now));
+    }
+
+    #[test]
+    fn inbound_data_before_handshake_is_dropped() {
+        let mut conntrack = ConnTrack::default();
+        let now = Instant::now();
+
+        assert!(conntrack.handle_outbound(&client_tcp(TcpFlags::SYN).to_immutable(), now));
+        assert!(!conntrack.handle_inbound(&resource_tcp(TcpFlags::ACK).to_immutable(), now));
+    }
+
+    #[test]
+    fn rst_removes_flow() {
+        let mut conntrack = ConnTrack::default();
+        let now = Instant::now();
+
+        establish_tcp(&mut conntrack, now);
+
+        assert!(conntrack.handle_inbound(&resource_tcp(TcpFlags::RST).to_immutable(), now));
+        assert_eq!(conntrack.len(), 0);
+        assert!(!conntrack.handle_inbound(&resource_tcp(TcpFlags::ACK).to_immutable(), now));
+    }
+
+    #[test]
+    fn closing_tcp_flow_expires() {
+        let mut conntrack = ConnTrack::default();
+        let now = Instant::now();
+
+        establish_tcp(&mut conntrack, now);
+        assert!(conntrack.handle_outbound(
+            &client_tcp(TcpFlags::FIN | TcpFlags::ACK).to_immutable(),
+            now
+        ));
+        assert!(conntrack.handle_inbound(
+            &resource_tcp(TcpFlags::FIN | TcpFlags::ACK).to_immutable(),
+            now
+        ));
+
+        conntrack.handle_timeout(now + TCP_CLOSING_TIMEOUT);
+
+        assert_eq!(conntrack.len(), 0);
+    }
+
+    #[test]
+    fn udp_reply_is_allowed_until_idle_timeout() {
+        let mut conntrack = ConnTrack::default();
+        let now = Instant::now();
+
+        assert!(conntrack.handle_outbound(&client_udp().to_immutable(), now));
+        assert!(conntrack.handle_inbound(&resource_udp().to_immutable(), now));
+
+        conntrack.handle_timeout(now + STREAM_TIMEOUT - Duration::from_secs(1));
+        assert!(conntrack.handle_inbound(&resource_udp().to_immutable(), now));
+
+        conntrack.handle_timeout(now + STREAM_TIMEOUT);
+        assert!(!conntrack.handle_inbound(&resource_udp().to_immutable(), now));
+    }
+
+    #[test]
+    fn unreplied_udp_flow_expires_early() {
+        let mut conntrack = ConnTrack::default();
+        let now = Instant::now();
+
+        assert!(conntrack.handle_outbound(&client_udp().to_immutable(), now));
+
+        conntrack.handle_timeout(now + UNREPLIED_TIMEOUT);
+
+        assert!(!conntrack.handle_inbound(&resource_udp().to_immutable(), now));
+    }
+
+    #[test]
+    fn unsolicited_inbound_udp_is_dropped() {
+        let mut conntrack = ConnTrack::default();
+
+        assert!(!conntrack.handle_inbound(&resource_udp().to_immutable(), Instant::now()));
+    }
+
+    #[test]
+    fn icmp_echo_reply_requires_request() {
+        let mut conntrack = ConnTrack::default();
+        let now = Instant::now();
+        let reply = icmp_reply_packet(resource_ip(), client_ip(), 1, 42, &[]).unwrap();
+
+        assert!(!conntrack.handle_inbound(&reply.to_immutable(), now));
+
+        let request = icmp_request_packet(client_ip(), resource_ip(), 1, 42, &[]).unwrap();
+        assert!(conntrack.handle_outbound(&request.to_immutable(), now));
+        assert!(conntrack.handle_inbound(&reply.to_immutable(), now));
+    }
+
+    #[test]
+    fn client_cannot_answer_unsolicited_echo() {
+        let mut conntrack = ConnTrack::default();
+        let reply = icmp_reply_packet(client_ip(), resource_ip(), 1, 42, &[]).unwrap();
+
+        assert!(!conntrack.handle_outbound(&reply.to_immutable(), Instant::now()));
+    }
+
+    #[test]
+    fn icmp_error_for_flow_is_allowed() {
+        let mut conntrack = ConnTrack::default();
+        let now = Instant::now();
+        let request = client_udp();
+        let fragmentation_needed =
+            icmp_error_packet(router_ip(), &request.to_immutable(), 3, 4).unwrap();
+
+        assert!(conntrack.handle_outbound(&request.to_immutable(), now));
+        assert!(conntrack.handle_inbound(&fragmentation_needed.to_immutable(), now));
+    }
+
+    #[test]
+    fn icmp_error_without_flow_is_dropped() {
+        let mut conntrack = ConnTrack::default();
+        let now = Instant::now();
+        let other_port = udp_packet(client_ip(), resource_ip(), 5402, 53, vec![0; 10]).unwrap();
+        let unreachable = icmp_error_packet(router_ip(), &other_port.to_immutable(), 3, 3).unwrap();
+
+        assert!(!conntrack.handle_inbound(&unreachable.to_immutable(), now));
+
+        assert!(conntrack.handle_outbound(&client_udp().to_immutable(), now));
+        assert!(!conntrack.handle_inbound(&unreachable.to_immutable(), now));
+    }
+
+    #[test]
+    fn icmpv6_packet_too_big_for_echo_is_allowed() {
+        let mut conntrack = ConnTrack::default();
+        let now = Instant::now();
+        let client = IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1));
+        let resource = IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2));
+        let router = IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 3));
+        let request = icmp_request_packet(client, resource, 1, 42, &[0; 1400]).unwrap();
+        let packet_too_big = icmp_error_packet(router, &request.to_immutable(), 2, 0).unwrap();
+
+        assert!(conntrack.handle_outbound(&request.to_immutable(), now));
+        assert!(conntrack.handle_inbound(&packet_too_big.to_immutable(), now));
+    }
+
+    #[test]
+    fn new_flow_evicts_oldest_once_full() {
+        let mut conntrack = ConnTrack::default();
+        let now = Instant::now();
+
+        assert!(conntrack.handle_outbound(&client_udp().to_immutable(), now));
+        assert!(conntrack.handle_inbound(&resource_udp().to_immutable(), now));
+
+        for i in 1..MAX_FLOWS {
+            let sport = 10_000 + (i / 1000) as u16;
+            let dport = 1 + (i % 1000) as u16;
+            let packet = udp_packet(client_ip(), resource_ip(), sport, dport, vec![]).unwrap();
+
+            assert!(
+                conntrack.handle_outbound(&packet.to_immutable(), now + Duration::from_millis(1))
+            );
+        }
+        assert_eq!(conntrack.len(), MAX_FLOWS);
+        assert!(conntrack.handle_inbound(&resource_udp().to_immutable(), now));
+
+        let new = udp_packet(client_ip(), resource_ip(), 5402, 53, vec![]).unwrap();
+        assert!(conntrack.handle_outbound(&new.to_immutable(), now + Duration::from_millis(2)));
+
+        assert_eq!(conntrack.len(), MAX_FLOWS);
+        assert!(!conntrack.handle_inbound(&resource_udp().to_immutable(), now));
+    }
+
+    fn establish_tcp(conntrack: &mut ConnTrack, now: Instant) {
+        assert!(conntrack.handle_outbound(&client_tcp(TcpFlags::SYN).to_immutable(), now));
+        assert!(conntrack.handle_inbound(
+            &resource_tcp(TcpFlags::SYN | TcpFlags::ACK).to_immutable(),
+            now
+        ));
+        assert!(conntrack.handle_outbound(&client_tcp(TcpFlags::ACK).to_immutable(), now));
+    }
+
+    fn client_tcp(flags: u8) -> MutableIpPacket<'static> {
+        tcp_with_flags(client_ip(), resource_ip(), 5401, 80, flags)
+    }
+
+    fn resource_tcp(flags: u8) -> MutableIpPacket<'static> {
+        tcp_with_flags(resource_ip(), client_ip(), 80, 5401, flags)
+    }
+
+    fn client_udp() -> MutableIpPacket<'static> {
+        udp_packet(client_ip(), resource_ip(), 5401, 53, vec![0; 10]).unwrap()
+    }
+
+    fn resource_udp() -> MutableIpPacket<'static> {
+        udp_packet(resource_ip(), client_ip(), 53, 5401, vec![0; 10]).unwrap()
+    }
+
+    fn tcp_with_flags(
+        src: IpAddr,
+        dst: IpAddr,
+        sport: u16,
+        dport: u16,
+        flags: u8,
+    ) -> MutableIpPacket<'static> {
+        let mut packet = ip_packet::make::tcp_packet(src, dst, sport, dport, vec![]).unwrap();
+        packet
+            .as_tcp()
+            .expect("just built a TCP packet")
+            .set_flags(flags);
+
+        packet
+    }
+
+    fn client_ip() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(100, 64, 0, 1))
+    }
+
+    fn resource_ip() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))
+    }
+
+    fn router_ip() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1))
+    }
+}
@@ -12,6 +12,7 @@ use std::time::{Duration, Instant};
 /// after no incoming traffic is received.
 ///
 /// Note that for ICMP echo/reply the identity number is used as a stand in for the source port.
+/// GRE doesn't have ports, thus only a single GRE session can be translated to the same outside IP at any time.
 ///
 /// Also, the proxy_ip and the real_ip version may not coincide, in that case a translation mechanism must be used (RFC6145)
 ///
@@ -25,6 +26,10 @@ pub(crate) struct NatTable {
 const TTL: Duration = Duration::from_secs(60);
 
 impl NatTable {
+    pub(crate) fn len(&self) -> usize {
+        self.table.len()
+    }
+
     pub(crate) fn handle_timeout(&mut self, now: Instant) {
         let mut removed = Vec::new();
         for (outside, e) in self.last_seen.iter() {
@@ -64,6 +69,21 @@ impl NatTable {
             tracing::trace!(?inside, ?outside, "Outgoing packet for expired translation");
         }
 
+        if src == Protocol::Gre {
+            let outside = (src, outside_dst);
+            anyhow::ensure!(
+                !self.table.contains_right(&outside),
+                "GRE session to {outside_dst} is already in use"
+            );
+
+            self.table.insert(inside, outside);
+            self.last_seen.insert(outside, now);
+
+            tracing::debug!(?inside, ?outside, "New NAT session");
+
+            return Ok(outside);
+        }
+
         // Find the first available public port, starting from the port of the to-be-mapped packet.
         // This will re-assign the same port in most cases, even after the mapping expires.
         let outside = (src.value()..=u16::MAX)
@@ -87,6 +107,12 @@ impl NatTable {
         packet: IpPacket,
         now: Instant,
     ) -> anyhow::Result<Option<(Protocol, IpAddr)>> {
+        // ICMP errors don't have a protocol of their own, they quote the packet that failed.
+        // We don't translate the quoted packet, so pass them on as they are.
+        if packet.as_icmp().is_some_and(|icmp| icmp.is_error()) {
+            return Ok(None);
+        }
+
         let outside = (packet.destination_protocol()?, packet.source());
 
         if let Some(inside) = self.table.get_by_right(&outside) {
@@ -107,6 +133,7 @@ mod tests {
     use super::*;
     use ip_packet::{proptest::*, MutableIpPacket};
     use proptest::prelude::*;
+    use std::net::Ipv4Addr;
 
     #[test_strategy::proptest(ProptestConfig { max_local_rejects: 10_000, max_global_rejects: 10_000, ..ProptestConfig::default() })]
     fn translates_back_and_forth_packet(
@@ -196,4 +223,29 @@ mod tests {
 
         assert_eq!(responses, original_src_p_and_dst);
     }
+
+    #[test_strategy::proptest]
+    fn gre_sessions_cannot_share_outside_ip(
+        #[strategy(any::<Ipv4Addr>())] src: Ipv4Addr,
+        #[strategy(any::<Ipv4Addr>())] proxy_ip1: Ipv4Addr,
+        #[strategy(any::<Ipv4Addr>())] proxy_ip2: Ipv4Addr,
+        #[strategy(any::<Ipv4Addr>())] outside_dst: Ipv4Addr,
+    ) {
+        proptest::prop_assume!(proxy_ip1 != proxy_ip2);
+
+        let mut table = NatTable::default();
+        let now = Instant::now();
+
+        let packet1 = ip_packet::make::gre_packet(src, proxy_ip1, vec![]).unwrap();
+        let packet2 = ip_packet::make::gre_packet(src, proxy_ip2, vec![]).unwrap();
+
+        let outside = table
+            .translate_outgoing(packet1.as_immutable(), outside_dst.into(), now)
+            .unwrap();
+
+        assert_eq!(outside, (Protocol::Gre, IpAddr::V4(outside_dst)));
+        assert!(table
+            .translate_outgoing(packet2.as_immutable(), outside_dst.into(), now)
+            .is_err());
+    }
 }
@@ -95,6 +95,10 @@ where
         self.peer_by_id.get_mut(id)
     }
 
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &P> {
+        self.peer_by_id.values()
+    }
+
     pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = &mut P> {
         self.peer_by_id.values_mut()
     }
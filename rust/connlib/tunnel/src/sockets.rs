@@ -57,6 +57,16 @@ impl Sockets {
         Poll::Ready(Ok(()))
     }
 
+    /// The maximum number of GSO segments the socket for the given destination can send at once.
+    pub fn max_gso_segments(&self, dst: SocketAddr) -> usize {
+        let socket = match dst {
+            SocketAddr::V4(_) => self.socket_v4.as_ref(),
+            SocketAddr::V6(_) => self.socket_v6.as_ref(),
+        };
+
+        socket.map_or(1, |s| s.max_gso_segments())
+    }
+
     pub fn send(&mut self, datagram: DatagramOut) -> io::Result<()> {
         let socket = match datagram.dst {
             SocketAddr::V4(dst) => self.socket_v4.as_mut().ok_or_else(|| {
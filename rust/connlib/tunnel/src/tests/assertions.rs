@@ -1,15 +1,17 @@
 use super::{
     sim_client::{RefClient, SimClient},
     sim_gateway::SimGateway,
+    QueryId,
 };
 use crate::tests::reference::ResourceDst;
 use connlib_shared::{messages::GatewayId, DomainName};
+use domain::base::iana::Rcode;
 use ip_packet::IpPacket;
 use itertools::Itertools;
 use std::{
     collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, VecDeque},
     marker::PhantomData,
-    net::IpAddr,
+    net::{IpAddr, SocketAddr},
     sync::atomic::{AtomicBool, Ordering},
 };
 use tracing::{Level, Subscriber};
@@ -194,9 +196,32 @@ pub(crate) fn assert_dns_packets_properties(ref_client: &RefClient, sim_client:
 
         assert_correct_src_and_dst_ips(client_sent_query, client_received_response);
         assert_correct_src_and_dst_udp_ports(client_sent_query, client_received_response);
+
+        if client_received_response.unwrap_as_dns().header().tc() {
+            assert_retried_over_tcp(sim_client, key);
+        }
     }
 }
 
+fn assert_retried_over_tcp(sim_client: &SimClient, key: &(SocketAddr, QueryId)) {
+    let Some(response) = sim_client.received_tcp_dns_responses.get(key) else {
+        tracing::error!(target: "assertions", "❌ Missing DNS response over TCP for truncated response");
+        return;
+    };
+
+    if response.header().tc() {
+        tracing::error!(target: "assertions", "❌ DNS response over TCP is truncated");
+        return;
+    }
+
+    if response.header().rcode() != Rcode::NOERROR {
+        tracing::error!(target: "assertions", rcode = %response.header().rcode(), "❌ DNS query over TCP failed");
+        return;
+    }
+
+    tracing::info!(target: "assertions", "✅ Truncated DNS response was retried over TCP");
+}
+
 fn assert_correct_src_and_dst_ips(
     client_sent_request: &IpPacket<'_>,
     client_received_reply: &IpPacket<'_>,
@@ -24,7 +24,7 @@ use domain::{
 };
 use ip_network::{IpNetwork, Ipv4Network, Ipv6Network};
 use ip_network_table::IpNetworkTable;
-use ip_packet::{IpPacket, MutableIpPacket, Packet as _};
+use ip_packet::{make::TcpControl, tcp::TcpFlags, IpPacket, MutableIpPacket, Packet as _};
 use itertools::Itertools as _;
 use prop::collection;
 use proptest::prelude::*;
@@ -55,6 +55,12 @@ pub(crate) struct SimClient {
 
     pub(crate) sent_dns_queries: HashMap<(SocketAddr, QueryId), IpPacket<'static>>,
     pub(crate) received_dns_responses: BTreeMap<(SocketAddr, QueryId), IpPacket<'static>>,
+    /// DNS responses received over TCP after the UDP response was truncated.
+    pub(crate) received_tcp_dns_responses: BTreeMap<(SocketAddr, QueryId), Message<Vec<u8>>>,
+
+    /// DNS queries we are retrying over TCP, indexed by the local port of the connection.
+    tcp_dns_retries: HashMap<u16, TcpDnsRetry>,
+    next_tcp_port: u16,
 
     pub(crate) sent_icmp_requests: HashMap<(u16, u16), IpPacket<'static>>,
     pub(crate) received_icmp_replies: BTreeMap<(u16, u16), IpPacket<'static>>,
@@ -72,6 +78,9 @@ impl SimClient {
             dns_by_sentinel: Default::default(),
             sent_dns_queries: Default::default(),
             received_dns_responses: Default::default(),
+            received_tcp_dns_responses: Default::default(),
+            tcp_dns_retries: Default::default(),
+            next_tcp_port: 40000,
             sent_icmp_requests: Default::default(),
             received_icmp_replies: Default::default(),
             buffer: vec![0u8; (1 << 16) - 1],
@@ -174,11 +183,11 @@ impl SimClient {
         };
         let packet = packet.to_owned();
 
-        self.on_received_packet(packet);
+        self.on_received_packet(packet, now);
     }
 
     /// Process an IP packet received on the client.
-    pub(crate) fn on_received_packet(&mut self, packet: IpPacket<'_>) {
+    pub(crate) fn on_received_packet(&mut self, packet: IpPacket<'_>, now: Instant) {
         if let Some(icmp) = packet.as_icmp() {
             let echo_reply = icmp.as_echo_reply().expect("to be echo reply");
 
@@ -202,31 +211,20 @@ impl SimClient {
                 self.received_dns_responses
                     .insert((upstream, message.header().id()), packet.to_owned());
 
-                for record in message.answer().unwrap() {
-                    let record = record.unwrap();
-                    let domain = record.owner().to_name();
-
-                    #[allow(clippy::wildcard_enum_match_arm)]
-                    let ip = match record
-                        .into_any_record::<AllRecordData<_, _>>()
-                        .unwrap()
-                        .data()
-                    {
-                        AllRecordData::A(a) => IpAddr::from(a.addr()),
-                        AllRecordData::Aaaa(aaaa) => IpAddr::from(aaaa.addr()),
-                        unhandled => {
-                            panic!("Unexpected record data: {unhandled:?}")
-                        }
-                    };
-
-                    self.dns_records.entry(domain).or_default().push(ip);
+                if message.header().tc() {
+                    self.retry_dns_query_over_tcp(sentinel, upstream, message.header().id(), now);
+                    return;
                 }
 
-                // Ensure all IPs are always sorted.
-                for ips in self.dns_records.values_mut() {
-                    ips.sort()
-                }
+                self.on_dns_response(message);
+
+                return;
+            }
+        }
 
+        if let Some(tcp) = packet.as_tcp() {
+            if tcp.get_source() == 53 {
+                self.on_tcp_dns_segment(&packet, now);
                 return;
             }
         }
@@ -234,6 +232,161 @@ impl SimClient {
         tracing::error!("Unhandled packet");
     }
 
+    fn on_dns_response(&mut self, message: Message<&[u8]>) {
+        for record in message.answer().unwrap() {
+            let record = record.unwrap();
+            let domain = record.owner().to_name();
+
+            #[allow(clippy::wildcard_enum_match_arm)]
+            let ip = match record
+                .into_any_record::<AllRecordData<_, _>>()
+                .unwrap()
+                .data()
+            {
+                AllRecordData::A(a) => IpAddr::from(a.addr()),
+                AllRecordData::Aaaa(aaaa) => IpAddr::from(aaaa.addr()),
+                unhandled => {
+                    panic!("Unexpected record data: {unhandled:?}")
+                }
+            };
+
+            self.dns_records.entry(domain).or_default().push(ip);
+        }
+
+        // Ensure all IPs are always sorted.
+        for ips in self.dns_records.values_mut() {
+            ips.sort()
+        }
+    }
+
+    /// Retries a DNS query over TCP, like an application would after receiving a truncated response.
+    fn retry_dns_query_over_tcp(
+        &mut self,
+        sentinel: SocketAddr,
+        upstream: SocketAddr,
+        query_id: QueryId,
+        now: Instant,
+    ) {
+        let Some(query) = self.sent_dns_queries.get(&(upstream, query_id)) else {
+            tracing::error!(%upstream, %query_id, "Received truncated response for unknown query");
+            return;
+        };
+
+        let local = SocketAddr::new(query.source(), self.next_tcp_port);
+        let query = query.unwrap_as_udp().payload().to_vec();
+        self.next_tcp_port = self.next_tcp_port.wrapping_add(1).max(40000);
+
+        tracing::debug!(%upstream, %query_id, "Retrying DNS query over TCP");
+
+        self.tcp_dns_retries.insert(
+            local.port(),
+            TcpDnsRetry {
+                local,
+                sentinel,
+                upstream,
+                query,
+                received: Vec::new(),
+            },
+        );
+
+        let syn = ip_packet::make::tcp_segment(
+            local.ip(),
+            sentinel.ip(),
+            local.port(),
+            sentinel.port(),
+            TcpControl {
+                syn: true,
+                ..Default::default()
+            },
+            Vec::new(),
+        )
+        .unwrap();
+
+        self.send_tcp_segment(syn, now);
+    }
+
+    /// Drives our side of a DNS-over-TCP connection.
+    ///
+    /// We send the query together with a FIN right after the handshake and acknowledge the server's FIN once it closes the connection.
+    fn on_tcp_dns_segment(&mut self, packet: &IpPacket<'_>, now: Instant) {
+        let tcp = packet.as_tcp().unwrap();
+        let flags = tcp.get_flags();
+        let seq = tcp.get_sequence();
+
+        let Some(retry) = self.tcp_dns_retries.get_mut(&tcp.get_destination()) else {
+            tracing::error!(port = %tcp.get_destination(), "Unknown TCP connection");
+            return;
+        };
+        let (local, sentinel) = (retry.local, retry.sentinel);
+        // Our SYN occupies sequence number 0, the query starts at 1 and is followed by our FIN.
+        let fin_seq = 1 + 2 + retry.query.len() as u32;
+
+        if flags & TcpFlags::SYN != 0 {
+            let mut payload = (retry.query.len() as u16).to_be_bytes().to_vec();
+            payload.extend_from_slice(&retry.query);
+
+            let segment = ip_packet::make::tcp_segment(
+                local.ip(),
+                sentinel.ip(),
+                local.port(),
+                sentinel.port(),
+                TcpControl {
+                    seq: 1,
+                    ack: Some(seq.wrapping_add(1)),
+                    fin: true,
+                    ..Default::default()
+                },
+                payload,
+            )
+            .unwrap();
+
+            self.send_tcp_segment(segment, now);
+            return;
+        }
+
+        retry.received.extend_from_slice(tcp.payload());
+
+        if let Some(message) = take_tcp_dns_message(&mut retry.received) {
+            let upstream = retry.upstream;
+            let message = Message::from_octets(message).expect("TCP DNS response to be valid");
+
+            self.on_dns_response(Message::from_slice(message.as_slice()).unwrap());
+            self.received_tcp_dns_responses
+                .insert((upstream, message.header().id()), message);
+        }
+
+        if flags & TcpFlags::FIN == 0 {
+            return;
+        }
+
+        self.tcp_dns_retries.remove(&local.port());
+
+        let ack = ip_packet::make::tcp_segment(
+            local.ip(),
+            sentinel.ip(),
+            local.port(),
+            sentinel.port(),
+            TcpControl {
+                seq: fin_seq.wrapping_add(1),
+                ack: Some(seq.wrapping_add(tcp.payload().len() as u32).wrapping_add(1)),
+                ..Default::default()
+            },
+            Vec::new(),
+        )
+        .unwrap();
+
+        self.send_tcp_segment(ack, now);
+    }
+
+    fn send_tcp_segment(&mut self, segment: MutableIpPacket<'static>, now: Instant) {
+        let transmit = self.encapsulate(segment, now);
+
+        debug_assert!(
+            transmit.is_none(),
+            "TCP segments to sentinel DNS servers should be handled by connlib"
+        );
+    }
+
     pub(crate) fn update_relays<'a>(
         &mut self,
         to_remove: impl Iterator<Item = RelayId>,
@@ -254,6 +407,29 @@ impl SimClient {
     }
 }
 
+struct TcpDnsRetry {
+    local: SocketAddr,
+    sentinel: SocketAddr,
+    upstream: SocketAddr,
+    query: Vec<u8>,
+    /// The bytes received from the server so far.
+    received: Vec<u8>,
+}
+
+/// Takes a complete, length-prefixed DNS message out of the given buffer.
+fn take_tcp_dns_message(buf: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let len = u16::from_be_bytes([*buf.first()?, *buf.get(1)?]) as usize;
+
+    if buf.len() < len + 2 {
+        return None;
+    }
+
+    let message = buf[2..len + 2].to_vec();
+    buf.drain(..len + 2);
+
+    Some(message)
+}
+
 /// Reference state for a particular client.
 ///
 /// The reference state machine is designed to be as abstract as possible over connlib's functionality.
@@ -335,6 +511,7 @@ impl RefClient {
             ipv4: self.tunnel_ip4,
             ipv6: self.tunnel_ip6,
             upstream_dns: self.upstream_dns_resolvers.clone(),
+            search_domains: vec![],
         });
         client_state.update_system_resolvers(self.system_dns_resolvers.clone());
 
@@ -15,7 +15,7 @@ use proptest::{
     arbitrary::any,
     strategy::{Just, Strategy},
 };
-use snownet::Transmit;
+use snownet::{Transmit, Transport};
 use std::{
     borrow::Cow,
     collections::{BTreeMap, BTreeSet},
@@ -47,6 +47,12 @@ pub(crate) struct RefDns {}
 #[derive(Debug)]
 pub(crate) struct SimDns {}
 
+/// The maximum number of records we include in a response sent over UDP.
+///
+/// Real DNS servers truncate responses that don't fit into a single UDP datagram.
+/// Our records are tiny so we emulate this with a limit on the number of records, forcing the client to retry over TCP.
+const MAX_UDP_RECORDS: usize = 2;
+
 impl SimDns {
     pub(crate) fn receive(
         &mut self,
@@ -54,7 +60,34 @@ impl SimDns {
         transmit: Transmit,
         _now: Instant,
     ) -> Option<Transmit<'static>> {
-        let query = Message::from_octets(&transmit.payload).ok()?;
+        let payload = self.answer(global_dns_records, &transmit.payload, Some(MAX_UDP_RECORDS))?;
+
+        Some(Transmit {
+            src: Some(transmit.dst),
+            dst: transmit.src.unwrap(),
+            transport: Transport::Udp,
+            payload: Cow::Owned(payload),
+        })
+    }
+
+    /// Answers a DNS query that was sent to us over TCP.
+    ///
+    /// Responses over TCP are never truncated.
+    pub(crate) fn receive_tcp(
+        &mut self,
+        global_dns_records: &BTreeMap<DomainName, BTreeSet<IpAddr>>,
+        query: &[u8],
+    ) -> Option<Vec<u8>> {
+        self.answer(global_dns_records, query, None)
+    }
+
+    fn answer(
+        &mut self,
+        global_dns_records: &BTreeMap<DomainName, BTreeSet<IpAddr>>,
+        query: &[u8],
+        max_records: Option<usize>,
+    ) -> Option<Vec<u8>> {
+        let query = Message::from_octets(query).ok()?;
 
         let response = MessageBuilder::new_vec();
         let mut answers = response.start_answer(&query, Rcode::NOERROR).unwrap();
@@ -80,21 +113,24 @@ impl SimDns {
                 IpAddr::V4(v4) => AllRecordData::<Vec<_>, DomainName>::A(v4.into()),
                 IpAddr::V6(v6) => AllRecordData::<Vec<_>, DomainName>::Aaaa(v6.into()),
             })
-            .map(|rdata| Record::new(name.clone(), Class::IN, Ttl::from_days(1), rdata));
+            .map(|rdata| Record::new(name.clone(), Class::IN, Ttl::from_days(1), rdata))
+            .collect::<Vec<_>>();
+
+        if max_records.is_some_and(|max| records.len() > max) {
+            tracing::debug!(%name, %qtype, "Truncating DNS response");
+
+            answers.header_mut().set_tc(true);
+
+            return Some(answers.finish());
+        }
 
         for record in records {
             answers.push(record).unwrap();
         }
 
-        let payload = answers.finish();
-
         tracing::debug!(%name, %qtype, "Responding to DNS query");
 
-        Some(Transmit {
-            src: Some(transmit.dst),
-            dst: transmit.src.unwrap(),
-            payload: Cow::Owned(payload),
-        })
+        Some(answers.finish())
     }
 }
 
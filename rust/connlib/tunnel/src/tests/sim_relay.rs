@@ -7,7 +7,7 @@ use firezone_relay::{AddressFamily, AllocationPort, ClientSocket, IpStack, PeerS
 use proptest::prelude::*;
 use rand::{rngs::StdRng, SeedableRng as _};
 use secrecy::SecretString;
-use snownet::{RelaySocket, Transmit};
+use snownet::{RelaySocket, Transmit, Transport};
 use std::{
     borrow::Cow,
     collections::HashSet,
@@ -110,6 +110,7 @@ impl SimRelay {
             payload,
             PeerSocket::new(sender),
             AllocationPort::new(dst.port()),
+            now,
         )
     }
 
@@ -156,6 +157,7 @@ impl SimRelay {
         Some(Transmit {
             src: Some(src),
             dst,
+            transport: Transport::Udp,
             payload: Cow::Owned(payload.to_vec()),
         })
     }
@@ -165,8 +167,9 @@ impl SimRelay {
         payload: &[u8],
         peer: PeerSocket,
         port: AllocationPort,
+        now: Instant,
     ) -> Option<Transmit<'static>> {
-        let (client, channel) = self.sut.handle_peer_traffic(payload, peer, port)?;
+        let (client, channel) = self.sut.handle_peer_traffic(payload, peer, port, now)?;
 
         let full_length = firezone_relay::ChannelData::encode_header_to_slice(
             channel,
@@ -183,6 +186,7 @@ impl SimRelay {
         Some(Transmit {
             src: Some(sending_socket),
             dst: receiving_socket,
+            transport: Transport::Udp,
             payload: Cow::Owned(self.buffer[..full_length].to_vec()),
         })
     }
@@ -7,7 +7,7 @@ use super::sim_net::{Host, HostId, RoutingTable};
 use super::sim_relay::SimRelay;
 use super::stub_portal::StubPortal;
 use super::transition::DnsQuery;
-use crate::dns::is_subdomain;
+use crate::dns::{is_subdomain, ForwardedQuery};
 use crate::tests::assertions::*;
 use crate::tests::flux_capacitor::FluxCapacitor;
 use crate::tests::transition::Transition;
@@ -19,11 +19,12 @@ use connlib_shared::{
     DomainName,
 };
 use secrecy::ExposeSecret as _;
-use snownet::Transmit;
+use snownet::{Transmit, Transport};
 use std::collections::BTreeSet;
 use std::iter;
 use std::{
     collections::BTreeMap,
+    io,
     net::IpAddr,
     time::{Duration, Instant},
 };
@@ -235,6 +236,7 @@ impl TunnelTest {
                         ipv4: c.sut.tunnel_ip4().unwrap(),
                         ipv6: c.sut.tunnel_ip6().unwrap(),
                         upstream_dns: servers,
+                        search_domains: vec![],
                     })
                 });
             }
@@ -266,6 +268,7 @@ impl TunnelTest {
                         ipv4,
                         ipv6,
                         upstream_dns,
+                        search_domains: vec![],
                     });
                     c.update_relays(iter::empty(), state.relays.iter(), now);
                     c.sut.set_resources(all_resources);
@@ -390,6 +393,7 @@ impl TunnelTest {
                             Transmit {
                                 src: Some(src),
                                 dst,
+                                transport: Transport::Udp,
                                 payload: payload.into(),
                             },
                             relay,
@@ -432,6 +436,10 @@ impl TunnelTest {
                 buffered_transmits.push_from(transmit, &self.client, now);
                 continue;
             }
+            if let Some(query) = self.client.exec_mut(|c| c.sut.poll_dns_query()) {
+                self.forward_dns_query(query, &ref_state.global_dns_records, now);
+                continue;
+            }
             if let Some(event) = self.client.exec_mut(|c| c.sut.poll_event()) {
                 self.on_client_event(
                     self.client.inner().id,
@@ -443,7 +451,7 @@ impl TunnelTest {
             }
             self.client.exec_mut(|sim| {
                 while let Some(packet) = sim.sut.poll_packets() {
-                    sim.on_received_packet(packet)
+                    sim.on_received_packet(packet, now)
                 }
             });
 
@@ -608,6 +616,38 @@ impl TunnelTest {
         }
     }
 
+    /// Answers a DNS query that the client forwards to an upstream server over TCP.
+    ///
+    /// Unlike UDP, TCP traffic doesn't go through our simulated network so we hand the query to the DNS server directly.
+    fn forward_dns_query(
+        &mut self,
+        query: ForwardedQuery,
+        global_dns_records: &BTreeMap<DomainName, BTreeSet<IpAddr>>,
+        now: Instant,
+    ) {
+        let server = query.server.address();
+
+        let response = match self.network.host_by_ip(server.ip()) {
+            Some(HostId::DnsServer(id)) => self
+                .dns_servers
+                .get_mut(&id)
+                .expect("unknown DNS server")
+                .exec_mut(|d| d.receive_tcp(global_dns_records, &query.message))
+                .ok_or_else(|| io::Error::other("Invalid DNS query")),
+            Some(HostId::Client(_))
+            | Some(HostId::Gateway(_))
+            | Some(HostId::Relay(_))
+            | Some(HostId::Stale)
+            | None => {
+                tracing::error!(%server, "Unhandled TCP DNS query");
+                Err(io::ErrorKind::ConnectionRefused.into())
+            }
+        };
+
+        self.client
+            .exec_mut(|c| c.sut.handle_dns_response(query, response, now));
+    }
+
     fn on_client_event(
         &mut self,
         src: ClientId,
@@ -686,6 +726,7 @@ impl TunnelTest {
             ClientEvent::ResourcesChanged { .. } => {
                 tracing::warn!("Unimplemented");
             }
+            ClientEvent::DnsResolved(_) | ClientEvent::ConnectionPathChanged { .. } => {}
             ClientEvent::TunInterfaceUpdated(config) => {
                 if self.client.inner().dns_by_sentinel == config.dns_by_sentinel
                     && self.client.inner().ipv4_routes == config.ipv4_routes
@@ -792,5 +833,6 @@ fn on_gateway_event(
             }
         }),
         GatewayEvent::RefreshDns { .. } => todo!(),
+        GatewayEvent::ConnectionPathChanged { .. } => {}
     }
 }
@@ -5,15 +5,16 @@ use crate::messages::{
 use anyhow::Result;
 use boringtun::x25519::PublicKey;
 use connlib_shared::messages::{
-    ClientId, ConnectionAccepted, Interface, RelaysPresence, ResourceAccepted, ResourceId,
+    Answer, ClientId, ConnectionAccepted, Interface, RelayId, RelaysPresence, ResourceAccepted,
+    ResourceId,
 };
 use connlib_shared::{messages::GatewayResponse, DomainName};
 #[cfg(not(target_os = "windows"))]
 use dns_lookup::{AddrInfoHints, AddrInfoIter, LookupError};
-use firezone_tunnel::GatewayTunnel;
+use firezone_tunnel::ShardedGatewayTunnel;
 use futures::channel::mpsc;
 use futures_bounded::Timeout;
-use phoenix_channel::PhoenixChannel;
+use phoenix_channel::ControlPlane;
 use std::collections::BTreeSet;
 use std::convert::Infallible;
 use std::net::IpAddr;
@@ -25,6 +26,12 @@ pub const PHOENIX_TOPIC: &str = "gateway";
 /// How long we allow a DNS resolution via `libc::get_addr_info`.
 const DNS_RESOLUTION_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// How long we wait for a shard of the tunnel to process a request.
+const SHARD_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often we log the statistics of our connections to clients.
+const CONNECTION_STATS_INTERVAL: Duration = Duration::from_secs(60);
+
 // DNS resolution happens as part of every connection setup.
 // For a connection to succeed, DNS resolution must be less than `snownet`'s handshake timeout.
 static_assertions::const_assert!(
@@ -38,30 +45,52 @@ enum ResolveTrigger {
     Refresh(DomainName, ClientId, ResourceId),
 }
 
-pub struct Eventloop {
-    tunnel: GatewayTunnel,
-    portal: PhoenixChannel<(), IngressMessages, ()>,
+pub struct Eventloop<P> {
+    tunnel: ShardedGatewayTunnel,
+    portal: P,
     tun_device_channel: mpsc::Sender<Interface>,
 
+    /// The interface and relays from the last `InitGateway`.
+    ///
+    /// The portal sends a new one every time we re-join, e.g. after a flaky link.
+    /// We diff it against these so we don't needlessly reconfigure the TUN device or our relays.
+    interface: Option<Interface>,
+    relays: BTreeSet<RelayId>,
+
     resolve_tasks: futures_bounded::FuturesTupleSet<Vec<IpAddr>, ResolveTrigger>,
+    /// Connection requests waiting for the client's shard, together with the resolved addresses of the resource.
+    accept_tasks:
+        futures_bounded::FuturesTupleSet<Result<Answer>, (RequestConnection, Vec<IpAddr>)>,
+    /// Access requests waiting for the client's shard, together with the resolved addresses of the resource.
+    allow_access_tasks: futures_bounded::FuturesTupleSet<Result<()>, (AllowAccess, Vec<IpAddr>)>,
+
+    connection_stats_interval: tokio::time::Interval,
 }
 
-impl Eventloop {
+impl<P> Eventloop<P> {
     pub(crate) fn new(
-        tunnel: GatewayTunnel,
-        portal: PhoenixChannel<(), IngressMessages, ()>,
+        tunnel: ShardedGatewayTunnel,
+        portal: P,
         tun_device_channel: mpsc::Sender<Interface>,
     ) -> Self {
         Self {
             tunnel,
             portal,
             resolve_tasks: futures_bounded::FuturesTupleSet::new(DNS_RESOLUTION_TIMEOUT, 100),
+            accept_tasks: futures_bounded::FuturesTupleSet::new(SHARD_REQUEST_TIMEOUT, 100),
+            allow_access_tasks: futures_bounded::FuturesTupleSet::new(SHARD_REQUEST_TIMEOUT, 100),
             tun_device_channel,
+            interface: None,
+            relays: BTreeSet::default(),
+            connection_stats_interval: tokio::time::interval(CONNECTION_STATS_INTERVAL),
         }
     }
 }
 
-impl Eventloop {
+impl<P> Eventloop<P>
+where
+    P: ControlPlane<IngressMessages, ()>,
+{
     pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<Result<Infallible>> {
         loop {
             match self.tunnel.poll_next_event(cx) {
@@ -70,8 +99,7 @@ impl Eventloop {
                     continue;
                 }
                 Poll::Ready(Err(e)) => {
-                    tracing::warn!("Tunnel error: {e}");
-                    continue;
+                    return Poll::Ready(Err(anyhow::Error::new(e).context("Tunnel failed")));
                 }
                 Poll::Pending => {}
             }
@@ -92,6 +120,17 @@ impl Eventloop {
                 Poll::Pending => {}
             }
 
+            if let Poll::Ready((result, (req, addresses))) = self.accept_tasks.poll_unpin(cx) {
+                self.connection_accepted(result, req, addresses);
+                continue;
+            }
+
+            if let Poll::Ready((result, (req, addresses))) = self.allow_access_tasks.poll_unpin(cx)
+            {
+                self.access_allowed(result, req, addresses);
+                continue;
+            }
+
             match self.portal.poll(cx)? {
                 Poll::Ready(event) => {
                     self.handle_portal_event(event);
@@ -100,10 +139,33 @@ impl Eventloop {
                 Poll::Pending => {}
             }
 
+            if self.connection_stats_interval.poll_tick(cx).is_ready() {
+                self.log_connection_stats();
+                continue;
+            }
+
             return Poll::Pending;
         }
     }
 
+    fn log_connection_stats(&self) {
+        let stats = self.tunnel.stats_handle().get();
+
+        for (client, stats) in stats.clients {
+            let Some(connection) = stats.connection else {
+                continue;
+            };
+
+            tracing::debug!(
+                %client,
+                ?connection,
+                nat_table_size = %stats.nat_table_size,
+                conntrack_size = %stats.conntrack_size,
+                "Connection stats"
+            );
+        }
+    }
+
     fn handle_tunnel_event(&mut self, event: firezone_tunnel::GatewayEvent) {
         match event {
             firezone_tunnel::GatewayEvent::AddedIceCandidates {
@@ -146,6 +208,9 @@ impl Eventloop {
                     tracing::warn!("Too many dns resolution requests, dropping existing one");
                 };
             }
+            firezone_tunnel::GatewayEvent::ConnectionPathChanged { conn_id, old, new } => {
+                tracing::info!(client = %conn_id, ?old, ?new, relayed = %new.is_relayed(), "Connection path changed");
+            }
         }
     }
 
@@ -222,20 +287,36 @@ impl Eventloop {
                         connected,
                     }),
                 ..
-            } => self
-                .tunnel
-                .update_relays(BTreeSet::from_iter(disconnected_ids), connected),
+            } => {
+                for id in &disconnected_ids {
+                    self.relays.remove(id);
+                }
+                self.relays.extend(connected.iter().map(|r| r.id()));
+
+                self.tunnel
+                    .update_relays(BTreeSet::from_iter(disconnected_ids), connected)
+            }
             phoenix_channel::Event::InboundMessage {
                 msg: IngressMessages::Init(init),
                 ..
             } => {
-                self.tunnel.update_relays(BTreeSet::default(), init.relays);
+                let relay_ids = init.relays.iter().map(|r| r.id()).collect::<BTreeSet<_>>();
+                let stale_relays = self.relays.difference(&relay_ids).copied().collect();
+                self.relays = relay_ids;
+
+                self.tunnel.update_relays(stale_relays, init.relays);
+
+                if self.interface.as_ref() == Some(&init.interface) {
+                    tracing::debug!("Interface is unchanged, not reconfiguring TUN device");
+                    return;
+                }
 
                 // FIXME(tech-debt): Currently, the `Tunnel` creates the TUN device as part of `set_interface`.
                 // For the gateway, it doesn't do anything else so in an ideal world, we would cause the side-effect out here and just pass an opaque `Device` to the `Tunnel`.
                 // That requires more refactoring of other platforms, so for now, we need to rely on the `Tunnel` interface and cause the side-effect separately via the `TunDeviceManager`.
-                if let Err(e) = self.tun_device_channel.try_send(init.interface) {
-                    tracing::warn!("Failed to set interface: {e}");
+                match self.tun_device_channel.try_send(init.interface.clone()) {
+                    Ok(()) => self.interface = Some(init.interface),
+                    Err(e) => tracing::warn!("Failed to set interface: {e}"),
                 }
             }
             phoenix_channel::Event::InboundMessage {
@@ -265,23 +346,51 @@ impl Eventloop {
             .inspect_err(|e| tracing::debug!(client = %req.client.id, reference = %req.reference, "DNS resolution timed out as part of connection request: {e}"))
             .unwrap_or_default();
 
+        let client = req.client.id;
+
+        // Both requests go to the same shard, which processes them in order.
         let answer = self.tunnel.accept(
-            req.client.id,
-            req.client.peer.preshared_key,
-            req.client.payload.ice_parameters,
+            client,
+            req.client.peer.preshared_key.clone(),
+            req.client.payload.ice_parameters.clone(),
             PublicKey::from(req.client.peer.public_key.0),
         );
-
-        match self.tunnel.allow_access(
-            req.client.id,
+        let allowed = self.tunnel.allow_access(
+            client,
             req.client.peer.ipv4,
             req.client.peer.ipv6,
             req.client.payload.domain.as_ref().map(|r| r.as_tuple()),
             req.expires_at,
-            req.resource.into_resolved(addresses.clone()),
-        ) {
-            Ok(()) => {
-                self.portal.send(
+            req.resource.clone().into_resolved(addresses.clone()),
+        );
+
+        if self
+            .accept_tasks
+            .try_push(
+                async move {
+                    let answer = answer.await?;
+                    allowed.await?;
+
+                    Ok(answer)
+                },
+                (req, addresses),
+            )
+            .is_err()
+        {
+            self.tunnel.cleanup_connection(&client);
+            tracing::warn!(%client, "Too many pending connection requests, dropping this one");
+        }
+    }
+
+    fn connection_accepted(
+        &mut self,
+        result: Result<Result<Answer>, Timeout>,
+        req: RequestConnection,
+        addresses: Vec<IpAddr>,
+    ) {
+        match result.map_err(anyhow::Error::from).and_then(|r| r) {
+            Ok(answer) => {
+                self.portal.send_reliable(
                     PHOENIX_TOPIC,
                     EgressMessages::ConnectionReady(ConnectionReady {
                         reference: req.reference,
@@ -313,18 +422,37 @@ impl Eventloop {
             .inspect_err(|e| tracing::debug!(client = %req.client_id, reference = %req.reference, "DNS resolution timed out as part of allow access request: {e}"))
             .unwrap_or_default();
 
-        if let (Ok(()), Some(resolve_request)) = (
-            self.tunnel.allow_access(
-                req.client_id,
-                req.client_ipv4,
-                req.client_ipv6,
-                req.payload.as_ref().map(|r| r.as_tuple()),
-                req.expires_at,
-                req.resource.into_resolved(addresses.clone()),
-            ),
-            req.payload,
-        ) {
-            self.portal.send(
+        let allowed = self.tunnel.allow_access(
+            req.client_id,
+            req.client_ipv4,
+            req.client_ipv6,
+            req.payload.as_ref().map(|r| r.as_tuple()),
+            req.expires_at,
+            req.resource.clone().into_resolved(addresses.clone()),
+        );
+
+        if self
+            .allow_access_tasks
+            .try_push(allowed, (req, addresses))
+            .is_err()
+        {
+            tracing::warn!("Too many pending allow access requests, dropping this one");
+        }
+    }
+
+    fn access_allowed(
+        &mut self,
+        result: Result<Result<()>, Timeout>,
+        req: AllowAccess,
+        addresses: Vec<IpAddr>,
+    ) {
+        if let Err(e) = result.map_err(anyhow::Error::from).and_then(|r| r) {
+            tracing::debug!(client = %req.client_id, reference = %req.reference, "Allow access request failed: {e:#}");
+            return;
+        }
+
+        if let Some(resolve_request) = req.payload {
+            self.portal.send_reliable(
                 PHOENIX_TOPIC,
                 EgressMessages::ConnectionReady(ConnectionReady {
                     reference: req.reference,
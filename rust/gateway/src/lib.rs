@@ -0,0 +1,5 @@
+//! The gateway's side of the portal protocol.
+//!
+//! Exposed as a library so other tools, e.g. the portal emulator, can speak it too.
+
+pub mod messages;
@@ -13,14 +13,14 @@ use connlib_shared::{
 };
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct ClientPayload {
     pub ice_parameters: Offer,
     pub domain: Option<ResolveRequest>,
 }
 
 // TODO: Should this have a resource?
-#[derive(Debug, PartialEq, Eq, Deserialize, Clone)]
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone)]
 pub struct InitGateway {
     pub interface: Interface,
     pub config: Config,
@@ -28,20 +28,20 @@ pub struct InitGateway {
     pub relays: Vec<Relay>,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct Config {
     pub ipv4_masquerade_enabled: bool,
     pub ipv6_masquerade_enabled: bool,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Client {
     pub id: ClientId,
     pub payload: ClientPayload,
     pub peer: Peer,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct RequestConnection {
     pub resource: ResourceDescription,
     pub client: Client,
@@ -56,7 +56,7 @@ pub struct RemoveResource {
     pub id: ResourceId,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum ResolveRequest {
     ReturnResponse(DomainName),
@@ -86,7 +86,7 @@ impl ResolveRequest {
     }
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct AllowAccess {
     pub client_id: ClientId,
     pub resource: ResourceDescription,
@@ -101,7 +101,7 @@ pub struct AllowAccess {
     pub client_ipv6: Ipv6Addr,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct RejectAccess {
     pub client_id: ClientId,
     pub resource_id: ResourceId,
@@ -109,7 +109,7 @@ pub struct RejectAccess {
 
 // These messages are the messages that can be received
 // either by a client or a gateway by the client.
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(rename_all = "snake_case", tag = "event", content = "payload")]
 pub enum IngressMessages {
     RequestConnection(RequestConnection),
@@ -123,7 +123,7 @@ pub enum IngressMessages {
 }
 
 /// A client's ice candidate message.
-#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct ClientsIceCandidates {
     /// Client's id the ice candidates are meant for
     pub client_ids: Vec<ClientId>,
@@ -132,7 +132,7 @@ pub struct ClientsIceCandidates {
 }
 
 /// A client's ice candidate message.
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct ClientIceCandidates {
     /// Client's id the ice candidates came from
     pub client_id: ClientId,
@@ -142,7 +142,7 @@ pub struct ClientIceCandidates {
 
 // These messages can be sent from a gateway
 // to a control pane.
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "snake_case", tag = "event", content = "payload")]
 pub enum EgressMessages {
     ConnectionReady(ConnectionReady),
@@ -150,7 +150,7 @@ pub enum EgressMessages {
     BroadcastInvalidatedIceCandidates(ClientsIceCandidates),
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ConnectionReady {
     #[serde(rename = "ref")]
     pub reference: String,
@@ -160,9 +160,9 @@ pub struct ConnectionReady {
 #[cfg(test)]
 mod test {
     use super::*;
-    use connlib_shared::messages::gateway::Filter;
     use connlib_shared::messages::gateway::PortRange;
     use connlib_shared::messages::gateway::ResourceDescriptionDns;
+    use connlib_shared::messages::gateway::{Filter, IcmpFilter};
     use connlib_shared::messages::Turn;
     use phoenix_channel::PhoenixMessage;
 
@@ -417,6 +417,7 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    search_domains: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -442,6 +443,7 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    search_domains: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -467,6 +469,7 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    search_domains: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -492,6 +495,7 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    search_domains: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -517,6 +521,7 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    search_domains: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -542,6 +547,7 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    search_domains: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -567,6 +573,7 @@ mod test {
                     ipv4: "100.115.164.78".parse().unwrap(),
                     ipv6: "fd00:2021:1111::2c:f6ab".parse().unwrap(),
                     upstream_dns: vec![],
+                    search_domains: vec![],
                 },
                 config: Config {
                     ipv4_masquerade_enabled: true,
@@ -592,10 +599,12 @@ mod test {
                 address: "?.httpbin".to_string(),
                 name: "?.httpbin".to_string(),
                 filters: vec![
-                    Filter::Icmp,
+                    Filter::Icmp(IcmpFilter::all()),
                     Filter::Tcp(PortRange {
                         port_range_end: 65535,
                         port_range_start: 0,
+                        source_port_range_start: None,
+                        source_port_range_end: None,
                     }),
                 ],
             }));
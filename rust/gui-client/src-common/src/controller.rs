@@ -7,14 +7,21 @@ use crate::{
     updates,
 };
 use anyhow::{anyhow, Context, Result};
-use connlib_shared::callbacks::ResourceDescription;
+use connlib_shared::{
+    callbacks::{self, ResourceDescription},
+    messages::ResourceId,
+};
 use firezone_bin_shared::{new_dns_notifier, new_network_notifier};
 use firezone_headless_client::{
     IpcClientMsg::{self, SetDisabledResources},
     IpcServerMsg, IpcServiceError, LogFilterReloader,
 };
 use secrecy::{ExposeSecret as _, SecretString};
-use std::{collections::BTreeSet, path::PathBuf, time::Instant};
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::PathBuf,
+    time::Instant,
+};
 use tokio::sync::{mpsc, oneshot};
 use url::Url;
 
@@ -35,6 +42,8 @@ pub struct Controller<I: GuiIntegration> {
     pub ipc_rx: mpsc::Receiver<ipc::Event>,
     pub integration: I,
     pub log_filter_reloader: LogFilterReloader,
+    /// Whether we reach the Gateway of a Resource via a relay, for the Resources we are connected to.
+    pub relayed_resources: HashMap<ResourceId, bool>,
     /// A release that's ready to download
     pub release: Option<updates::Release>,
     pub rx: mpsc::Receiver<ControllerRequest>,
@@ -175,7 +184,9 @@ impl<I: GuiIntegration> Controller<I> {
                     result?;
                     if self.status.needs_network_changes() {
                         tracing::debug!("Internet up/down changed, calling `Session::reset`");
-                        self.ipc_client.reset().await?
+                        self.ipc_client.reset().await?;
+                        // Resetting drops all connections to Gateways.
+                        self.relayed_resources.clear();
                     }
                     self.try_retry_connection().await?
                 }
@@ -455,6 +466,12 @@ impl<I: GuiIntegration> Controller<I> {
             }
             IpcServerMsg::OnUpdateResources(resources) => {
                 tracing::debug!(len = resources.len(), "Got new Resources");
+                // Forget the paths of Resources that are gone or whose Gateways are offline.
+                self.relayed_resources.retain(|id, _| {
+                    resources
+                        .iter()
+                        .any(|r| r.id() == *id && r.status() != callbacks::Status::Offline)
+                });
                 self.status = Status::TunnelReady { resources };
                 if let Err(error) = self.refresh_system_tray_menu() {
                     tracing::error!(?error, "Failed to refresh menu");
@@ -464,6 +481,16 @@ impl<I: GuiIntegration> Controller<I> {
 
                 Ok(())
             }
+            IpcServerMsg::OnConnectionPathChanged { resources, relayed } => {
+                tracing::debug!(?resources, %relayed, "Connection path changed");
+                self.relayed_resources
+                    .extend(resources.into_iter().map(|id| (id, relayed)));
+                if let Err(error) = self.refresh_system_tray_menu() {
+                    tracing::error!(?error, "Failed to refresh menu");
+                }
+
+                Ok(())
+            }
             IpcServerMsg::TerminatingGracefully => {
                 tracing::info!("Caught TerminatingGracefully");
                 self.integration
@@ -532,6 +559,7 @@ impl<I: GuiIntegration> Controller<I> {
                     "Failed to connect to Firezone Portal, will try again when the network changes"
                 );
                 self.status = Status::RetryingConnection { token };
+                self.relayed_resources.clear();
                 if let Err(error) = self.refresh_system_tray_menu() {
                     tracing::error!(?error, "Failed to refresh menu");
                 }
@@ -617,6 +645,7 @@ impl<I: GuiIntegration> Controller<I> {
                         internet_resource_enabled: &self
                             .advanced_settings
                             .internet_resource_enabled,
+                        relayed_resources: &self.relayed_resources,
                         resources,
                     })
                 }
@@ -655,6 +684,7 @@ impl<I: GuiIntegration> Controller<I> {
     async fn sign_out(&mut self) -> Result<()> {
         self.auth.sign_out()?;
         self.status = Status::Disconnected;
+        self.relayed_resources.clear();
         tracing::debug!("disconnecting connlib");
         // This is redundant if the token is expired, in that case
         // connlib already disconnected itself.
@@ -3,7 +3,7 @@ use connlib_shared::{
     callbacks::{ResourceDescription, Status},
     messages::ResourceId,
 };
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use url::Url;
 
 use builder::item;
@@ -15,6 +15,9 @@ const NO_ACTIVITY: &str = "[-] No activity";
 const GATEWAY_CONNECTED: &str = "[O] Gateway connected";
 const ALL_GATEWAYS_OFFLINE: &str = "[X] All Gateways offline";
 
+const CONNECTED_DIRECTLY: &str = "Direct connection";
+const CONNECTED_VIA_RELAY: &str = "Connected via relay";
+
 const ENABLED_SYMBOL: &str = "<->";
 const DISABLED_SYMBOL: &str = "—";
 
@@ -74,6 +77,8 @@ pub struct SignedIn<'a> {
     pub favorite_resources: &'a HashSet<ResourceId>,
     pub resources: &'a [ResourceDescription],
     pub internet_resource_enabled: &'a Option<bool>,
+    /// Whether we reach the Gateway of a Resource via a relay, for the Resources we are connected to.
+    pub relayed_resources: &'a HashMap<ResourceId, bool>,
 }
 
 impl<'a> SignedIn<'a> {
@@ -115,11 +120,17 @@ impl<'a> SignedIn<'a> {
                 Status::Offline => ALL_GATEWAYS_OFFLINE,
             };
 
-            submenu
+            let submenu = submenu
                 .separator()
                 .disabled("Site")
                 .copyable(&site.name) // Hope this is okay - The code is simpler if every enabled item sends an `Event` on click
-                .copyable(status)
+                .copyable(status);
+
+            match self.relayed_resources.get(&res.id()) {
+                Some(true) => submenu.copyable(CONNECTED_VIA_RELAY),
+                Some(false) => submenu.copyable(CONNECTED_DIRECTLY),
+                None => submenu,
+            }
         } else {
             submenu
         }
@@ -313,6 +324,7 @@ mod tests {
         resources: &'a [ResourceDescription],
         favorite_resources: &'a HashSet<ResourceId>,
         internet_resource_enabled: &'a Option<bool>,
+        relayed_resources: &'a HashMap<ResourceId, bool>,
     ) -> AppState<'a> {
         AppState {
             connlib: ConnlibState::SignedIn(SignedIn {
@@ -320,6 +332,7 @@ mod tests {
                 favorite_resources,
                 resources,
                 internet_resource_enabled,
+                relayed_resources,
             }),
             release: None,
         }
@@ -363,7 +376,13 @@ mod tests {
         let resources = vec![];
         let favorites = Default::default();
         let disabled_resources = Default::default();
-        let input = signed_in(&resources, &favorites, &disabled_resources);
+        let relayed_resources = Default::default();
+        let input = signed_in(
+            &resources,
+            &favorites,
+            &disabled_resources,
+            &relayed_resources,
+        );
         let actual = input.into_menu();
         let expected = Menu::default()
             .disabled("Signed in as Jane Doe")
@@ -385,7 +404,13 @@ mod tests {
         let resources = vec![];
         let favorites = HashSet::from([ResourceId::from_u128(42)]);
         let disabled_resources = Default::default();
-        let input = signed_in(&resources, &favorites, &disabled_resources);
+        let relayed_resources = Default::default();
+        let input = signed_in(
+            &resources,
+            &favorites,
+            &disabled_resources,
+            &relayed_resources,
+        );
         let actual = input.into_menu();
         let expected = Menu::default()
             .disabled("Signed in as Jane Doe")
@@ -407,7 +432,13 @@ mod tests {
         let resources = resources();
         let favorites = Default::default();
         let disabled_resources = Default::default();
-        let input = signed_in(&resources, &favorites, &disabled_resources);
+        let relayed_resources = Default::default();
+        let input = signed_in(
+            &resources,
+            &favorites,
+            &disabled_resources,
+            &relayed_resources,
+        );
         let actual = input.into_menu();
         let expected = Menu::default()
             .disabled("Signed in as Jane Doe")
@@ -475,6 +506,44 @@ mod tests {
         );
     }
 
+    #[test]
+    fn relayed_resource_shows_connection_path() {
+        let resources = resources();
+        let gitlab = &resources[1];
+        let favorites = Default::default();
+        let relayed_resources = HashMap::from([(gitlab.id(), true)]);
+        let signed_in = SignedIn {
+            actor_name: "Jane Doe",
+            favorite_resources: &favorites,
+            resources: &resources,
+            internet_resource_enabled: &None,
+            relayed_resources: &relayed_resources,
+        };
+        let actual = signed_in.resource_submenu(gitlab);
+        let expected = Menu::default()
+            .item(
+                Event::Url("https://gitlab.mycorp.com".parse().unwrap()),
+                "<https://gitlab.mycorp.com>",
+            )
+            .separator()
+            .disabled("Resource")
+            .copyable("MyCorp GitLab")
+            .copyable("gitlab.mycorp.com")
+            .item(Event::AddFavorite(gitlab.id()), ADD_FAVORITE)
+            .separator()
+            .disabled("Site")
+            .copyable("test")
+            .copyable(GATEWAY_CONNECTED)
+            .copyable(CONNECTED_VIA_RELAY);
+
+        assert_eq!(
+            actual,
+            expected,
+            "{}",
+            serde_json::to_string_pretty(&actual).unwrap(),
+        );
+    }
+
     #[test]
     fn some_resources_one_favorite() -> Result<()> {
         let resources = resources();
@@ -482,7 +551,13 @@ mod tests {
             "03000143-e25e-45c7-aafb-144990e57dcd",
         )?]);
         let disabled_resources = Default::default();
-        let input = signed_in(&resources, &favorites, &disabled_resources);
+        let relayed_resources = Default::default();
+        let input = signed_in(
+            &resources,
+            &favorites,
+            &disabled_resources,
+            &relayed_resources,
+        );
         let actual = input.into_menu();
         let expected = Menu::default()
             .disabled("Signed in as Jane Doe")
@@ -564,7 +639,13 @@ mod tests {
             "00000000-0000-0000-0000-000000000000",
         )?]);
         let disabled_resources = Default::default();
-        let input = signed_in(&resources, &favorites, &disabled_resources);
+        let relayed_resources = Default::default();
+        let input = signed_in(
+            &resources,
+            &favorites,
+            &disabled_resources,
+            &relayed_resources,
+        );
         let actual = input.into_menu();
         let expected = Menu::default()
             .disabled("Signed in as Jane Doe")
@@ -451,6 +451,7 @@ async fn run_controller(
         ipc_rx,
         integration,
         log_filter_reloader,
+        relayed_resources: Default::default(),
         release: None,
         rx,
         status: Default::default(),
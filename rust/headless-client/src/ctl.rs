@@ -0,0 +1,238 @@
+//! If started with `--ctl-socket`, the headless Client listens on that Unix domain socket
+//! for [`Request`]s from `firezone-headless-client ctl` or fleet automation.
+//!
+//! Every request is answered with exactly one [`Response`].
+//! Both are JSON, framed the same way as the messages between the GUI and the IPC service.
+
+use crate::ipc::{Decoder, Encoder};
+use anyhow::{Context as _, Result};
+use connlib_client_shared::ConnectionStats;
+use connlib_shared::{
+    callbacks::ResourceDescription,
+    messages::{GatewayId, ResourceId},
+};
+use futures::{SinkExt as _, StreamExt as _};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+#[cfg(target_os = "linux")]
+#[path = "ctl/linux.rs"]
+mod platform;
+
+#[cfg(target_os = "windows")]
+#[path = "ctl/windows.rs"]
+mod platform;
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub enum Request {
+    /// List all Resources with their status and whether they are enabled.
+    ListResources,
+    /// Route traffic for this Resource through the tunnel again.
+    EnableResource(ResourceId),
+    /// Stop routing traffic for this Resource through the tunnel, until it is enabled again or the Client restarts.
+    DisableResource(ResourceId),
+    /// Show statistics for the connection to each Gateway.
+    ConnectionStats,
+    /// Show how many forwarded DNS queries were answered from the cache.
+    DnsCacheStats,
+    /// Replace the log filter with these directives, e.g. `debug` or `firezone_tunnel=trace,info`.
+    ReloadLogFilter(String),
+    /// Reconnect to the portal and all Gateways, as if the network changed.
+    Reset,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub enum Response {
+    Ok,
+    Error(String),
+    Resources(Vec<ResourceStatus>),
+    ConnectionStats(Vec<GatewayStats>),
+    DnsCacheStats { hits: u64, misses: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ResourceStatus {
+    #[serde(flatten)]
+    pub resource: ResourceDescription,
+    pub enabled: bool,
+}
+
+/// The statistics connlib last reported for the connection to a Gateway.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct GatewayStats {
+    pub gateway_id: GatewayId,
+    /// `None` until ICE picked a path to the Gateway.
+    pub relayed: Option<bool>,
+    pub data_bytes_sent: usize,
+    pub data_bytes_received: usize,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub packets_dropped: u64,
+    pub handshakes_initiated: u64,
+    /// The round-trip time measured by WireGuard's handshakes.
+    pub rtt_ms: Option<f64>,
+    /// The packet loss estimated by WireGuard, between `0.0` and `1.0`.
+    pub estimated_loss: f32,
+}
+
+impl GatewayStats {
+    pub fn new(gateway_id: GatewayId, stats: &ConnectionStats) -> Self {
+        Self {
+            gateway_id,
+            relayed: stats.path.map(|path| path.is_relayed()),
+            data_bytes_sent: stats.data_bytes_sent.0,
+            data_bytes_received: stats.data_bytes_received.0,
+            packets_sent: stats.packets_sent,
+            packets_received: stats.packets_received,
+            packets_dropped: stats.packets_dropped,
+            handshakes_initiated: stats.handshakes_initiated,
+            rtt_ms: stats.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+            estimated_loss: stats.estimated_loss,
+        }
+    }
+}
+
+/// A [`Request`] that the headless Client's main loop needs to answer.
+pub struct Command {
+    pub request: Request,
+    responder: oneshot::Sender<Response>,
+}
+
+impl Command {
+    pub fn respond(self, response: Response) {
+        // The `ctl` client may have hung up already, nothing to do then.
+        let _ = self.responder.send(response);
+    }
+}
+
+pub struct Server {
+    listener: platform::Listener,
+}
+
+impl Server {
+    pub async fn new(path: &Path) -> Result<Self> {
+        let listener = platform::Listener::bind(path).await?;
+        tracing::info!(path = %path.display(), "Listening for `ctl` commands");
+
+        Ok(Self { listener })
+    }
+
+    /// Accepts connections until the headless Client exits, forwarding their requests to `commands`.
+    pub async fn run(mut self, commands: mpsc::Sender<Command>) -> Result<()> {
+        loop {
+            let stream = self.listener.accept().await?;
+            let commands = commands.clone();
+
+            tokio::spawn(async move {
+                if let Err(error) = handle_connection(stream, commands).await {
+                    tracing::debug!(?error, "Error on `ctl` connection");
+                }
+            });
+        }
+    }
+}
+
+async fn handle_connection(
+    stream: platform::Stream,
+    commands: mpsc::Sender<Command>,
+) -> Result<()> {
+    let (rx, tx) = tokio::io::split(stream);
+    let mut rx = FramedRead::new(rx, Decoder::<Request>::default());
+    let mut tx = FramedWrite::new(tx, Encoder::<Response>::default());
+
+    while let Some(request) = rx.next().await {
+        let (responder, response) = oneshot::channel();
+        commands
+            .send(Command {
+                request: request?,
+                responder,
+            })
+            .await
+            .context("Headless Client is shutting down")?;
+
+        let response = response
+            .await
+            .context("Headless Client dropped the request")?;
+        tx.send(&response).await?;
+    }
+
+    Ok(())
+}
+
+/// Sends a single request to the headless Client listening on `path` and waits for its response.
+pub async fn request(path: &Path, request: Request) -> Result<Response> {
+    let (rx, tx) = tokio::io::split(platform::connect(path).await?);
+    let mut rx = FramedRead::new(rx, Decoder::<Response>::default());
+    let mut tx = FramedWrite::new(tx, Encoder::<Request>::default());
+
+    tx.send(&request).await?;
+
+    rx.next()
+        .await
+        .context("Headless Client closed the connection without responding")?
+}
+
+#[cfg(test)]
+#[cfg(target_os = "linux")]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn requests_are_answered_by_main_loop() -> Result<()> {
+        let _guard = firezone_logging::test("trace");
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("ctl.sock");
+
+        let server = Server::new(&path).await?;
+        let (commands_tx, mut commands_rx) = mpsc::channel(1);
+        tokio::spawn(server.run(commands_tx));
+        tokio::spawn(async move {
+            while let Some(command) = commands_rx.recv().await {
+                let response = if command.request == Request::Reset {
+                    Response::Ok
+                } else {
+                    Response::Error("Not implemented".to_owned())
+                };
+
+                command.respond(response);
+            }
+        });
+
+        assert_eq!(request(&path, Request::Reset).await?, Response::Ok);
+        assert_eq!(
+            request(&path, Request::ConnectionStats).await?,
+            Response::Error("Not implemented".to_owned())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn resource_status_is_flat_json() {
+        let status = ResourceStatus {
+            resource: serde_json::from_str(
+                r#"{
+                    "type": "internet",
+                    "id": "73037362-715d-4a83-a749-f18eadd970e6",
+                    "name": "Internet Resource",
+                    "sites": [],
+                    "status": "Online"
+                }"#,
+            )
+            .unwrap(),
+            enabled: false,
+        };
+
+        let json = serde_json::to_value(&status).unwrap();
+
+        assert_eq!(json["type"], "internet");
+        assert_eq!(json["status"], "Online");
+        assert_eq!(json["enabled"], false);
+        assert_eq!(
+            serde_json::from_value::<ResourceStatus>(json).unwrap(),
+            status
+        );
+    }
+}
@@ -0,0 +1,49 @@
+use anyhow::{Context as _, Result};
+use std::{os::unix::fs::PermissionsExt, path::Path};
+use tokio::net::{UnixListener, UnixStream};
+
+pub(crate) type Stream = UnixStream;
+
+pub(crate) struct Listener {
+    listener: UnixListener,
+}
+
+impl Listener {
+    pub(crate) async fn bind(path: &Path) -> Result<Self> {
+        // Remove the socket if a previous run left it there
+        tokio::fs::remove_file(path).await.ok();
+        let dir = path
+            .parent()
+            .context("`ctl` socket path should always have a parent")?;
+        tokio::fs::create_dir_all(dir).await?;
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Couldn't bind UDS `{}`", path.display()))?;
+        // Like the IPC service, rely on filesystem permissions: only root and the socket's group may steer the Client.
+        let perms = std::fs::Permissions::from_mode(0o660);
+        tokio::fs::set_permissions(path, perms).await?;
+
+        Ok(Self { listener })
+    }
+
+    pub(crate) async fn accept(&mut self) -> Result<Stream> {
+        let (stream, _) = self.listener.accept().await?;
+        let cred = stream.peer_cred()?;
+        tracing::debug!(
+            uid = cred.uid(),
+            gid = cred.gid(),
+            pid = cred.pid(),
+            "Accepted a `ctl` connection"
+        );
+
+        Ok(stream)
+    }
+}
+
+pub(crate) async fn connect(path: &Path) -> Result<Stream> {
+    UnixStream::connect(path).await.with_context(|| {
+        format!(
+            "Couldn't connect to `{}`, is the headless Client running with `--ctl-socket`?",
+            path.display()
+        )
+    })
+}
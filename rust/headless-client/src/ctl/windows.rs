@@ -0,0 +1,23 @@
+use anyhow::{bail, Result};
+use std::path::Path;
+
+pub(crate) type Stream = tokio::io::DuplexStream;
+
+pub(crate) struct Listener {}
+
+impl Listener {
+    #[allow(clippy::unused_async)]
+    pub(crate) async fn bind(_path: &Path) -> Result<Self> {
+        bail!("The `ctl` socket is not implemented on Windows yet")
+    }
+
+    #[allow(clippy::unused_async)]
+    pub(crate) async fn accept(&mut self) -> Result<Stream> {
+        bail!("The `ctl` socket is not implemented on Windows yet")
+    }
+}
+
+#[allow(clippy::unused_async)]
+pub(crate) async fn connect(_path: &Path) -> Result<Stream> {
+    bail!("The `ctl` socket is not implemented on Windows yet")
+}
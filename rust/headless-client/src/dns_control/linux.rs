@@ -1,5 +1,6 @@
 use super::DnsController;
 use anyhow::{bail, Context as _, Result};
+use connlib_shared::DomainName;
 use firezone_bin_shared::{platform::DnsControlMethod, TunDeviceManager};
 use std::{net::IpAddr, process::Command, str::FromStr};
 
@@ -16,21 +17,27 @@ impl DnsController {
         Ok(())
     }
 
-    /// Set the computer's system-wide DNS servers
+    /// Set the computer's system-wide DNS servers and search domains
     ///
     /// The `mut` in `&mut self` is not needed by Rust's rules, but
     /// it would be bad if this was called from 2 threads at once.
     ///
     /// Cancel safety: Try not to cancel this.
-    pub async fn set_dns(&mut self, dns_config: Vec<IpAddr>) -> Result<()> {
+    pub async fn set_dns(
+        &mut self,
+        dns_config: Vec<IpAddr>,
+        search_domains: Vec<DomainName>,
+    ) -> Result<()> {
         match self.dns_control_method {
             DnsControlMethod::Disabled => Ok(()),
-            DnsControlMethod::EtcResolvConf => {
-                tokio::task::spawn_blocking(move || etc_resolv_conf::configure(&dns_config))
-                    .await
-                    .context("Failed to `spawn_blocking` DNS control task")?
+            DnsControlMethod::EtcResolvConf => tokio::task::spawn_blocking(move || {
+                etc_resolv_conf::configure(&dns_config, &search_domains)
+            })
+            .await
+            .context("Failed to `spawn_blocking` DNS control task")?,
+            DnsControlMethod::SystemdResolved => {
+                configure_systemd_resolved(&dns_config, &search_domains).await
             }
-            DnsControlMethod::SystemdResolved => configure_systemd_resolved(&dns_config).await,
         }
         .context("Failed to control DNS")
     }
@@ -53,16 +60,33 @@ impl DnsController {
 ///
 /// Cancel safety: Cancelling the future may leave running subprocesses
 /// which should eventually exit on their own.
-async fn configure_systemd_resolved(dns_config: &[IpAddr]) -> Result<()> {
+async fn configure_systemd_resolved(
+    dns_config: &[IpAddr],
+    search_domains: &[DomainName],
+) -> Result<()> {
     configure_dns_for_tun("dns", dns_config).await?;
-    configure_dns_for_tun("domain", &["~."]).await?;
+    configure_dns_for_tun("domain", &resolved_domains(search_domains)).await?;
     configure_dns_for_tun("llmnr", &[false]).await?; // Must disable LLMNR to not interfere with local search domains.
 
-    tracing::info!(?dns_config, "Configured DNS sentinels with `resolvectl`");
+    tracing::info!(
+        ?dns_config,
+        ?search_domains,
+        "Configured DNS sentinels with `resolvectl`"
+    );
 
     Ok(())
 }
 
+/// The domains to configure for our TUN device with `resolvectl domain`.
+///
+/// `~.` is a routing-only domain that makes us the default resolver for all queries.
+/// The search domains are listed without the `~` prefix such that `systemd-resolved` also uses them to qualify single-label names.
+fn resolved_domains(search_domains: &[DomainName]) -> Vec<String> {
+    std::iter::once("~.".to_owned())
+        .chain(search_domains.iter().map(ToString::to_string))
+        .collect()
+}
+
 /// Executes the provided `resolvectl` command for our TUN device.
 async fn configure_dns_for_tun(cmd: &str, params: &[impl ToString]) -> Result<()> {
     let status = tokio::process::Command::new("resolvectl")
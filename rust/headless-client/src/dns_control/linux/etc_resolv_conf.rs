@@ -1,4 +1,5 @@
 use anyhow::{bail, Context, Result};
+use connlib_shared::DomainName;
 use std::{
     fs,
     io::{self, Write},
@@ -35,8 +36,8 @@ impl Default for ResolvPaths {
 /// This is async because it's called in a Tokio context and it's nice to use their
 /// `fs` module
 #[cfg_attr(test, mutants::skip)] // Would modify system-wide `/etc/resolv.conf`
-pub(crate) fn configure(dns_config: &[IpAddr]) -> Result<()> {
-    configure_at_paths(dns_config, &ResolvPaths::default())
+pub(crate) fn configure(dns_config: &[IpAddr], search_domains: &[DomainName]) -> Result<()> {
+    configure_at_paths(dns_config, search_domains, &ResolvPaths::default())
 }
 
 /// Revert changes Firezone made to `/etc/resolv.conf`
@@ -47,7 +48,11 @@ pub(crate) fn revert() -> Result<()> {
     revert_at_paths(&ResolvPaths::default())
 }
 
-fn configure_at_paths(dns_config: &[IpAddr], paths: &ResolvPaths) -> Result<()> {
+fn configure_at_paths(
+    dns_config: &[IpAddr],
+    search_domains: &[DomainName],
+    paths: &ResolvPaths,
+) -> Result<()> {
     if dns_config.is_empty() {
         tracing::warn!("`dns_config` is empty, leaving `/etc/resolv.conf` unchanged");
         return Ok(());
@@ -92,6 +97,20 @@ fn configure_at_paths(dns_config: &[IpAddr], paths: &ResolvPaths) -> Result<()>
 
     new_resolv_conf.nameservers = dns_config.iter().map(|addr| (*addr).into()).collect();
 
+    // Our search domains take precedence, the system's ones still apply to names that don't resolve with ours.
+    if !search_domains.is_empty() {
+        let mut search = search_domains
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        for domain in new_resolv_conf.get_search().cloned().unwrap_or_default() {
+            if !search.contains(&domain) {
+                search.push(domain);
+            }
+        }
+        new_resolv_conf.set_search(search);
+    }
+
     // Over-writing `/etc/resolv.conf` actually violates Docker's plan for handling DNS
     // https://docs.docker.com/network/#dns-services
     // But this is just a hack to get a smoke test working in CI for now.
@@ -304,7 +323,7 @@ nameserver 100.100.111.2
 
         write_resolv_conf(&paths.resolv, &[GOOGLE_DNS.into()])?;
 
-        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &paths)?;
+        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &[], &paths)?;
 
         check_resolv_conf(&paths.resolv, &[IpAddr::from([100, 100, 111, 1])])?;
         check_resolv_conf(&paths.backup, &[GOOGLE_DNS.into()])?;
@@ -326,7 +345,7 @@ nameserver 100.100.111.2
 
         write_resolv_conf(&paths.resolv, &[GOOGLE_DNS.into()])?;
 
-        configure_at_paths(&[], &paths)?;
+        configure_at_paths(&[], &[], &paths)?;
 
         check_resolv_conf(&paths.resolv, &[GOOGLE_DNS.into()])?;
         // No backup since we didn't touch the original file
@@ -341,11 +360,11 @@ nameserver 100.100.111.2
         let (_temp_dir, paths) = create_temp_paths();
 
         write_resolv_conf(&paths.resolv, &[GOOGLE_DNS.into()])?;
-        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &paths)?;
+        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &[], &paths)?;
         revert_at_paths(&paths)?;
 
         write_resolv_conf(&paths.resolv, &[CLOUDFLARE_DNS.into()])?;
-        configure_at_paths(&[IpAddr::from([100, 100, 111, 2])], &paths)?;
+        configure_at_paths(&[IpAddr::from([100, 100, 111, 2])], &[], &paths)?;
         check_resolv_conf(&paths.resolv, &[IpAddr::from([100, 100, 111, 2])])?;
         check_resolv_conf(&paths.backup, &[CLOUDFLARE_DNS.into()])?;
         revert_at_paths(&paths)?;
@@ -368,7 +387,7 @@ nameserver 100.100.111.2
         write_resolv_conf(&paths.resolv, &[GOOGLE_DNS.into()])?;
 
         // First run
-        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &paths)?;
+        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &[], &paths)?;
         check_resolv_conf(&paths.resolv, &[IpAddr::from([100, 100, 111, 1])])
             .context("First run, resolv.conf should have sentinel")?;
         check_resolv_conf(&paths.backup, &[GOOGLE_DNS.into()])
@@ -377,7 +396,7 @@ nameserver 100.100.111.2
         // Crash happens
 
         // Second run
-        configure_at_paths(&[IpAddr::from([100, 100, 111, 2])], &paths)?;
+        configure_at_paths(&[IpAddr::from([100, 100, 111, 2])], &[], &paths)?;
         check_resolv_conf(&paths.resolv, &[IpAddr::from([100, 100, 111, 2])])
             .context("Second run, resolv.conf should have new sentinel")?;
         check_resolv_conf(&paths.backup, &[GOOGLE_DNS.into()])
@@ -401,7 +420,7 @@ nameserver 100.100.111.2
         write_resolv_conf(&paths.resolv, &[GOOGLE_DNS.into()])?;
 
         // First run
-        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &paths)?;
+        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &[], &paths)?;
         check_resolv_conf(&paths.resolv, &[IpAddr::from([100, 100, 111, 1])])
             .context("First run, resolv.conf should have sentinel")?;
         check_resolv_conf(&paths.backup, &[GOOGLE_DNS.into()])
@@ -412,7 +431,7 @@ nameserver 100.100.111.2
         write_resolv_conf(&paths.resolv, &[CLOUDFLARE_DNS.into()])?;
 
         // Second run
-        configure_at_paths(&[IpAddr::from([100, 100, 111, 2])], &paths)?;
+        configure_at_paths(&[IpAddr::from([100, 100, 111, 2])], &[], &paths)?;
         check_resolv_conf(&paths.resolv, &[IpAddr::from([100, 100, 111, 2])])
             .context("Second run, resolv.conf should have new sentinel")?;
         check_resolv_conf(&paths.backup, &[CLOUDFLARE_DNS.into()])
@@ -437,11 +456,11 @@ nameserver 100.100.111.2
         write_resolv_conf(&paths.resolv, &[GOOGLE_DNS.into()])?;
 
         // Configure twice
-        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &paths)?;
+        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &[], &paths)?;
         check_resolv_conf(&paths.resolv, &[IpAddr::from([100, 100, 111, 1])])?;
         check_resolv_conf(&paths.backup, &[GOOGLE_DNS.into()])?;
 
-        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &paths)?;
+        configure_at_paths(&[IpAddr::from([100, 100, 111, 1])], &[], &paths)?;
         check_resolv_conf(&paths.resolv, &[IpAddr::from([100, 100, 111, 1])])?;
         check_resolv_conf(&paths.backup, &[GOOGLE_DNS.into()])?;
 
@@ -456,4 +475,39 @@ nameserver 100.100.111.2
 
         Ok(())
     }
+
+    /// Our search domains should come first, followed by the ones the system had configured before.
+    #[tokio::test]
+    async fn search_domains() -> Result<()> {
+        let (_temp_dir, paths) = create_temp_paths();
+
+        let mut conf = resolv_conf::Config::new();
+        conf.nameservers = vec![GOOGLE_DNS.into()];
+        conf.set_search(vec!["home.arpa".into(), "example.com".into()]);
+        std::fs::write(&paths.resolv, conf.to_string())?;
+
+        configure_at_paths(
+            &[IpAddr::from([100, 100, 111, 1])],
+            &["corp.example.com".parse()?, "example.com".parse()?],
+            &paths,
+        )?;
+
+        let text = std::fs::read_to_string(&paths.resolv)?;
+        let parsed = resolv_conf::Config::parse(text)?;
+        ensure!(
+            parsed.get_search()
+                == Some(&vec![
+                    "corp.example.com".to_owned(),
+                    "example.com".to_owned(),
+                    "home.arpa".to_owned()
+                ]),
+            "Unexpected search domains {:?}",
+            parsed.get_search()
+        );
+
+        revert_at_paths(&paths)?;
+        ensure!(std::fs::read_to_string(&paths.resolv)? == conf.to_string());
+
+        Ok(())
+    }
 }
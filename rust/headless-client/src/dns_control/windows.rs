@@ -14,8 +14,12 @@
 //! <https://superuser.com/a/1752670>
 
 use super::DnsController;
-use anyhow::{Context as _, Result};
-use firezone_bin_shared::platform::{DnsControlMethod, CREATE_NO_WINDOW};
+use anyhow::{bail, Context as _, Result};
+use connlib_shared::DomainName;
+use firezone_bin_shared::{
+    platform::{DnsControlMethod, CREATE_NO_WINDOW},
+    TUNNEL_NAME,
+};
 use std::{
     io::ErrorKind, net::IpAddr, os::windows::process::CommandExt, path::Path, process::Command,
 };
@@ -53,11 +57,16 @@ impl DnsController {
     ///
     /// Must be async and an owned `Vec` to match the Linux signature
     #[allow(clippy::unused_async)]
-    pub async fn set_dns(&mut self, dns_config: Vec<IpAddr>) -> Result<()> {
+    pub async fn set_dns(
+        &mut self,
+        dns_config: Vec<IpAddr>,
+        search_domains: Vec<DomainName>,
+    ) -> Result<()> {
         match self.dns_control_method {
             DnsControlMethod::Disabled => {}
             DnsControlMethod::Nrpt => {
-                activate(&dns_config).context("Failed to activate DNS control")?
+                activate(&dns_config).context("Failed to activate DNS control")?;
+                set_search_domain(&search_domains).context("Failed to set search domain")?;
             }
         }
         Ok(())
@@ -140,6 +149,36 @@ fn activate(dns_config: &[IpAddr]) -> Result<()> {
     Ok(())
 }
 
+/// Sets the connection-specific DNS suffix of our tunnel interface, which Windows appends to single-label names
+///
+/// Windows only has one such suffix per interface, so only the first search domain is applied.
+fn set_search_domain(search_domains: &[DomainName]) -> Result<()> {
+    let suffix = search_domains
+        .first()
+        .map(ToString::to_string)
+        .unwrap_or_default();
+    if search_domains.len() > 1 {
+        tracing::warn!(
+            ?search_domains,
+            %suffix,
+            "Windows only supports one search domain per interface, ignoring the others"
+        );
+    }
+
+    let status = Command::new("powershell")
+        .creation_flags(CREATE_NO_WINDOW)
+        .arg("-Command")
+        .arg(format!(
+            "Set-DnsClient -InterfaceAlias '{TUNNEL_NAME}' -ConnectionSpecificSuffix '{suffix}'"
+        ))
+        .status()?;
+    if !status.success() {
+        bail!("`Set-DnsClient` exited with {status}");
+    }
+
+    Ok(())
+}
+
 /// Returns the registry path we can use to set NRPT rules when Group Policy is not in effect.
 fn local_nrpt_path() -> &'static Path {
     // Must be backslashes.
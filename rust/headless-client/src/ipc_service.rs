@@ -1,6 +1,6 @@
 use crate::{
     device_id, dns_control::DnsController, known_dirs, signals, CallbackHandler, CliCommon,
-    ConnlibMsg, LogFilterReloader,
+    ConnectionPathChanged, ConnlibMsg, InterfaceConfig, LogFilterReloader,
 };
 use anyhow::{bail, Context as _, Result};
 use clap::Parser;
@@ -27,7 +27,7 @@ pub mod ipc;
 use backoff::ExponentialBackoffBuilder;
 use connlib_shared::{get_user_agent, messages::ResourceId, DEFAULT_MTU};
 use ipc::{Server as IpcServer, ServiceId};
-use phoenix_channel::PhoenixChannel;
+use phoenix_channel::{PhoenixChannel, Proxy};
 use secrecy::Secret;
 
 #[cfg(target_os = "linux")]
@@ -94,6 +94,12 @@ pub enum ServerMsg {
         is_authentication_error: bool,
     },
     OnUpdateResources(Vec<ResourceDescription>),
+    /// The path to the gateway of these resources changed.
+    OnConnectionPathChanged {
+        resources: BTreeSet<ResourceId>,
+        /// Whether we now reach the gateway via a relay.
+        relayed: bool,
+    },
     /// The IPC service is terminating, maybe due to a software update
     ///
     /// This is a hint that the Client should exit with a message like,
@@ -384,9 +390,15 @@ impl<'a> Handler<'a> {
                 })
                 .await
                 .context("Error while sending IPC message `OnDisconnect`")?,
-            ConnlibMsg::OnSetInterfaceConfig { ipv4, ipv6, dns } => {
+            ConnlibMsg::OnSetInterfaceConfig(config) => {
+                let InterfaceConfig {
+                    ipv4,
+                    ipv6,
+                    dns,
+                    search_domains,
+                } = *config;
                 self.tun_device.set_ips(ipv4, ipv6).await?;
-                self.dns_controller.set_dns(dns).await?;
+                self.dns_controller.set_dns(dns, search_domains).await?;
                 if let Some(instant) = self.last_connlib_start_instant.take() {
                     tracing::info!(elapsed = ?instant.elapsed(), "Tunnel ready");
                 }
@@ -407,6 +419,24 @@ impl<'a> Handler<'a> {
                 self.tun_device.set_routes(ipv4, ipv6).await?;
                 self.dns_controller.flush()?;
             }
+            ConnlibMsg::OnConnectionStats(_) | ConnlibMsg::OnDnsCacheStats(_) => {
+                // Already logged by the callback handler, the GUI doesn't display them.
+            }
+            ConnlibMsg::OnConnectionPathChanged(changed) => {
+                let ConnectionPathChanged {
+                    gateway_id,
+                    resources,
+                    path,
+                } = *changed;
+                tracing::info!(%gateway_id, ?path, "Connection path changed");
+                self.ipc_tx
+                    .send(&ServerMsg::OnConnectionPathChanged {
+                        resources,
+                        relayed: path.is_relayed(),
+                    })
+                    .await
+                    .context("Error while sending IPC message `OnConnectionPathChanged`")?;
+            }
         }
         Ok(())
     }
@@ -504,7 +534,11 @@ impl<'a> Handler<'a> {
 
         self.last_connlib_start_instant = Some(Instant::now());
         let (cb_tx, cb_rx) = mpsc::channel(1_000);
-        let callbacks = CallbackHandler { cb_tx };
+        // The GUI doesn't show DNS resolutions.
+        let callbacks = CallbackHandler {
+            cb_tx,
+            dns_log: false,
+        };
         let args = ConnectArgs {
             tcp_socket_factory: Arc::new(tcp_socket_factory),
             udp_socket_factory: Arc::new(udp_socket_factory),
@@ -522,6 +556,7 @@ impl<'a> Handler<'a> {
                 .with_max_elapsed_time(Some(Duration::from_secs(60 * 60 * 24 * 30)))
                 .build(),
             Arc::new(tcp_socket_factory),
+            Proxy::from_env(),
         )
         .map_err(|e| Error::PortalConnection(e.to_string()))?;
 
@@ -9,10 +9,17 @@
 //! Otherwise we would just make it a normal binary crate.
 
 use anyhow::{Context as _, Result};
-use connlib_client_shared::{Callbacks, DisconnectError};
-use connlib_shared::callbacks;
+use connlib_client_shared::{
+    CacheStats, Callbacks, ConnectionPath, ConnectionStats, DisconnectError,
+};
+use connlib_shared::{
+    callbacks,
+    messages::{GatewayId, ResourceId},
+    DomainName,
+};
 use firezone_bin_shared::platform::DnsControlMethod;
 use std::{
+    collections::{BTreeMap, BTreeSet},
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
     path::PathBuf,
 };
@@ -21,6 +28,8 @@ use tracing::subscriber::set_global_default;
 use tracing_subscriber::{fmt, layer::SubscriberExt as _, EnvFilter, Layer as _, Registry};
 
 mod clear_logs;
+/// A local control API for the headless Client.
+pub mod ctl;
 /// Generate a persistent device ID, stores it to disk, and reads it back.
 pub mod device_id;
 // Pub because the GUI reads the system resolvers
@@ -77,21 +86,38 @@ pub enum ConnlibMsg {
         is_authentication_error: bool,
     },
     /// Use this as `TunnelReady`, per `callbacks.rs`
-    OnSetInterfaceConfig {
-        ipv4: Ipv4Addr,
-        ipv6: Ipv6Addr,
-        dns: Vec<IpAddr>,
-    },
+    ///
+    /// Boxed to keep [`ConnlibMsg`] small, see the `callback_msg_size` test.
+    OnSetInterfaceConfig(Box<InterfaceConfig>),
     OnUpdateResources(Vec<callbacks::ResourceDescription>),
+    /// Boxed to keep [`ConnlibMsg`] small, see the `callback_msg_size` test.
+    OnConnectionPathChanged(Box<ConnectionPathChanged>),
     OnUpdateRoutes {
         ipv4: Vec<Ipv4Network>,
         ipv6: Vec<Ipv6Network>,
     },
+    OnConnectionStats(BTreeMap<GatewayId, ConnectionStats>),
+    OnDnsCacheStats(CacheStats),
+}
+
+pub struct InterfaceConfig {
+    pub ipv4: Ipv4Addr,
+    pub ipv6: Ipv6Addr,
+    pub dns: Vec<IpAddr>,
+    pub search_domains: Vec<DomainName>,
+}
+
+pub struct ConnectionPathChanged {
+    pub gateway_id: GatewayId,
+    pub resources: BTreeSet<ResourceId>,
+    pub path: ConnectionPath,
 }
 
 #[derive(Clone)]
 pub struct CallbackHandler {
     pub cb_tx: mpsc::Sender<ConnlibMsg>,
+    /// Whether to write every DNS query connlib resolves to the [`firezone_logging::dns_log`].
+    pub dns_log: bool,
 }
 
 impl Callbacks for CallbackHandler {
@@ -103,31 +129,103 @@ impl Callbacks for CallbackHandler {
         } else {
             false
         };
-        self.cb_tx
-            .try_send(ConnlibMsg::OnDisconnect {
-                error_msg: error.to_string(),
-                is_authentication_error,
-            })
-            .expect("should be able to send OnDisconnect");
+        if let Err(error) = self.cb_tx.try_send(ConnlibMsg::OnDisconnect {
+            error_msg: error.to_string(),
+            is_authentication_error,
+        }) {
+            tracing::error!("Failed to send OnDisconnect: {error}");
+        }
     }
 
-    fn on_set_interface_config(&self, ipv4: Ipv4Addr, ipv6: Ipv6Addr, dns: Vec<IpAddr>) {
-        self.cb_tx
-            .try_send(ConnlibMsg::OnSetInterfaceConfig { ipv4, ipv6, dns })
-            .expect("Should be able to send OnSetInterfaceConfig");
+    fn on_set_interface_config(
+        &self,
+        ipv4: Ipv4Addr,
+        ipv6: Ipv6Addr,
+        dns: Vec<IpAddr>,
+        search_domains: Vec<DomainName>,
+    ) {
+        let config = Box::new(InterfaceConfig {
+            ipv4,
+            ipv6,
+            dns,
+            search_domains,
+        });
+
+        if let Err(error) = self
+            .cb_tx
+            .try_send(ConnlibMsg::OnSetInterfaceConfig(config))
+        {
+            tracing::error!("Failed to send OnSetInterfaceConfig: {error}");
+        }
+    }
+
+    fn on_dns_resolution(&self, resolution: callbacks::DnsResolution) {
+        if !self.dns_log {
+            return;
+        }
+
+        match serde_json::to_string(&resolution) {
+            Ok(line) => tracing::info!(target: firezone_logging::dns_log::TARGET, "{line}"),
+            Err(error) => tracing::debug!("Failed to serialize DNS resolution: {error}"),
+        }
+    }
+
+    fn on_connection_stats(&self, stats: BTreeMap<GatewayId, ConnectionStats>) {
+        for (gateway, stats) in &stats {
+            tracing::debug!(%gateway, ?stats, "Connection stats");
+        }
+
+        // These are only informational and we get new ones soon, don't crash if we can't keep up.
+        if let Err(error) = self.cb_tx.try_send(ConnlibMsg::OnConnectionStats(stats)) {
+            tracing::debug!("Failed to send OnConnectionStats: {error}");
+        }
+    }
+
+    fn on_dns_cache_stats(&self, stats: CacheStats) {
+        tracing::debug!(?stats, "DNS cache stats");
+
+        if let Err(error) = self.cb_tx.try_send(ConnlibMsg::OnDnsCacheStats(stats)) {
+            tracing::debug!("Failed to send OnDnsCacheStats: {error}");
+        }
+    }
+
+    fn on_connection_path_changed(
+        &self,
+        gateway_id: GatewayId,
+        resources: BTreeSet<ResourceId>,
+        path: ConnectionPath,
+    ) {
+        if let Err(error) = self
+            .cb_tx
+            .try_send(ConnlibMsg::OnConnectionPathChanged(Box::new(
+                ConnectionPathChanged {
+                    gateway_id,
+                    resources,
+                    path,
+                },
+            )))
+        {
+            tracing::error!("Failed to send OnConnectionPathChanged: {error}");
+        }
     }
 
     fn on_update_resources(&self, resources: Vec<callbacks::ResourceDescription>) {
         tracing::debug!(len = resources.len(), "New resource list");
-        self.cb_tx
+        if let Err(error) = self
+            .cb_tx
             .try_send(ConnlibMsg::OnUpdateResources(resources))
-            .expect("Should be able to send OnUpdateResources");
+        {
+            tracing::error!("Failed to send OnUpdateResources: {error}");
+        }
     }
 
     fn on_update_routes(&self, ipv4: Vec<Ipv4Network>, ipv6: Vec<Ipv6Network>) {
-        self.cb_tx
+        if let Err(error) = self
+            .cb_tx
             .try_send(ConnlibMsg::OnUpdateRoutes { ipv4, ipv6 })
-            .expect("Should be able to send messages");
+        {
+            tracing::error!("Failed to send OnUpdateRoutes: {error}");
+        }
     }
 }
 
@@ -1,22 +1,29 @@
 //! The headless Client, AKA standalone Client
 
-use anyhow::{anyhow, Context as _, Result};
+use anyhow::{anyhow, bail, Context as _, Result};
 use backoff::ExponentialBackoffBuilder;
 use clap::Parser;
-use connlib_client_shared::{keypair, ConnectArgs, LoginUrl, Session};
-use connlib_shared::{get_user_agent, DEFAULT_MTU};
+use connlib_client_shared::{keypair, CacheStats, ConnectArgs, ConnectionStats, LoginUrl, Session};
+use connlib_shared::{
+    callbacks::ResourceDescription,
+    get_user_agent,
+    messages::{GatewayId, ResourceId},
+    DEFAULT_MTU,
+};
 use firezone_bin_shared::{
     new_dns_notifier, new_network_notifier,
     platform::{tcp_socket_factory, udp_socket_factory},
     TunDeviceManager, TOKEN_ENV_KEY,
 };
 use firezone_headless_client::{
-    device_id, signals, CallbackHandler, CliCommon, ConnlibMsg, DnsController,
+    ctl, device_id, signals, CallbackHandler, CliCommon, ConnectionPathChanged, ConnlibMsg,
+    DnsController, InterfaceConfig, LogFilterReloader,
 };
 use futures::{FutureExt as _, StreamExt as _};
-use phoenix_channel::PhoenixChannel;
+use phoenix_channel::{PhoenixChannel, Proxy};
 use secrecy::{Secret, SecretString};
 use std::{
+    collections::{BTreeMap, BTreeSet},
     path::{Path, PathBuf},
     pin::pin,
     sync::Arc,
@@ -38,10 +45,8 @@ use platform::default_token_path;
 #[derive(clap::Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    // Needed to preserve CLI arg compatibility
-    // TODO: Remove when we can break CLI compatibility for headless Clients
     #[command(subcommand)]
-    _command: Option<Cmd>,
+    command: Option<Cmd>,
 
     #[command(flatten)]
     common: CliCommon,
@@ -68,6 +73,28 @@ struct Cli {
     #[arg(long)]
     exit: bool,
 
+    /// Append a JSON line for every DNS query Firezone answers or forwards to this file
+    ///
+    /// Useful to debug why a Resource is not reachable.
+    #[arg(long, env = "FIREZONE_DNS_LOG")]
+    dns_log: Option<PathBuf>,
+
+    /// How long applications may cache the IPs we resolve DNS Resources to. Accepts human times, e.g. "30s" or "5m"
+    #[arg(long, env = "FIREZONE_DNS_RESOURCE_TTL")]
+    dns_resource_ttl: Option<humantime::Duration>,
+
+    /// Reach the portal through this HTTP or SOCKS5 proxy, e.g. `socks5://proxy:1080`
+    ///
+    /// Falls back to `HTTPS_PROXY` or `ALL_PROXY` if not set.
+    #[arg(long, env = "FIREZONE_PROXY")]
+    proxy: Option<Proxy>,
+
+    /// Listen for `firezone-headless-client ctl` commands on this Unix domain socket
+    ///
+    /// Anyone who can write to the socket can list and disable Resources, so only root and the socket's group are allowed to.
+    #[arg(long, env = "FIREZONE_CTL_SOCKET")]
+    ctl_socket: Option<PathBuf>,
+
     /// Friendly name for this client to display in the UI.
     #[arg(long, env = "FIREZONE_NAME")]
     firezone_name: Option<String>,
@@ -95,11 +122,43 @@ struct Cli {
     token_path: PathBuf,
 }
 
-#[derive(clap::Subcommand, Clone, Copy)]
+#[derive(clap::Subcommand, Clone)]
 enum Cmd {
+    /// Control a headless Client that is running with `--ctl-socket`
+    Ctl(CtlArgs),
+    // Needed to preserve CLI arg compatibility
+    // TODO: Remove when we can break CLI compatibility for headless Clients
     Standalone,
 }
 
+#[derive(clap::Args, Clone)]
+struct CtlArgs {
+    /// The `--ctl-socket` of the headless Client
+    #[arg(long, env = "FIREZONE_CTL_SOCKET")]
+    socket: PathBuf,
+
+    #[command(subcommand)]
+    command: CtlCmd,
+}
+
+#[derive(clap::Subcommand, Clone)]
+enum CtlCmd {
+    /// Print all Resources as JSON, with their status and whether they are enabled
+    ListResources,
+    /// Route traffic for a disabled Resource through the tunnel again
+    Enable { id: ResourceId },
+    /// Stop routing traffic for a Resource through the tunnel, until it is enabled again or the Client restarts
+    Disable { id: ResourceId },
+    /// Print statistics for the connection to each Gateway as JSON
+    Stats,
+    /// Print how many forwarded DNS queries were answered from the cache as JSON
+    DnsCacheStats,
+    /// Replace the log filter, e.g. `debug` or `firezone_tunnel=trace,info`
+    LogFilter { directives: String },
+    /// Reconnect to the portal and all Gateways, as if the network changed
+    Reset,
+}
+
 fn main() -> Result<()> {
     rustls::crypto::ring::default_provider()
         .install_default()
@@ -107,6 +166,10 @@ fn main() -> Result<()> {
 
     let mut cli = Cli::try_parse()?;
 
+    if let Some(Cmd::Ctl(args)) = cli.command.take() {
+        return run_ctl(args);
+    }
+
     // Modifying the environment of a running process is unsafe. If any other
     // thread is reading or writing the environment, something bad can happen.
     // So `run` must take over as early as possible during startup, and
@@ -134,7 +197,16 @@ fn main() -> Result<()> {
         .as_deref()
         .map(firezone_logging::file::layer)
         .unzip();
-    firezone_logging::setup_global_subscriber(layer);
+    let (dns_log, _dns_log_guard) = cli
+        .dns_log
+        .as_deref()
+        .map(|path| {
+            firezone_logging::dns_log::layer(path)
+                .with_context(|| format!("Couldn't open DNS log `{}`", path.display()))
+        })
+        .transpose()?
+        .unzip();
+    let log_filter_reloader = firezone_logging::setup_global_subscriber(layer, dns_log);
 
     tracing::info!(
         arch = std::env::consts::ARCH,
@@ -175,7 +247,10 @@ fn main() -> Result<()> {
     }
 
     let (cb_tx, cb_rx) = mpsc::channel(1_000);
-    let callbacks = CallbackHandler { cb_tx };
+    let callbacks = CallbackHandler {
+        cb_tx,
+        dns_log: cli.dns_log.is_some(),
+    };
 
     // The name matches that in `ipc_service.rs`
     let mut last_connlib_start_instant = Some(Instant::now());
@@ -201,8 +276,12 @@ fn main() -> Result<()> {
             .with_max_elapsed_time(max_partition_time)
             .build(),
         Arc::new(tcp_socket_factory),
+        cli.proxy.or_else(Proxy::from_env),
     )?;
     let session = Session::connect(args, portal, rt.handle().clone());
+    if let Some(ttl) = cli.dns_resource_ttl {
+        session.set_dns_resource_ttl(ttl.into());
+    }
 
     let result = rt.block_on(async {
         let mut terminate = signals::Terminate::new()?;
@@ -216,6 +295,18 @@ fn main() -> Result<()> {
         dns_controller.deactivate()?;
         let mut tun_device = TunDeviceManager::new(DEFAULT_MTU)?;
         let mut cb_rx = ReceiverStream::new(cb_rx).fuse();
+        let mut tunnel = TunnelState::default();
+
+        let (ctl_tx, ctl_rx) = mpsc::channel(10);
+        if let Some(path) = cli.ctl_socket.as_deref() {
+            let server = ctl::Server::new(path).await?;
+            tokio::spawn(async move {
+                if let Err(error) = server.run(ctl_tx).await {
+                    tracing::error!(?error, "`ctl` socket failed");
+                }
+            });
+        }
+        let mut ctl_rx = ReceiverStream::new(ctl_rx).fuse();
 
         let tokio_handle = tokio::runtime::Handle::current();
 
@@ -240,7 +331,7 @@ fn main() -> Result<()> {
                 },
                 () = hangup => {
                     tracing::info!("Caught SIGHUP");
-                    session.reset();
+                    tunnel.reset(&session);
                     continue;
                 },
                 result = dns_changed => {
@@ -254,7 +345,14 @@ fn main() -> Result<()> {
                 result = network_changed => {
                     result?;
                     tracing::info!("Network change, resetting Session");
-                    session.reset();
+                    tunnel.reset(&session);
+                    continue;
+                },
+                command = ctl_rx.next() => {
+                    if let Some(command) = command {
+                        let response = tunnel.handle_ctl_request(&command.request, &session, &log_filter_reloader);
+                        command.respond(response);
+                    }
                     continue;
                 },
                 cb = cb_rx.next() => cb.context("cb_rx unexpectedly ran empty")?,
@@ -266,13 +364,20 @@ fn main() -> Result<()> {
                     error_msg,
                     is_authentication_error: _,
                 } => break Err(anyhow!(error_msg).context("Firezone disconnected")),
-                ConnlibMsg::OnUpdateResources(_) => {
+                ConnlibMsg::OnUpdateResources(resources) => {
+                    tunnel.resources = resources;
                     // On every Resources update, flush DNS to mitigate <https://github.com/firezone/firezone/issues/5052>
                     dns_controller.flush()?;
                 }
-                ConnlibMsg::OnSetInterfaceConfig { ipv4, ipv6, dns } => {
+                ConnlibMsg::OnSetInterfaceConfig(config) => {
+                    let InterfaceConfig {
+                        ipv4,
+                        ipv6,
+                        dns,
+                        search_domains,
+                    } = *config;
                     tun_device.set_ips(ipv4, ipv6).await?;
-                    dns_controller.set_dns(dns).await?;
+                    dns_controller.set_dns(dns, search_domains).await?;
                     // `on_set_interface_config` is guaranteed to be called when the tunnel is completely ready
                     // <https://github.com/firezone/firezone/pull/6026#discussion_r1692297438>
                     if let Some(instant) = last_connlib_start_instant.take() {
@@ -288,6 +393,24 @@ fn main() -> Result<()> {
                 ConnlibMsg::OnUpdateRoutes { ipv4, ipv6 } => {
                     tun_device.set_routes(ipv4, ipv6).await?;
                 }
+                ConnlibMsg::OnConnectionPathChanged(changed) => {
+                    let ConnectionPathChanged {
+                        gateway_id,
+                        resources,
+                        path,
+                    } = *changed;
+                    if path.is_relayed() {
+                        tracing::info!(%gateway_id, ?resources, ?path, "Connected via relay");
+                    } else {
+                        tracing::info!(%gateway_id, ?resources, ?path, "Connected directly");
+                    }
+                }
+                ConnlibMsg::OnConnectionStats(stats) => {
+                    tunnel.connection_stats = stats;
+                }
+                ConnlibMsg::OnDnsCacheStats(stats) => {
+                    tunnel.dns_cache_stats = stats;
+                }
             }
         };
 
@@ -303,6 +426,138 @@ fn main() -> Result<()> {
     result
 }
 
+/// What the main loop knows about the tunnel, so it can answer `ctl` requests
+#[derive(Default)]
+struct TunnelState {
+    resources: Vec<ResourceDescription>,
+    disabled_resources: BTreeSet<ResourceId>,
+    connection_stats: BTreeMap<GatewayId, ConnectionStats>,
+    dns_cache_stats: CacheStats,
+}
+
+impl TunnelState {
+    fn handle_ctl_request(
+        &mut self,
+        request: &ctl::Request,
+        session: &Session,
+        log_filter_reloader: &LogFilterReloader,
+    ) -> ctl::Response {
+        match request {
+            ctl::Request::ListResources => ctl::Response::Resources(
+                self.resources
+                    .iter()
+                    .map(|resource| ctl::ResourceStatus {
+                        resource: resource.clone(),
+                        enabled: !self.disabled_resources.contains(&resource.id()),
+                    })
+                    .collect(),
+            ),
+            ctl::Request::EnableResource(id) => self.set_resource_enabled(*id, true, session),
+            ctl::Request::DisableResource(id) => self.set_resource_enabled(*id, false, session),
+            ctl::Request::ConnectionStats => ctl::Response::ConnectionStats(
+                self.connection_stats
+                    .iter()
+                    .map(|(gateway_id, stats)| ctl::GatewayStats::new(*gateway_id, stats))
+                    .collect(),
+            ),
+            ctl::Request::DnsCacheStats => ctl::Response::DnsCacheStats {
+                hits: self.dns_cache_stats.hits,
+                misses: self.dns_cache_stats.misses,
+            },
+            ctl::Request::ReloadLogFilter(directives) => {
+                let result = firezone_logging::try_filter(directives)
+                    .context("Invalid log filter")
+                    .and_then(|filter| {
+                        log_filter_reloader
+                            .reload(filter)
+                            .context("Couldn't reload log filter")
+                    });
+
+                match result {
+                    Ok(()) => {
+                        tracing::info!(%directives, "Reloaded log filter");
+                        ctl::Response::Ok
+                    }
+                    Err(error) => ctl::Response::Error(format!("{error:#}")),
+                }
+            }
+            ctl::Request::Reset => {
+                tracing::info!("Got `ctl` reset, resetting Session");
+                self.reset(session);
+                ctl::Response::Ok
+            }
+        }
+    }
+
+    fn set_resource_enabled(
+        &mut self,
+        id: ResourceId,
+        enabled: bool,
+        session: &Session,
+    ) -> ctl::Response {
+        if !self.resources.iter().any(|resource| resource.id() == id) {
+            return ctl::Response::Error(format!("Unknown Resource {id}"));
+        }
+
+        let changed = if enabled {
+            self.disabled_resources.remove(&id)
+        } else {
+            self.disabled_resources.insert(id)
+        };
+        if changed {
+            tracing::info!(%id, %enabled, "Changing Resource");
+            session.set_disabled_resources(self.disabled_resources.clone());
+        }
+
+        ctl::Response::Ok
+    }
+
+    fn reset(&mut self, session: &Session) {
+        // All connections are torn down, so these would only be stale
+        self.connection_stats.clear();
+        session.reset();
+    }
+}
+
+/// Sends one request to a headless Client's `--ctl-socket` and prints the response
+#[allow(clippy::print_stdout)]
+fn run_ctl(args: CtlArgs) -> Result<()> {
+    let request = match args.command {
+        CtlCmd::ListResources => ctl::Request::ListResources,
+        CtlCmd::Enable { id } => ctl::Request::EnableResource(id),
+        CtlCmd::Disable { id } => ctl::Request::DisableResource(id),
+        CtlCmd::Stats => ctl::Request::ConnectionStats,
+        CtlCmd::DnsCacheStats => ctl::Request::DnsCacheStats,
+        CtlCmd::LogFilter { directives } => ctl::Request::ReloadLogFilter(directives),
+        CtlCmd::Reset => ctl::Request::Reset,
+    };
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    match rt.block_on(ctl::request(&args.socket, request))? {
+        ctl::Response::Ok => {}
+        ctl::Response::Error(error) => bail!(error),
+        ctl::Response::Resources(resources) => {
+            println!("{}", serde_json::to_string_pretty(&resources)?);
+        }
+        ctl::Response::ConnectionStats(stats) => {
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        }
+        ctl::Response::DnsCacheStats { hits, misses } => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(
+                    &serde_json::json!({ "hits": hits, "misses": misses })
+                )?
+            );
+        }
+    }
+
+    Ok(())
+}
+
 /// Read the token from disk if it was not in the environment
 ///
 /// # Returns
@@ -357,9 +612,10 @@ fn read_token_file(path: &Path) -> Result<Option<SecretString>> {
 
 #[cfg(test)]
 mod tests {
-    use super::Cli;
+    use super::{Cli, Cmd, CtlCmd};
     use clap::Parser;
-    use std::path::PathBuf;
+    use connlib_shared::callbacks::{DnsAnswerSource, DnsResolution};
+    use std::{net::IpAddr, path::PathBuf};
     use url::Url;
 
     // Can't remember how Clap works sometimes
@@ -379,5 +635,43 @@ mod tests {
             Cli::try_parse_from([exe_name, "--check", "--log-dir", "bogus_log_dir"]).unwrap();
         assert!(actual.check);
         assert_eq!(actual.common.log_dir, Some(PathBuf::from("bogus_log_dir")));
+
+        let actual = Cli::try_parse_from([
+            exe_name,
+            "ctl",
+            "--socket",
+            "/run/firezone/ctl.sock",
+            "disable",
+            "73037362-715d-4a83-a749-f18eadd970e6",
+        ])
+        .unwrap();
+        let Some(Cmd::Ctl(args)) = actual.command else {
+            panic!("Expected `ctl` subcommand");
+        };
+        assert_eq!(args.socket, PathBuf::from("/run/firezone/ctl.sock"));
+        assert!(matches!(
+            args.command,
+            CtlCmd::Disable { id } if id.to_string() == "73037362-715d-4a83-a749-f18eadd970e6"
+        ));
+    }
+
+    #[test]
+    fn dns_log_line() {
+        let resolution = DnsResolution {
+            name: "app.example.com".parse().unwrap(),
+            qtype: "A".to_owned(),
+            resource: Some("73037362-715d-4a83-a749-f18eadd970e6".parse().unwrap()),
+            ips: vec![IpAddr::from([100, 96, 0, 1])],
+            answered_by: DnsAnswerSource::Local,
+        };
+
+        let line = serde_json::to_string(&resolution).unwrap();
+
+        assert!(!line.contains('\n'));
+        assert!(line.contains(r#""answered_by":"local""#));
+        assert_eq!(
+            serde_json::from_str::<DnsResolution>(&line).unwrap(),
+            resolution
+        );
     }
 }
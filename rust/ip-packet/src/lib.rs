@@ -6,6 +6,7 @@ mod nat46;
 mod nat64;
 #[cfg(feature = "proptest")]
 pub mod proptest;
+pub mod sctp;
 mod slice_utils;
 
 pub use pnet_packet::*;
@@ -29,6 +30,7 @@ use pnet_packet::{
     tcp::{MutableTcpPacket, TcpPacket},
     udp::{MutableUdpPacket, UdpPacket},
 };
+use sctp::{MutableSctpPacket, SctpPacket};
 use std::{
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
     ops::{Deref, DerefMut},
@@ -51,6 +53,10 @@ pub enum Protocol {
     Udp(u16),
     /// Contains the `identifier` of the ICMP packet.
     Icmp(u16),
+    /// Contains either the source or destination port.
+    Sctp(u16),
+    /// GRE doesn't have ports, thus there is at most one GRE session between a pair of IPs.
+    Gre,
 }
 
 impl Protocol {
@@ -60,6 +66,8 @@ impl Protocol {
             (Protocol::Tcp(_), Protocol::Tcp(_))
                 | (Protocol::Udp(_), Protocol::Udp(_))
                 | (Protocol::Icmp(_), Protocol::Icmp(_))
+                | (Protocol::Sctp(_), Protocol::Sctp(_))
+                | (Protocol::Gre, Protocol::Gre)
         )
     }
 
@@ -68,6 +76,8 @@ impl Protocol {
             Protocol::Tcp(v) => *v,
             Protocol::Udp(v) => *v,
             Protocol::Icmp(v) => *v,
+            Protocol::Sctp(v) => *v,
+            Protocol::Gre => 0,
         }
     }
 
@@ -76,6 +86,8 @@ impl Protocol {
             Protocol::Tcp(_) => Protocol::Tcp(value),
             Protocol::Udp(_) => Protocol::Udp(value),
             Protocol::Icmp(_) => Protocol::Icmp(value),
+            Protocol::Sctp(_) => Protocol::Sctp(value),
+            Protocol::Gre => Protocol::Gre,
         }
     }
 }
@@ -120,6 +132,15 @@ pub enum IcmpType {
     V6(icmpv6::Icmpv6Type),
 }
 
+/// The addresses and protocols of a packet that caused an ICMP error, as quoted in the error message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FailedPacket {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub src_proto: Protocol,
+    pub dst_proto: Protocol,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum IcmpEchoRequest<'a> {
     Ipv4(icmp::echo_request::EchoRequestPacket<'a>),
@@ -470,6 +491,10 @@ impl<'a> MutableIpPacket<'a> {
             p.set_source(v);
         }
 
+        if let Some(mut p) = self.as_sctp() {
+            p.set_source(v);
+        }
+
         self.set_icmp_identifier(v);
     }
 
@@ -482,6 +507,10 @@ impl<'a> MutableIpPacket<'a> {
             p.set_destination(v);
         }
 
+        if let Some(mut p) = self.as_sctp() {
+            p.set_destination(v);
+        }
+
         self.set_icmp_identifier(v);
     }
 
@@ -530,6 +559,7 @@ impl<'a> MutableIpPacket<'a> {
         self.set_icmpv4_checksum();
         self.set_udp_checksum();
         self.set_tcp_checksum();
+        self.set_sctp_checksum();
         // Note: Ipv4 checksum should be set after the others,
         // since it's in an upper layer.
         self.set_ipv4_checksum();
@@ -566,6 +596,15 @@ impl<'a> MutableIpPacket<'a> {
             .set_checksum(checksum);
     }
 
+    fn set_sctp_checksum(&mut self) {
+        let Some(mut p) = self.as_sctp() else {
+            return;
+        };
+
+        let checksum = sctp::checksum(&p.to_immutable());
+        p.set_checksum(checksum);
+    }
+
     pub fn into_immutable(self) -> IpPacket<'a> {
         match self {
             Self::Ipv4(p) => p.consume_to_immutable().into(),
@@ -587,13 +626,20 @@ impl<'a> MutableIpPacket<'a> {
             .flatten()
     }
 
-    fn as_tcp(&mut self) -> Option<MutableTcpPacket> {
+    pub fn as_tcp(&mut self) -> Option<MutableTcpPacket> {
         self.to_immutable()
             .is_tcp()
             .then(|| MutableTcpPacket::new(self.payload_mut()))
             .flatten()
     }
 
+    pub fn as_sctp(&mut self) -> Option<MutableSctpPacket> {
+        self.to_immutable()
+            .is_sctp()
+            .then(|| MutableSctpPacket::new(self.payload_mut()))
+            .flatten()
+    }
+
     fn set_icmpv6_checksum(&mut self) {
         let (src_addr, dst_addr) = match self {
             MutableIpPacket::Ipv4(_) => return,
@@ -777,6 +823,14 @@ impl<'a> IpPacket<'a> {
             return Ok(Protocol::Icmp(id));
         }
 
+        if let Some(p) = self.as_sctp() {
+            return Ok(Protocol::Sctp(p.get_source()));
+        }
+
+        if self.is_gre() {
+            return Ok(Protocol::Gre);
+        }
+
         Err(UnsupportedProtocol::UnsupportedIpPayload(
             self.next_header(),
         ))
@@ -800,6 +854,14 @@ impl<'a> IpPacket<'a> {
             return Ok(Protocol::Icmp(id));
         }
 
+        if let Some(p) = self.as_sctp() {
+            return Ok(Protocol::Sctp(p.get_destination()));
+        }
+
+        if self.is_gre() {
+            return Ok(Protocol::Gre);
+        }
+
         Err(UnsupportedProtocol::UnsupportedIpPayload(
             self.next_header(),
         ))
@@ -836,6 +898,14 @@ impl<'a> IpPacket<'a> {
         self.next_header() == IpNextHeaderProtocols::Icmpv6
     }
 
+    fn is_sctp(&self) -> bool {
+        self.next_header() == IpNextHeaderProtocols::Sctp
+    }
+
+    fn is_gre(&self) -> bool {
+        self.next_header() == IpNextHeaderProtocols::Gre
+    }
+
     pub fn as_udp(&self) -> Option<UdpPacket> {
         self.is_udp()
             .then(|| UdpPacket::new(self.payload()))
@@ -866,6 +936,12 @@ impl<'a> IpPacket<'a> {
             .flatten()
     }
 
+    pub fn as_sctp(&self) -> Option<SctpPacket> {
+        self.is_sctp()
+            .then(|| SctpPacket::new(self.payload()))
+            .flatten()
+    }
+
     pub fn as_icmp(&self) -> Option<IcmpPacket> {
         match self {
             IpPacket::Ipv4(v4) if v4.get_next_level_protocol() == IpNextHeaderProtocols::Icmp => {
@@ -939,6 +1015,100 @@ impl<'a> IcmpPacket<'a> {
     pub fn is_echo_request(&self) -> bool {
         self.as_echo_request().is_some()
     }
+
+    /// Whether this is an error about another packet, e.g. "Destination Unreachable" or "Packet Too Big".
+    pub fn is_error(&self) -> bool {
+        match self {
+            IcmpPacket::Ipv4(v4) => matches!(
+                v4.get_icmp_type(),
+                icmp::IcmpTypes::DestinationUnreachable
+                    | icmp::IcmpTypes::TimeExceeded
+                    | icmp::IcmpTypes::ParameterProblem
+            ),
+            IcmpPacket::Ipv6(v6) => matches!(
+                v6.get_icmpv6_type(),
+                icmpv6::Icmpv6Types::DestinationUnreachable
+                    | icmpv6::Icmpv6Types::PacketTooBig
+                    | icmpv6::Icmpv6Types::TimeExceeded
+                    | icmpv6::Icmpv6Types::ParameterProblem
+            ),
+        }
+    }
+
+    /// For an ICMP error, parses the packet that caused it from the quote in the error message.
+    ///
+    /// Only the IP header and the first 8 bytes of the layer 4 header are guaranteed to be quoted,
+    /// so this only reads ports or the ICMP identifier from there.
+    pub fn failed_packet(&self) -> Option<FailedPacket> {
+        if !self.is_error() {
+            return None;
+        }
+
+        // The quote starts after the 4 bytes of type, code and checksum and 4 bytes of type-specific data.
+        let quote = match self {
+            IcmpPacket::Ipv4(v4) => v4.packet(),
+            IcmpPacket::Ipv6(v6) => v6.packet(),
+        }
+        .get(8..)?;
+
+        let (src, dst, next_header, l4) = match quote.first()? >> 4 {
+            4 => {
+                let header_len = usize::from(quote[0] & 0x0f) * 4;
+                let src = Ipv4Addr::from(<[u8; 4]>::try_from(quote.get(12..16)?).ok()?);
+                let dst = Ipv4Addr::from(<[u8; 4]>::try_from(quote.get(16..20)?).ok()?);
+
+                (
+                    IpAddr::from(src),
+                    IpAddr::from(dst),
+                    IpNextHeaderProtocol(*quote.get(9)?),
+                    quote.get(header_len..)?,
+                )
+            }
+            6 => {
+                let src = Ipv6Addr::from(<[u8; 16]>::try_from(quote.get(8..24)?).ok()?);
+                let dst = Ipv6Addr::from(<[u8; 16]>::try_from(quote.get(24..40)?).ok()?);
+
+                (
+                    IpAddr::from(src),
+                    IpAddr::from(dst),
+                    IpNextHeaderProtocol(*quote.get(6)?),
+                    quote.get(40..)?,
+                )
+            }
+            _ => return None,
+        };
+
+        let port = |offset: usize| -> Option<u16> {
+            Some(u16::from_be_bytes([*l4.get(offset)?, *l4.get(offset + 1)?]))
+        };
+
+        // Echo requests are the only ICMP messages that can fail, there are no errors about errors.
+        let echo_request = |echo_request_type: u8| -> Option<(Protocol, Protocol)> {
+            if *l4.first()? != echo_request_type {
+                return None;
+            }
+            let identifier = port(4)?;
+
+            Some((Protocol::Icmp(identifier), Protocol::Icmp(identifier)))
+        };
+
+        let (src_proto, dst_proto) = match next_header {
+            IpNextHeaderProtocols::Tcp => (Protocol::Tcp(port(0)?), Protocol::Tcp(port(2)?)),
+            IpNextHeaderProtocols::Udp => (Protocol::Udp(port(0)?), Protocol::Udp(port(2)?)),
+            IpNextHeaderProtocols::Sctp => (Protocol::Sctp(port(0)?), Protocol::Sctp(port(2)?)),
+            IpNextHeaderProtocols::Gre => (Protocol::Gre, Protocol::Gre),
+            IpNextHeaderProtocols::Icmp => echo_request(icmp::IcmpTypes::EchoRequest.0)?,
+            IpNextHeaderProtocols::Icmpv6 => echo_request(icmpv6::Icmpv6Types::EchoRequest.0)?,
+            _ => return None,
+        };
+
+        Some(FailedPacket {
+            src,
+            dst,
+            src_proto,
+            dst_proto,
+        })
+    }
 }
 
 impl<'a> IcmpEchoRequest<'a> {
@@ -8,7 +8,7 @@ use domain::{
     },
     rdata::AllRecordData,
 };
-use etherparse::PacketBuilder;
+use etherparse::{IpNumber, Ipv4Header, Ipv6Header, PacketBuilder};
 use std::net::{IpAddr, SocketAddr};
 
 /// Helper macro to turn a [`PacketBuilder`] into a [`MutableIpPacket`].
@@ -74,6 +74,65 @@ pub fn icmp_reply_packet(
     }
 }
 
+/// Makes an ICMPv4 or ICMPv6 packet (depending on the IP version) with the given raw type and code.
+pub fn icmp_packet(
+    src: IpAddr,
+    dst: impl Into<IpAddr>,
+    icmp_type: u8,
+    code: u8,
+) -> Result<MutableIpPacket<'static>, IpVersionMismatch> {
+    let payload: &[u8] = &[];
+
+    match (src, dst.into()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let packet = PacketBuilder::ipv4(src.octets(), dst.octets(), 64)
+                .icmpv4_raw(icmp_type, code, [0u8; 4]);
+
+            Ok(build!(packet, payload))
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let packet = PacketBuilder::ipv6(src.octets(), dst.octets(), 64)
+                .icmpv6_raw(icmp_type, code, [0u8; 4]);
+
+            Ok(build!(packet, payload))
+        }
+        _ => Err(IpVersionMismatch),
+    }
+}
+
+/// Makes an ICMP error about `failed`, e.g. "Destination Unreachable", sent by `src` to the sender of `failed`.
+///
+/// Like most routers, it only quotes the IP header and the first 8 bytes of the layer 4 header of `failed`.
+pub fn icmp_error_packet(
+    src: IpAddr,
+    failed: &IpPacket<'_>,
+    icmp_type: u8,
+    code: u8,
+) -> Result<MutableIpPacket<'static>, IpVersionMismatch> {
+    let header_len = match failed {
+        IpPacket::Ipv4(v4) => usize::from(v4.get_header_length()) * 4,
+        IpPacket::Ipv6(_) => 40,
+    };
+    let failed_bytes = pnet_packet::Packet::packet(failed);
+    let payload = &failed_bytes[..failed_bytes.len().min(header_len + 8)];
+
+    match (src, failed.source()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let packet = PacketBuilder::ipv4(src.octets(), dst.octets(), 64)
+                .icmpv4_raw(icmp_type, code, [0u8; 4]);
+
+            Ok(build!(packet, payload))
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let packet = PacketBuilder::ipv6(src.octets(), dst.octets(), 64)
+                .icmpv6_raw(icmp_type, code, [0u8; 4]);
+
+            Ok(build!(packet, payload))
+        }
+        _ => Err(IpVersionMismatch),
+    }
+}
+
 pub fn tcp_packet<IP>(
     saddr: IP,
     daddr: IP,
@@ -101,6 +160,64 @@ where
     }
 }
 
+/// The sequencing information and control flags of a single TCP segment.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TcpControl {
+    pub seq: u32,
+    pub ack: Option<u32>,
+    pub syn: bool,
+    pub fin: bool,
+    pub rst: bool,
+    /// The receive window to advertise, the maximum if `None`.
+    pub window: Option<u16>,
+}
+
+/// Makes a TCP segment with the given sequencing information and flags.
+///
+/// Unlike [`tcp_packet`], this allows driving an actual TCP connection.
+pub fn tcp_segment<IP>(
+    saddr: IP,
+    daddr: IP,
+    sport: u16,
+    dport: u16,
+    control: TcpControl,
+    payload: Vec<u8>,
+) -> Result<MutableIpPacket<'static>, IpVersionMismatch>
+where
+    IP: Into<IpAddr>,
+{
+    let builder = match (saddr.into(), daddr.into()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => PacketBuilder::ipv4(src.octets(), dst.octets(), 64),
+        (IpAddr::V6(src), IpAddr::V6(dst)) => PacketBuilder::ipv6(src.octets(), dst.octets(), 64),
+        _ => return Err(IpVersionMismatch),
+    };
+
+    let mut packet = builder.tcp(
+        sport,
+        dport,
+        control.seq,
+        control.window.unwrap_or(u16::MAX),
+    );
+
+    if let Some(ack) = control.ack {
+        packet = packet.ack(ack);
+    }
+    if control.syn {
+        packet = packet.syn();
+    }
+    if control.fin {
+        packet = packet.fin();
+    }
+    if control.rst {
+        packet = packet.rst();
+    }
+    if !payload.is_empty() {
+        packet = packet.psh();
+    }
+
+    Ok(build!(packet, payload))
+}
+
 pub fn udp_packet<IP>(
     saddr: IP,
     daddr: IP,
@@ -126,6 +243,83 @@ where
     }
 }
 
+/// Makes an SCTP packet with an empty verification tag and a correct checksum.
+pub fn sctp_packet<IP>(
+    saddr: IP,
+    daddr: IP,
+    sport: u16,
+    dport: u16,
+    payload: Vec<u8>,
+) -> Result<MutableIpPacket<'static>, IpVersionMismatch>
+where
+    IP: Into<IpAddr>,
+{
+    let mut sctp = Vec::with_capacity(12 + payload.len());
+    sctp.extend_from_slice(&sport.to_be_bytes());
+    sctp.extend_from_slice(&dport.to_be_bytes());
+    sctp.extend_from_slice(&[0u8; 8]); // Verification tag & checksum; the latter is set by `update_checksum`.
+    sctp.extend_from_slice(&payload);
+
+    raw_ip_packet(saddr.into(), daddr.into(), IpNumber::SCTP, &sctp)
+}
+
+/// Makes a GRE packet carrying the given payload as IPv4.
+pub fn gre_packet<IP>(
+    saddr: IP,
+    daddr: IP,
+    payload: Vec<u8>,
+) -> Result<MutableIpPacket<'static>, IpVersionMismatch>
+where
+    IP: Into<IpAddr>,
+{
+    let mut gre = Vec::with_capacity(4 + payload.len());
+    gre.extend_from_slice(&[0, 0]); // No checksum, key or sequence number.
+    gre.extend_from_slice(&0x0800u16.to_be_bytes());
+    gre.extend_from_slice(&payload);
+
+    raw_ip_packet(saddr.into(), daddr.into(), IpNumber::GRE, &gre)
+}
+
+/// Makes an IP packet for a protocol that [`PacketBuilder`] doesn't know about.
+fn raw_ip_packet(
+    src: IpAddr,
+    dst: IpAddr,
+    protocol: IpNumber,
+    payload: &[u8],
+) -> Result<MutableIpPacket<'static>, IpVersionMismatch> {
+    let payload_len = u16::try_from(payload.len()).expect("Payload should fit into an IP packet");
+    let mut buf = vec![0u8; 20];
+
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            Ipv4Header::new(payload_len, 64, protocol, src.octets(), dst.octets())
+                .expect("Payload should fit into an IPv4 packet")
+                .write(&mut buf)
+                .expect("Writing to a `Vec` never fails");
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            Ipv6Header {
+                payload_length: payload_len,
+                next_header: protocol,
+                hop_limit: 64,
+                source: src.octets(),
+                destination: dst.octets(),
+                ..Default::default()
+            }
+            .write(&mut buf)
+            .expect("Writing to a `Vec` never fails");
+        }
+        _ => return Err(IpVersionMismatch),
+    }
+
+    buf.extend_from_slice(payload);
+
+    let mut packet = MutableIpPacket::owned(buf).expect("Should be a valid IP packet");
+    packet.update_checksum();
+
+    Ok(packet)
+}
+
 pub fn dns_query(
     domain: Name<Vec<u8>>,
     kind: Rtype,
@@ -0,0 +1,135 @@
+//! Minimal support for SCTP (RFC 9260) packets.
+//!
+//! We only ever need to read and rewrite the ports of the SCTP common header, so we don't pull in a full SCTP implementation for this.
+//! Rewriting the ports invalidates the CRC32c checksum of the packet which we can recompute with [`checksum`].
+
+const HEADER_LEN: usize = 12;
+const CHECKSUM_OFFSET: usize = 8;
+
+#[derive(Debug, PartialEq)]
+pub struct SctpPacket<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> SctpPacket<'a> {
+    pub fn new(buf: &'a [u8]) -> Option<Self> {
+        (buf.len() >= HEADER_LEN).then_some(Self { buf })
+    }
+
+    pub fn get_source(&self) -> u16 {
+        u16::from_be_bytes([self.buf[0], self.buf[1]])
+    }
+
+    pub fn get_destination(&self) -> u16 {
+        u16::from_be_bytes([self.buf[2], self.buf[3]])
+    }
+
+    pub fn get_verification_tag(&self) -> u32 {
+        u32::from_be_bytes([self.buf[4], self.buf[5], self.buf[6], self.buf[7]])
+    }
+
+    pub fn get_checksum(&self) -> u32 {
+        u32::from_le_bytes([self.buf[8], self.buf[9], self.buf[10], self.buf[11]])
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.buf[HEADER_LEN..]
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MutableSctpPacket<'a> {
+    buf: &'a mut [u8],
+}
+
+impl<'a> MutableSctpPacket<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Option<Self> {
+        (buf.len() >= HEADER_LEN).then_some(Self { buf })
+    }
+
+    pub fn to_immutable(&self) -> SctpPacket<'_> {
+        SctpPacket { buf: self.buf }
+    }
+
+    pub fn set_source(&mut self, port: u16) {
+        self.buf[0..2].copy_from_slice(&port.to_be_bytes());
+    }
+
+    pub fn set_destination(&mut self, port: u16) {
+        self.buf[2..4].copy_from_slice(&port.to_be_bytes());
+    }
+
+    pub fn set_checksum(&mut self, checksum: u32) {
+        // The CRC32c is transmitted in "reflected" byte order, see RFC 9260, Appendix A.
+        self.buf[CHECKSUM_OFFSET..HEADER_LEN].copy_from_slice(&checksum.to_le_bytes());
+    }
+}
+
+/// Computes the CRC32c checksum of an SCTP packet, treating the checksum field itself as zero.
+///
+/// Unlike TCP and UDP, SCTP doesn't use a pseudo-header so the checksum only depends on the SCTP packet.
+pub fn checksum(packet: &SctpPacket<'_>) -> u32 {
+    let buf = packet.buf;
+
+    let crc = crc32c_update(!0, &buf[..CHECKSUM_OFFSET]);
+    let crc = crc32c_update(crc, &[0u8; 4]);
+    let crc = crc32c_update(crc, &buf[HEADER_LEN..]);
+
+    !crc
+}
+
+fn crc32c_update(mut crc: u32, data: &[u8]) -> u32 {
+    const POLYNOMIAL: u32 = 0x82F6_3B78; // Castagnoli, reversed.
+
+    for byte in data {
+        crc ^= u32::from(*byte);
+
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(!crc32c_update(!0, b"123456789"), 0xE306_9283);
+    }
+
+    #[test]
+    fn checksum_ignores_existing_checksum_field() {
+        let mut buf = [
+            0x13, 0x88, 0x13, 0x89, 0x00, 0x00, 0x00, 0x00, 0xde, 0xad, 0xbe, 0xef,
+        ];
+        let expected = checksum(&SctpPacket::new(&buf).unwrap());
+
+        let mut packet = MutableSctpPacket::new(&mut buf).unwrap();
+        packet.set_checksum(expected);
+
+        let packet = packet.to_immutable();
+        assert_eq!(packet.get_checksum(), expected);
+        assert_eq!(checksum(&packet), expected);
+    }
+
+    #[test]
+    fn can_rewrite_ports() {
+        let mut buf = [0u8; HEADER_LEN];
+
+        let mut packet = MutableSctpPacket::new(&mut buf).unwrap();
+        packet.set_source(5000);
+        packet.set_destination(5001);
+
+        let packet = packet.to_immutable();
+        assert_eq!(packet.get_source(), 5000);
+        assert_eq!(packet.get_destination(), 5001);
+    }
+}
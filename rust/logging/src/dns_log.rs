@@ -0,0 +1,86 @@
+//! A log of all DNS queries resolved by connlib, see `--dns-log` of the headless Client.
+//!
+//! Each resolution is emitted as an event with the [`TARGET`] target whose message is a single line of JSON.
+
+use std::{fmt, fs, io, path::Path};
+use tracing::{Event, Level, Subscriber};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::{
+    filter::Targets,
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
+    registry::LookupSpan,
+    Layer, Registry,
+};
+
+/// The target of events that end up in the DNS log.
+pub const TARGET: &str = "dns_log";
+
+/// Create a new layer that appends the message of every event with the [`TARGET`] target to the file at `path`.
+///
+/// The layer brings its own filter, so the DNS log is complete regardless of `RUST_LOG`.
+/// The writes happen on a separate thread and the returned guard must be kept alive for them to arrive at the file.
+pub fn layer(
+    path: &Path,
+) -> io::Result<(
+    Box<dyn Layer<Registry> + Send + Sync + 'static>,
+    WorkerGuard,
+)> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let (writer, guard) = tracing_appender::non_blocking(file);
+
+    let layer = tracing_subscriber::fmt::layer()
+        .event_format(MessageOnly)
+        .with_ansi(false)
+        .with_writer(writer)
+        .with_filter(Targets::new().with_target(TARGET, Level::INFO))
+        .boxed();
+
+    Ok((layer, guard))
+}
+
+/// Formats an event as just its message, without timestamp, level or spans.
+struct MessageOnly;
+
+impl<S, N> FormatEvent<S, N> for MessageOnly
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        ctx.format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt as _;
+
+    #[test]
+    fn writes_one_line_per_event() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dns.jsonl");
+
+        let (layer, guard) = layer(&path).unwrap();
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            tracing::info!(target: TARGET, r#"{{"name":"app.example.com"}}"#);
+            tracing::info!("Not a DNS resolution");
+            tracing::info!(target: TARGET, r#"{{"name":"foo.example.com"}}"#);
+        });
+        drop(guard); // Flushes the log.
+
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap(),
+            "{\"name\":\"app.example.com\"}\n{\"name\":\"foo.example.com\"}\n"
+        );
+    }
+}
@@ -1,25 +1,41 @@
+pub mod dns_log;
 pub mod file;
 
 use tracing::subscriber::DefaultGuard;
 use tracing_log::LogTracer;
 use tracing_subscriber::{
-    filter::ParseError, fmt, layer::SubscriberExt as _, util::SubscriberInitExt, EnvFilter, Layer,
-    Registry,
+    filter::{filter_fn, ParseError},
+    fmt,
+    layer::SubscriberExt as _,
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
 };
 
-/// Registers a global subscriber with stdout logging and `additional_layer`
-pub fn setup_global_subscriber<L>(additional_layer: L)
+/// Registers a global subscriber with stdout logging, `additional_layer` and optionally the [`dns_log`].
+///
+/// Stdout logging and `additional_layer` are filtered by `RUST_LOG`, the returned handle replaces that filter at runtime.
+/// The DNS log has its own filter and doesn't end up in the other logs.
+pub fn setup_global_subscriber<L>(
+    additional_layer: L,
+    dns_log: Option<Box<dyn Layer<Registry> + Send + Sync>>,
+) -> reload::Handle<EnvFilter, Registry>
 where
-    L: Layer<Registry> + Send + Sync,
+    L: Layer<Registry> + Send + Sync + 'static,
 {
     let directives = std::env::var("RUST_LOG").unwrap_or_default();
+    let (filter, reloader) = reload::Layer::new(filter(&directives));
 
-    let subscriber = Registry::default()
-        .with(additional_layer)
-        .with(fmt::layer())
-        .with(filter(&directives));
+    let layer = additional_layer
+        .and_then(fmt::layer())
+        .with_filter(filter)
+        .with_filter(filter_fn(|metadata| metadata.target() != dns_log::TARGET));
+
+    let subscriber = Registry::default().with(vec![layer.boxed(), dns_log.boxed()]);
     tracing::subscriber::set_global_default(subscriber).expect("Could not set global default");
     LogTracer::init().unwrap();
+
+    reloader
 }
 
 /// Constructs an opinionated [`EnvFilter`] with some crates already silenced.
@@ -0,0 +1,84 @@
+use crate::{Connecting, Error, Event, OutboundRequestId, PhoenixChannel};
+use serde::{de::DeserializeOwned, Serialize};
+use std::net::IpAddr;
+use std::task::{Context, Poll};
+
+/// A connection to the control plane that tells our components what to do.
+///
+/// [`PhoenixChannel`] talks to the portal via a WebSocket.
+/// [`Loopback`](crate::Loopback) delivers messages within the same process, e.g. to a scripted portal in tests or an embedding orchestrator.
+pub trait ControlPlane<TInboundMsg, TOutboundRes> {
+    /// Join the provided room.
+    ///
+    /// If successful, a [`Event::JoinedRoom`] event will be emitted.
+    fn join(&mut self, topic: impl Into<String>, payload: impl Serialize);
+
+    /// Send a message to a topic.
+    ///
+    /// The message may be lost if the connection fails before the control plane processed it.
+    fn send(&mut self, topic: impl Into<String>, message: impl Serialize) -> OutboundRequestId;
+
+    /// Send a message to a topic and re-send it after reconnecting until the control plane replies to it.
+    fn send_reliable(
+        &mut self,
+        topic: impl Into<String>,
+        message: impl Serialize,
+    ) -> OutboundRequestId;
+
+    /// Re-establishes the connection to the control plane.
+    fn reconnect(&mut self);
+
+    /// Initiate a graceful close of the connection.
+    fn close(&mut self) -> Result<(), Connecting>;
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Event<TInboundMsg, TOutboundRes>, Error>>;
+
+    /// The host of the control plane and the addresses it resolved to, `None` if it isn't reached over the network.
+    fn resolved_host(&self) -> Option<(String, Vec<IpAddr>)>;
+}
+
+impl<TInitReq, TInboundMsg, TOutboundRes> ControlPlane<TInboundMsg, TOutboundRes>
+    for PhoenixChannel<TInitReq, TInboundMsg, TOutboundRes>
+where
+    TInitReq: Serialize + Clone,
+    TInboundMsg: DeserializeOwned,
+    TOutboundRes: DeserializeOwned,
+{
+    fn join(&mut self, topic: impl Into<String>, payload: impl Serialize) {
+        PhoenixChannel::join(self, topic, payload)
+    }
+
+    fn send(&mut self, topic: impl Into<String>, message: impl Serialize) -> OutboundRequestId {
+        PhoenixChannel::send(self, topic, message)
+    }
+
+    fn send_reliable(
+        &mut self,
+        topic: impl Into<String>,
+        message: impl Serialize,
+    ) -> OutboundRequestId {
+        PhoenixChannel::send_reliable(self, topic, message)
+    }
+
+    fn reconnect(&mut self) {
+        PhoenixChannel::reconnect(self)
+    }
+
+    fn close(&mut self) -> Result<(), Connecting> {
+        PhoenixChannel::close(self)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Event<TInboundMsg, TOutboundRes>, Error>> {
+        PhoenixChannel::poll(self, cx)
+    }
+
+    fn resolved_host(&self) -> Option<(String, Vec<IpAddr>)> {
+        Some((self.server_host().to_owned(), self.resolved_addresses()))
+    }
+}
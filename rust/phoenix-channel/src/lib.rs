@@ -1,7 +1,11 @@
+mod control_plane;
 mod heartbeat;
 mod login_url;
+mod loopback;
+mod outbound;
+mod proxy;
 
-use std::collections::{HashSet, VecDeque};
+use std::collections::HashSet;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
@@ -14,6 +18,7 @@ use base64::Engine;
 use futures::future::BoxFuture;
 use futures::{FutureExt, SinkExt, StreamExt};
 use heartbeat::{Heartbeat, MissedLastHeartbeat};
+use outbound::{Kind, Outbound, OutboundQueue};
 use rand_core::{OsRng, RngCore};
 use secrecy::{ExposeSecret, Secret};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
@@ -27,14 +32,18 @@ use tokio_tungstenite::{
 };
 use url::{Host, Url};
 
+pub use control_plane::ControlPlane;
 pub use login_url::{LoginUrl, LoginUrlError};
+pub use loopback::{loopback, Loopback, LoopbackPortal, LoopbackRequest};
+pub use proxy::{InvalidProxy, Proxy};
 
 pub struct PhoenixChannel<TInitReq, TInboundMsg, TOutboundRes> {
     state: State,
     waker: Option<Waker>,
-    pending_messages: VecDeque<String>,
+    outbound: OutboundQueue,
     next_request_id: Arc<AtomicU64>,
     socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
+    proxy: Option<Proxy>,
 
     heartbeat: Heartbeat,
 
@@ -67,8 +76,11 @@ impl State {
         url: Secret<LoginUrl>,
         user_agent: String,
         socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
+        proxy: Option<Proxy>,
     ) -> Self {
-        Self::Connecting(create_and_connect_websocket(url, user_agent, socket_factory).boxed())
+        Self::Connecting(
+            create_and_connect_websocket(url, user_agent, socket_factory, proxy).boxed(),
+        )
     }
 }
 
@@ -76,8 +88,14 @@ async fn create_and_connect_websocket(
     url: Secret<LoginUrl>,
     user_agent: String,
     socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
+    proxy: Option<Proxy>,
 ) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, InternalError> {
-    let socket = make_socket(url.expose_secret().inner(), &*socket_factory).await?;
+    let socket = make_socket(
+        url.expose_secret().inner(),
+        &*socket_factory,
+        proxy.as_ref(),
+    )
+    .await?;
 
     let (stream, _) = client_async_tls(make_request(url, user_agent), socket)
         .await
@@ -89,11 +107,34 @@ async fn create_and_connect_websocket(
 async fn make_socket(
     url: &Url,
     socket_factory: &dyn SocketFactory<TcpSocket>,
+    proxy: Option<&Proxy>,
 ) -> Result<TcpStream, InternalError> {
     let port = url
         .port_or_known_default()
         .expect("scheme to be http, https, ws or wss");
-    let addrs: Vec<SocketAddr> = match url.host().ok_or(InternalError::InvalidUrl)? {
+    let host = url.host().ok_or(InternalError::InvalidUrl)?;
+
+    let Some(proxy) = proxy.filter(|p| !p.bypasses(&host)) else {
+        return connect_to_host(host, port, socket_factory).await;
+    };
+
+    tracing::debug!(%proxy, "Connecting to portal through proxy");
+
+    let mut socket = connect_to_host(proxy.host(), proxy.port(), socket_factory).await?;
+    proxy
+        .handshake(&mut socket, host, port)
+        .await
+        .map_err(InternalError::Proxy)?;
+
+    Ok(socket)
+}
+
+async fn connect_to_host(
+    host: Host<&str>,
+    port: u16,
+    socket_factory: &dyn SocketFactory<TcpSocket>,
+) -> Result<TcpStream, InternalError> {
+    let addrs: Vec<SocketAddr> = match host {
         Host::Domain(n) => tokio::net::lookup_host((n, port))
             .await
             .map_err(|_| InternalError::InvalidUrl)?
@@ -158,6 +199,7 @@ enum InternalError {
     StreamClosed,
     InvalidUrl,
     SocketConnection(std::io::Error),
+    Proxy(std::io::Error),
 }
 
 impl fmt::Display for InternalError {
@@ -180,6 +222,7 @@ impl fmt::Display for InternalError {
             InternalError::StreamClosed => write!(f, "websocket stream was closed"),
             InternalError::InvalidUrl => write!(f, "failed to resolve url"),
             InternalError::SocketConnection(e) => write!(f, "failed to connect socket: {e}"),
+            InternalError::Proxy(e) => write!(f, "proxy failed to open tunnel: {e}"),
         }
     }
 }
@@ -222,6 +265,8 @@ where
     ///
     /// The provided URL must contain a host.
     /// Additionally, you must already provide any query parameters required for authentication.
+    ///
+    /// If a [`Proxy`] is given, we tunnel the connection through it.
     pub fn connect(
         url: Secret<LoginUrl>,
         user_agent: String,
@@ -229,19 +274,23 @@ where
         init_req: TInitReq,
         reconnect_backoff: ExponentialBackoff,
         socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
+        proxy: Option<Proxy>,
     ) -> io::Result<Self> {
         let next_request_id = Arc::new(AtomicU64::new(0));
 
         // Statically resolve the host in the URL to a set of addresses.
         // We don't use these directly because we need to connect to the domain via TLS which requires a hostname.
         // We expose them to other components that deal with DNS stuff to ensure our domain always resolves to these IPs.
-        let resolved_addresses = url
-            .expose_secret()
-            .inner()
-            .socket_addrs(|| None)?
-            .iter()
-            .map(|addr| addr.ip())
-            .collect();
+        let resolved_addresses = match url.expose_secret().inner().socket_addrs(|| None) {
+            Ok(addrs) => addrs.iter().map(|addr| addr.ip()).collect(),
+            // Networks that force all traffic through a proxy often can't resolve public hostnames, the proxy does that for us.
+            Err(e) if proxy.is_some() => {
+                tracing::debug!("Failed to resolve portal host, relying on proxy: {e}");
+
+                Vec::new()
+            }
+            Err(e) => return Err(e),
+        };
 
         tracing::debug!(host = %url.expose_secret().host(), %user_agent, "Connecting to portal");
 
@@ -249,10 +298,11 @@ where
             reconnect_backoff,
             url: url.clone(),
             user_agent: user_agent.clone(),
-            state: State::connect(url, user_agent, socket_factory.clone()),
+            state: State::connect(url, user_agent, socket_factory.clone(), proxy.clone()),
             socket_factory,
+            proxy,
             waker: None,
-            pending_messages: Default::default(),
+            outbound: Default::default(),
             _phantom: PhantomData,
             heartbeat: Heartbeat::new(
                 heartbeat::INTERVAL,
@@ -281,16 +331,34 @@ where
     ///
     /// If successful, a [`Event::JoinedRoom`] event will be emitted.
     pub fn join(&mut self, topic: impl Into<String>, payload: impl Serialize) {
-        let (request_id, msg) = self.make_message(topic, EgressControlMessage::PhxJoin(payload));
-        self.pending_messages.push_front(msg); // Must send the join message before all others.
+        let (request_id, msg) =
+            self.make_message(topic, EgressControlMessage::PhxJoin(payload), Kind::Control);
+        self.outbound.push_front(msg); // Must send the join message before all others.
 
         self.pending_join_requests.insert(request_id);
     }
 
     /// Send a message to a topic.
+    ///
+    /// The message is lost if the connection fails before the portal processed it.
     pub fn send(&mut self, topic: impl Into<String>, message: impl Serialize) -> OutboundRequestId {
-        let (id, msg) = self.make_message(topic, message);
-        self.pending_messages.push_back(msg);
+        let (id, msg) = self.make_message(topic, message, Kind::Message);
+        self.outbound.push_back(msg);
+
+        id
+    }
+
+    /// Send a message to a topic and re-send it after reconnecting until the portal replies to it.
+    ///
+    /// A re-sent message keeps its [`OutboundRequestId`], so the eventual reply still correlates with it.
+    /// Only use this for idempotent messages that the portal replies to: the portal may see a message twice if only its reply got lost.
+    pub fn send_reliable(
+        &mut self,
+        topic: impl Into<String>,
+        message: impl Serialize,
+    ) -> OutboundRequestId {
+        let (id, msg) = self.make_message(topic, message, Kind::Reliable);
+        self.outbound.push_back(msg);
 
         id
     }
@@ -299,11 +367,17 @@ where
     pub fn reconnect(&mut self) {
         // 1. Reset the backoff.
         self.reconnect_backoff.reset();
+        self.prepare_reconnect();
 
         // 2. Set state to `Connecting` without a timer.
         let url = self.url.clone();
         let user_agent = self.user_agent.clone();
-        self.state = State::connect(url, user_agent, self.socket_factory.clone());
+        self.state = State::connect(
+            url,
+            user_agent,
+            self.socket_factory.clone(),
+            self.proxy.clone(),
+        );
 
         // 3. In case we were already re-connecting, we need to wake the suspended task.
         if let Some(waker) = self.waker.take() {
@@ -377,13 +451,19 @@ where
                         let secret_url = self.url.clone();
                         let user_agent = self.user_agent.clone();
                         let socket_factory = self.socket_factory.clone();
+                        let proxy = self.proxy.clone();
 
                         tracing::debug!(?backoff, max_elapsed_time = ?self.reconnect_backoff.max_elapsed_time, "Reconnecting to portal on transient client error: {e}");
 
                         self.state = State::Connecting(Box::pin(async move {
                             tokio::time::sleep(backoff).await;
-                            create_and_connect_websocket(secret_url, user_agent, socket_factory)
-                                .await
+                            create_and_connect_websocket(
+                                secret_url,
+                                user_agent,
+                                socket_factory,
+                                proxy,
+                            )
+                            .await
                         }));
 
                         continue;
@@ -399,10 +479,11 @@ where
             // Priority 1: Keep local buffers small and send pending messages.
             match stream.poll_ready_unpin(cx) {
                 Poll::Ready(Ok(())) => {
-                    if let Some(message) = self.pending_messages.pop_front() {
-                        match stream.start_send_unpin(Message::Text(message.clone())) {
+                    if let Some(message) = self.outbound.pop_front() {
+                        match stream.start_send_unpin(Message::Text(message.text.clone())) {
                             Ok(()) => {
-                                tracing::trace!(target: "wire::api::send", %message);
+                                tracing::trace!(target: "wire::api::send", message = %message.text);
+                                self.outbound.sent(message);
 
                                 match stream.poll_flush_unpin(cx) {
                                     Poll::Ready(Ok(())) => {
@@ -418,7 +499,7 @@ where
                                 }
                             }
                             Err(e) => {
-                                self.pending_messages.push_front(message);
+                                self.outbound.push_front(message);
                                 self.reconnect_on_transient_error(InternalError::WebSocket(e));
                             }
                         }
@@ -457,6 +538,12 @@ where
                         }
                     };
 
+                    if let (Payload::Reply(_), Some(req_id)) =
+                        (&message.payload, &message.reference)
+                    {
+                        self.outbound.acknowledge(&message.topic, req_id);
+                    }
+
                     match (message.payload, message.reference) {
                         (Payload::Message(msg), _) => {
                             return Poll::Ready(Ok(Event::InboundMessage {
@@ -542,11 +629,16 @@ where
             // Priority 3: Handle heartbeats.
             match self.heartbeat.poll(cx) {
                 Poll::Ready(Ok(id)) => {
-                    self.pending_messages.push_back(serialize_msg(
-                        "phoenix",
-                        EgressControlMessage::<()>::Heartbeat(Empty {}),
-                        id.copy(),
-                    ));
+                    self.outbound.push_back(Outbound {
+                        text: serialize_msg(
+                            "phoenix",
+                            EgressControlMessage::<()>::Heartbeat(Empty {}),
+                            id.copy(),
+                        ),
+                        id,
+                        topic: "phoenix".to_owned(),
+                        kind: Kind::Control,
+                    });
 
                     return Poll::Ready(Ok(Event::HeartbeatSent));
                 }
@@ -565,20 +657,39 @@ where
     ///
     /// The [`PhoenixChannel::poll`] function will handle the reconnect if appropriate for the given error.
     fn reconnect_on_transient_error(&mut self, e: InternalError) {
+        self.prepare_reconnect();
         self.state = State::Connecting(future::ready(Err(e)).boxed())
     }
 
+    /// Discards all state that is specific to the current connection.
+    ///
+    /// We re-join once connected again, so replies to the current join requests will never arrive.
+    fn prepare_reconnect(&mut self) {
+        self.outbound.reconnecting();
+        self.pending_join_requests.clear();
+    }
+
     fn make_message(
         &mut self,
         topic: impl Into<String>,
         payload: impl Serialize,
-    ) -> (OutboundRequestId, String) {
+        kind: Kind,
+    ) -> (OutboundRequestId, Outbound) {
+        let topic = topic.into();
         let request_id = self.fetch_add_request_id();
 
         // We don't care about the reply type when serializing
-        let msg = serialize_msg(topic, payload, request_id.copy());
-
-        (request_id, msg)
+        let text = serialize_msg(topic.clone(), payload, request_id.copy());
+
+        (
+            request_id.copy(),
+            Outbound {
+                id: request_id,
+                topic,
+                text,
+                kind,
+            },
+        )
     }
 
     fn fetch_add_request_id(&mut self) -> OutboundRequestId {
@@ -719,8 +830,7 @@ impl<T, R> PhoenixMessage<T, R> {
         }
     }
 
-    #[cfg(test)]
-    fn new_err_reply(
+    pub fn new_err_reply(
         topic: impl Into<String>,
         reason: ErrorReply,
         reference: Option<OutboundRequestId>,
@@ -937,4 +1047,87 @@ mod tests {
 
         assert_eq!(actual, expected)
     }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "snake_case", tag = "event", content = "payload")]
+    enum Ping {
+        Ping { seq: u32 },
+    }
+
+    #[tokio::test]
+    async fn replied_messages_are_not_resent_after_reconnect() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = LoginUrl::relay(
+            Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap(),
+            &secrecy::SecretString::new("token".to_owned()),
+            None,
+            3478,
+            None,
+            None,
+        )
+        .unwrap();
+        let mut channel = PhoenixChannel::<(), (), ()>::connect(
+            Secret::new(url),
+            "test".to_owned(),
+            "relay",
+            (),
+            backoff::ExponentialBackoffBuilder::default()
+                .with_initial_interval(std::time::Duration::from_millis(10))
+                .build(),
+            Arc::new(socket_factory::tcp),
+            None,
+        )
+        .unwrap();
+
+        channel.send_reliable("relay", Ping::Ping { seq: 0 });
+        let unacked = channel.send_reliable("relay", Ping::Ping { seq: 1 });
+
+        let mut portal = tokio::spawn(async move {
+            let mut ws = accept(&listener).await;
+            assert_eq!(next_message(&mut ws).await["event"], "phx_join");
+            let ping = next_message(&mut ws).await;
+            reply_ok(&mut ws, &ping).await;
+            next_message(&mut ws).await;
+            ws.close(None).await.unwrap();
+
+            let mut ws = accept(&listener).await;
+            assert_eq!(next_message(&mut ws).await["event"], "phx_join");
+            next_message(&mut ws).await
+        });
+
+        let resent = loop {
+            tokio::select! {
+                resent = &mut portal => break resent.unwrap(),
+                event = future::poll_fn(|cx| channel.poll(cx)) => {
+                    event.unwrap();
+                }
+            }
+        };
+
+        assert_eq!(resent["payload"]["seq"], 1);
+        assert_eq!(resent["ref"], unacked.0);
+    }
+
+    async fn accept(listener: &tokio::net::TcpListener) -> WebSocketStream<tokio::net::TcpStream> {
+        let (stream, _) = listener.accept().await.unwrap();
+
+        tokio_tungstenite::accept_async(stream).await.unwrap()
+    }
+
+    async fn next_message(ws: &mut WebSocketStream<tokio::net::TcpStream>) -> serde_json::Value {
+        let message = ws.next().await.unwrap().unwrap();
+
+        serde_json::from_str(message.to_text().unwrap()).unwrap()
+    }
+
+    async fn reply_ok(ws: &mut WebSocketStream<tokio::net::TcpStream>, msg: &serde_json::Value) {
+        let reply = serde_json::json!({
+            "topic": msg["topic"],
+            "event": "phx_reply",
+            "ref": msg["ref"],
+            "payload": { "status": "ok", "response": {} }
+        });
+
+        ws.send(Message::Text(reply.to_string())).await.unwrap();
+    }
 }
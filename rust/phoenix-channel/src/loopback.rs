@@ -0,0 +1,273 @@
+//! An in-process [`ControlPlane`].
+//!
+//! [`loopback`] returns two connected halves: the [`Loopback`] is handed to the component under control, e.g. connlib's `Eventloop`.
+//! The [`LoopbackPortal`] plays the portal: it receives everything the component sends and decides what to respond with.
+
+use crate::{Connecting, ControlPlane, Error, ErrorReply, Event, OutboundRequestId};
+use futures::channel::mpsc;
+use futures::StreamExt as _;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::task::{Context, Poll};
+
+/// Creates a [`Loopback`] control plane together with the [`LoopbackPortal`] that drives it.
+///
+/// Like [`PhoenixChannel`](crate::PhoenixChannel), the [`Loopback`] joins `login` with `init_req` right away and again on every [`reconnect`](ControlPlane::reconnect).
+pub fn loopback<TInboundMsg, TOutboundRes>(
+    login: &'static str,
+    init_req: impl Serialize,
+) -> (
+    Loopback<TInboundMsg, TOutboundRes>,
+    LoopbackPortal<TInboundMsg, TOutboundRes>,
+) {
+    let (request_tx, request_rx) = mpsc::unbounded();
+    let (event_tx, event_rx) = mpsc::unbounded();
+
+    let mut loopback = Loopback {
+        login,
+        init_req: to_value(init_req),
+        next_request_id: 0,
+        requests: request_tx,
+        events: event_rx,
+        closed: false,
+    };
+    loopback.join(login, loopback.init_req.clone());
+
+    let portal = LoopbackPortal {
+        requests: request_rx,
+        events: event_tx,
+    };
+
+    (loopback, portal)
+}
+
+/// The half of a [`loopback`] control plane that is used by the component under control.
+pub struct Loopback<TInboundMsg, TOutboundRes> {
+    login: &'static str,
+    init_req: serde_json::Value,
+    next_request_id: u64,
+
+    requests: mpsc::UnboundedSender<LoopbackRequest>,
+    events: mpsc::UnboundedReceiver<Result<Event<TInboundMsg, TOutboundRes>, Error>>,
+
+    closed: bool,
+}
+
+/// The half of a [`loopback`] control plane that plays the portal.
+pub struct LoopbackPortal<TInboundMsg, TOutboundRes> {
+    requests: mpsc::UnboundedReceiver<LoopbackRequest>,
+    events: mpsc::UnboundedSender<Result<Event<TInboundMsg, TOutboundRes>, Error>>,
+}
+
+/// A message sent to the [`LoopbackPortal`].
+///
+/// Payloads are passed as JSON values so the portal can deserialize them into its own types, just like the real portal would.
+#[derive(Debug)]
+pub enum LoopbackRequest {
+    Join {
+        topic: String,
+        payload: serde_json::Value,
+    },
+    Message {
+        topic: String,
+        id: OutboundRequestId,
+        payload: serde_json::Value,
+    },
+}
+
+impl<TInboundMsg, TOutboundRes> ControlPlane<TInboundMsg, TOutboundRes>
+    for Loopback<TInboundMsg, TOutboundRes>
+{
+    fn join(&mut self, topic: impl Into<String>, payload: impl Serialize) {
+        let _ = self.requests.unbounded_send(LoopbackRequest::Join {
+            topic: topic.into(),
+            payload: to_value(payload),
+        });
+    }
+
+    fn send(&mut self, topic: impl Into<String>, message: impl Serialize) -> OutboundRequestId {
+        let id = OutboundRequestId(self.next_request_id);
+        self.next_request_id += 1;
+
+        let _ = self.requests.unbounded_send(LoopbackRequest::Message {
+            topic: topic.into(),
+            id: id.copy(),
+            payload: to_value(message),
+        });
+
+        id
+    }
+
+    /// Messages within the same process cannot get lost, so this is the same as [`ControlPlane::send`].
+    fn send_reliable(
+        &mut self,
+        topic: impl Into<String>,
+        message: impl Serialize,
+    ) -> OutboundRequestId {
+        self.send(topic, message)
+    }
+
+    fn reconnect(&mut self) {
+        self.closed = false;
+        self.join(self.login, self.init_req.clone());
+    }
+
+    fn close(&mut self) -> Result<(), Connecting> {
+        self.closed = true;
+
+        Ok(())
+    }
+
+    /// Once the [`LoopbackPortal`] is dropped, this never returns another event, just like a [`PhoenixChannel`](crate::PhoenixChannel) that keeps trying to reconnect.
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Event<TInboundMsg, TOutboundRes>, Error>> {
+        if self.closed {
+            return Poll::Ready(Ok(Event::Closed));
+        }
+
+        match self.events.poll_next_unpin(cx) {
+            Poll::Ready(Some(event)) => Poll::Ready(event),
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn resolved_host(&self) -> Option<(String, Vec<IpAddr>)> {
+        None
+    }
+}
+
+impl<TInboundMsg, TOutboundRes> LoopbackPortal<TInboundMsg, TOutboundRes> {
+    /// Waits for the next message from the [`Loopback`], returns `None` once it has been dropped.
+    pub async fn next_request(&mut self) -> Option<LoopbackRequest> {
+        self.requests.next().await
+    }
+
+    /// Returns the next message from the [`Loopback`] if there is one.
+    pub fn try_next_request(&mut self) -> Option<LoopbackRequest> {
+        self.requests.try_next().ok().flatten()
+    }
+
+    /// Confirms that the [`Loopback`] joined the given topic.
+    pub fn joined(&self, topic: impl Into<String>) {
+        self.emit(Ok(Event::JoinedRoom {
+            topic: topic.into(),
+        }));
+    }
+
+    /// Pushes a message to the [`Loopback`].
+    pub fn push(&self, topic: impl Into<String>, msg: TInboundMsg) {
+        self.emit(Ok(Event::InboundMessage {
+            topic: topic.into(),
+            msg,
+        }));
+    }
+
+    /// Replies to a [`LoopbackRequest::Message`] with a successful response.
+    pub fn reply_ok(&self, topic: impl Into<String>, req_id: OutboundRequestId, res: TOutboundRes) {
+        self.emit(Ok(Event::SuccessResponse {
+            topic: topic.into(),
+            req_id,
+            res,
+        }));
+    }
+
+    /// Replies to a [`LoopbackRequest::Message`] with an error.
+    pub fn reply_error(
+        &self,
+        topic: impl Into<String>,
+        req_id: OutboundRequestId,
+        res: ErrorReply,
+    ) {
+        self.emit(Ok(Event::ErrorResponse {
+            topic: topic.into(),
+            req_id,
+            res,
+        }));
+    }
+
+    /// Fails the [`Loopback`] with the given error, e.g. [`Error::TokenExpired`].
+    pub fn fail(&self, error: Error) {
+        self.emit(Err(error));
+    }
+
+    fn emit(&self, event: Result<Event<TInboundMsg, TOutboundRes>, Error>) {
+        let _ = self.events.unbounded_send(event);
+    }
+}
+
+fn to_value(payload: impl Serialize) -> serde_json::Value {
+    serde_json::to_value(payload).expect("we should always be able to serialize a message")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+
+    #[test]
+    fn joins_login_topic_on_creation_and_reconnect() {
+        let (mut loopback, mut portal) = loopback::<(), ()>("client", "init");
+
+        assert!(matches!(
+            portal.try_next_request(),
+            Some(LoopbackRequest::Join { topic, payload }) if topic == "client" && payload == "init"
+        ));
+        assert!(portal.try_next_request().is_none());
+
+        loopback.reconnect();
+
+        assert!(matches!(
+            portal.try_next_request(),
+            Some(LoopbackRequest::Join { topic, .. }) if topic == "client"
+        ));
+    }
+
+    #[test]
+    fn replies_correlate_with_requests() {
+        let (mut loopback, mut portal) = loopback::<(), u32>("client", ());
+        let _join = portal.try_next_request();
+
+        let sent_id = loopback.send("client", "ping");
+
+        let Some(LoopbackRequest::Message { topic, id, payload }) = portal.try_next_request()
+        else {
+            panic!("expected a message");
+        };
+        assert_eq!(payload, "ping");
+        portal.reply_ok(topic, id, 42);
+
+        let Poll::Ready(Ok(Event::SuccessResponse { req_id, res, .. })) = loopback.poll(&mut cx())
+        else {
+            panic!("expected a response");
+        };
+        assert_eq!(req_id, sent_id);
+        assert_eq!(res, 42);
+    }
+
+    #[test]
+    fn closing_emits_closed_event() {
+        let (mut loopback, portal) = loopback::<(), ()>("client", ());
+        portal.push("client", ());
+
+        loopback.close().unwrap();
+
+        assert!(matches!(
+            loopback.poll(&mut cx()),
+            Poll::Ready(Ok(Event::Closed))
+        ));
+    }
+
+    #[test]
+    fn pending_after_portal_is_dropped() {
+        let (mut loopback, portal) = loopback::<(), ()>("client", ());
+        drop(portal);
+
+        assert!(loopback.poll(&mut cx()).is_pending());
+    }
+
+    fn cx() -> Context<'static> {
+        Context::from_waker(noop_waker_ref())
+    }
+}
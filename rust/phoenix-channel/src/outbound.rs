@@ -0,0 +1,200 @@
+//! The queue of messages we send to the portal.
+//!
+//! Messages sent via [`PhoenixChannel::send_reliable`](crate::PhoenixChannel::send_reliable) are retained after we wrote them to the websocket until the portal replies on the same topic.
+//! If the connection fails before that, they are re-sent once we are connected again.
+//! This gives us at-least-once delivery: the portal may see a message twice if only its reply got lost.
+
+use crate::OutboundRequestId;
+use std::collections::{BTreeMap, VecDeque};
+use std::mem;
+
+/// How many messages we at most retain while waiting for the portal to acknowledge them.
+///
+/// If the portal never replies to a message, we would otherwise grow this buffer forever.
+const MAX_UNACKED_MESSAGES: usize = 1_000;
+
+#[derive(Default)]
+pub(crate) struct OutboundQueue {
+    pending: VecDeque<Outbound>,
+    unacked: BTreeMap<OutboundRequestId, Outbound>,
+}
+
+pub(crate) struct Outbound {
+    pub(crate) id: OutboundRequestId,
+    pub(crate) topic: String,
+    pub(crate) text: String,
+    pub(crate) kind: Kind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    /// `phx_join` and heartbeats: only meaningful on the connection they were created for.
+    Control,
+    /// Sent at most once.
+    Message,
+    /// Re-sent after a reconnect until the portal replies to it.
+    Reliable,
+}
+
+impl OutboundQueue {
+    pub(crate) fn push_front(&mut self, msg: Outbound) {
+        self.pending.push_front(msg);
+    }
+
+    pub(crate) fn push_back(&mut self, msg: Outbound) {
+        self.pending.push_back(msg);
+    }
+
+    pub(crate) fn pop_front(&mut self) -> Option<Outbound> {
+        self.pending.pop_front()
+    }
+
+    /// Records that we wrote the given message to the websocket.
+    pub(crate) fn sent(&mut self, msg: Outbound) {
+        if msg.kind != Kind::Reliable {
+            return;
+        }
+
+        if self.unacked.len() >= MAX_UNACKED_MESSAGES {
+            if let Some((id, dropped)) = self.unacked.pop_first() {
+                tracing::warn!(%id, topic = %dropped.topic, "Too many unacknowledged messages, will not re-send oldest one");
+            }
+        }
+
+        self.unacked.insert(msg.id.copy(), msg);
+    }
+
+    /// Handles a reply from the portal, returns whether it acknowledged one of our reliable messages.
+    ///
+    /// Both successful and error replies count: either way, the portal processed the message.
+    pub(crate) fn acknowledge(&mut self, topic: &str, id: &OutboundRequestId) -> bool {
+        match self.unacked.get(id) {
+            Some(msg) if msg.topic == topic => {
+                self.unacked.remove(id);
+
+                true
+            }
+            Some(_) | None => false,
+        }
+    }
+
+    /// Prepares the queue for a new connection.
+    ///
+    /// Control messages are dropped.
+    /// Unacknowledged messages are queued again, oldest first and ahead of all messages we haven't sent yet.
+    pub(crate) fn reconnecting(&mut self) {
+        self.pending.retain(|msg| msg.kind != Kind::Control);
+
+        let unacked = mem::take(&mut self.unacked);
+        if !unacked.is_empty() {
+            tracing::debug!(num_messages = %unacked.len(), "Re-sending unacknowledged messages after reconnect");
+        }
+
+        for (_, msg) in unacked.into_iter().rev() {
+            self.pending.push_front(msg);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resends_unacknowledged_messages_before_pending_ones() {
+        let mut queue = OutboundQueue::default();
+        queue.push_back(outbound(0, Kind::Reliable));
+        queue.push_back(outbound(1, Kind::Reliable));
+        queue.push_back(outbound(2, Kind::Message));
+
+        send_all(&mut queue, 2);
+        queue.push_back(outbound(3, Kind::Reliable));
+        queue.reconnecting();
+
+        assert_eq!(pending_ids(&mut queue), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn acknowledged_messages_are_not_resent() {
+        let mut queue = OutboundQueue::default();
+        queue.push_back(outbound(0, Kind::Reliable));
+        queue.push_back(outbound(1, Kind::Reliable));
+        send_all(&mut queue, 2);
+
+        assert!(queue.acknowledge("client", &OutboundRequestId(0)));
+        queue.reconnecting();
+
+        assert_eq!(pending_ids(&mut queue), vec![1]);
+    }
+
+    #[test]
+    fn reply_on_other_topic_does_not_acknowledge() {
+        let mut queue = OutboundQueue::default();
+        queue.push_back(outbound(0, Kind::Reliable));
+        send_all(&mut queue, 1);
+
+        assert!(!queue.acknowledge("phoenix", &OutboundRequestId(0)));
+        queue.reconnecting();
+
+        assert_eq!(pending_ids(&mut queue), vec![0]);
+    }
+
+    #[test]
+    fn sent_messages_without_ack_are_not_resent() {
+        let mut queue = OutboundQueue::default();
+        queue.push_back(outbound(0, Kind::Message));
+        queue.push_back(outbound(1, Kind::Control));
+        send_all(&mut queue, 2);
+
+        queue.reconnecting();
+
+        assert!(pending_ids(&mut queue).is_empty());
+    }
+
+    #[test]
+    fn drops_unsent_control_messages_on_reconnect() {
+        let mut queue = OutboundQueue::default();
+        queue.push_back(outbound(0, Kind::Control));
+        queue.push_back(outbound(1, Kind::Message));
+
+        queue.reconnecting();
+
+        assert_eq!(pending_ids(&mut queue), vec![1]);
+    }
+
+    #[test]
+    fn retains_bounded_number_of_unacked_messages() {
+        let mut queue = OutboundQueue::default();
+        for id in 0..(MAX_UNACKED_MESSAGES as u64 + 1) {
+            queue.sent(outbound(id, Kind::Reliable));
+        }
+
+        queue.reconnecting();
+
+        let ids = pending_ids(&mut queue);
+        assert_eq!(ids.len(), MAX_UNACKED_MESSAGES);
+        assert_eq!(ids.first(), Some(&1));
+    }
+
+    fn outbound(id: u64, kind: Kind) -> Outbound {
+        Outbound {
+            id: OutboundRequestId(id),
+            topic: "client".to_owned(),
+            text: format!("message {id}"),
+            kind,
+        }
+    }
+
+    fn send_all(queue: &mut OutboundQueue, num: usize) {
+        for _ in 0..num {
+            let msg = queue.pop_front().unwrap();
+            queue.sent(msg);
+        }
+    }
+
+    fn pending_ids(queue: &mut OutboundQueue) -> Vec<u64> {
+        std::iter::from_fn(|| queue.pop_front())
+            .map(|msg| msg.id.0)
+            .collect()
+    }
+}
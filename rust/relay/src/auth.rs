@@ -17,6 +17,7 @@ pub(crate) trait MessageIntegrityExt {
     fn verify(
         &self,
         relay_secret: &SecretString,
+        realm: &Realm,
         username: &str,
         now: SystemTime,
     ) -> Result<(), Error>;
@@ -26,6 +27,7 @@ impl MessageIntegrityExt for MessageIntegrity {
     fn verify(
         &self,
         relay_secret: &SecretString,
+        realm: &Realm,
         username: &str,
         now: SystemTime,
     ) -> Result<(), Error> {
@@ -41,7 +43,7 @@ impl MessageIntegrityExt for MessageIntegrity {
         self.check_long_term_credential(
             &Username::new(format!("{}:{}", expiry_unix_timestamp, salt))
                 .map_err(|_| Error::InvalidUsername)?,
-            &FIREZONE,
+            realm,
             &password,
         )
         .map_err(|_| Error::InvalidPassword)?;
@@ -70,6 +72,18 @@ impl Nonces {
         self.inner.insert(nonce, Self::NUM_REQUESTS);
     }
 
+    /// Re-registers a nonce from a [`Snapshot`](crate::Snapshot) with its remaining number of requests.
+    pub(crate) fn restore(&mut self, nonce: Uuid, remaining_requests: u64) {
+        self.inner
+            .insert(nonce, remaining_requests.min(Self::NUM_REQUESTS));
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (Uuid, u64)> + '_ {
+        self.inner
+            .iter()
+            .map(|(nonce, remaining)| (*nonce, *remaining))
+    }
+
     /// Record the usage of a nonce in a request.
     pub(crate) fn handle_nonce_used(&mut self, nonce: Uuid) -> Result<(), Error> {
         let mut entry = match self.inner.entry(nonce) {
@@ -136,6 +150,25 @@ pub fn generate_password(
     BASE64_STANDARD_NO_PAD.encode(array.as_slice())
 }
 
+/// Generates a username and password that are valid until `expiry`.
+///
+/// These are the same credentials the portal hands out to clients and gateways.
+pub fn generate_credentials(
+    relay_secret: &SecretString,
+    expiry: SystemTime,
+    username_salt: &str,
+) -> (String, String) {
+    let expiry_secs = expiry
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("expiry must be later than UNIX_EPOCH")
+        .as_secs();
+
+    let username = format!("{expiry_secs}:{username_salt}");
+    let password = generate_password(relay_secret, expiry, username_salt);
+
+    (username, password)
+}
+
 pub(crate) fn systemtime_from_unix(seconds: u64) -> SystemTime {
     SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
 }
@@ -181,6 +214,7 @@ mod tests {
 
         let result = message_integrity.verify(
             &RELAY_SECRET_1.parse().unwrap(),
+            &FIREZONE,
             "1685200000:n23JJ2wKKtt30oXi",
             systemtime_from_unix(1685200000 - 1000),
         );
@@ -198,6 +232,7 @@ mod tests {
 
         let result = message_integrity.verify(
             &RELAY_SECRET_1.parse().unwrap(),
+            &FIREZONE,
             "1685199000:n23JJ2wKKtt30oXi",
             systemtime_from_unix(1685200000),
         );
@@ -215,6 +250,7 @@ mod tests {
 
         let result = message_integrity.verify(
             &RELAY_SECRET_1.parse().unwrap(),
+            &FIREZONE,
             "1685200000:n23JJ2wKKtt30oXi",
             systemtime_from_unix(168520000 + 1000),
         );
@@ -232,6 +268,7 @@ mod tests {
 
         let result = message_integrity.verify(
             &RELAY_SECRET_1.parse().unwrap(),
+            &FIREZONE,
             "foobar",
             systemtime_from_unix(168520000 + 1000),
         );
@@ -239,6 +276,34 @@ mod tests {
         assert_eq!(result.unwrap_err(), Error::InvalidUsername)
     }
 
+    #[test]
+    fn generated_credentials_are_valid() {
+        let relay_secret = RELAY_SECRET_1.parse().unwrap();
+        let realm = Realm::new("example.com".to_owned()).unwrap();
+
+        let (username, password) = generate_credentials(
+            &relay_secret,
+            systemtime_from_unix(1685200000),
+            SAMPLE_USERNAME,
+        );
+        let message_integrity = MessageIntegrity::new_long_term_credential(
+            &sample_message(),
+            &Username::new(username.clone()).unwrap(),
+            &realm,
+            &password,
+        )
+        .unwrap();
+
+        message_integrity
+            .verify(
+                &relay_secret,
+                &realm,
+                &username,
+                systemtime_from_unix(1685200000 - 1000),
+            )
+            .expect("credentials to be valid");
+    }
+
     #[test]
     fn nonces_are_valid_for_100_requests() {
         let mut nonces = Nonces::default();
@@ -0,0 +1,123 @@
+//! The config file for running the relay as a general-purpose TURN server, i.e. without the portal.
+//!
+//! ```toml
+//! secret = "a-long-random-string"
+//! realm = "example.com"
+//! lowest_port = 49152
+//! highest_port = 65535
+//!
+//! [limits]
+//! allocation_bytes_per_sec = 1000000
+//! ```
+
+use crate::Limits;
+use anyhow::{anyhow, Context as _, Result};
+use secrecy::SecretString;
+use serde::Deserialize;
+use std::path::Path;
+use stun_codec::rfc5389::attributes::Realm;
+
+/// The settings of a relay, loaded from a TOML file.
+///
+/// All settings are optional and are overridden by the corresponding command-line arguments and environment variables.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The secret to derive passwords from, see [`generate_credentials`](crate::auth::generate_credentials).
+    ///
+    /// Private so it doesn't end up in logs by accident.
+    secret: Option<String>,
+    /// The realm to authenticate clients in, defaults to `firezone`.
+    pub realm: Option<String>,
+    /// The lowest port used for TURN allocations.
+    pub lowest_port: Option<u16>,
+    /// The highest port used for TURN allocations.
+    pub highest_port: Option<u16>,
+    #[serde(default)]
+    pub limits: Limits,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read '{}'", path.display()))?;
+
+        Self::parse(&content).with_context(|| format!("Invalid config file '{}'", path.display()))
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let config = toml::from_str::<Self>(content)?;
+
+        if config.secret.as_ref().is_some_and(|s| s.is_empty()) {
+            return Err(anyhow!("`secret` must not be empty"));
+        }
+
+        Ok(config)
+    }
+
+    pub fn secret(&self) -> Option<SecretString> {
+        self.secret.clone().map(SecretString::from)
+    }
+
+    pub fn realm(&self) -> Result<Option<Realm>> {
+        self.realm
+            .clone()
+            .map(|realm| Realm::new(realm).map_err(|e| anyhow!("Invalid realm: {e}")))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secrecy::ExposeSecret as _;
+
+    #[test]
+    fn parses_full_config() {
+        let config = Config::parse(
+            r#"
+            secret = "foobar"
+            realm = "example.com"
+            lowest_port = 50000
+            highest_port = 50100
+
+            [limits]
+            allocation_bytes_per_sec = 1000
+            allocation_max_bytes = 1000000
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.secret().unwrap().expose_secret(), "foobar");
+        assert_eq!(config.realm().unwrap().unwrap().text(), "example.com");
+        assert_eq!(config.lowest_port, Some(50000));
+        assert_eq!(config.highest_port, Some(50100));
+        assert_eq!(
+            config.limits,
+            Limits {
+                allocation_bytes_per_sec: Some(1000),
+                username_bytes_per_sec: None,
+                allocation_max_bytes: Some(1000000),
+            }
+        );
+    }
+
+    #[test]
+    fn all_settings_are_optional() {
+        let config = Config::parse("").unwrap();
+
+        assert!(config.secret().is_none());
+        assert!(config.realm().unwrap().is_none());
+        assert_eq!(config.limits, Limits::default());
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        assert!(Config::parse("listen_port = 3478").is_err());
+    }
+
+    #[test]
+    fn rejects_empty_secret() {
+        assert!(Config::parse(r#"secret = """#).is_err());
+    }
+}
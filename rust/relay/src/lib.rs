@@ -3,14 +3,16 @@ mod server;
 mod sleep;
 
 pub mod auth;
+pub mod config;
 #[cfg(feature = "proptest")]
 pub mod proptest;
 pub mod sockets;
+pub mod streams;
 
 pub use net_ext::IpAddrExt;
 pub use server::{
     Allocate, AllocationPort, Attribute, Binding, ChannelBind, ChannelData, ClientMessage, Command,
-    CreatePermission, Refresh, Server,
+    CreatePermission, Limits, Refresh, Server, Snapshot,
 };
 pub use sleep::Sleep;
 pub use stun_codec::rfc8656::attributes::AddressFamily;
@@ -89,20 +91,42 @@ impl From<(Option<Ipv4Addr>, Option<Ipv6Addr>)> for IpStack {
 /// From the [spec](https://www.rfc-editor.org/rfc/rfc8656#section-2-4.4):
 ///
 /// > A STUN client that implements this specification.
+///
+/// The same address may talk to us via UDP and a stream at the same time, hence we also track the transport.
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
-pub struct ClientSocket(SocketAddr);
+pub struct ClientSocket {
+    addr: SocketAddr,
+    transport: ClientTransport,
+}
+
+/// How a client talks to us.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
+pub enum ClientTransport {
+    Udp,
+    Tcp,
+    Tls,
+}
 
 impl ClientSocket {
+    /// A client that talks to us via UDP.
     pub fn new(addr: SocketAddr) -> Self {
-        Self(addr)
+        Self::with_transport(addr, ClientTransport::Udp)
+    }
+
+    pub fn with_transport(addr: SocketAddr, transport: ClientTransport) -> Self {
+        Self { addr, transport }
     }
 
     pub fn into_socket(self) -> SocketAddr {
-        self.0
+        self.addr
+    }
+
+    pub fn transport(&self) -> ClientTransport {
+        self.transport
     }
 
     pub fn family(&self) -> AddressFamily {
-        match self.0 {
+        match self.addr {
             SocketAddr::V4(_) => AddressFamily::V4,
             SocketAddr::V6(_) => AddressFamily::V6,
         }
@@ -111,7 +135,7 @@ impl ClientSocket {
 
 impl fmt::Display for ClientSocket {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.0.fmt(f)
+        self.addr.fmt(f)
     }
 }
 
@@ -2,22 +2,28 @@ use anyhow::{anyhow, bail, Context, Result};
 use backoff::ExponentialBackoffBuilder;
 use clap::Parser;
 use firezone_bin_shared::http_health_check;
+use firezone_relay::config::Config;
 use firezone_relay::sockets::Sockets;
+use firezone_relay::streams::{self, Streams};
 use firezone_relay::{
-    sockets, AddressFamily, AllocationPort, ChannelData, ClientSocket, Command, IpStack,
-    PeerSocket, Server, Sleep,
+    auth, sockets, AddressFamily, AllocationPort, ChannelData, ClientSocket, ClientTransport,
+    Command, IpStack, Limits, PeerSocket, Server, Sleep, Snapshot,
 };
 use futures::{future, FutureExt};
-use phoenix_channel::{Event, LoginUrl, PhoenixChannel};
+use phoenix_channel::{ControlPlane, Event, LoginUrl, PhoenixChannel, Proxy};
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
 use secrecy::{Secret, SecretString};
+use std::fs::File;
+use std::io::{self, BufReader};
 use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use std::task::Poll;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::signal::unix;
+use tokio_rustls::TlsAcceptor;
 use tracing::{level_filters::LevelFilter, Subscriber};
 use tracing_core::Dispatch;
 use tracing_stackdriver::CloudTraceConfiguration;
@@ -25,11 +31,26 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilte
 use url::Url;
 
 const STATS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+/// How often we update the metrics served at `/metrics`.
+const METRICS_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
 
 const MAX_PARTITION_TIME: Duration = Duration::from_secs(60 * 15);
 
+// See https://www.rfc-editor.org/rfc/rfc8656.html#name-allocations
+const DEFAULT_LOWEST_PORT: u16 = 49152;
+const DEFAULT_HIGHEST_PORT: u16 = 65535;
+
 #[derive(Parser, Debug)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Cmd>,
+
+    /// A TOML file with the shared secret, realm, port range and limits.
+    ///
+    /// The corresponding command-line arguments and environment variables take precedence over the settings in this file.
+    /// Setting a `secret` allows running the relay without the portal.
+    #[arg(long, env, global = true)]
+    config: Option<PathBuf>,
     /// The public (i.e. internet-reachable) IPv4 address of the relay server.
     #[arg(long, env)]
     public_ip4_addr: Option<Ipv4Addr>,
@@ -37,15 +58,48 @@ struct Args {
     #[arg(long, env)]
     public_ip6_addr: Option<Ipv6Addr>,
     /// The port to listen on for STUN messages.
+    ///
+    /// We accept TURN via UDP and TCP on this port.
     #[arg(long, env, hide = true, default_value = "3478")]
     listen_port: u16,
-    // See https://www.rfc-editor.org/rfc/rfc8656.html#name-allocations
+    /// The port to listen on for TURN over TLS.
+    ///
+    /// Only used if `--tls-cert-file` and `--tls-key-file` are set.
+    #[arg(long, env, hide = true, default_value = "443")]
+    tls_listen_port: u16,
+    /// A PEM-encoded certificate chain to use for TURN over TLS.
+    #[arg(long, env, requires = "tls_key_file")]
+    tls_cert_file: Option<PathBuf>,
+    /// The PEM-encoded private key of `--tls-cert-file`.
+    #[arg(long, env, requires = "tls_cert_file")]
+    tls_key_file: Option<PathBuf>,
     /// The lowest port used for TURN allocations.
-    #[arg(long, env, hide = true, default_value = "49152")]
-    lowest_port: u16,
+    ///
+    /// Defaults to 49152.
+    #[arg(long, env, hide = true)]
+    lowest_port: Option<u16>,
     /// The highest port used for TURN allocations.
-    #[arg(long, env, hide = true, default_value = "65535")]
-    highest_port: u16,
+    ///
+    /// Defaults to 65535.
+    #[arg(long, env, hide = true)]
+    highest_port: Option<u16>,
+    /// The maximum rate in bytes per second at which a single allocation may relay data.
+    ///
+    /// Data exceeding this rate is dropped.
+    #[arg(long, env)]
+    allocation_bytes_per_sec: Option<u64>,
+    /// The maximum rate in bytes per second at which all allocations of a single username may relay data combined.
+    #[arg(long, env)]
+    username_bytes_per_sec: Option<u64>,
+    /// The maximum number of bytes a single allocation may relay over its entire lifetime.
+    #[arg(long, env)]
+    allocation_max_bytes: Option<u64>,
+    /// Where to persist allocations and channel bindings when receiving SIGTERM.
+    ///
+    /// If set, the relay exits right away on SIGTERM instead of waiting for all allocations to expire
+    /// and restores them from this file on the next startup.
+    #[arg(long, env)]
+    state_file: Option<PathBuf>,
     #[arg(
         long,
         env = "FIREZONE_API_URL",
@@ -62,6 +116,11 @@ struct Args {
     /// the system hostname is used by default.
     #[arg(env = "FIREZONE_NAME")]
     name: Option<String>,
+    /// An HTTP or SOCKS5 proxy to use for the portal connection, e.g. `http://proxy:3128`.
+    ///
+    /// If omitted, we use `HTTPS_PROXY` or `ALL_PROXY`, if set.
+    #[arg(long, env = "FIREZONE_PROXY")]
+    proxy: Option<Proxy>,
     /// A seed to use for all randomness operations.
     #[arg(long, env, hide = true)]
     rng_seed: Option<u64>,
@@ -87,6 +146,21 @@ struct Args {
     health_check: http_health_check::HealthCheckArgs,
 }
 
+#[derive(clap::Subcommand, Debug)]
+enum Cmd {
+    /// Prints a username and password that are valid for the `secret` in `--config`.
+    MintCredentials {
+        /// How many seconds the credentials should be valid for.
+        #[arg(long, default_value = "86400")]
+        ttl_secs: u64,
+        /// The salt to embed in the username.
+        ///
+        /// If omitted, a random one is generated.
+        #[arg(long)]
+        salt: Option<String>,
+    },
+}
+
 #[derive(clap::ValueEnum, Debug, Clone, Copy)]
 enum LogFormat {
     Human,
@@ -102,6 +176,17 @@ async fn main() -> Result<()> {
 
     let args = Args::parse();
 
+    let config = args
+        .config
+        .as_deref()
+        .map(Config::load)
+        .transpose()?
+        .unwrap_or_default();
+
+    if let Some(Cmd::MintCredentials { ttl_secs, salt }) = &args.command {
+        return mint_credentials(&config, Duration::from_secs(*ttl_secs), salt.clone());
+    }
+
     setup_tracing(&args)?;
 
     let public_addr = match (args.public_ip4_addr, args.public_ip6_addr) {
@@ -113,19 +198,77 @@ async fn main() -> Result<()> {
         }
     };
 
-    let server = Server::new(
+    let tls = args
+        .tls_cert_file
+        .as_deref()
+        .zip(args.tls_key_file.as_deref())
+        .map(|(cert_file, key_file)| make_tls_acceptor(cert_file, key_file))
+        .transpose()?
+        .map(|acceptor| (args.tls_listen_port, acceptor));
+
+    let lowest_port = args
+        .lowest_port
+        .or(config.lowest_port)
+        .unwrap_or(DEFAULT_LOWEST_PORT);
+    let highest_port = args
+        .highest_port
+        .or(config.highest_port)
+        .unwrap_or(DEFAULT_HIGHEST_PORT);
+
+    let mut server = Server::new(
         public_addr,
         make_rng(args.rng_seed),
         args.listen_port,
-        args.lowest_port..=args.highest_port,
-    );
+        lowest_port..=highest_port,
+    )
+    .with_limits(Limits {
+        allocation_bytes_per_sec: args
+            .allocation_bytes_per_sec
+            .or(config.limits.allocation_bytes_per_sec),
+        username_bytes_per_sec: args
+            .username_bytes_per_sec
+            .or(config.limits.username_bytes_per_sec),
+        allocation_max_bytes: args
+            .allocation_max_bytes
+            .or(config.limits.allocation_max_bytes),
+    });
+
+    if let Some(realm) = config.realm()? {
+        server = server.with_realm(realm);
+    }
+
+    if let Some(state_file) = args.state_file.as_deref() {
+        match take_snapshot(state_file) {
+            Ok(Some(snapshot)) => server.restore(snapshot, Instant::now(), SystemTime::now()),
+            Ok(None) => {}
+            Err(e) => {
+                tracing::warn!(target: "relay", "Failed to restore state from '{}': {e:#}", state_file.display())
+            }
+        }
+    }
+
+    // A configured secret always wins over the one restored from a snapshot.
+    if let Some(secret) = config.secret() {
+        server = server.with_auth_secret(secret);
+    }
 
     let last_heartbeat_sent = Arc::new(Mutex::new(Option::<Instant>::None));
+    let metrics = Arc::new(Mutex::new(RelayMetrics::default()));
 
-    tokio::spawn(http_health_check::serve(
-        args.health_check.health_check_addr,
-        make_is_healthy(last_heartbeat_sent.clone()),
-    ));
+    if args.health_check.metrics {
+        let metrics = metrics.clone();
+
+        tokio::spawn(http_health_check::serve_with_metrics(
+            args.health_check.health_check_addr,
+            make_is_healthy(last_heartbeat_sent.clone()),
+            move || metrics.lock().unwrap().render(),
+        ));
+    } else {
+        tokio::spawn(http_health_check::serve(
+            args.health_check.health_check_addr,
+            make_is_healthy(last_heartbeat_sent.clone()),
+        ));
+    }
 
     let channel = if let Some(token) = args.token.as_ref() {
         use secrecy::ExposeSecret;
@@ -150,16 +293,29 @@ async fn main() -> Result<()> {
                 .with_max_elapsed_time(Some(MAX_PARTITION_TIME))
                 .build(),
             Arc::new(socket_factory::tcp),
+            args.proxy.clone().or_else(Proxy::from_env),
         )?)
     } else {
-        tracing::warn!(target: "relay", "No portal token supplied, starting standalone mode");
+        if config.secret().is_none() {
+            tracing::warn!(target: "relay", "Neither a portal token nor a `secret` in `--config` supplied, clients won't be able to authenticate");
+        }
+
+        tracing::info!(target: "relay", "No portal token supplied, starting standalone mode");
 
         None
     };
 
-    let mut eventloop = Eventloop::new(server, channel, public_addr, last_heartbeat_sent)?;
+    let mut eventloop = Eventloop::new(
+        server,
+        channel,
+        public_addr,
+        tls,
+        args.state_file,
+        last_heartbeat_sent,
+        metrics,
+    )?;
 
-    tracing::info!(target: "relay", "Listening for incoming traffic on UDP port {0}", args.listen_port);
+    tracing::info!(target: "relay", "Listening for incoming traffic on UDP and TCP port {0}", args.listen_port);
 
     future::poll_fn(|cx| eventloop.poll(cx))
         .await
@@ -269,6 +425,97 @@ where
     }
 }
 
+#[allow(clippy::print_stdout)] // Printing the credentials is the purpose of this command.
+fn mint_credentials(config: &Config, ttl: Duration, salt: Option<String>) -> Result<()> {
+    let secret = config
+        .secret()
+        .context("`mint-credentials` requires a `secret` in the file passed to `--config`")?;
+
+    let salt = salt.unwrap_or_else(|| {
+        rand::thread_rng()
+            .sample_iter(rand::distributions::Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect()
+    });
+    if salt.contains(':') {
+        bail!("Salt must not contain ':'");
+    }
+
+    let (username, password) = auth::generate_credentials(&secret, SystemTime::now() + ttl, &salt);
+
+    println!("username: {username}");
+    println!("password: {password}");
+    println!(
+        "realm: {}",
+        config.realm.as_deref().unwrap_or(auth::FIREZONE.text())
+    );
+
+    Ok(())
+}
+
+/// Loads the certificate chain and private key for TURN over TLS.
+fn make_tls_acceptor(cert_file: &Path, key_file: &Path) -> Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert_file)
+            .with_context(|| format!("Failed to open '{}'", cert_file.display()))?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .context("Failed to parse certificate chain")?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(
+        File::open(key_file).with_context(|| format!("Failed to open '{}'", key_file.display()))?,
+    ))
+    .context("Failed to parse private key")?
+    .context("No private key found")?;
+
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid certificate chain or private key")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Reads the snapshot at `path`, if any.
+///
+/// The file is deleted so we never restore the same state twice.
+fn take_snapshot(path: &Path) -> Result<Option<Snapshot>> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read '{}'", path.display())),
+    };
+    std::fs::remove_file(path).with_context(|| format!("Failed to remove '{}'", path.display()))?;
+
+    let snapshot = serde_json::from_slice(&bytes).context("Failed to parse snapshot")?;
+
+    Ok(Some(snapshot))
+}
+
+/// Atomically writes the snapshot to `path`.
+///
+/// The snapshot contains our auth secret, so only the owner may read it.
+fn write_snapshot(path: &Path, snapshot: &Snapshot) -> Result<()> {
+    use std::os::unix::fs::OpenOptionsExt as _;
+
+    let tmp_path = path.with_extension("tmp");
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .with_context(|| format!("Failed to create '{}'", tmp_path.display()))?;
+    serde_json::to_writer(&mut file, snapshot).context("Failed to serialize snapshot")?;
+    file.sync_all()?;
+
+    std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to move snapshot to '{}'", path.display()))?;
+
+    Ok(())
+}
+
 fn env_filter() -> EnvFilter {
     EnvFilter::builder()
         .with_default_directive(LevelFilter::INFO.into())
@@ -301,35 +548,116 @@ fn make_rng(seed: Option<u64>) -> StdRng {
 
 const MAX_UDP_SIZE: usize = 65536;
 
-struct Eventloop<R> {
+struct Eventloop<R, P> {
     sockets: Sockets,
+    /// Clients that talk to us via TCP or TLS.
+    streams: Streams,
 
     server: Server<R>,
-    channel: Option<PhoenixChannel<JoinMessage, IngressMessage, ()>>,
+    channel: Option<P>,
     sleep: Sleep,
 
     sigterm: unix::Signal,
     shutting_down: bool,
+    /// Where to persist our state on SIGTERM, see [`Args::state_file`].
+    state_file: Option<PathBuf>,
 
     stats_log_interval: tokio::time::Interval,
     last_num_bytes_relayed: u64,
 
     last_heartbeat_sent: Arc<Mutex<Option<Instant>>>,
 
+    metrics: Arc<Mutex<RelayMetrics>>,
+    metrics_update_interval: tokio::time::Interval,
+
     buffer: [u8; MAX_UDP_SIZE],
 }
 
-impl<R> Eventloop<R>
+/// A snapshot of the relay's state, served at `/metrics`.
+#[derive(Debug, Default)]
+struct RelayMetrics {
+    num_allocations: usize,
+    num_active_channels: usize,
+    num_relayed_bytes: u64,
+    num_limited_bytes: u64,
+}
+
+impl RelayMetrics {
+    fn render(&self) -> String {
+        let mut text = http_health_check::PrometheusText::default();
+
+        text.single(
+            "firezone_relay_allocations",
+            "gauge",
+            "The number of active TURN allocations.",
+            self.num_allocations,
+        )
+        .single(
+            "firezone_relay_channels",
+            "gauge",
+            "The number of bound TURN channels.",
+            self.num_active_channels,
+        )
+        .single(
+            "firezone_relay_relayed_bytes_total",
+            "counter",
+            "The number of bytes relayed between clients and peers.",
+            self.num_relayed_bytes,
+        )
+        .single(
+            "firezone_relay_limited_bytes_total",
+            "counter",
+            "The number of bytes dropped because an allocation exceeded a bandwidth limit.",
+            self.num_limited_bytes,
+        );
+
+        text.finish()
+    }
+}
+
+impl<R, P> Eventloop<R, P>
 where
     R: Rng,
+    P: ControlPlane<IngressMessage, ()>,
 {
     fn new(
         server: Server<R>,
-        channel: Option<PhoenixChannel<JoinMessage, IngressMessage, ()>>,
+        channel: Option<P>,
         public_address: IpStack,
+        tls: Option<(u16, TlsAcceptor)>,
+        state_file: Option<PathBuf>,
         last_heartbeat_sent: Arc<Mutex<Option<Instant>>>,
+        metrics: Arc<Mutex<RelayMetrics>>,
     ) -> Result<Self> {
         let mut sockets = Sockets::new();
+        let mut streams = Streams::new();
+
+        for family in [
+            public_address.as_v4().map(|_| AddressFamily::V4),
+            public_address.as_v6().map(|_| AddressFamily::V6),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            streams
+                .listen(server.listen_port(), family, None)
+                .with_context(|| {
+                    format!(
+                        "Failed to listen on TCP port {0} on {family} interfaces",
+                        server.listen_port()
+                    )
+                })?;
+
+            if let Some((port, acceptor)) = tls.clone() {
+                streams
+                    .listen(port, family, Some(acceptor))
+                    .with_context(|| {
+                        format!("Failed to listen on TLS port {port} on {family} interfaces")
+                    })?;
+
+                tracing::info!(target: "relay", %family, "Listening for TURN over TLS on port {port}");
+            }
+        }
 
         if public_address.as_v4().is_some() {
             sockets
@@ -359,10 +687,14 @@ where
             stats_log_interval: tokio::time::interval(STATS_LOG_INTERVAL),
             last_num_bytes_relayed: 0,
             sockets,
+            streams,
             buffer: [0u8; MAX_UDP_SIZE],
             last_heartbeat_sent,
+            metrics,
+            metrics_update_interval: tokio::time::interval(METRICS_UPDATE_INTERVAL),
             sigterm: unix::signal(unix::SignalKind::terminate())?,
             shutting_down: false,
+            state_file,
         })
     }
 
@@ -376,9 +708,11 @@ where
             if let Some(next_command) = self.server.next_command() {
                 match next_command {
                     Command::SendMessage { payload, recipient } => {
-                        if let Err(e) = self.sockets.try_send(
+                        if let Err(e) = send_to_client(
+                            &self.sockets,
+                            &self.streams,
                             self.server.listen_port(),
-                            recipient.into_socket(),
+                            recipient,
                             &payload,
                         ) {
                             tracing::warn!(target: "relay", %recipient, "Failed to send message: {e}");
@@ -465,6 +799,7 @@ where
                         packet,
                         PeerSocket::new(from),
                         AllocationPort::new(port),
+                        Instant::now(),
                     ) {
                         let total_length = ChannelData::encode_header_to_slice(
                             channel,
@@ -472,9 +807,11 @@ where
                             header,
                         );
 
-                        if let Err(e) = self.sockets.try_send(
+                        if let Err(e) = send_to_client(
+                            &self.sockets,
+                            &self.streams,
                             self.server.listen_port(), // Packets coming in from peers always go out on the TURN port
-                            client.into_socket(),
+                            client,
                             &self.buffer[..total_length],
                         ) {
                             tracing::warn!(target: "relay", %client, "Failed to relay data to client: {e}");
@@ -490,6 +827,39 @@ where
                 Poll::Pending => {}
             }
 
+            // Priority 2b: Read from clients connected via TCP or TLS.
+            match self.streams.poll_next(cx) {
+                Poll::Ready(streams::Event::Received {
+                    from,
+                    transport,
+                    packet,
+                }) => {
+                    if let Some((port, peer)) = self.server.handle_client_input(
+                        &packet,
+                        ClientSocket::with_transport(from, transport),
+                        Instant::now(),
+                    ) {
+                        let payload = ChannelData::parse(&packet)
+                            .expect("valid ChannelData if we should relay it")
+                            .data();
+
+                        if let Err(e) =
+                            self.sockets
+                                .try_send(port.value(), peer.into_socket(), payload)
+                        {
+                            tracing::warn!(target: "relay", %peer, "Failed to relay data to peer: {e}");
+                        }
+                    };
+                    continue;
+                }
+                Poll::Ready(streams::Event::Closed { from, transport }) => {
+                    self.server
+                        .handle_client_disconnected(ClientSocket::with_transport(from, transport));
+                    continue;
+                }
+                Poll::Pending => {}
+            }
+
             // Priority 3: Check when we need to next be woken. This needs to happen after all state modifications.
             if let Some(timeout) = self.server.poll_timeout() {
                 Pin::new(&mut self.sleep).reset(timeout);
@@ -522,6 +892,22 @@ where
                         return Poll::Ready(Err(anyhow!("Forcing shutdown on repeated SIGTERM")));
                     }
 
+                    if let Some(state_file) = self.state_file.as_deref() {
+                        let snapshot = self.server.snapshot(Instant::now(), SystemTime::now());
+
+                        match write_snapshot(state_file, &snapshot) {
+                            Ok(()) => {
+                                // We deliberately don't leave the portal so clients keep using this relay once we are back.
+                                tracing::info!(target: "relay", allocations = %snapshot.num_allocations(), path = %state_file.display(), "Received SIGTERM, persisted state and shutting down");
+
+                                return Poll::Ready(Ok(()));
+                            }
+                            Err(e) => {
+                                tracing::warn!(target: "relay", "Failed to persist state: {e:#}");
+                            }
+                        }
+                    }
+
                     tracing::info!(active_allocations = %self.server.num_allocations(), "Received SIGTERM, initiating graceful shutdown");
 
                     self.shutting_down = true;
@@ -555,6 +941,17 @@ where
                 continue;
             }
 
+            if self.metrics_update_interval.poll_tick(cx).is_ready() {
+                *self.metrics.lock().unwrap() = RelayMetrics {
+                    num_allocations: self.server.num_allocations(),
+                    num_active_channels: self.server.num_active_channels(),
+                    num_relayed_bytes: self.server.num_relayed_bytes(),
+                    num_limited_bytes: self.server.num_limited_bytes(),
+                };
+
+                continue;
+            }
+
             return Poll::Pending;
         }
     }
@@ -583,6 +980,20 @@ where
     }
 }
 
+/// Sends a message to a client via the transport it talks to us with.
+fn send_to_client(
+    sockets: &Sockets,
+    streams: &Streams,
+    listen_port: u16,
+    client: ClientSocket,
+    msg: &[u8],
+) -> io::Result<()> {
+    match client.transport() {
+        ClientTransport::Udp => sockets.try_send(listen_port, client.into_socket(), msg),
+        ClientTransport::Tcp | ClientTransport::Tls => streams.try_send(client.into_socket(), msg),
+    }
+}
+
 fn fmt_human_throughput(mut throughput: f64) -> String {
     let units = ["B/s", "kB/s", "MB/s", "GB/s", "TB/s"];
 
@@ -684,6 +1095,13 @@ mod tests {
         assert_eq!(args.otlp_grpc_endpoint.unwrap(), "127.0.0.1:4317");
     }
 
+    #[test]
+    fn args_require_tls_key_with_certificate() {
+        let result = Args::try_parse_from(["relay", "--tls-cert-file", "cert.pem"]);
+
+        assert!(result.is_err());
+    }
+
     #[test]
     fn args_can_parse_otlp_endpoint_from_domain() {
         let args =
@@ -1,13 +1,18 @@
 mod channel_data;
 mod client_message;
+mod limits;
+mod snapshot;
 
 pub use crate::server::channel_data::ChannelData;
 pub use crate::server::client_message::{
     Allocate, Binding, ChannelBind, ClientMessage, CreatePermission, Refresh,
 };
+pub use crate::server::limits::Limits;
+pub use crate::server::snapshot::Snapshot;
 
 use crate::auth::{MessageIntegrityExt, Nonces, FIREZONE};
 use crate::net_ext::IpAddrExt;
+use crate::server::limits::{Limit, TokenBucket};
 use crate::{ClientSocket, IpStack, PeerSocket};
 use anyhow::Result;
 use bytecodec::EncodeExt;
@@ -77,12 +82,19 @@ pub struct Server<R> {
     rng: R,
 
     auth_secret: SecretString,
+    realm: Realm,
 
     nonces: Nonces,
 
+    limits: Limits,
+    /// The bandwidth shared by all allocations of a username, see [`Limits::username_bytes_per_sec`].
+    username_buckets: HashMap<String, TokenBucket>,
+
     allocations_up_down_counter: UpDownCounter<i64>,
     data_relayed_counter: Counter<u64>,
     data_relayed: u64, // Keep a separate counter because `Counter` doesn't expose the current value :(
+    data_limited_counter: Counter<u64>,
+    data_limited: u64,
     responses_counter: Counter<u64>,
 }
 
@@ -178,6 +190,13 @@ where
             .with_description("The number of bytes relayed")
             .with_unit("b")
             .init();
+        let data_limited_counter = meter
+            .u64_counter("data_limited_bytes")
+            .with_description(
+                "The number of bytes dropped because an allocation hit a bandwidth limit",
+            )
+            .with_unit("b")
+            .init();
 
         Self {
             decoder: Default::default(),
@@ -192,15 +211,43 @@ where
             pending_commands: Default::default(),
             auth_secret: SecretString::from(hex::encode(rng.gen::<[u8; 32]>())),
             rng,
+            realm: (*FIREZONE).clone(),
             nonces: Default::default(),
             allocations_up_down_counter,
             responses_counter,
             data_relayed_counter,
             data_relayed: 0,
+            data_limited_counter,
+            data_limited: 0,
+            limits: Limits::default(),
+            username_buckets: Default::default(),
             channel_and_client_by_port_and_peer: Default::default(),
         }
     }
 
+    /// Enforces the given bandwidth limits on all allocations.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+
+        self
+    }
+
+    /// Authenticates clients with the given secret instead of a random one.
+    ///
+    /// Useful when credentials are not minted by the portal but e.g. by [`generate_credentials`](crate::auth::generate_credentials).
+    pub fn with_auth_secret(mut self, auth_secret: SecretString) -> Self {
+        self.auth_secret = auth_secret;
+
+        self
+    }
+
+    /// Uses the given realm instead of `firezone` for authenticating clients.
+    pub fn with_realm(mut self, realm: Realm) -> Self {
+        self.realm = realm;
+
+        self
+    }
+
     pub fn auth_secret(&self) -> &SecretString {
         &self.auth_secret
     }
@@ -232,6 +279,11 @@ where
         self.data_relayed
     }
 
+    /// The number of bytes we dropped because an allocation hit one of its [`Limits`].
+    pub fn num_limited_bytes(&self) -> u64 {
+        self.data_limited
+    }
+
     pub fn num_allocations(&self) -> usize {
         self.allocations.len()
     }
@@ -305,7 +357,7 @@ where
                 return None;
             }
             ClientMessage::ChannelData(msg) => {
-                return self.handle_channel_data_message(msg, sender);
+                return self.handle_channel_data_message(msg, sender, now);
             }
         };
 
@@ -330,10 +382,12 @@ where
         msg: &[u8],
         sender: PeerSocket,
         allocation: AllocationPort,
+        now: Instant,
     ) -> Option<(ClientSocket, ChannelNumber)> {
         let Some((client, channel_number)) = self
             .channel_and_client_by_port_and_peer
             .get(&(allocation, sender))
+            .copied()
         else {
             tracing::debug!(target: "relay", "no channel");
 
@@ -342,12 +396,16 @@ where
 
         Span::current().record("recipient", field::display(&client));
 
+        if !self.try_consume_quota(allocation, msg.len(), now) {
+            return None;
+        }
+
         self.data_relayed_counter.add(msg.len() as u64, &[]);
         self.data_relayed += msg.len() as u64;
 
         tracing::trace!(target: "wire", num_bytes = %msg.len());
 
-        Some((*client, *channel_number))
+        Some((client, channel_number))
     }
 
     /// An allocation failed.
@@ -356,6 +414,18 @@ where
         self.delete_allocation(allocation)
     }
 
+    /// A client's TCP or TLS connection was closed.
+    ///
+    /// An allocation made over a stream lives only as long as the connection, see <https://www.rfc-editor.org/rfc/rfc8656#section-3.1>.
+    #[tracing::instrument(level = "debug", skip(self), fields(%client))]
+    pub fn handle_client_disconnected(&mut self, client: ClientSocket) {
+        let Some(port) = self.allocations.get(&client).map(|a| a.port) else {
+            return;
+        };
+
+        self.delete_allocation(port)
+    }
+
     /// Return the next command to be executed.
     pub fn next_command(&mut self) -> Option<Command> {
         self.pending_commands.pop_front()
@@ -482,8 +552,14 @@ where
         // TODO: Do we need to handle EVEN/ODD-PORT?
         let effective_lifetime = request.effective_lifetime();
 
+        let username = request
+            .username()
+            .map(|u| u.name().to_owned())
+            .unwrap_or_default(); // Authenticated requests always have a username.
+
         let allocation = self.create_new_allocation(
             now,
+            username,
             &effective_lifetime,
             first_relay_address,
             maybe_second_relay_addr,
@@ -732,6 +808,7 @@ where
         &mut self,
         message: ChannelData,
         sender: ClientSocket,
+        now: Instant,
     ) -> Option<(AllocationPort, PeerSocket)> {
         let channel_number = message.channel();
         let data = message.data();
@@ -756,12 +833,88 @@ where
         Span::current().record("recipient", field::display(&channel.peer_address));
         Span::current().record("channel", field::display(&channel_number.value()));
 
+        let allocation = channel.allocation;
+        let peer = channel.peer_address;
+
+        if !self.try_consume_quota(allocation, data.len(), now) {
+            return None;
+        }
+
         tracing::trace!(target: "wire", num_bytes = %data.len());
 
         self.data_relayed_counter.add(data.len() as u64, &[]);
         self.data_relayed += data.len() as u64;
 
-        Some((channel.allocation, channel.peer_address))
+        Some((allocation, peer))
+    }
+
+    /// Charges the given number of bytes against the [`Limits`] of an allocation.
+    ///
+    /// Returns `false` if the data must be dropped because the allocation hit one of its limits.
+    fn try_consume_quota(&mut self, port: AllocationPort, num_bytes: usize, now: Instant) -> bool {
+        let Some(allocation) = self
+            .clients_by_allocation
+            .get(&port)
+            .and_then(|client| self.allocations.get_mut(client))
+        else {
+            return true;
+        };
+        let num_bytes = num_bytes as u64;
+
+        if let Some(bucket) = allocation.bucket.as_mut() {
+            bucket.refill(now);
+        }
+
+        let mut username_bucket = self.username_buckets.get_mut(&allocation.username);
+        if let Some(bucket) = username_bucket.as_deref_mut() {
+            bucket.refill(now);
+        }
+
+        let exceeds_max_bytes = self
+            .limits
+            .allocation_max_bytes
+            .is_some_and(|max| allocation.bytes_relayed + num_bytes > max);
+        let exceeds_allocation_rate = allocation
+            .bucket
+            .as_ref()
+            .is_some_and(|b| !b.has(num_bytes));
+        let exceeds_username_rate = username_bucket
+            .as_deref()
+            .is_some_and(|b| !b.has(num_bytes));
+
+        let limit = if exceeds_max_bytes {
+            Limit::AllocationMaxBytes
+        } else if exceeds_allocation_rate {
+            Limit::AllocationRate
+        } else if exceeds_username_rate {
+            Limit::UsernameRate
+        } else {
+            if let Some(bucket) = allocation.bucket.as_mut() {
+                bucket.consume(num_bytes);
+            }
+            if let Some(bucket) = username_bucket {
+                bucket.consume(num_bytes);
+            }
+            allocation.bytes_relayed += num_bytes;
+
+            if let Some(limit) = allocation.limited_by.take() {
+                tracing::debug!(target: "relay", allocation = %port, %limit, "Allocation is no longer limited");
+            }
+
+            return true;
+        };
+
+        if allocation.limited_by != Some(limit) {
+            tracing::warn!(target: "relay", allocation = %port, username = %allocation.username, %limit, "Allocation hit bandwidth limit, dropping data");
+
+            allocation.limited_by = Some(limit);
+        }
+
+        self.data_limited_counter
+            .add(num_bytes, &[KeyValue::new("limit", limit.as_str())]);
+        self.data_limited += num_bytes;
+
+        false
     }
 
     fn verify_auth(
@@ -792,7 +945,12 @@ where
         })?;
 
         message_integrity
-            .verify(&self.auth_secret, username.name(), SystemTime::now()) // This is impure but we don't need to control this in our tests.
+            .verify(
+                &self.auth_secret,
+                &self.realm,
+                username.name(),
+                SystemTime::now(),
+            ) // This is impure but we don't need to control this in our tests.
             .map_err(|_| {
                 self.make_error_response(Unauthorized, request, ResponseErrorLevel::Warn)
             })?;
@@ -803,6 +961,7 @@ where
     fn create_new_allocation(
         &mut self,
         now: Instant,
+        username: String,
         lifetime: &Lifetime,
         first_relay_addr: IpAddr,
         second_relay_addr: Option<IpAddr>,
@@ -820,11 +979,24 @@ where
             }
         };
 
+        if let Some(bytes_per_sec) = self.limits.username_bytes_per_sec {
+            self.username_buckets
+                .entry(username.clone())
+                .or_insert_with(|| TokenBucket::new(bytes_per_sec, now));
+        }
+
         Allocation {
             port,
             expires_at: now + lifetime.lifetime(),
             first_relay_addr,
             second_relay_addr,
+            username,
+            bucket: self
+                .limits
+                .allocation_bytes_per_sec
+                .map(|bytes_per_sec| TokenBucket::new(bytes_per_sec, now)),
+            bytes_relayed: 0,
+            limited_by: None,
         }
     }
 
@@ -955,6 +1127,14 @@ where
                 false
             });
 
+        if !self
+            .allocations
+            .values()
+            .any(|a| a.username == allocation.username)
+        {
+            self.username_buckets.remove(&allocation.username);
+        }
+
         self.allocations_up_down_counter.add(-1, &[]);
         self.pending_commands.push_back(Command::FreeAllocation {
             port,
@@ -1025,7 +1205,7 @@ where
             self.add_nonce(new_nonce);
 
             message.add_attribute(Nonce::new(new_nonce.to_string()).unwrap());
-            message.add_attribute((*FIREZONE).clone());
+            message.add_attribute(self.realm.clone());
         }
 
         message
@@ -1067,6 +1247,17 @@ struct Allocation {
 
     first_relay_addr: IpAddr,
     second_relay_addr: Option<IpAddr>,
+
+    /// The username the allocation was made with.
+    username: String,
+    /// Limits the rate at which this allocation can relay data, see [`Limits::allocation_bytes_per_sec`].
+    bucket: Option<TokenBucket>,
+    /// How many bytes this allocation relayed so far.
+    bytes_relayed: u64,
+    /// The limit this allocation is currently hitting, if any.
+    ///
+    /// Used to only log once when an allocation starts hitting a limit.
+    limited_by: Option<Limit>,
 }
 
 #[derive(Debug, Clone)]
@@ -0,0 +1,107 @@
+use core::fmt;
+use std::time::Instant;
+
+/// Bandwidth limits enforced by the [`Server`](super::Server).
+///
+/// All limits are optional and apply to data relayed in both directions, i.e. from a client to its peers and back.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// The sustained rate in bytes per second at which a single allocation may relay data.
+    pub allocation_bytes_per_sec: Option<u64>,
+    /// The sustained rate in bytes per second at which all allocations of the same username may relay data combined.
+    pub username_bytes_per_sec: Option<u64>,
+    /// How many bytes a single allocation may relay over its entire lifetime.
+    pub allocation_max_bytes: Option<u64>,
+}
+
+/// The limit that caused us to drop data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Limit {
+    AllocationRate,
+    UsernameRate,
+    AllocationMaxBytes,
+}
+
+impl Limit {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Limit::AllocationRate => "allocation_rate",
+            Limit::UsernameRate => "username_rate",
+            Limit::AllocationMaxBytes => "allocation_max_bytes",
+        }
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A token bucket that allows bursts of up to one second worth of data.
+///
+/// A message larger than the rate can never pass.
+#[derive(Debug, Clone)]
+pub(crate) struct TokenBucket {
+    bytes_per_sec: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(bytes_per_sec: u64, now: Instant) -> Self {
+        Self {
+            bytes_per_sec,
+            tokens: bytes_per_sec as f64,
+            last_refill: now,
+        }
+    }
+
+    pub(crate) fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        let capacity = self.bytes_per_sec as f64;
+
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * capacity).min(capacity);
+        self.last_refill = self.last_refill.max(now);
+    }
+
+    pub(crate) fn has(&self, num_bytes: u64) -> bool {
+        self.tokens >= num_bytes as f64
+    }
+
+    pub(crate) fn consume(&mut self, num_bytes: u64) {
+        self.tokens -= num_bytes as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn bucket_starts_full_and_refills_over_time() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(1000, now);
+
+        assert!(bucket.has(1000));
+        bucket.consume(1000);
+        assert!(!bucket.has(1));
+
+        bucket.refill(now + Duration::from_millis(500));
+        assert!(bucket.has(500));
+        assert!(!bucket.has(501));
+    }
+
+    #[test]
+    fn bucket_does_not_exceed_capacity() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(1000, now);
+
+        bucket.refill(now + Duration::from_secs(60));
+
+        assert!(bucket.has(1000));
+        assert!(!bucket.has(1001));
+    }
+}
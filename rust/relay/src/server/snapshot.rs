@@ -0,0 +1,274 @@
+//! Persisting the state of a [`Server`] across restarts.
+//!
+//! A [`Snapshot`] captures all allocations, channel bindings and nonces together with the secret used to authenticate clients.
+//! Allocations made via TCP or TLS are not captured: they are deleted once their connection closes, i.e. as soon as the relay exits.
+//! [`Instant`]s cannot be serialized, so all deadlines are stored relative to the moment the snapshot was taken.
+//!
+//! The server does not track permissions: every channel binding implicitly installs one for its peer.
+//! Hence, restoring the channel bindings also restores all permissions.
+
+use super::{Allocation, Channel, Server, TokenBucket};
+use crate::net_ext::IpAddrExt as _;
+use crate::{AllocationPort, ClientSocket, ClientTransport, Command, PeerSocket};
+use rand::Rng;
+use secrecy::{ExposeSecret as _, SecretString};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime};
+use stun_codec::rfc5766::attributes::ChannelNumber;
+use uuid::Uuid;
+
+/// Bump this whenever the format of [`Snapshot`] changes in an incompatible way.
+const VERSION: u32 = 1;
+
+/// The persistable state of a [`Server`].
+///
+/// Deliberately does not implement [`Debug`] because it contains the server's auth secret.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    version: u32,
+    taken_at: SystemTime,
+    auth_secret: String,
+    nonces: Vec<(Uuid, u64)>,
+    allocations: Vec<AllocationSnapshot>,
+    channels: Vec<ChannelSnapshot>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AllocationSnapshot {
+    client: SocketAddr,
+    port: u16,
+    /// Milliseconds until the allocation expires.
+    expires_in_ms: i64,
+    first_relay_addr: IpAddr,
+    second_relay_addr: Option<IpAddr>,
+    username: String,
+    bytes_relayed: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChannelSnapshot {
+    client: SocketAddr,
+    number: u16,
+    peer: SocketAddr,
+    allocation: u16,
+    /// Milliseconds until the channel expires; negative if it already expired but cannot be rebound yet.
+    expires_in_ms: i64,
+    bound: bool,
+}
+
+impl Snapshot {
+    pub fn num_allocations(&self) -> usize {
+        self.allocations.len()
+    }
+}
+
+impl<R> Server<R>
+where
+    R: Rng,
+{
+    /// Captures the current state of the server so it can be [restored](Server::restore) later, e.g. after a restart.
+    pub fn snapshot(&self, now: Instant, wall_clock: SystemTime) -> Snapshot {
+        Snapshot {
+            version: VERSION,
+            taken_at: wall_clock,
+            auth_secret: self.auth_secret.expose_secret().to_owned(),
+            nonces: self.nonces.iter().collect(),
+            allocations: self
+                .allocations
+                .iter()
+                .filter(|(client, _)| client.transport() == ClientTransport::Udp)
+                .map(|(client, a)| AllocationSnapshot {
+                    client: client.into_socket(),
+                    port: a.port.value(),
+                    expires_in_ms: offset_ms(a.expires_at, now),
+                    first_relay_addr: a.first_relay_addr,
+                    second_relay_addr: a.second_relay_addr,
+                    username: a.username.clone(),
+                    bytes_relayed: a.bytes_relayed,
+                })
+                .collect(),
+            channels: self
+                .channels_by_client_and_number
+                .iter()
+                .filter(|((client, _), _)| client.transport() == ClientTransport::Udp)
+                .map(|((client, number), c)| ChannelSnapshot {
+                    client: client.into_socket(),
+                    number: number.value(),
+                    peer: c.peer_address.into_socket(),
+                    allocation: c.allocation.value(),
+                    expires_in_ms: offset_ms(c.expiry, now),
+                    bound: c.bound,
+                })
+                .collect(),
+        }
+    }
+
+    /// Restores the state captured in a [`Snapshot`].
+    ///
+    /// Must be called on a fresh [`Server`], before it handled any input.
+    /// The time that passed since the snapshot was taken is deducted from all deadlines.
+    /// Allocations that have since expired or no longer fit the server's configuration (port range or public IPs) are dropped.
+    ///
+    /// For each restored allocation, a [`Command::CreateAllocation`] is queued so the caller re-binds its ports.
+    pub fn restore(&mut self, snapshot: Snapshot, now: Instant, wall_clock: SystemTime) {
+        debug_assert!(
+            self.allocations.is_empty(),
+            "must restore into a fresh server"
+        );
+
+        if snapshot.version != VERSION {
+            tracing::warn!(target: "relay", version = %snapshot.version, "Ignoring snapshot with unsupported version");
+
+            return;
+        }
+
+        let downtime = wall_clock
+            .duration_since(snapshot.taken_at)
+            .unwrap_or_default();
+        let downtime_ms = i64::try_from(downtime.as_millis()).unwrap_or(i64::MAX);
+
+        self.auth_secret = SecretString::from(snapshot.auth_secret);
+
+        for (nonce, remaining_requests) in snapshot.nonces {
+            self.nonces.restore(nonce, remaining_requests);
+        }
+
+        for a in snapshot.allocations {
+            let client = ClientSocket::new(a.client);
+            let port = AllocationPort::new(a.port);
+            let expires_at = instant_from_offset(a.expires_in_ms.saturating_sub(downtime_ms), now);
+
+            if expires_at <= now {
+                tracing::debug!(target: "relay", %client, %port, "Allocation expired while we were offline");
+                continue;
+            }
+            if !self.ports.contains(&a.port) || self.clients_by_allocation.contains_key(&port) {
+                tracing::warn!(target: "relay", %client, %port, "Cannot restore allocation outside of port range");
+                continue;
+            }
+            if !self.is_public_addr(a.first_relay_addr)
+                || a.second_relay_addr
+                    .is_some_and(|addr| !self.is_public_addr(addr))
+            {
+                tracing::warn!(target: "relay", %client, %port, "Cannot restore allocation for a different public address");
+                continue;
+            }
+
+            if let Some(bytes_per_sec) = self.limits.username_bytes_per_sec {
+                self.username_buckets
+                    .entry(a.username.clone())
+                    .or_insert_with(|| TokenBucket::new(bytes_per_sec, now));
+            }
+
+            self.pending_commands.push_back(Command::CreateAllocation {
+                port,
+                family: a.first_relay_addr.family(),
+            });
+            if let Some(second_relay_addr) = a.second_relay_addr {
+                self.pending_commands.push_back(Command::CreateAllocation {
+                    port,
+                    family: second_relay_addr.family(),
+                });
+            }
+
+            self.clients_by_allocation.insert(port, client);
+            self.allocations.insert(
+                client,
+                Allocation {
+                    port,
+                    expires_at,
+                    first_relay_addr: a.first_relay_addr,
+                    second_relay_addr: a.second_relay_addr,
+                    username: a.username,
+                    bucket: self
+                        .limits
+                        .allocation_bytes_per_sec
+                        .map(|bytes_per_sec| TokenBucket::new(bytes_per_sec, now)),
+                    bytes_relayed: a.bytes_relayed,
+                    limited_by: None,
+                },
+            );
+            self.allocations_up_down_counter.add(1, &[]);
+        }
+
+        for c in snapshot.channels {
+            let client = ClientSocket::new(c.client);
+            let peer = PeerSocket::new(c.peer);
+            let allocation = AllocationPort::new(c.allocation);
+
+            let Ok(number) = ChannelNumber::new(c.number) else {
+                continue;
+            };
+            if self.clients_by_allocation.get(&allocation) != Some(&client) {
+                continue; // The allocation of this channel wasn't restored.
+            }
+
+            let channel = Channel {
+                expiry: instant_from_offset(c.expires_in_ms.saturating_sub(downtime_ms), now),
+                peer_address: peer,
+                allocation,
+                bound: c.bound,
+            };
+
+            if channel.can_be_deleted(now) {
+                continue;
+            }
+
+            // Expired channels are unbound by the next `handle_timeout`, just like for channels that expire during normal operation.
+            if channel.bound {
+                self.channel_and_client_by_port_and_peer
+                    .insert((allocation, peer), (client, number));
+            }
+            self.channel_numbers_by_client_and_peer
+                .insert((client, peer), number);
+            self.channels_by_client_and_number
+                .insert((client, number), channel);
+        }
+
+        tracing::info!(target: "relay", allocations = %self.num_allocations(), channels = %self.num_active_channels(), ?downtime, "Restored state from snapshot");
+    }
+
+    fn is_public_addr(&self, addr: IpAddr) -> bool {
+        match addr {
+            IpAddr::V4(ip4) => self.public_address.as_v4() == Some(&ip4),
+            IpAddr::V6(ip6) => self.public_address.as_v6() == Some(&ip6),
+        }
+    }
+}
+
+/// The signed number of milliseconds from `now` until `instant`.
+fn offset_ms(instant: Instant, now: Instant) -> i64 {
+    match instant.checked_duration_since(now) {
+        Some(ahead) => i64::try_from(ahead.as_millis()).unwrap_or(i64::MAX),
+        None => -i64::try_from(now.duration_since(instant).as_millis()).unwrap_or(i64::MAX),
+    }
+}
+
+fn instant_from_offset(offset_ms: i64, now: Instant) -> Instant {
+    let offset = Duration::from_millis(offset_ms.unsigned_abs());
+
+    if offset_ms >= 0 {
+        now + offset
+    } else {
+        now.checked_sub(offset).unwrap_or(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_roundtrip_in_both_directions() {
+        let now = Instant::now() + Duration::from_secs(3600);
+
+        for instant in [
+            now,
+            now + Duration::from_millis(1500),
+            now - Duration::from_millis(1500),
+        ] {
+            assert_eq!(instant_from_offset(offset_ms(instant, now), now), instant);
+        }
+    }
+}
@@ -0,0 +1,490 @@
+//! TURN over TCP and TLS, see <https://www.rfc-editor.org/rfc/rfc8656#section-3.1>.
+//!
+//! Each connection is handled by its own task that reads framed messages from the stream and forwards them to the [`Streams`] handle.
+//! Messages to a client are queued on a per-connection channel.
+//!
+//! Only the client side of an allocation uses a stream: peers are always reached via UDP.
+//! TCP allocations towards peers (`Connect` and `ConnectionBind` from RFC 6062) are out of scope.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use stun_codec::rfc8656::attributes::AddressFamily;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _},
+    net::TcpListener,
+    sync::{mpsc, Semaphore},
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::ClientTransport;
+
+/// How many messages we queue per connection before we start dropping them.
+const MAX_QUEUED_MESSAGES: usize = 1_024;
+
+/// How many TCP and TLS connections we keep open at most, across all listeners.
+const MAX_CONNECTIONS: usize = 10_000;
+
+/// How long a client may take to complete the TLS handshake before we close the connection.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const STUN_HEADER_LEN: usize = 20;
+const CHANNEL_DATA_HEADER_LEN: usize = 4;
+
+/// The TCP and TLS connections of all clients that talk to us via a stream.
+pub struct Streams {
+    connections: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>,
+    /// Each open connection, including those still in the TLS handshake, holds one permit.
+    permits: Arc<Semaphore>,
+
+    event_tx: mpsc::Sender<ConnectionEvent>,
+    event_rx: mpsc::Receiver<ConnectionEvent>,
+}
+
+#[derive(Debug)]
+pub enum Event {
+    /// A client sent us a STUN or ChannelData message.
+    Received {
+        from: SocketAddr,
+        transport: ClientTransport,
+        packet: Vec<u8>,
+    },
+    /// A client's connection was closed.
+    Closed {
+        from: SocketAddr,
+        transport: ClientTransport,
+    },
+}
+
+enum ConnectionEvent {
+    Connected {
+        from: SocketAddr,
+        outbound: mpsc::Sender<Vec<u8>>,
+    },
+    Received {
+        from: SocketAddr,
+        transport: ClientTransport,
+        packet: Vec<u8>,
+    },
+    Closed {
+        from: SocketAddr,
+        transport: ClientTransport,
+    },
+}
+
+impl Default for Streams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Streams {
+    pub fn new() -> Self {
+        let (event_tx, event_rx) = mpsc::channel(1_024);
+
+        Self {
+            connections: Default::default(),
+            permits: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
+            event_tx,
+            event_rx,
+        }
+    }
+
+    /// Accepts connections on the given port and address family, optionally secured with TLS.
+    ///
+    /// Must be called within a Tokio runtime context.
+    pub fn listen(
+        &mut self,
+        port: u16,
+        family: AddressFamily,
+        tls: Option<TlsAcceptor>,
+    ) -> io::Result<()> {
+        let listener = TcpListener::from_std(make_wildcard_listener(family, port)?)?;
+
+        tokio::spawn(accept_connections(
+            listener,
+            tls,
+            self.permits.clone(),
+            TLS_HANDSHAKE_TIMEOUT,
+            self.event_tx.clone(),
+        ));
+
+        Ok(())
+    }
+
+    /// Queues a message for the given client.
+    ///
+    /// ChannelData messages are padded as required by <https://www.rfc-editor.org/rfc/rfc8656#section-12.5>.
+    pub fn try_send(&self, client: SocketAddr, msg: &[u8]) -> io::Result<()> {
+        let connection = self
+            .connections
+            .get(&client)
+            .ok_or(io::ErrorKind::NotConnected)?;
+
+        let mut frame = Vec::with_capacity(padded_len(msg));
+        frame.extend_from_slice(msg);
+        frame.resize(padded_len(msg), 0);
+
+        connection.try_send(frame).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => io::Error::from(io::ErrorKind::WouldBlock),
+            mpsc::error::TrySendError::Closed(_) => io::Error::from(io::ErrorKind::NotConnected),
+        })
+    }
+
+    pub fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Event> {
+        loop {
+            let Some(event) = std::task::ready!(self.event_rx.poll_recv(cx)) else {
+                unreachable!("we hold a sender ourselves")
+            };
+
+            match event {
+                ConnectionEvent::Connected { from, outbound } => {
+                    self.connections.insert(from, outbound);
+                }
+                ConnectionEvent::Received {
+                    from,
+                    transport,
+                    packet,
+                } => {
+                    return Poll::Ready(Event::Received {
+                        from,
+                        transport,
+                        packet,
+                    })
+                }
+                ConnectionEvent::Closed { from, transport } => {
+                    self.connections.remove(&from);
+
+                    return Poll::Ready(Event::Closed { from, transport });
+                }
+            }
+        }
+    }
+}
+
+async fn accept_connections(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    permits: Arc<Semaphore>,
+    tls_handshake_timeout: Duration,
+    events: mpsc::Sender<ConnectionEvent>,
+) {
+    loop {
+        let (stream, from) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!(target: "relay", "Failed to accept TCP connection: {e}");
+                continue;
+            }
+        };
+
+        // Closing the connection right away lets the client fall back to another relay instead of waiting in our backlog.
+        let Ok(permit) = permits.clone().try_acquire_owned() else {
+            tracing::debug!(target: "relay", %from, "Too many open connections, closing new one");
+            continue;
+        };
+
+        let events = events.clone();
+
+        match tls.clone() {
+            Some(acceptor) => {
+                tokio::spawn(async move {
+                    let _permit = permit;
+
+                    match tokio::time::timeout(tls_handshake_timeout, acceptor.accept(stream)).await
+                    {
+                        Ok(Ok(stream)) => {
+                            handle_connection(stream, from, ClientTransport::Tls, events).await
+                        }
+                        Ok(Err(e)) => {
+                            tracing::debug!(target: "relay", %from, "TLS handshake failed: {e}")
+                        }
+                        Err(_) => {
+                            tracing::debug!(target: "relay", %from, "TLS handshake timed out")
+                        }
+                    }
+                });
+            }
+            None => {
+                tokio::spawn(async move {
+                    let _permit = permit;
+
+                    handle_connection(stream, from, ClientTransport::Tcp, events).await
+                });
+            }
+        }
+    }
+}
+
+async fn handle_connection<S>(
+    stream: S,
+    from: SocketAddr,
+    transport: ClientTransport,
+    events: mpsc::Sender<ConnectionEvent>,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (outbound, outbound_rx) = mpsc::channel(MAX_QUEUED_MESSAGES);
+
+    if events
+        .send(ConnectionEvent::Connected { from, outbound })
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    tracing::debug!(target: "relay", %from, ?transport, "New stream connection");
+
+    let (reader, writer) = tokio::io::split(stream);
+
+    let result = tokio::select! {
+        result = read_messages(reader, from, transport, &events) => result,
+        result = write_messages(writer, outbound_rx) => result,
+    };
+
+    if let Err(e) = result {
+        tracing::debug!(target: "relay", %from, "Stream connection failed: {e}");
+    }
+
+    let _ = events
+        .send(ConnectionEvent::Closed { from, transport })
+        .await;
+}
+
+async fn read_messages<R>(
+    mut reader: R,
+    from: SocketAddr,
+    transport: ClientTransport,
+    events: &mpsc::Sender<ConnectionEvent>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        let packet = read_message(&mut reader).await?;
+
+        events
+            .send(ConnectionEvent::Received {
+                from,
+                transport,
+                packet,
+            })
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+    }
+}
+
+async fn write_messages<W>(mut writer: W, mut outbound: mpsc::Receiver<Vec<u8>>) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(frame) = outbound.recv().await {
+        writer.write_all(&frame).await?;
+    }
+
+    Ok(())
+}
+
+/// Reads a single STUN or ChannelData message from the stream, stripping any padding.
+async fn read_message<R>(reader: &mut R) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 4];
+    reader.read_exact(&mut header).await?;
+
+    let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+
+    let (message_len, frame_len) = match header[0] {
+        0..=3 => (STUN_HEADER_LEN + len, STUN_HEADER_LEN + len),
+        64..=79 => (
+            CHANNEL_DATA_HEADER_LEN + len,
+            (CHANNEL_DATA_HEADER_LEN + len).next_multiple_of(4),
+        ),
+        other => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown message type: {other}"),
+            ))
+        }
+    };
+
+    let mut frame = vec![0u8; frame_len];
+    frame[..4].copy_from_slice(&header);
+    reader.read_exact(&mut frame[4..]).await?;
+    frame.truncate(message_len);
+
+    Ok(frame)
+}
+
+fn padded_len(msg: &[u8]) -> usize {
+    match msg.first() {
+        Some(64..=79) => msg.len().next_multiple_of(4),
+        Some(_) | None => msg.len(),
+    }
+}
+
+/// Creates a [`std::net::TcpListener`] on all interfaces of the given family.
+///
+/// Like our UDP sockets, IPv6 listeners set `IPV6_V6ONLY` so we can listen on IPv4 and IPv6 on the same port.
+fn make_wildcard_listener(family: AddressFamily, port: u16) -> io::Result<std::net::TcpListener> {
+    use socket2::*;
+
+    let domain = match family {
+        AddressFamily::V4 => Domain::IPV4,
+        AddressFamily::V6 => Domain::IPV6,
+    };
+    let address = match family {
+        AddressFamily::V4 => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        AddressFamily::V6 => IpAddr::from(Ipv6Addr::UNSPECIFIED),
+    };
+
+    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    if family == AddressFamily::V6 {
+        socket.set_only_v6(true)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SockAddr::from(SocketAddr::new(address, port)))?;
+    socket.listen(1024)?;
+
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn strips_channel_data_padding() {
+        let mut stream: &[u8] = &[0x40, 0x00, 0x00, 0x03, 1, 2, 3, 0, 0x40, 0x00, 0x00, 0x00];
+
+        assert_eq!(
+            read_message(&mut stream).await.unwrap(),
+            vec![0x40, 0x00, 0x00, 0x03, 1, 2, 3]
+        );
+        assert_eq!(
+            read_message(&mut stream).await.unwrap(),
+            vec![0x40, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_stun_message_with_attributes() {
+        let mut message = vec![0x00, 0x01, 0x00, 0x04];
+        message.extend_from_slice(&[0; 20]);
+        let mut stream = message.as_slice();
+
+        assert_eq!(read_message(&mut stream).await.unwrap(), message);
+    }
+
+    #[test]
+    fn pads_channel_data_only() {
+        assert_eq!(padded_len(&[0x40, 0x00, 0x00, 0x01, 1]), 8);
+        assert_eq!(padded_len(&[0x00, 0x01, 0x00, 0x00]), 4);
+    }
+
+    #[tokio::test]
+    async fn closes_connections_above_limit() {
+        let (addr, mut events) = spawn_listener(None, 1, Duration::from_secs(10)).await;
+
+        let _first = TcpStream::connect(addr).await.unwrap();
+        let connected = events.recv().await.unwrap(); // Holds the outbound channel, just like `Streams`.
+        assert!(matches!(connected, ConnectionEvent::Connected { .. }));
+
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(second.read(&mut [0u8; 1]).await.unwrap(), 0);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn closed_connections_release_their_permit() {
+        let (addr, mut events) = spawn_listener(None, 1, Duration::from_secs(10)).await;
+
+        let first = TcpStream::connect(addr).await.unwrap();
+        let connected = events.recv().await.unwrap(); // Holds the outbound channel, just like `Streams`.
+        assert!(matches!(connected, ConnectionEvent::Connected { .. }));
+        drop(first);
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            ConnectionEvent::Closed { .. }
+        ));
+
+        let _second = TcpStream::connect(addr).await.unwrap();
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            ConnectionEvent::Connected { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn closes_connections_that_dont_complete_tls_handshake() {
+        let timeout = Duration::from_millis(100);
+        let (addr, mut events) = spawn_listener(Some(tls_acceptor()), 1, timeout).await;
+
+        let start = Instant::now();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(client.read(&mut [0u8; 1]).await.unwrap(), 0);
+        assert!(start.elapsed() >= timeout);
+        assert!(events.try_recv().is_err());
+
+        // The timed out connection no longer counts towards the limit.
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(client.read(&mut [0u8; 1]).await.unwrap(), 0);
+        assert!(start.elapsed() >= 2 * timeout);
+    }
+
+    async fn spawn_listener(
+        tls: Option<TlsAcceptor>,
+        max_connections: usize,
+        tls_handshake_timeout: Duration,
+    ) -> (SocketAddr, mpsc::Receiver<ConnectionEvent>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (event_tx, event_rx) = mpsc::channel(10);
+
+        tokio::spawn(accept_connections(
+            listener,
+            tls,
+            Arc::new(Semaphore::new(max_connections)),
+            tls_handshake_timeout,
+            event_tx,
+        ));
+
+        (addr, event_rx)
+    }
+
+    /// A [`TlsAcceptor`] without a certificate: good enough for clients that never send a `ClientHello`.
+    fn tls_acceptor() -> TlsAcceptor {
+        #[derive(Debug)]
+        struct NoCertificate;
+
+        impl rustls::server::ResolvesServerCert for NoCertificate {
+            fn resolve(
+                &self,
+                _: rustls::server::ClientHello<'_>,
+            ) -> Option<Arc<rustls::sign::CertifiedKey>> {
+                None
+            }
+        }
+
+        let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(NoCertificate));
+
+        TlsAcceptor::from(Arc::new(config))
+    }
+}
@@ -1,10 +1,11 @@
 use bytecodec::{DecodeExt, EncodeExt};
 use firezone_relay::{
     AddressFamily, Allocate, AllocationPort, Attribute, Binding, ChannelBind, ChannelData,
-    ClientMessage, ClientSocket, Command, IpStack, PeerSocket, Refresh, Server,
+    ClientMessage, ClientSocket, ClientTransport, Command, IpStack, Limits, PeerSocket, Refresh,
+    Server,
 };
 use rand::rngs::mock::StepRng;
-use secrecy::SecretString;
+use secrecy::{ExposeSecret as _, SecretString};
 use std::iter;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::time::{Duration, Instant, SystemTime};
@@ -294,6 +295,293 @@ fn when_receiving_lifetime_0_for_existing_allocation_then_delete(
     server.assert_commands(forward_time_to(first_wake + Duration::from_secs(1)), []);
 }
 
+#[proptest]
+fn closing_stream_connection_deletes_allocation(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] allocate_lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(allocate_lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &allocate_lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        disconnect(source),
+        [free_allocation(49152, AddressFamily::V4)],
+    );
+
+    // Assert that the allocation doesn't expire again later.
+    server.assert_commands(
+        forward_time_to(now + allocate_lifetime.lifetime() + Duration::from_secs(1)),
+        [],
+    );
+}
+
+#[proptest]
+fn allocation_rate_limit_drops_excess_data(
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_limits(Limits {
+            allocation_bytes_per_sec: Some(1000),
+            ..Default::default()
+        });
+    server.allocate_and_bind_channel(
+        source,
+        peer,
+        channel,
+        valid_username(&username_salt),
+        nonce,
+        now,
+    );
+
+    // We can burst up to 1 second worth of data, in both directions combined.
+    assert!(server.client_to_peer(source, channel, 600, now).is_some());
+    assert!(server.peer_to_client(peer, 400, now).is_some());
+    assert!(server.client_to_peer(source, channel, 1, now).is_none());
+    assert_eq!(server.server.num_limited_bytes(), 1);
+
+    // Half a second later, the bucket has refilled by 500 bytes.
+    let now = now + Duration::from_millis(500);
+
+    assert!(server.client_to_peer(source, channel, 500, now).is_some());
+    assert!(server.peer_to_client(peer, 1, now).is_none());
+    assert_eq!(server.server.num_limited_bytes(), 2);
+}
+
+#[proptest]
+fn username_rate_limit_is_shared_between_allocations(
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source1: SocketAddrV4,
+    source2: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    proptest::prop_assume!(source1 != source2);
+
+    let now = Instant::now();
+
+    // Step the RNG so the two allocations get different ports.
+    let mut server = TestServer {
+        server: Server::new(
+            public_relay_addr,
+            StepRng::new(0, 1 << 31),
+            3478,
+            49152..=65535,
+        ),
+    }
+    .with_nonce(nonce)
+    .with_limits(Limits {
+        username_bytes_per_sec: Some(1000),
+        ..Default::default()
+    });
+    let username = valid_username(&username_salt);
+
+    server.allocate_and_bind_channel(source1, peer, channel, username.clone(), nonce, now);
+    server.allocate_and_bind_channel(source2, peer, channel, username, nonce, now);
+
+    assert!(server.client_to_peer(source1, channel, 800, now).is_some());
+    assert!(server.client_to_peer(source2, channel, 800, now).is_none());
+    assert!(server.client_to_peer(source2, channel, 200, now).is_some());
+    assert_eq!(server.server.num_limited_bytes(), 800);
+}
+
+#[proptest]
+fn allocation_max_bytes_limit_does_not_reset_over_time(
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_limits(Limits {
+            allocation_max_bytes: Some(1000),
+            ..Default::default()
+        });
+    server.allocate_and_bind_channel(
+        source,
+        peer,
+        channel,
+        valid_username(&username_salt),
+        nonce,
+        now,
+    );
+
+    assert!(server.client_to_peer(source, channel, 600, now).is_some());
+    assert!(server.peer_to_client(peer, 400, now).is_some());
+    assert!(server.client_to_peer(source, channel, 1, now).is_none());
+
+    let now = now + Duration::from_secs(60);
+
+    assert!(server.client_to_peer(source, channel, 1, now).is_none());
+    assert!(server.peer_to_client(peer, 1, now).is_none());
+    assert_eq!(server.server.num_limited_bytes(), 3);
+}
+
+#[proptest]
+fn restored_server_relays_data_of_existing_allocation(
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+    let wall_clock = SystemTime::now();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    server.allocate_and_bind_channel(
+        source,
+        peer,
+        channel,
+        valid_username(&username_salt),
+        nonce,
+        now,
+    );
+    let snapshot = serde_json::to_vec(&server.server.snapshot(now, wall_clock)).unwrap();
+
+    let now = now + Duration::from_secs(1);
+    let wall_clock = wall_clock + Duration::from_secs(10);
+
+    let mut restored = TestServer::new(public_relay_addr);
+    restored
+        .server
+        .restore(serde_json::from_slice(&snapshot).unwrap(), now, wall_clock);
+
+    assert_eq!(
+        restored.server.auth_secret().expose_secret(),
+        server.server.auth_secret().expose_secret()
+    );
+    assert_eq!(
+        restored.server.next_command(),
+        Some(Command::CreateAllocation {
+            port: AllocationPort::new(49152),
+            family: AddressFamily::V4,
+        })
+    );
+    assert_eq!(restored.server.next_command(), None);
+    assert!(restored.client_to_peer(source, channel, 100, now).is_some());
+    assert!(restored.peer_to_client(peer, 100, now).is_some());
+}
+
+#[proptest]
+fn allocations_via_tcp_are_not_restored(
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+    let wall_clock = SystemTime::now();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    server.allocate_and_bind_channel_via(
+        ClientTransport::Tcp,
+        source,
+        peer,
+        channel,
+        valid_username(&username_salt),
+        nonce,
+        now,
+    );
+    assert_eq!(server.server.num_allocations(), 1);
+    let snapshot = serde_json::to_vec(&server.server.snapshot(now, wall_clock)).unwrap();
+
+    let mut restored = TestServer::new(public_relay_addr);
+    restored
+        .server
+        .restore(serde_json::from_slice(&snapshot).unwrap(), now, wall_clock);
+
+    assert_eq!(restored.server.next_command(), None);
+    assert_eq!(restored.server.num_allocations(), 0);
+    assert_eq!(restored.server.num_active_channels(), 0);
+    assert!(restored.client_to_peer(source, channel, 100, now).is_none());
+}
+
+#[proptest]
+fn allocations_that_expired_while_offline_are_not_restored(
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+    let wall_clock = SystemTime::now();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    server.allocate_and_bind_channel(
+        source,
+        peer,
+        channel,
+        valid_username(&username_salt),
+        nonce,
+        now,
+    );
+    let snapshot = server.server.snapshot(now, wall_clock);
+
+    let mut restored = TestServer::new(public_relay_addr);
+    restored.server.restore(
+        snapshot,
+        now + Duration::from_secs(1),
+        wall_clock + Duration::from_secs(2 * 60 * 60),
+    );
+
+    assert_eq!(restored.server.next_command(), None);
+    assert_eq!(restored.server.num_allocations(), 0);
+    assert_eq!(restored.server.num_active_channels(), 0);
+}
+
 #[proptest]
 fn freeing_allocation_clears_all_channels(
     #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
@@ -453,6 +741,7 @@ fn ping_pong_relay(
         peer_to_client_ping.as_slice(),
         PeerSocket::new(peer.into()),
         AllocationPort::new(49152),
+        now,
     );
 
     assert_eq!(
@@ -681,6 +970,7 @@ fn ping_pong_ip6_relay(
         peer_to_client_ping.as_slice(),
         PeerSocket::new(peer.into()),
         AllocationPort::new(49152),
+        now,
     );
 
     assert_eq!(
@@ -706,10 +996,108 @@ impl TestServer {
         self
     }
 
+    fn with_limits(mut self, limits: Limits) -> Self {
+        self.server = self.server.with_limits(limits);
+
+        self
+    }
+
     fn auth_secret(&self) -> &SecretString {
         self.server.auth_secret()
     }
 
+    /// Makes an allocation for `source` and binds `channel` to `peer`, discarding all commands.
+    fn allocate_and_bind_channel(
+        &mut self,
+        source: SocketAddrV4,
+        peer: SocketAddrV4,
+        channel: ChannelNumber,
+        username: Username,
+        nonce: Uuid,
+        now: Instant,
+    ) {
+        self.allocate_and_bind_channel_via(
+            ClientTransport::Udp,
+            source,
+            peer,
+            channel,
+            username,
+            nonce,
+            now,
+        );
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn allocate_and_bind_channel_via(
+        &mut self,
+        transport: ClientTransport,
+        source: SocketAddrV4,
+        peer: SocketAddrV4,
+        channel: ChannelNumber,
+        username: Username,
+        nonce: Uuid,
+        now: Instant,
+    ) {
+        let client = ClientSocket::with_transport(source.into(), transport);
+        let secret = self.auth_secret().to_owned();
+
+        let _ = self.server.handle_client_message(
+            Allocate::new_authenticated_udp_implicit_ip4(
+                TransactionId::new([1; 12]),
+                Some(Lifetime::new(Duration::from_secs(60 * 60)).unwrap()),
+                username.clone(),
+                &secret,
+                nonce,
+            )
+            .into(),
+            client,
+            now,
+        );
+        let _ = self.server.handle_client_message(
+            ChannelBind::new(
+                TransactionId::new([2; 12]),
+                channel,
+                XorPeerAddress::new(peer.into()),
+                username,
+                &secret,
+                nonce,
+            )
+            .into(),
+            client,
+            now,
+        );
+
+        let _ = iter::from_fn(|| self.server.next_command()).collect::<Vec<_>>();
+    }
+
+    fn client_to_peer(
+        &mut self,
+        source: SocketAddrV4,
+        channel: ChannelNumber,
+        payload_len: u16,
+        now: Instant,
+    ) -> Option<(AllocationPort, PeerSocket)> {
+        let mut msg = vec![0u8; 4 + payload_len as usize];
+        ChannelData::encode_header_to_slice(channel, payload_len, &mut msg[..4]);
+
+        self.server
+            .handle_client_input(&msg, ClientSocket::new(source.into()), now)
+    }
+
+    fn peer_to_client(
+        &mut self,
+        peer: SocketAddrV4,
+        payload_len: usize,
+        now: Instant,
+    ) -> Option<(ClientSocket, ChannelNumber)> {
+        self.server.handle_peer_traffic(
+            &vec![0u8; payload_len],
+            PeerSocket::new(peer.into()),
+            AllocationPort::new(49152),
+            now,
+        )
+    }
+
     fn assert_commands<const N: usize>(&mut self, input: Input, output: [Output; N]) {
         match input {
             Input::Client(sender, message, now) => {
@@ -718,6 +1106,9 @@ impl TestServer {
             Input::Time(now) => {
                 self.server.handle_timeout(now);
             }
+            Input::Disconnected(client) => {
+                self.server.handle_client_disconnected(client);
+            }
         }
 
         for expected_output in output {
@@ -861,6 +1252,7 @@ fn parse_message(message: &[u8]) -> Message<Attribute> {
 enum Input<'a> {
     Client(ClientSocket, ClientMessage<'a>, Instant),
     Time(Instant),
+    Disconnected(ClientSocket),
 }
 
 fn from_client<'a>(
@@ -875,6 +1267,10 @@ fn forward_time_to<'a>(when: Instant) -> Input<'a> {
     Input::Time(when)
 }
 
+fn disconnect<'a>(client: impl Into<SocketAddr>) -> Input<'a> {
+    Input::Disconnected(ClientSocket::new(client.into()))
+}
+
 #[derive(Debug)]
 enum Output {
     SendMessage((ClientSocket, Message<Attribute>)),
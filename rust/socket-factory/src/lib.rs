@@ -14,6 +14,11 @@ use std::collections::hash_map::Entry;
 use std::pin::Pin;
 use tokio::io::Interest;
 
+/// The largest payload of a single UDP datagram over IPv4.
+///
+/// A GSO batch is sent as one large datagram, so all its segments together must not exceed this.
+pub const MAX_UDP_PAYLOAD_LEN: usize = 65_507;
+
 pub trait SocketFactory<S>: Fn(&SocketAddr) -> io::Result<S> + Send + Sync + 'static {}
 
 impl<F, S> SocketFactory<S> for F where F: Fn(&SocketAddr) -> io::Result<S> + Send + Sync + 'static {}
@@ -85,6 +90,12 @@ pub struct TcpStream {
     _backpack: Option<Box<dyn Any + Send + Sync + Unpin + 'static>>,
 }
 
+impl TcpStream {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
 impl tokio::io::AsyncWrite for TcpStream {
     fn poll_write(
         mut self: Pin<&mut Self>,
@@ -145,9 +156,12 @@ pub struct UdpSocket {
 impl UdpSocket {
     fn new(inner: tokio::net::UdpSocket) -> io::Result<Self> {
         let port = inner.local_addr()?.port();
+        let state = quinn_udp::UdpSocketState::new(quinn_udp::UdpSockRef::from(&inner))?;
+
+        tracing::debug!(%port, gso = %state.max_gso_segments(), gro = %state.gro_segments(), "Created UDP socket");
 
         Ok(UdpSocket {
-            state: quinn_udp::UdpSocketState::new(quinn_udp::UdpSockRef::from(&inner))?,
+            state,
             port,
             inner,
             source_ip_resolver: Box::new(|_| Ok(None)),
@@ -169,6 +183,23 @@ impl UdpSocket {
         self.source_ip_resolver = resolver;
         self
     }
+
+    /// The maximum number of segments we can send in a single [`DatagramOut`].
+    ///
+    /// This is 1 if the platform doesn't support GSO.
+    /// It may also drop to 1 at runtime if the network driver rejects GSO sends.
+    pub fn max_gso_segments(&self) -> usize {
+        self.state.max_gso_segments()
+    }
+
+    /// The maximum number of segments of the given size we can send in a single [`DatagramOut`].
+    ///
+    /// Like [`UdpSocket::max_gso_segments`] but also limited by [`MAX_UDP_PAYLOAD_LEN`].
+    pub fn max_gso_segments_of(&self, segment_size: usize) -> usize {
+        self.max_gso_segments()
+            .min(MAX_UDP_PAYLOAD_LEN / segment_size.max(1))
+            .max(1)
+    }
 }
 
 #[cfg(unix)]
@@ -197,6 +228,11 @@ pub struct DatagramOut<'a> {
     pub src: Option<SocketAddr>,
     pub dst: SocketAddr,
     pub packet: Cow<'a, [u8]>,
+    /// If set, `packet` contains several datagrams of this size, back to back.
+    ///
+    /// Only the last datagram may be shorter.
+    /// Such a batch is sent using generic segmentation offload (GSO) if the platform supports it.
+    pub segment_size: Option<usize>,
 }
 
 impl UdpSocket {
@@ -253,9 +289,48 @@ impl UdpSocket {
     }
 
     pub fn send(&mut self, datagram: DatagramOut) -> io::Result<()> {
-        tracing::trace!(target: "wire::net::send", src = ?datagram.src, dst = %datagram.dst, num_bytes = %datagram.packet.len());
+        tracing::trace!(target: "wire::net::send", src = ?datagram.src, dst = %datagram.dst, num_bytes = %datagram.packet.len(), segment_size = ?datagram.segment_size);
+
+        let Some(segment_size) = datagram
+            .segment_size
+            .filter(|size| *size > 0 && *size < datagram.packet.len())
+        else {
+            return self.try_send(&DatagramOut {
+                segment_size: None,
+                ..datagram
+            });
+        };
+
+        let max_segments = self.max_gso_segments_of(segment_size);
+
+        if datagram.packet.len().div_ceil(segment_size) <= max_segments {
+            return self.try_send(&datagram);
+        }
 
-        self.try_send(&datagram)?;
+        // The batch is too large for a single GSO send (or GSO isn't available at all), send it in several chunks.
+        for (i, chunk) in datagram
+            .packet
+            .chunks(segment_size * max_segments)
+            .enumerate()
+        {
+            let res = self.try_send(&DatagramOut {
+                src: datagram.src,
+                dst: datagram.dst,
+                packet: Cow::Borrowed(chunk),
+                segment_size: (max_segments > 1).then_some(segment_size),
+            });
+
+            match res {
+                Ok(()) => {}
+                // If nothing has been sent yet, the caller can try again later.
+                Err(e) if i == 0 => return Err(e),
+                // Otherwise, retrying would duplicate the chunks we already sent, so we drop the rest.
+                Err(e) => {
+                    tracing::debug!(dst = %datagram.dst, "Dropping remainder of GSO batch: {e}");
+                    break;
+                }
+            }
+        }
 
         Ok(())
     }
@@ -282,7 +357,7 @@ impl UdpSocket {
             destination,
             ecn: None,
             contents: &transmit.packet,
-            segment_size: None,
+            segment_size: transmit.segment_size,
             src_ip,
         };
 
@@ -0,0 +1,322 @@
+//! The account the emulator serves, read from a YAML file.
+//!
+//! See `example.yaml` for the format.
+//!
+//! Clients and gateways without a `token` get a random one when the emulator starts.
+
+use anyhow::{bail, Context as _, Result};
+use connlib_shared::messages::{gateway::Filters, DnsServer};
+use ip_network::IpNetwork;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::Path;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub sites: Vec<Site>,
+    #[serde(default)]
+    pub gateways: Vec<Gateway>,
+    #[serde(default)]
+    pub clients: Vec<Client>,
+    #[serde(default)]
+    pub resources: Vec<Resource>,
+    #[serde(default)]
+    pub policies: Vec<Policy>,
+    #[serde(default)]
+    pub relays: Vec<Relay>,
+    /// Handed to clients, who otherwise use the system's resolvers.
+    #[serde(default)]
+    pub upstream_dns: Vec<DnsServer>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Site {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Gateway {
+    pub name: String,
+    pub site: String,
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Client {
+    pub name: String,
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Resource {
+    Cidr {
+        name: String,
+        address: IpNetwork,
+        site: String,
+        #[serde(default)]
+        filters: Filters,
+    },
+    Dns {
+        name: String,
+        address: String,
+        site: String,
+        #[serde(default)]
+        filters: Filters,
+    },
+    Internet {
+        name: String,
+        site: String,
+    },
+}
+
+/// Grants a client access to a resource.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    pub client: String,
+    pub resource: String,
+}
+
+/// A relay running in standalone mode with the given `secret`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Relay {
+    pub addrs: Vec<SocketAddr>,
+    pub secret: String,
+}
+
+impl Resource {
+    pub fn name(&self) -> &str {
+        match self {
+            Resource::Cidr { name, .. }
+            | Resource::Dns { name, .. }
+            | Resource::Internet { name, .. } => name,
+        }
+    }
+
+    pub fn site(&self) -> &str {
+        match self {
+            Resource::Cidr { site, .. }
+            | Resource::Dns { site, .. }
+            | Resource::Internet { site, .. } => site,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let yaml = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read `{}`", path.display()))?;
+
+        Self::parse(&yaml).with_context(|| format!("Invalid config in `{}`", path.display()))
+    }
+
+    pub fn parse(yaml: &str) -> Result<Self> {
+        let config = serde_yaml_ng::from_str::<Self>(yaml)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Assigns a random token to every client and gateway that doesn't have one yet.
+    ///
+    /// Returns the role, name and token of each one that was generated.
+    pub fn generate_missing_tokens(&mut self) -> Vec<(&'static str, String, String)> {
+        let clients = self
+            .clients
+            .iter_mut()
+            .filter_map(|c| generate_token(&mut c.token).map(|t| ("client", c.name.clone(), t)));
+        let gateways = self
+            .gateways
+            .iter_mut()
+            .filter_map(|g| generate_token(&mut g.token).map(|t| ("gateway", g.name.clone(), t)));
+
+        clients.chain(gateways).collect()
+    }
+
+    fn validate(&self) -> Result<()> {
+        let sites = unique_names("site", self.sites.iter().map(|s| s.name.as_str()))?;
+        let clients = unique_names("client", self.clients.iter().map(|c| c.name.as_str()))?;
+        let resources = unique_names("resource", self.resources.iter().map(|r| r.name()))?;
+        unique_names("gateway", self.gateways.iter().map(|g| g.name.as_str()))?;
+
+        for gateway in &self.gateways {
+            if !sites.contains(gateway.site.as_str()) {
+                bail!(
+                    "Gateway `{}` is in unknown site `{}`",
+                    gateway.name,
+                    gateway.site
+                );
+            }
+        }
+
+        for resource in &self.resources {
+            if !sites.contains(resource.site()) {
+                bail!(
+                    "Resource `{}` is in unknown site `{}`",
+                    resource.name(),
+                    resource.site()
+                );
+            }
+        }
+
+        for policy in &self.policies {
+            if !clients.contains(policy.client.as_str()) {
+                bail!("Policy refers to unknown client `{}`", policy.client);
+            }
+            if !resources.contains(policy.resource.as_str()) {
+                bail!("Policy refers to unknown resource `{}`", policy.resource);
+            }
+        }
+
+        for relay in &self.relays {
+            if relay.addrs.is_empty() {
+                bail!("Relay must have at least one address");
+            }
+            if relay.secret.is_empty() {
+                bail!("Relay secret must not be empty");
+            }
+        }
+
+        let tokens = self
+            .clients
+            .iter()
+            .filter_map(|c| c.token.as_deref())
+            .chain(self.gateways.iter().filter_map(|g| g.token.as_deref()));
+        unique_names("token", tokens)?;
+
+        Ok(())
+    }
+}
+
+fn unique_names<'a>(kind: &str, names: impl Iterator<Item = &'a str>) -> Result<HashSet<&'a str>> {
+    let mut seen = HashSet::new();
+
+    for name in names {
+        if !seen.insert(name) {
+            bail!("Duplicate {kind} `{name}`");
+        }
+    }
+
+    Ok(seen)
+}
+
+fn generate_token(token: &mut Option<String>) -> Option<String> {
+    use rand::distributions::{Alphanumeric, DistString as _};
+
+    if token.is_some() {
+        return None;
+    }
+
+    let generated = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+    *token = Some(generated.clone());
+
+    Some(generated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use connlib_shared::messages::gateway::{Filter, PortRange};
+
+    #[test]
+    fn parses_example() {
+        let config = Config::parse(include_str!("../example.yaml")).unwrap();
+
+        assert_eq!(config.sites.len(), 1);
+        assert_eq!(config.gateways[0].token.as_deref(), Some("gateway-token"));
+        let Resource::Dns {
+            address, filters, ..
+        } = &config.resources[1]
+        else {
+            panic!("expected a DNS resource");
+        };
+        assert_eq!(address, "*.wiki.test");
+        assert_eq!(
+            filters,
+            &vec![Filter::Tcp(PortRange {
+                port_range_start: 443,
+                port_range_end: 443,
+                source_port_range_start: None,
+                source_port_range_end: None,
+            })]
+        );
+        assert_eq!(
+            config.relays[0].addrs,
+            vec!["172.28.0.101:3478".parse().unwrap()]
+        );
+    }
+
+    #[test]
+    fn rejects_policy_for_unknown_resource() {
+        let error = Config::parse(
+            r#"
+clients:
+  - name: ci
+policies:
+  - client: ci
+    resource: intranet
+"#,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Policy refers to unknown resource `intranet`"
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_tokens() {
+        let error = Config::parse(
+            r#"
+sites:
+  - name: office
+clients:
+  - name: ci
+    token: secret
+gateways:
+  - name: gateway-1
+    site: office
+    token: secret
+"#,
+        )
+        .unwrap_err();
+
+        assert_eq!(error.to_string(), "Duplicate token `secret`");
+    }
+
+    #[test]
+    fn generates_only_missing_tokens() {
+        let mut config = Config::parse(
+            r#"
+sites:
+  - name: office
+clients:
+  - name: ci
+  - name: laptop
+    token: laptop-token
+gateways:
+  - name: gateway-1
+    site: office
+"#,
+        )
+        .unwrap();
+
+        let generated = config.generate_missing_tokens();
+
+        assert_eq!(generated.len(), 2);
+        assert_eq!(generated[0].1, "ci");
+        assert_eq!(generated[1].1, "gateway-1");
+        assert_eq!(config.clients[0].token.as_ref(), Some(&generated[0].2));
+        assert_eq!(config.clients[1].token.as_deref(), Some("laptop-token"));
+        assert!(config.generate_missing_tokens().is_empty());
+    }
+}
@@ -0,0 +1,160 @@
+//! A stand-in for the portal that runs without a database or Elixir.
+//!
+//! It speaks enough of the Phoenix channel protocol to onboard real `firezone-headless-client` and `firezone-gateway` processes:
+//! it hands out interfaces, resources and relay credentials and relays connection setup and ICE candidates between them.
+
+use anyhow::{Context as _, Result};
+use clap::Parser;
+use config::Config;
+use connlib_shared::messages::Key;
+use futures::{SinkExt as _, StreamExt as _};
+use portal::{Identity, Portal, Role};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{StatusCode, Uri};
+use tokio_tungstenite::tungstenite::Message;
+use tracing_subscriber::layer;
+
+mod config;
+mod portal;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// YAML file with the sites, gateways, clients, resources, policies and relays to serve.
+    #[arg(long, env = "PORTAL_EMULATOR_CONFIG")]
+    config: PathBuf,
+    /// Address to accept WebSocket connections on.
+    ///
+    /// Point `FIREZONE_API_URL` of clients and gateways at `ws://<this address>`.
+    #[arg(
+        long,
+        env = "PORTAL_EMULATOR_LISTEN_ADDR",
+        default_value = "127.0.0.1:8081"
+    )]
+    listen_addr: SocketAddr,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    firezone_logging::setup_global_subscriber(layer::Identity::new());
+
+    let mut config = Config::load(&args.config)?;
+    print_tokens(config.generate_missing_tokens());
+
+    let portal = Arc::new(Mutex::new(Portal::new(config)));
+    let listener = TcpListener::bind(args.listen_addr)
+        .await
+        .with_context(|| format!("Failed to listen on {}", args.listen_addr))?;
+
+    tracing::info!(addr = %args.listen_addr, "Accepting connections");
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("Failed to accept connection: {e}");
+                continue;
+            }
+        };
+
+        let portal = portal.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(portal, stream, peer).await {
+                tracing::debug!(%peer, "Connection failed: {e:#}");
+            }
+        });
+    }
+}
+
+/// Tokens are needed to start clients and gateways, so they go to stdout rather than the log.
+#[allow(clippy::print_stdout)]
+fn print_tokens(tokens: Vec<(&'static str, String, String)>) {
+    for (role, name, token) in tokens {
+        println!("{role} {name} {token}");
+    }
+}
+
+async fn handle_connection(
+    portal: Arc<Mutex<Portal>>,
+    stream: TcpStream,
+    peer: SocketAddr,
+) -> Result<()> {
+    let mut login = None;
+    let websocket = tokio_tungstenite::accept_hdr_async(
+        stream,
+        |req: &Request, res: Response| -> Result<Response, ErrorResponse> {
+            login = Some(authenticate(&portal, req.uri())?);
+
+            Ok(res)
+        },
+    )
+    .await
+    .context("WebSocket handshake failed")?;
+    let (identity, public_key) = login.context("Handshake succeeded without login")?;
+
+    let (outbound_tx, mut outbound_rx) = mpsc::unbounded_channel();
+    let connection = lock(&portal).connect(identity, public_key, peer.ip(), outbound_tx);
+    let (mut sink, mut stream) = websocket.split();
+
+    let result = loop {
+        tokio::select! {
+            msg = stream.next() => match msg {
+                Some(Ok(Message::Text(text))) => lock(&portal).handle_message(identity, &text),
+                Some(Ok(Message::Close(_))) | None => break Ok(()),
+                Some(Ok(Message::Binary(_) | Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                Some(Err(e)) => break Err(e),
+            },
+            Some(text) = outbound_rx.recv() => {
+                if let Err(e) = sink.send(Message::Text(text)).await {
+                    break Err(e);
+                }
+            }
+        }
+    };
+
+    lock(&portal).disconnect(identity, connection);
+
+    result.context("WebSocket connection failed")
+}
+
+/// Authenticates a WebSocket upgrade request the way `LoginUrl` builds it: the role is part of the path, the token and public key are query parameters.
+fn authenticate(portal: &Mutex<Portal>, uri: &Uri) -> Result<(Identity, Key), ErrorResponse> {
+    let role = if uri.path().ends_with("/client/websocket") {
+        Role::Client
+    } else if uri.path().ends_with("/gateway/websocket") {
+        Role::Gateway
+    } else {
+        return Err(error_response(StatusCode::NOT_FOUND));
+    };
+
+    let query = url::form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes())
+        .collect::<HashMap<_, _>>();
+    let public_key = query
+        .get("public_key")
+        .and_then(|key| key.parse::<Key>().ok())
+        .ok_or_else(|| error_response(StatusCode::BAD_REQUEST))?;
+    let identity = query
+        .get("token")
+        .and_then(|token| lock(portal).authenticate(role, token))
+        .ok_or_else(|| error_response(StatusCode::UNAUTHORIZED))?;
+
+    Ok((identity, public_key))
+}
+
+fn error_response(status: StatusCode) -> ErrorResponse {
+    let mut response = ErrorResponse::new(None);
+    *response.status_mut() = status;
+
+    response
+}
+
+fn lock(portal: &Mutex<Portal>) -> std::sync::MutexGuard<'_, Portal> {
+    portal.lock().expect("portal lock should never be poisoned")
+}
@@ -0,0 +1,1050 @@
+//! The emulated portal: who is connected and how to answer their messages.
+//!
+//! Each connected client and gateway has a [`Session`] with a channel to its WebSocket.
+//! Messages are handled synchronously, anything we send is queued on the recipient's channel.
+
+use crate::config::{self, Config};
+use chrono::{DateTime, Utc};
+use connlib_client_shared::messages as client_api;
+use connlib_shared::messages::{
+    client, gateway, ClientId, GatewayId, Interface, Key, Peer, Relay, RelayId, ResourceId, Turn,
+};
+use firezone_gateway::messages as gateway_api;
+use phoenix_channel::{ErrorReply, OutboundRequestId, PhoenixMessage};
+use secrecy::SecretString;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Digest as _;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+
+const PERSISTENT_KEEPALIVE: u16 = 25;
+const RELAY_CREDENTIALS_VALIDITY: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+// Clients get their tunnel addresses from the lower half of the range, gateways from the upper half.
+const CLIENT_IPV4_BASE: Ipv4Addr = Ipv4Addr::new(100, 64, 0, 0);
+const GATEWAY_IPV4_BASE: Ipv4Addr = Ipv4Addr::new(100, 64, 128, 0);
+const CLIENT_IPV6_BASE: Ipv6Addr = Ipv6Addr::new(0xfd00, 0x2021, 0x1111, 0, 0, 0, 0, 0);
+const GATEWAY_IPV6_BASE: Ipv6Addr = Ipv6Addr::new(0xfd00, 0x2021, 0x1111, 0, 0, 0, 0x8000, 0);
+
+/// The WebSocket endpoint a component connected to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Gateway,
+}
+
+/// Who is on the other end of a WebSocket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Identity {
+    Client(ClientId),
+    Gateway(GatewayId),
+}
+
+pub struct Portal {
+    config: Config,
+
+    clients: HashMap<ClientId, Session>,
+    gateways: HashMap<GatewayId, Session>,
+
+    /// Connection requests we forwarded to a gateway but haven't answered to the client yet, by their reference.
+    pending: HashMap<String, PendingConnection>,
+
+    next_connection: u64,
+    next_reference: u64,
+}
+
+struct Session {
+    /// Identifies the WebSocket this session belongs to, in case a component reconnects before we noticed the old one failing.
+    connection: u64,
+    public_key: Key,
+    remote_ip: IpAddr,
+    ipv4: Ipv4Addr,
+    ipv6: Ipv6Addr,
+    outbound: mpsc::UnboundedSender<String>,
+}
+
+struct PendingConnection {
+    client_id: ClientId,
+    request_id: Option<OutboundRequestId>,
+    resource_id: ResourceId,
+    gateway_id: GatewayId,
+}
+
+/// The envelope of every message sent to the portal.
+#[derive(Deserialize)]
+struct InboundMessage {
+    topic: String,
+    event: String,
+    #[serde(default)]
+    payload: serde_json::Value,
+    #[serde(rename = "ref")]
+    reference: Option<OutboundRequestId>,
+}
+
+impl InboundMessage {
+    /// Decodes `event` and `payload` into one of the `EgressMessages` enums.
+    fn decode<T>(&self) -> serde_json::Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        serde_json::from_value(serde_json::json!({
+            "event": self.event,
+            "payload": self.payload,
+        }))
+    }
+}
+
+impl Portal {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            clients: Default::default(),
+            gateways: Default::default(),
+            pending: Default::default(),
+            next_connection: 0,
+            next_reference: 0,
+        }
+    }
+
+    pub fn authenticate(&self, role: Role, token: &str) -> Option<Identity> {
+        match role {
+            Role::Client => self
+                .config
+                .clients
+                .iter()
+                .find(|c| c.token.as_deref() == Some(token))
+                .map(|c| Identity::Client(client_id(&c.name))),
+            Role::Gateway => self
+                .config
+                .gateways
+                .iter()
+                .find(|g| g.token.as_deref() == Some(token))
+                .map(|g| Identity::Gateway(gateway_id(&g.name))),
+        }
+    }
+
+    /// Registers a new WebSocket for `identity`, replacing any previous one.
+    ///
+    /// Returns the identifier to pass to [`Portal::disconnect`].
+    pub fn connect(
+        &mut self,
+        identity: Identity,
+        public_key: Key,
+        remote_ip: IpAddr,
+        outbound: mpsc::UnboundedSender<String>,
+    ) -> u64 {
+        self.next_connection += 1;
+        let connection = self.next_connection;
+
+        match identity {
+            Identity::Client(id) => {
+                let index = self
+                    .config
+                    .clients
+                    .iter()
+                    .position(|c| client_id(&c.name) == id)
+                    .expect("only configured clients can authenticate");
+
+                self.clients.insert(
+                    id,
+                    Session {
+                        connection,
+                        public_key,
+                        remote_ip,
+                        ipv4: nth_ipv4(CLIENT_IPV4_BASE, index),
+                        ipv6: nth_ipv6(CLIENT_IPV6_BASE, index),
+                        outbound,
+                    },
+                );
+            }
+            Identity::Gateway(id) => {
+                let index = self
+                    .config
+                    .gateways
+                    .iter()
+                    .position(|g| gateway_id(&g.name) == id)
+                    .expect("only configured gateways can authenticate");
+
+                self.gateways.insert(
+                    id,
+                    Session {
+                        connection,
+                        public_key,
+                        remote_ip,
+                        ipv4: nth_ipv4(GATEWAY_IPV4_BASE, index),
+                        ipv6: nth_ipv6(GATEWAY_IPV6_BASE, index),
+                        outbound,
+                    },
+                );
+            }
+        }
+
+        tracing::info!(?identity, %remote_ip, "Connected");
+
+        connection
+    }
+
+    pub fn disconnect(&mut self, identity: Identity, connection: u64) {
+        let removed = match identity {
+            Identity::Client(id) => remove_session(&mut self.clients, id, connection),
+            Identity::Gateway(id) => remove_session(&mut self.gateways, id, connection),
+        };
+
+        if removed {
+            tracing::info!(?identity, "Disconnected");
+        }
+    }
+
+    pub fn handle_message(&mut self, identity: Identity, text: &str) {
+        let msg = match serde_json::from_str::<InboundMessage>(text) {
+            Ok(msg) => msg,
+            Err(e) => {
+                tracing::warn!(?identity, "Failed to parse message: {e}");
+                return;
+            }
+        };
+
+        match msg.event.as_str() {
+            "heartbeat" => {
+                self.reply_ok(identity, msg.topic, msg.reference, serde_json::json!({}));
+                return;
+            }
+            "phx_join" => {
+                self.reply_ok(identity, msg.topic, msg.reference, serde_json::json!({}));
+                self.send_init(identity);
+                return;
+            }
+            _ => {}
+        }
+
+        match identity {
+            Identity::Client(id) => match msg.decode::<client_api::EgressMessages>() {
+                Ok(egress) => self.handle_client_message(id, msg.topic, msg.reference, egress),
+                Err(e) => tracing::warn!(?identity, event = %msg.event, "Unknown message: {e}"),
+            },
+            Identity::Gateway(id) => match msg.decode::<gateway_api::EgressMessages>() {
+                Ok(egress) => self.handle_gateway_message(id, msg.topic, msg.reference, egress),
+                Err(e) => tracing::warn!(?identity, event = %msg.event, "Unknown message: {e}"),
+            },
+        }
+    }
+
+    fn handle_client_message(
+        &mut self,
+        client_id: ClientId,
+        topic: String,
+        request_id: Option<OutboundRequestId>,
+        msg: client_api::EgressMessages,
+    ) {
+        let identity = Identity::Client(client_id);
+
+        match msg {
+            client_api::EgressMessages::PrepareConnection {
+                resource_id,
+                connected_gateway_ids,
+            } => {
+                // Prefer a gateway the client is already connected to.
+                let details = self
+                    .online_gateways_for(client_id, resource_id)
+                    .and_then(|ids| {
+                        ids.iter()
+                            .find(|id| connected_gateway_ids.contains(*id))
+                            .or(ids.first())
+                            .copied()
+                            .ok_or(ErrorReply::Offline)
+                    })
+                    .and_then(|gateway_id| {
+                        let gateway = self.gateways.get(&gateway_id).ok_or(ErrorReply::Offline)?;
+
+                        Ok(client_api::ConnectionDetails {
+                            resource_id,
+                            gateway_id,
+                            gateway_remote_ip: gateway.remote_ip,
+                            site_id: self.resource_site_id(resource_id),
+                        })
+                    });
+
+                match details {
+                    Ok(details) => {
+                        self.reply_ok(
+                            identity,
+                            topic,
+                            request_id,
+                            client_api::ReplyMessages::ConnectionDetails(details),
+                        );
+                    }
+                    Err(reason) => self.reply_error(identity, topic, request_id, reason),
+                }
+            }
+            client_api::EgressMessages::RequestConnection(req) => {
+                let resource = match self.authorize(client_id, req.resource_id, req.gateway_id) {
+                    Ok(resource) => resource,
+                    Err(reason) => return self.reply_error(identity, topic, request_id, reason),
+                };
+                let Some(client) = self.clients.get(&client_id) else {
+                    tracing::warn!(%client_id, "Dropping message from unknown client");
+                    return;
+                };
+                let peer = Peer {
+                    persistent_keepalive: Some(PERSISTENT_KEEPALIVE),
+                    public_key: client.public_key,
+                    ipv4: client.ipv4,
+                    ipv6: client.ipv6,
+                    preshared_key: req.client_preshared_key,
+                };
+
+                let reference = self.next_reference();
+                self.pending.insert(
+                    reference.clone(),
+                    PendingConnection {
+                        client_id,
+                        request_id,
+                        resource_id: req.resource_id,
+                        gateway_id: req.gateway_id,
+                    },
+                );
+
+                self.push(
+                    Identity::Gateway(req.gateway_id),
+                    "gateway",
+                    gateway_api::IngressMessages::RequestConnection(
+                        gateway_api::RequestConnection {
+                            resource,
+                            client: gateway_api::Client {
+                                id: client_id,
+                                payload: gateway_api::ClientPayload {
+                                    ice_parameters: req.client_payload.ice_parameters,
+                                    domain: req.client_payload.domain.map(map_response),
+                                },
+                                peer,
+                            },
+                            reference,
+                            expires_at: None,
+                        },
+                    ),
+                );
+            }
+            client_api::EgressMessages::ReuseConnection(req) => {
+                let resource = match self.authorize(client_id, req.resource_id, req.gateway_id) {
+                    Ok(resource) => resource,
+                    Err(reason) => return self.reply_error(identity, topic, request_id, reason),
+                };
+                let Some(client) = self.clients.get(&client_id) else {
+                    tracing::warn!(%client_id, "Dropping message from unknown client");
+                    return;
+                };
+                let (client_ipv4, client_ipv6) = (client.ipv4, client.ipv6);
+                let reference = self.next_reference();
+
+                // Without a domain to resolve, the gateway doesn't answer, so neither does the portal.
+                let awaits_gateway = req.payload.is_some();
+
+                self.push(
+                    Identity::Gateway(req.gateway_id),
+                    "gateway",
+                    gateway_api::IngressMessages::AllowAccess(gateway_api::AllowAccess {
+                        client_id,
+                        resource,
+                        expires_at: None,
+                        payload: req.payload.map(map_response),
+                        reference: reference.clone(),
+                        client_ipv4,
+                        client_ipv6,
+                    }),
+                );
+
+                if awaits_gateway {
+                    self.pending.insert(
+                        reference,
+                        PendingConnection {
+                            client_id,
+                            request_id,
+                            resource_id: req.resource_id,
+                            gateway_id: req.gateway_id,
+                        },
+                    );
+                }
+            }
+            client_api::EgressMessages::BroadcastIceCandidates(candidates) => {
+                for gateway_id in candidates.gateway_ids {
+                    self.push(
+                        Identity::Gateway(gateway_id),
+                        "gateway",
+                        gateway_api::IngressMessages::IceCandidates(
+                            gateway_api::ClientIceCandidates {
+                                client_id,
+                                candidates: Vec::from_iter(candidates.candidates.iter().cloned()),
+                            },
+                        ),
+                    );
+                }
+            }
+            client_api::EgressMessages::BroadcastInvalidatedIceCandidates(candidates) => {
+                for gateway_id in candidates.gateway_ids {
+                    self.push(
+                        Identity::Gateway(gateway_id),
+                        "gateway",
+                        gateway_api::IngressMessages::InvalidateIceCandidates(
+                            gateway_api::ClientIceCandidates {
+                                client_id,
+                                candidates: Vec::from_iter(candidates.candidates.iter().cloned()),
+                            },
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    fn handle_gateway_message(
+        &mut self,
+        gateway_id: GatewayId,
+        topic: String,
+        request_id: Option<OutboundRequestId>,
+        msg: gateway_api::EgressMessages,
+    ) {
+        match msg {
+            gateway_api::EgressMessages::ConnectionReady(ready) => {
+                self.reply_ok(
+                    Identity::Gateway(gateway_id),
+                    topic,
+                    request_id,
+                    serde_json::json!({}),
+                );
+
+                let Some(pending) = self.pending.remove(&ready.reference) else {
+                    tracing::warn!(%gateway_id, reference = %ready.reference, "Unknown connection reference");
+                    return;
+                };
+                if pending.gateway_id != gateway_id {
+                    tracing::warn!(%gateway_id, reference = %ready.reference, "Connection reference belongs to a different gateway");
+                    return;
+                }
+
+                let Some(gateway) = self.gateways.get(&gateway_id) else {
+                    return self.reply_error(
+                        Identity::Client(pending.client_id),
+                        "client",
+                        pending.request_id,
+                        ErrorReply::Offline,
+                    );
+                };
+                let gateway_public_key = gateway.public_key;
+
+                self.reply_ok(
+                    Identity::Client(pending.client_id),
+                    "client",
+                    pending.request_id,
+                    client_api::ReplyMessages::Connect(client_api::Connect {
+                        gateway_payload: ready.gateway_payload,
+                        resource_id: pending.resource_id,
+                        gateway_public_key,
+                        persistent_keepalive: u64::from(PERSISTENT_KEEPALIVE),
+                    }),
+                );
+            }
+            gateway_api::EgressMessages::BroadcastIceCandidates(candidates) => {
+                for client_id in candidates.client_ids {
+                    self.push(
+                        Identity::Client(client_id),
+                        "client",
+                        client_api::IngressMessages::IceCandidates(
+                            client_api::GatewayIceCandidates {
+                                gateway_id,
+                                candidates: Vec::from_iter(candidates.candidates.iter().cloned()),
+                            },
+                        ),
+                    );
+                }
+            }
+            gateway_api::EgressMessages::BroadcastInvalidatedIceCandidates(candidates) => {
+                for client_id in candidates.client_ids {
+                    self.push(
+                        Identity::Client(client_id),
+                        "client",
+                        client_api::IngressMessages::InvalidateIceCandidates(
+                            client_api::GatewayIceCandidates {
+                                gateway_id,
+                                candidates: Vec::from_iter(candidates.candidates.iter().cloned()),
+                            },
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    fn send_init(&self, identity: Identity) {
+        match identity {
+            Identity::Client(id) => {
+                let Some((client, session)) = self
+                    .config
+                    .clients
+                    .iter()
+                    .find(|c| client_id(&c.name) == id)
+                    .zip(self.clients.get(&id))
+                else {
+                    return;
+                };
+
+                let init = client_api::InitClient {
+                    interface: Interface {
+                        ipv4: session.ipv4,
+                        ipv6: session.ipv6,
+                        upstream_dns: self.config.upstream_dns.clone(),
+                        search_domains: Vec::new(),
+                    },
+                    resources: self
+                        .config
+                        .resources
+                        .iter()
+                        .filter(|r| self.is_allowed(&client.name, r.name()))
+                        .map(client_resource)
+                        .collect(),
+                    relays: self.relays(&id.to_string()),
+                };
+
+                self.push(identity, "client", client_api::IngressMessages::Init(init));
+            }
+            Identity::Gateway(id) => {
+                let Some(session) = self.gateways.get(&id) else {
+                    return;
+                };
+
+                let init = gateway_api::InitGateway {
+                    interface: Interface {
+                        ipv4: session.ipv4,
+                        ipv6: session.ipv6,
+                        upstream_dns: Vec::new(),
+                        search_domains: Vec::new(),
+                    },
+                    config: gateway_api::Config {
+                        ipv4_masquerade_enabled: true,
+                        ipv6_masquerade_enabled: true,
+                    },
+                    relays: self.relays(&id.to_string()),
+                };
+
+                self.push(
+                    identity,
+                    "gateway",
+                    gateway_api::IngressMessages::Init(init),
+                );
+            }
+        }
+    }
+
+    /// The online gateways that may serve `resource_id` to `client_id`, sorted by their ID.
+    fn online_gateways_for(
+        &self,
+        client_id: ClientId,
+        resource_id: ResourceId,
+    ) -> Result<Vec<GatewayId>, ErrorReply> {
+        let resource = self.allowed_resource(client_id, resource_id)?;
+
+        let mut gateways = self
+            .config
+            .gateways
+            .iter()
+            .filter(|g| g.site == resource.site())
+            .map(|g| gateway_id(&g.name))
+            .filter(|id| self.gateways.contains_key(id))
+            .collect::<Vec<_>>();
+        gateways.sort();
+
+        Ok(gateways)
+    }
+
+    /// Checks that `client_id` may access `resource_id` through `gateway_id` and returns the resource as the gateway sees it.
+    fn authorize(
+        &self,
+        client_id: ClientId,
+        resource_id: ResourceId,
+        gateway_id: GatewayId,
+    ) -> Result<gateway::ResourceDescription, ErrorReply> {
+        let resource = self.allowed_resource(client_id, resource_id)?;
+
+        if !self
+            .online_gateways_for(client_id, resource_id)?
+            .contains(&gateway_id)
+        {
+            return Err(ErrorReply::Offline);
+        }
+
+        Ok(gateway_resource(resource))
+    }
+
+    fn allowed_resource(
+        &self,
+        client_id: ClientId,
+        resource_id: ResourceId,
+    ) -> Result<&config::Resource, ErrorReply> {
+        let client = self
+            .config
+            .clients
+            .iter()
+            .find(|c| self::client_id(&c.name) == client_id)
+            .ok_or(ErrorReply::NotFound)?;
+
+        self.config
+            .resources
+            .iter()
+            .find(|r| self::resource_id(r.name()) == resource_id)
+            .filter(|r| self.is_allowed(&client.name, r.name()))
+            .ok_or(ErrorReply::NotFound)
+    }
+
+    fn resource_site_id(&self, resource_id: ResourceId) -> client::SiteId {
+        let resource = self
+            .config
+            .resources
+            .iter()
+            .find(|r| self::resource_id(r.name()) == resource_id)
+            .expect("resource was authorized before");
+
+        site_id(resource.site())
+    }
+
+    fn is_allowed(&self, client: &str, resource: &str) -> bool {
+        self.config
+            .policies
+            .iter()
+            .any(|p| p.client == client && p.resource == resource)
+    }
+
+    /// TURN credentials for all relays, salted with the ID of the component that receives them.
+    fn relays(&self, username_salt: &str) -> Vec<Relay> {
+        let expiry = SystemTime::now() + RELAY_CREDENTIALS_VALIDITY;
+
+        self.config
+            .relays
+            .iter()
+            .flat_map(|relay| {
+                let id = RelayId::from_u128(stable_id("relay", &format!("{:?}", relay.addrs)));
+                let (username, password) = firezone_relay::auth::generate_credentials(
+                    &SecretString::new(relay.secret.clone()),
+                    expiry,
+                    username_salt,
+                );
+
+                relay.addrs.iter().map(move |addr| {
+                    Relay::Turn(Turn {
+                        id,
+                        expires_at: DateTime::<Utc>::from(expiry),
+                        addr: *addr,
+                        username: username.clone(),
+                        password: password.clone(),
+                    })
+                })
+            })
+            .collect()
+    }
+
+    fn next_reference(&mut self) -> String {
+        self.next_reference += 1;
+
+        self.next_reference.to_string()
+    }
+
+    fn push(&self, identity: Identity, topic: &str, msg: impl Serialize) {
+        self.send(
+            identity,
+            &PhoenixMessage::<_, ()>::new_message(topic, msg, None),
+        );
+    }
+
+    fn reply_ok(
+        &self,
+        identity: Identity,
+        topic: impl Into<String>,
+        request_id: Option<OutboundRequestId>,
+        res: impl Serialize,
+    ) {
+        self.send(
+            identity,
+            &PhoenixMessage::<(), _>::new_ok_reply(topic, res, request_id),
+        );
+    }
+
+    fn reply_error(
+        &self,
+        identity: Identity,
+        topic: impl Into<String>,
+        request_id: Option<OutboundRequestId>,
+        reason: ErrorReply,
+    ) {
+        self.send(
+            identity,
+            &PhoenixMessage::<(), ()>::new_err_reply(topic, reason, request_id),
+        );
+    }
+
+    fn send(&self, identity: Identity, msg: &impl Serialize) {
+        let session = match identity {
+            Identity::Client(id) => self.clients.get(&id),
+            Identity::Gateway(id) => self.gateways.get(&id),
+        };
+        let Some(session) = session else {
+            tracing::debug!(?identity, "Dropping message for disconnected component");
+            return;
+        };
+
+        let text =
+            serde_json::to_string(msg).expect("we should always be able to serialize a message");
+        let _ = session.outbound.send(text);
+    }
+}
+
+fn remove_session<K>(sessions: &mut HashMap<K, Session>, id: K, connection: u64) -> bool
+where
+    K: std::hash::Hash + Eq,
+{
+    if sessions
+        .get(&id)
+        .is_some_and(|s| s.connection == connection)
+    {
+        sessions.remove(&id);
+        return true;
+    }
+
+    false
+}
+
+fn client_resource(resource: &config::Resource) -> client::ResourceDescription {
+    let id = resource_id(resource.name());
+    let sites = vec![client::Site {
+        id: site_id(resource.site()),
+        name: resource.site().to_owned(),
+    }];
+
+    match resource {
+        config::Resource::Cidr { name, address, .. } => {
+            client::ResourceDescription::Cidr(client::ResourceDescriptionCidr {
+                id,
+                address: *address,
+                name: name.clone(),
+                address_description: None,
+                sites,
+            })
+        }
+        config::Resource::Dns { name, address, .. } => {
+            client::ResourceDescription::Dns(client::ResourceDescriptionDns {
+                id,
+                address: address.clone(),
+                name: name.clone(),
+                address_description: None,
+                sites,
+            })
+        }
+        config::Resource::Internet { name, .. } => {
+            client::ResourceDescription::Internet(client::ResourceDescriptionInternet {
+                name: name.clone(),
+                id,
+                sites,
+            })
+        }
+    }
+}
+
+fn gateway_resource(resource: &config::Resource) -> gateway::ResourceDescription {
+    let id = resource_id(resource.name());
+
+    match resource {
+        config::Resource::Cidr {
+            name,
+            address,
+            filters,
+            ..
+        } => gateway::ResourceDescription::Cidr(gateway::ResourceDescriptionCidr {
+            id,
+            address: *address,
+            name: name.clone(),
+            filters: filters.clone(),
+        }),
+        config::Resource::Dns {
+            name,
+            address,
+            filters,
+            ..
+        } => gateway::ResourceDescription::Dns(gateway::ResourceDescriptionDns {
+            id,
+            address: address.clone(),
+            name: name.clone(),
+            filters: filters.clone(),
+        }),
+        config::Resource::Internet { .. } => {
+            gateway::ResourceDescription::Internet(gateway::ResourceDescriptionInternet { id })
+        }
+    }
+}
+
+fn map_response(req: connlib_shared::messages::ResolveRequest) -> gateway_api::ResolveRequest {
+    gateway_api::ResolveRequest::MapResponse {
+        name: req.name,
+        proxy_ips: req.proxy_ips,
+    }
+}
+
+pub fn client_id(name: &str) -> ClientId {
+    ClientId::from_u128(stable_id("client", name))
+}
+
+pub fn gateway_id(name: &str) -> GatewayId {
+    GatewayId::from_u128(stable_id("gateway", name))
+}
+
+pub fn resource_id(name: &str) -> ResourceId {
+    ResourceId::from_u128(stable_id("resource", name))
+}
+
+pub fn site_id(name: &str) -> client::SiteId {
+    client::SiteId::from_u128(stable_id("site", name))
+}
+
+/// Derives an ID from a name so it stays the same across restarts of the emulator.
+fn stable_id(kind: &str, name: &str) -> u128 {
+    let hash = sha2::Sha256::digest(format!("{kind}:{name}"));
+
+    u128::from_be_bytes(hash[..16].try_into().expect("SHA256 hashes are 32 bytes"))
+}
+
+fn nth_ipv4(base: Ipv4Addr, index: usize) -> Ipv4Addr {
+    Ipv4Addr::from(u32::from(base) + index as u32 + 1)
+}
+
+fn nth_ipv6(base: Ipv6Addr, index: usize) -> Ipv6Addr {
+    Ipv6Addr::from(u128::from(base) + index as u128 + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use connlib_shared::messages::{
+        Answer, ClientPayload, ConnectionAccepted, GatewayResponse, Offer, RequestConnection,
+        SecretKey,
+    };
+
+    const CONFIG: &str = r#"
+sites:
+  - name: office
+gateways:
+  - name: gateway-1
+    site: office
+    token: gateway-token
+clients:
+  - name: ci
+    token: client-token
+  - name: intern
+    token: intern-token
+resources:
+  - type: cidr
+    name: intranet
+    address: 172.20.0.0/16
+    site: office
+  - type: internet
+    name: internet
+    site: office
+policies:
+  - client: ci
+    resource: intranet
+relays:
+  - addrs: ["172.28.0.101:3478"]
+    secret: a-long-random-string
+"#;
+
+    #[test]
+    fn authenticates_by_token() {
+        let portal = portal();
+
+        assert_eq!(
+            portal.authenticate(Role::Client, "client-token"),
+            Some(Identity::Client(client_id("ci")))
+        );
+        assert_eq!(
+            portal.authenticate(Role::Gateway, "gateway-token"),
+            Some(Identity::Gateway(gateway_id("gateway-1")))
+        );
+        assert_eq!(portal.authenticate(Role::Gateway, "client-token"), None);
+    }
+
+    #[test]
+    fn init_contains_only_allowed_resources() {
+        let mut portal = portal();
+        let (ci, mut ci_rx) = connect(&mut portal, Identity::Client(client_id("ci")));
+        let (intern, mut intern_rx) = connect(&mut portal, Identity::Client(client_id("intern")));
+
+        join(&mut portal, ci, "client");
+        join(&mut portal, intern, "client");
+
+        let client_api::IngressMessages::Init(init) = next_push(&mut ci_rx) else {
+            panic!("expected init");
+        };
+        assert_eq!(init.interface.ipv4, Ipv4Addr::new(100, 64, 0, 1));
+        assert_eq!(init.resources.len(), 1);
+        assert_eq!(init.resources[0].id(), resource_id("intranet"));
+        assert!(matches!(&init.relays[..], [Relay::Turn(turn)] if turn.addr.port() == 3478));
+
+        let client_api::IngressMessages::Init(init) = next_push(&mut intern_rx) else {
+            panic!("expected init");
+        };
+        assert_eq!(init.interface.ipv4, Ipv4Addr::new(100, 64, 0, 2));
+        assert!(init.resources.is_empty());
+    }
+
+    #[test]
+    fn prepare_connection_fails_while_gateway_is_offline() {
+        let mut portal = portal();
+        let (client, mut client_rx) = connect(&mut portal, Identity::Client(client_id("ci")));
+
+        portal.handle_message(client, &prepare_connection("intranet"));
+
+        let reply = next_message(&mut client_rx);
+        assert_eq!(reply["payload"]["status"], "error");
+        assert_eq!(reply["payload"]["response"]["reason"], "offline");
+    }
+
+    #[test]
+    fn prepare_connection_fails_for_resource_without_policy() {
+        let mut portal = portal();
+        let (client, mut client_rx) = connect(&mut portal, Identity::Client(client_id("ci")));
+        let _gateway = connect(&mut portal, Identity::Gateway(gateway_id("gateway-1")));
+
+        portal.handle_message(client, &prepare_connection("internet"));
+
+        let reply = next_message(&mut client_rx);
+        assert_eq!(reply["payload"]["response"]["reason"], "not_found");
+    }
+
+    #[test]
+    fn relays_connection_request_between_client_and_gateway() {
+        let mut portal = portal();
+        let (client, mut client_rx) = connect(&mut portal, Identity::Client(client_id("ci")));
+        let (gateway, mut gateway_rx) =
+            connect(&mut portal, Identity::Gateway(gateway_id("gateway-1")));
+
+        portal.handle_message(client, &prepare_connection("intranet"));
+        let reply = next_message(&mut client_rx);
+        assert_eq!(
+            reply["payload"]["response"]["gateway_id"],
+            gateway_id("gateway-1").to_string()
+        );
+
+        let request = client_api::EgressMessages::RequestConnection(RequestConnection {
+            gateway_id: gateway_id("gateway-1"),
+            resource_id: resource_id("intranet"),
+            client_preshared_key: SecretKey::new(Key([1; 32])),
+            client_payload: ClientPayload {
+                ice_parameters: Offer {
+                    username: "user".to_owned(),
+                    password: "pass".to_owned(),
+                },
+                domain: None,
+            },
+        });
+        portal.handle_message(client, &message("client", request, 2));
+
+        let gateway_api::IngressMessages::RequestConnection(forwarded) = next_push(&mut gateway_rx)
+        else {
+            panic!("expected request_connection");
+        };
+        assert_eq!(forwarded.client.id, client_id("ci"));
+        assert_eq!(forwarded.client.peer.public_key, Key([0; 32]));
+        assert_eq!(forwarded.client.peer.ipv4, Ipv4Addr::new(100, 64, 0, 1));
+
+        let ready = gateway_api::EgressMessages::ConnectionReady(gateway_api::ConnectionReady {
+            reference: forwarded.reference,
+            gateway_payload: GatewayResponse::ConnectionAccepted(ConnectionAccepted {
+                ice_parameters: Answer {
+                    username: "gateway".to_owned(),
+                    password: "pass".to_owned(),
+                },
+                domain_response: None,
+            }),
+        });
+        portal.handle_message(gateway, &message("gateway", ready, 1));
+
+        assert_eq!(next_message(&mut gateway_rx)["payload"]["status"], "ok");
+        let reply = next_message(&mut client_rx);
+        assert_eq!(reply["ref"], 2);
+        assert_eq!(
+            reply["payload"]["response"]["resource_id"],
+            resource_id("intranet").to_string()
+        );
+        assert!(client_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn reconnect_is_not_removed_by_stale_disconnect() {
+        let mut portal = portal();
+        let identity = Identity::Gateway(gateway_id("gateway-1"));
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let first = portal.connect(identity, Key([0; 32]), IpAddr::from([127, 0, 0, 1]), tx);
+        let (tx, _rx2) = mpsc::unbounded_channel();
+        let _second = portal.connect(identity, Key([0; 32]), IpAddr::from([127, 0, 0, 1]), tx);
+
+        portal.disconnect(identity, first);
+
+        assert!(portal.gateways.contains_key(&gateway_id("gateway-1")));
+    }
+
+    fn portal() -> Portal {
+        Portal::new(Config::parse(CONFIG).unwrap())
+    }
+
+    fn connect(
+        portal: &mut Portal,
+        identity: Identity,
+    ) -> (Identity, mpsc::UnboundedReceiver<String>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        portal.connect(identity, Key([0; 32]), IpAddr::from([127, 0, 0, 1]), tx);
+
+        (identity, rx)
+    }
+
+    fn join(portal: &mut Portal, identity: Identity, topic: &str) {
+        portal.handle_message(
+            identity,
+            &format!(r#"{{"topic":"{topic}","event":"phx_join","payload":{{}},"ref":0}}"#),
+        );
+    }
+
+    fn prepare_connection(resource: &str) -> String {
+        message(
+            "client",
+            client_api::EgressMessages::PrepareConnection {
+                resource_id: resource_id(resource),
+                connected_gateway_ids: Default::default(),
+            },
+            1,
+        )
+    }
+
+    fn message(topic: &str, msg: impl Serialize, id: u64) -> String {
+        serde_json::to_string(&PhoenixMessage::<_, ()>::new_message(
+            topic,
+            msg,
+            Some(OutboundRequestId::for_test(id)),
+        ))
+        .unwrap()
+    }
+
+    /// Returns the next message that isn't a reply to a `phx_join`.
+    fn next_message(rx: &mut mpsc::UnboundedReceiver<String>) -> serde_json::Value {
+        loop {
+            let msg = serde_json::from_str::<serde_json::Value>(&rx.try_recv().unwrap()).unwrap();
+
+            if msg["ref"] != 0 {
+                return msg;
+            }
+        }
+    }
+
+    fn next_push<T>(rx: &mut mpsc::UnboundedReceiver<String>) -> T
+    where
+        T: DeserializeOwned,
+    {
+        serde_json::from_value(next_message(rx)).unwrap()
+    }
+}
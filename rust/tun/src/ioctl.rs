@@ -40,6 +40,14 @@ impl Request<SetTunFlagsPayload> {
             },
         }
     }
+
+    /// Requests a new queue of a multi-queue device.
+    ///
+    /// All queues of a device must be opened with this flag.
+    pub fn with_multi_queue(mut self) -> Self {
+        self.payload.flags |= libc::IFF_MULTI_QUEUE as std::ffi::c_short;
+        self
+    }
 }
 
 impl Request<GetInterfaceNamePayload> {
@@ -21,6 +21,7 @@ class NetworkSettings {
   public var tunnelAddressIPv4: String?
   public var tunnelAddressIPv6: String?
   public var dnsAddresses: [String] = []
+  public var searchDomains: [String] = []
   public var routes4: [NEIPv4Route] = []
   public var routes6: [NEIPv6Route] = []
   public var matchDomains: [String] = [""]
@@ -46,6 +47,7 @@ class NetworkSettings {
     ipv6Settings.includedRoutes = routes6
     dnsSettings.matchDomains = matchDomains
     dnsSettings.matchDomainsNoSearch = true
+    dnsSettings.searchDomains = searchDomains
     tunnelNetworkSettings.ipv4Settings = ipv4Settings
     tunnelNetworkSettings.ipv6Settings = ipv6Settings
     tunnelNetworkSettings.dnsSettings = dnsSettings